@@ -0,0 +1,22 @@
+//! Embeds the git commit the crate is built from as `CRABBY_GIT_HASH`, for
+//! VERSION and `--version`; builds outside a git checkout go without it
+
+use std::process::Command;
+
+fn main() {
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|hash| hash.trim().to_string())
+        .filter(|hash| !hash.is_empty());
+    if let Some(hash) = hash {
+        println!("cargo:rustc-env=CRABBY_GIT_HASH={}", hash);
+    }
+    // A new commit or checkout moves HEAD or the branch it points at
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+    println!("cargo:rerun-if-changed=.git/packed-refs");
+}
@@ -0,0 +1,75 @@
+/*
+ * C API for embedding the crabby_kv store.
+ *
+ * Build the library with `cargo build --release --features ffi` and link
+ * target/release/libmulti_threader.a together with:
+ *     -lpthread -ldl -lm -lrt -lutil
+ *
+ * Ownership:
+ *   - crabby_open returns an engine owned by the caller; release it with
+ *     crabby_close exactly once. Never use the pointer afterwards.
+ *   - Strings passed in are borrowed for the duration of the call only.
+ *   - Output is copied into the caller's buffer; the library never hands
+ *     out pointers to its own memory.
+ *
+ * A Rust panic never crosses into C: it is reported as CRABBY_ERR_PANIC.
+ */
+#ifndef CRABBY_H
+#define CRABBY_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Status codes returned by crabby_execute */
+#define CRABBY_OK 0
+/* A required pointer argument was NULL */
+#define CRABBY_ERR_NULL_ARG (-1)
+/* The command line is not valid UTF-8 */
+#define CRABBY_ERR_INVALID_UTF8 (-2)
+/* The line is not a valid command; out_buf holds the parse error */
+#define CRABBY_ERR_PARSE (-3)
+/* Expected miss (e.g. GET on a missing key); out_buf holds the message */
+#define CRABBY_ERR_SOFT (-4)
+/* The command failed (e.g. wrong type); out_buf holds the message */
+#define CRABBY_ERR_HARD (-5)
+/* out_buf was too small; it holds the truncated, NUL-terminated text */
+#define CRABBY_ERR_BUFFER_TOO_SMALL (-6)
+/* The engine has stopped and can no longer run commands */
+#define CRABBY_ERR_STOPPED (-7)
+/* Rust code panicked; the engine should be closed */
+#define CRABBY_ERR_PANIC (-8)
+
+typedef struct CrabbyEngine CrabbyEngine;
+
+typedef struct CrabbyOptions {
+    /* Number of keys the store pre-allocates room for */
+    size_t store_initial_capacity;
+} CrabbyOptions;
+
+/*
+ * Start an engine. `options` may be NULL for defaults.
+ * Returns NULL if the engine could not be started.
+ */
+CrabbyEngine *crabby_open(const CrabbyOptions *options);
+
+/*
+ * Run one NUL-terminated command line, blocking until it completes.
+ *
+ * The response (or error message) is written to `out_buf`, NUL-terminated,
+ * when `out_buf` is non-NULL; `out_len` is its capacity in bytes.
+ * Returns one of the CRABBY_* status codes above.
+ */
+int32_t crabby_execute(CrabbyEngine *engine, const char *line, char *out_buf, size_t out_len);
+
+/* Stop the engine and free it. Passing NULL is a no-op. */
+void crabby_close(CrabbyEngine *engine);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CRABBY_H */
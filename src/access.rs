@@ -0,0 +1,184 @@
+//! Per-key insertion times and read counts, for INSPECT
+//!
+//! The log mirrors the store's keyspace: commands note keys they create,
+//! move, or remove, and the handler notes every successful read. It keeps a
+//! copy of each key, so it is off unless `TRACK_KEY_ACCESS` asks for it,
+//! and every method is then a no-op.
+//!
+//! Keys the log never saw created (restored from a snapshot, or written by
+//! a custom command) are picked up on their first read, with no insertion
+//! time.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// What the log knows about one key
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyAccess {
+    /// When the key was created, if the log saw it happen
+    pub inserted_at: Option<Instant>,
+    /// When a command last read the key
+    pub last_access: Option<Instant>,
+    /// Successful reads since the key was created
+    pub accesses: u64,
+}
+
+impl KeyAccess {
+    fn new(inserted_at: Option<Instant>) -> Self {
+        Self {
+            inserted_at,
+            last_access: None,
+            accesses: 0,
+        }
+    }
+
+    /// Time since the key was created, as of `now`
+    pub fn inserted_age(&self, now: Instant) -> Option<Duration> {
+        self.inserted_at.map(|at| now.saturating_duration_since(at))
+    }
+
+    /// Time since the key was last read, as of `now`
+    pub fn last_access_age(&self, now: Instant) -> Option<Duration> {
+        self.last_access.map(|at| now.saturating_duration_since(at))
+    }
+}
+
+/// Insertion time and reads of every key, when enabled
+#[derive(Debug, Clone, Default)]
+pub struct KeyAccessLog {
+    enabled: bool,
+    keys: HashMap<String, KeyAccess>,
+}
+
+impl KeyAccessLog {
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled,
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Note a write to `key`; starts its clock if it didn't exist
+    ///
+    /// Call before the store insert, with `existed` saying whether the key
+    /// was already there: overwriting a key doesn't make it new.
+    pub fn written(&mut self, key: &str, existed: bool) {
+        if !self.enabled {
+            return;
+        }
+        if !existed || !self.keys.contains_key(key) {
+            let inserted_at = (!existed).then(Instant::now);
+            self.keys
+                .insert(key.to_string(), KeyAccess::new(inserted_at));
+        }
+    }
+
+    /// Note that `key` left the store
+    pub fn removed(&mut self, key: &str) {
+        if self.enabled {
+            self.keys.remove(key);
+        }
+    }
+
+    /// Note values that moved, each `(from, to)`, keeping their history
+    ///
+    /// Every source is taken out before any destination is filled, so a
+    /// destination may be another move's source.
+    pub fn renamed<'a>(&mut self, moves: impl IntoIterator<Item = (&'a str, &'a str)>) {
+        if !self.enabled {
+            return;
+        }
+        let moved: Vec<(&str, Option<KeyAccess>)> = moves
+            .into_iter()
+            .map(|(from, to)| (to, self.keys.remove(from)))
+            .collect();
+        for (to, access) in moved {
+            match access {
+                Some(access) => self.keys.insert(to.to_string(), access),
+                None => self.keys.remove(to),
+            };
+        }
+    }
+
+    /// Note a successful read of `key`
+    pub fn read(&mut self, key: &str) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let access = match self.keys.get_mut(key) {
+            Some(access) => access,
+            None => self
+                .keys
+                .entry(key.to_string())
+                .or_insert(KeyAccess::new(None)),
+        };
+        access.last_access = Some(now);
+        access.accesses += 1;
+    }
+
+    /// What the log knows about `key`, if anything
+    pub fn get(&self, key: &str) -> Option<&KeyAccess> {
+        self.keys.get(key)
+    }
+
+    /// Forget every key, as when the store is replaced wholesale
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_log_follows_writes_reads_and_renames() {
+        let mut log = KeyAccessLog::new(true);
+        log.written("a", false);
+        let inserted = log.get("a").unwrap().inserted_at;
+        assert!(inserted.is_some());
+
+        log.read("a");
+        log.read("a");
+        // Overwriting keeps the insertion time and the reads
+        log.written("a", true);
+        let access = *log.get("a").unwrap();
+        assert_eq!(access.inserted_at, inserted);
+        assert_eq!(access.accesses, 2);
+        assert!(access.last_access.is_some());
+
+        log.renamed([("a", "b")]);
+        assert_eq!(log.get("a"), None);
+        assert_eq!(log.get("b"), Some(&access));
+
+        // Chained moves, as RENAMEPREFIX a -> aa makes of b -> ab -> aab
+        log.written("ab", false);
+        log.read("ab");
+        log.renamed([("b", "ab"), ("ab", "aab")]);
+        assert_eq!(log.get("ab"), Some(&access));
+        assert_eq!(log.get("aab").unwrap().accesses, 1);
+
+        log.removed("ab");
+        assert_eq!(log.get("ab"), None);
+
+        // A key created behind the log's back has no insertion time
+        log.read("restored");
+        let access = log.get("restored").unwrap();
+        assert_eq!(access.inserted_at, None);
+        assert_eq!(access.accesses, 1);
+    }
+
+    #[test]
+    fn test_disabled_log_records_nothing() {
+        let mut log = KeyAccessLog::default();
+        assert!(!log.is_enabled());
+        log.written("a", false);
+        log.read("a");
+        assert_eq!(log.get("a"), None);
+    }
+}
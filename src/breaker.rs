@@ -0,0 +1,155 @@
+//! Circuit breaker on the parse-error rate (`ABORT_IF_PARSE_ERROR_RATE`,
+//! `PARSE_ERROR_WINDOW`)
+//!
+//! Input in the wrong format fails to parse line after line, and a run would
+//! otherwise grind through all of it. Every IO thread records each line it
+//! parses, and once more than the allowed share of the last `window` lines
+//! failed, the breaker trips: input stops being read, lines already taken
+//! are dropped unparsed, and the commands already parsed are applied before
+//! the run ends.
+//!
+//! The window is a ring of slots, one per line, updated with atomics so IO
+//! threads never wait on each other. Each parsed line takes a ticket and
+//! lands in slot `ticket % window`, replacing the line `window` tickets
+//! before it; the error count is adjusted by the difference.
+
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize, Ordering};
+
+/// The parse outcomes of the last `window` lines, shared by the IO threads
+#[derive(Debug)]
+pub struct ParseErrorBreaker {
+    max_rate: f64,
+    /// Each holds `(ticket + 1) << 1 | failed`, or 0 before its first line
+    slots: Box<[AtomicUsize]>,
+    next_ticket: AtomicUsize,
+    /// Failed lines among the slots; briefly negative while two threads
+    /// race on one slot
+    errors: AtomicIsize,
+    tripped: AtomicBool,
+}
+
+impl ParseErrorBreaker {
+    /// Trip once more than `max_rate` of the last `window` parsed lines
+    /// failed; nothing is judged until `window` lines have been parsed
+    pub fn new(window: usize, max_rate: f64) -> Self {
+        assert!(
+            window > 0,
+            "the parse error window must hold at least one line"
+        );
+        Self {
+            max_rate,
+            slots: (0..window).map(|_| AtomicUsize::new(0)).collect(),
+            next_ticket: AtomicUsize::new(0),
+            errors: AtomicIsize::new(0),
+            tripped: AtomicBool::new(false),
+        }
+    }
+
+    /// Lines the rate is measured over
+    pub fn window(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// The highest share of failed lines allowed in a full window
+    pub fn max_rate(&self) -> f64 {
+        self.max_rate
+    }
+
+    /// Failed lines among the last `window` parsed
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed).max(0) as usize
+    }
+
+    /// Whether the rate has been exceeded; stays set for the rest of the run
+    pub fn is_tripped(&self) -> bool {
+        self.tripped.load(Ordering::Relaxed)
+    }
+
+    /// Record whether a line failed to parse, returning `true` for the one
+    /// call that trips the breaker
+    pub fn record(&self, failed: bool) -> bool {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let entry = (ticket + 1) << 1 | usize::from(failed);
+        let slot = &self.slots[ticket % self.slots.len()];
+        let mut current = slot.load(Ordering::Relaxed);
+        loop {
+            // A thread that stalled past a whole window has nothing to add
+            if current >> 1 > ticket + 1 {
+                return false;
+            }
+            match slot.compare_exchange_weak(current, entry, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+        let delta = usize::from(failed) as isize - (current & 1) as isize;
+        let errors = self.errors.fetch_add(delta, Ordering::Relaxed) + delta;
+
+        let window = self.slots.len();
+        ticket + 1 >= window
+            && errors as f64 > self.max_rate * window as f64
+            && !self.tripped.swap(true, Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_trips_only_once_a_full_window_is_over_the_rate() {
+        let breaker = ParseErrorBreaker::new(4, 0.5);
+        // Three failures, but the window isn't full yet
+        for _ in 0..3 {
+            assert!(!breaker.record(true));
+        }
+        assert!(!breaker.is_tripped());
+        assert!(breaker.record(false));
+        assert!(breaker.is_tripped());
+        assert_eq!(breaker.errors(), 3);
+        // Only the tripping call reports it
+        assert!(!breaker.record(true));
+    }
+
+    #[test]
+    fn test_window_slides_past_old_failures() {
+        let breaker = ParseErrorBreaker::new(4, 0.5);
+        // Exactly half failing is allowed
+        for failed in [true, true, false, false] {
+            assert!(!breaker.record(failed));
+        }
+        assert_eq!(breaker.errors(), 2);
+        for _ in 0..4 {
+            assert!(!breaker.record(false));
+        }
+        assert_eq!(breaker.errors(), 0);
+        assert!(!breaker.record(true));
+        assert!(!breaker.record(true));
+        assert!(breaker.record(true));
+    }
+
+    #[test]
+    fn test_counts_agree_across_threads() {
+        let breaker = Arc::new(ParseErrorBreaker::new(64, 1.0));
+        std::thread::scope(|scope| {
+            for _ in 0..4 {
+                let breaker = Arc::clone(&breaker);
+                scope.spawn(move || {
+                    for line in 0..10_000 {
+                        breaker.record(line % 4 == 0);
+                    }
+                });
+            }
+        });
+        // Once the threads are done, the running count matches the failures
+        // the slots hold
+        let failed = breaker
+            .slots
+            .iter()
+            .filter(|slot| slot.load(Ordering::Relaxed) & 1 == 1)
+            .count();
+        assert_eq!(breaker.errors(), failed);
+        assert!(!breaker.is_tripped());
+    }
+}
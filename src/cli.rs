@@ -0,0 +1,419 @@
+//! Command-line argument parsing for the binary
+//!
+//! Runtime tuning lives in environment variables (see `config.rs`); flags are
+//! reserved for choosing what the process does on this particular run.
+//!
+//! ```text
+//! multi_threader [--record <session>] [--dry-run] [--save-on-exit] [--show-trace-ids] [--import-report] [FILTER FLAGS] [OUTPUT FLAGS]
+//! multi_threader --dir <path> [--glob <pattern>] [--fail-fast] [RUN FLAGS]
+//! multi_threader replay <session> [--speed <factor> | --as-fast-as-possible] [--record <session>] [--dry-run] [--save-on-exit] [--show-trace-ids] [--import-report] [FILTER FLAGS] [OUTPUT FLAGS]
+//! multi_threader inspect <snapshot> [--keys <glob>] [--get <key>]
+//! multi_threader selftest
+//!
+//! FILTER FLAGS (comma-separated command names):
+//!   --only <commands>   --skip <commands>
+//!
+//! OUTPUT FLAGS (a path, or `-` for the standard stream):
+//!   --responses-out <path>   --errors-out <path>   --summary-out <path>
+//! ```
+
+use std::path::PathBuf;
+
+use crate::filter::CommandFilter;
+use crate::output::OutputOptions;
+use crate::record::Pacing;
+
+/// What the binary should do on this run
+#[derive(Debug, Clone, PartialEq)]
+pub enum Mode {
+    /// Process the commands in `input.txt`
+    Run,
+    /// Process every file in a directory whose name matches `glob`, one
+    /// after another in name order, stopping after the first file with hard
+    /// errors if `fail_fast`
+    Dir {
+        path: PathBuf,
+        glob: String,
+        fail_fast: bool,
+    },
+    /// Feed a recorded session back through the pipeline
+    Replay { path: PathBuf, pacing: Pacing },
+    /// Print a snapshot's header, matching keys, or one value, then exit
+    Inspect {
+        path: PathBuf,
+        keys: Option<String>,
+        get: Option<String>,
+    },
+    /// Run the built-in workload against every command and report PASS/FAIL
+    Selftest,
+    /// Print the version banner, then exit
+    Version,
+}
+
+/// Parsed command-line options
+#[derive(Debug, Clone, PartialEq)]
+pub struct CliOptions {
+    pub mode: Mode,
+    /// Record every accepted command into this session file
+    pub record: Option<PathBuf>,
+    /// Destinations for responses, errors, and the summary
+    pub output: OutputOptions,
+    /// Validate the input against a shadow copy of the store, keeping nothing
+    pub dry_run: bool,
+    /// Write a snapshot to `SNAPSHOT_PATH` when the run ends
+    pub save_on_exit: bool,
+    /// Prefix responses and errors with trace ids, generating missing ones
+    pub show_trace_ids: bool,
+    /// Command types kept from the main thread (`--only`, `--skip`)
+    pub filter: CommandFilter,
+    /// Summarize how much SET-like writes overwrote
+    pub import_report: bool,
+    /// Write structured run events here as newline-delimited JSON
+    pub events: Option<PathBuf>,
+}
+
+impl CliOptions {
+    /// Parse options from the arguments following the program name
+    pub fn parse<I>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter().peekable();
+        let mut record = None;
+        let mut output = OutputOptions::default();
+        let mut replay_path = None;
+        let mut pacing = Pacing::Speed(1.0);
+        let mut dry_run = false;
+        let mut save_on_exit = false;
+        let mut show_trace_ids = false;
+        let mut filter = CommandFilter::new();
+        let mut import_report = false;
+        let mut dir = None;
+        let mut glob = None;
+        let mut fail_fast = false;
+        let mut events = None;
+
+        if args.peek().map(String::as_str) == Some("inspect") {
+            args.next();
+            return Self::parse_inspect(args);
+        }
+
+        for (keyword, mode) in [("selftest", Mode::Selftest), ("--version", Mode::Version)] {
+            if args.peek().map(String::as_str) != Some(keyword) {
+                continue;
+            }
+            args.next();
+            if let Some(arg) = args.next() {
+                return Err(format!("Unknown argument for {}: {}", keyword, arg));
+            }
+            return Ok(Self {
+                mode,
+                record: None,
+                output: OutputOptions::default(),
+                dry_run: false,
+                save_on_exit: false,
+                show_trace_ids: false,
+                filter: CommandFilter::new(),
+                import_report: false,
+                events: None,
+            });
+        }
+
+        if args.peek().map(String::as_str) == Some("replay") {
+            args.next();
+            match args.next() {
+                Some(path) if !path.starts_with("--") => replay_path = Some(PathBuf::from(path)),
+                _ => return Err("replay requires a session file path".to_string()),
+            }
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--record" => {
+                    let path = args.next().ok_or("--record requires a file path")?;
+                    record = Some(PathBuf::from(path));
+                }
+                "--events-out" => {
+                    let path = args.next().ok_or("--events-out requires a file path")?;
+                    events = Some(PathBuf::from(path));
+                }
+                "--dry-run" => dry_run = true,
+                "--save-on-exit" => save_on_exit = true,
+                "--show-trace-ids" => show_trace_ids = true,
+                "--import-report" => import_report = true,
+                "--dir" if replay_path.is_none() => {
+                    let path = args.next().ok_or("--dir requires a directory path")?;
+                    dir = Some(PathBuf::from(path));
+                }
+                "--glob" => glob = Some(args.next().ok_or("--glob requires a file name pattern")?),
+                "--fail-fast" => fail_fast = true,
+                "--only" | "--skip" => {
+                    let spec = args
+                        .next()
+                        .ok_or_else(|| format!("{} requires a list of commands", arg))?;
+                    filter = if arg == "--only" {
+                        filter.only(&spec)?
+                    } else {
+                        filter.skip(&spec)?
+                    };
+                }
+                "--responses-out" | "--errors-out" | "--summary-out" => {
+                    let path = args
+                        .next()
+                        .ok_or_else(|| format!("{} requires a file path or '-'", arg))?;
+                    // `-` selects the standard stream, which is the default
+                    let destination = (path != "-").then(|| PathBuf::from(path));
+                    match arg.as_str() {
+                        "--responses-out" => output.responses = destination,
+                        "--errors-out" => output.errors = destination,
+                        _ => output.summary = destination,
+                    }
+                }
+                "--speed" if replay_path.is_some() => {
+                    let raw = args.next().ok_or("--speed requires a factor")?;
+                    match raw.parse::<f64>() {
+                        Ok(speed) if speed > 0.0 && speed.is_finite() => {
+                            pacing = Pacing::Speed(speed)
+                        }
+                        _ => {
+                            return Err(format!(
+                                "Invalid --speed '{}': expected a positive number",
+                                raw
+                            ));
+                        }
+                    }
+                }
+                "--as-fast-as-possible" if replay_path.is_some() => {
+                    pacing = Pacing::AsFastAsPossible;
+                }
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+
+        if dir.is_none() && (glob.is_some() || fail_fast) {
+            return Err("--glob and --fail-fast require --dir".to_string());
+        }
+        let mode = match (replay_path, dir) {
+            (Some(path), _) => Mode::Replay { path, pacing },
+            (None, Some(path)) => Mode::Dir {
+                path,
+                glob: glob.unwrap_or_else(|| "*".to_string()),
+                fail_fast,
+            },
+            (None, None) => Mode::Run,
+        };
+
+        Ok(Self {
+            mode,
+            record,
+            output,
+            dry_run,
+            save_on_exit,
+            show_trace_ids,
+            filter,
+            import_report,
+            events,
+        })
+    }
+
+    /// Parse the arguments after `inspect`, which takes none of the run flags
+    fn parse_inspect(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let path = match args.next() {
+            Some(path) if !path.starts_with("--") => PathBuf::from(path),
+            _ => return Err("inspect requires a snapshot file path".to_string()),
+        };
+        let mut keys = None;
+        let mut get = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--keys" => keys = Some(args.next().ok_or("--keys requires a glob pattern")?),
+                "--get" => get = Some(args.next().ok_or("--get requires a key")?),
+                _ => return Err(format!("Unknown argument for inspect: {}", arg)),
+            }
+        }
+
+        Ok(Self {
+            mode: Mode::Inspect { path, keys, get },
+            record: None,
+            output: OutputOptions::default(),
+            dry_run: false,
+            save_on_exit: false,
+            show_trace_ids: false,
+            filter: CommandFilter::new(),
+            import_report: false,
+            events: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<CliOptions, String> {
+        CliOptions::parse(args.iter().map(|s| s.to_string()))
+    }
+
+    #[test]
+    fn test_parse_default_run() {
+        let options = parse(&[]).unwrap();
+        assert_eq!(options.mode, Mode::Run);
+        assert_eq!(options.record, None);
+        assert!(!options.dry_run);
+    }
+
+    #[test]
+    fn test_parse_dry_run() {
+        assert!(parse(&["--dry-run"]).unwrap().dry_run);
+
+        let options = parse(&["replay", "s.crkv", "--dry-run"]).unwrap();
+        assert!(options.dry_run);
+        assert!(matches!(options.mode, Mode::Replay { .. }));
+    }
+
+    #[test]
+    fn test_parse_save_on_exit() {
+        assert!(parse(&["--save-on-exit"]).unwrap().save_on_exit);
+        assert!(!parse(&[]).unwrap().save_on_exit);
+    }
+
+    #[test]
+    fn test_parse_command_filters() {
+        use crate::parser::Command;
+
+        let options = parse(&["--only", "SET,DELETE", "--dry-run"]).unwrap();
+        let set: Command = "SET k v".parse().unwrap();
+        let get: Command = "GET k".parse().unwrap();
+        assert!(options.filter.allows(&set.command_type));
+        assert!(!options.filter.allows(&get.command_type));
+
+        let err = parse(&["--skip", "GTE"]).unwrap_err();
+        assert_eq!(err, "Unknown command 'GTE' in --skip");
+        assert!(parse(&["--only"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_show_trace_ids() {
+        assert!(parse(&["--show-trace-ids"]).unwrap().show_trace_ids);
+        let options = parse(&["replay", "s.crkv", "--show-trace-ids"]).unwrap();
+        assert!(options.show_trace_ids);
+        assert!(!parse(&[]).unwrap().show_trace_ids);
+    }
+
+    #[test]
+    fn test_parse_import_report() {
+        assert!(!parse(&[]).unwrap().import_report);
+        assert!(parse(&["--import-report"]).unwrap().import_report);
+        let options = parse(&["replay", "s.crkv", "--import-report"]).unwrap();
+        assert!(options.import_report);
+    }
+
+    #[test]
+    fn test_parse_events_out() {
+        assert_eq!(parse(&[]).unwrap().events, None);
+        let options = parse(&["--events-out", "events.ndjson"]).unwrap();
+        assert_eq!(options.events, Some(PathBuf::from("events.ndjson")));
+        let options = parse(&["replay", "s.crkv", "--events-out", "e.ndjson"]).unwrap();
+        assert_eq!(options.events, Some(PathBuf::from("e.ndjson")));
+        assert!(parse(&["--events-out"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_record_and_replay() {
+        let options = parse(&["--record", "session.crkv"]).unwrap();
+        assert_eq!(options.record, Some(PathBuf::from("session.crkv")));
+
+        let options = parse(&["replay", "session.crkv", "--speed", "2.0"]).unwrap();
+        assert_eq!(
+            options.mode,
+            Mode::Replay {
+                path: PathBuf::from("session.crkv"),
+                pacing: Pacing::Speed(2.0)
+            }
+        );
+
+        let options = parse(&["replay", "s.crkv", "--as-fast-as-possible"]).unwrap();
+        assert!(matches!(
+            options.mode,
+            Mode::Replay {
+                pacing: Pacing::AsFastAsPossible,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_parse_dir() {
+        let options = parse(&["--dir", "logs", "--glob", "*.log", "--fail-fast"]).unwrap();
+        assert_eq!(
+            options.mode,
+            Mode::Dir {
+                path: PathBuf::from("logs"),
+                glob: "*.log".to_string(),
+                fail_fast: true
+            }
+        );
+
+        let options = parse(&["--dir", "logs", "--dry-run"]).unwrap();
+        assert!(options.dry_run);
+        assert_eq!(
+            options.mode,
+            Mode::Dir {
+                path: PathBuf::from("logs"),
+                glob: "*".to_string(),
+                fail_fast: false
+            }
+        );
+
+        assert!(parse(&["--dir"]).is_err());
+        assert!(parse(&["--fail-fast"]).is_err());
+        assert!(parse(&["--glob", "*.log"]).is_err());
+        assert!(parse(&["replay", "s.crkv", "--dir", "logs"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_inspect() {
+        let options = parse(&["inspect", "store.crkvs", "--keys", "user:*", "--get", "k"]).unwrap();
+        assert_eq!(
+            options.mode,
+            Mode::Inspect {
+                path: PathBuf::from("store.crkvs"),
+                keys: Some("user:*".to_string()),
+                get: Some("k".to_string()),
+            }
+        );
+
+        assert!(parse(&["inspect"]).is_err());
+        assert!(parse(&["inspect", "store.crkvs", "--keys"]).is_err());
+        assert!(parse(&["inspect", "store.crkvs", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_selftest() {
+        assert_eq!(parse(&["selftest"]).unwrap().mode, Mode::Selftest);
+        assert!(parse(&["selftest", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(parse(&["--version"]).unwrap().mode, Mode::Version);
+        assert!(parse(&["--version", "--dry-run"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_output_destinations() {
+        let options = parse(&["--responses-out", "out.txt", "--errors-out", "-"]).unwrap();
+        assert_eq!(options.output.responses, Some(PathBuf::from("out.txt")));
+        assert_eq!(options.output.errors, None);
+        assert_eq!(options.output.summary, None);
+        assert!(parse(&["--summary-out"]).is_err());
+    }
+
+    #[test]
+    fn test_parse_rejects_bad_arguments() {
+        assert!(parse(&["replay"]).is_err());
+        assert!(parse(&["replay", "s.crkv", "--speed", "0"]).is_err());
+        assert!(parse(&["--speed", "2.0"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! Redis Cluster key slots, for routing keys across several processes
+//!
+//! A key's slot is the CRC-16 (XMODEM) of the key modulo 16384. When the
+//! key has a hash tag, a non-empty `{...}` section, only the text inside
+//! the first one is hashed, so related keys such as `{user:1}:cart` and
+//! `{user:1}:orders` land in the same slot. Clients can call `key_slot`
+//! directly and get the same answer as KEYSLOT and as Redis itself.
+
+/// Number of slots keys are spread over
+pub const SLOTS: u16 = 16384;
+
+/// CRC-16/XMODEM polynomial, x^16 + x^12 + x^5 + 1
+const POLYNOMIAL: u16 = 0x1021;
+
+/// Per-byte lookup table, built at compile time
+const TABLE: [u16; 256] = build_table();
+
+const fn build_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ POLYNOMIAL
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Compute the CRC-16/XMODEM checksum of `bytes`, as Redis Cluster does
+pub fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0, |crc, &byte| {
+        TABLE[((crc >> 8) as u8 ^ byte) as usize] ^ (crc << 8)
+    })
+}
+
+/// The part of `key` that is hashed: the text between the first `{` and
+/// the first `}` after it, or the whole key if that text is empty or
+/// either brace is missing
+pub fn hash_tag(key: &str) -> &str {
+    key.split_once('{')
+        .and_then(|(_, rest)| rest.split_once('}'))
+        .map(|(tag, _)| tag)
+        .filter(|tag| !tag.is_empty())
+        .unwrap_or(key)
+}
+
+/// The slot `key` belongs to, from 0 to `SLOTS - 1`
+pub fn key_slot(key: &str) -> u16 {
+    crc16(hash_tag(key).as_bytes()) % SLOTS
+}
+
+/// The first and last slot of range `index` when the slots are split into
+/// `ranges` near-equal ranges
+///
+/// Panics unless `index < ranges` and `ranges` is between 1 and `SLOTS`.
+pub fn slot_range(index: usize, ranges: usize) -> (u16, u16) {
+    assert!(index < ranges && ranges <= SLOTS as usize);
+    let start = index * SLOTS as usize / ranges;
+    let end = (index + 1) * SLOTS as usize / ranges - 1;
+    (start as u16, end as u16)
+}
+
+/// The index of the range `slot` falls in, of `ranges` ranges split as
+/// `slot_range` splits them
+pub fn slot_range_index(slot: u16, ranges: usize) -> usize {
+    ((slot as usize + 1) * ranges).div_ceil(SLOTS as usize) - 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_crc16_check_value() {
+        assert_eq!(crc16(b""), 0);
+        // Check value from the Redis Cluster specification
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn test_key_slots_match_redis() {
+        // Outputs of CLUSTER KEYSLOT on Redis
+        let cases = [
+            ("foo", 12182),
+            ("bar", 5061),
+            ("hello", 866),
+            ("somekey", 11058),
+            ("foo{hash_tag}", 2515),
+            ("", 0),
+        ];
+        for (key, slot) in cases {
+            assert_eq!(key_slot(key), slot, "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_hash_tags_follow_redis_rules() {
+        let cases = [
+            ("{user1000}.following", "user1000"),
+            ("{user1000}.followers", "user1000"),
+            ("foo{}{bar}", "foo{}{bar}"),
+            ("foo{{bar}}zap", "{bar"),
+            ("foo{bar}{zap}", "bar"),
+            ("foo{bar", "foo{bar"),
+            ("foo}bar{", "foo}bar{"),
+        ];
+        for (key, tag) in cases {
+            assert_eq!(hash_tag(key), tag, "{}", key);
+        }
+        assert_eq!(
+            key_slot("{user1000}.following"),
+            key_slot("{user1000}.followers")
+        );
+        assert_eq!(key_slot("foo{hash_tag}"), key_slot("hash_tag"));
+    }
+
+    #[test]
+    fn test_slot_ranges_cover_every_slot_once() {
+        for ranges in [1, 3, 16, 1000, SLOTS as usize] {
+            let mut next = 0;
+            for index in 0..ranges {
+                let (start, end) = slot_range(index, ranges);
+                assert_eq!(start, next);
+                assert!(end >= start);
+                for slot in [start, end] {
+                    assert_eq!(slot_range_index(slot, ranges), index, "{}", slot);
+                }
+                next = end + 1;
+            }
+            assert_eq!(next, SLOTS);
+        }
+        assert_eq!(slot_range(1, 4), (4096, 8191));
+    }
+}
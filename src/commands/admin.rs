@@ -0,0 +1,529 @@
+//! Server administration commands: INFO, VERSION, STATS, STATSRESET, HOTKEYS,
+//! CHECK, PARSESTATS, SNAPSHOTID, FINGERPRINT, SHUTDOWN, DUMP, EXPLAIN
+
+use std::num::NonZero;
+use std::path::Path;
+
+use super::{CommandResult, StoreState, lookup, save_snapshot};
+use crate::crc32::crc32;
+use crate::handler::{HandlerError, Value};
+use crate::parser::Command;
+use crate::snapshot;
+use crate::version;
+
+/// Store statistics; soft-deleted keys are left out of `keys` and counted
+/// on their own, since they still take up memory
+///
+/// `in_flight` counts the commands sent to the main thread and not yet
+/// answered, this INFO included; both gauges stay 0 outside a pool.
+///
+/// Each validation rule adds a `validation_rejected[glob=type]` line.
+///
+/// `INFO quotas` instead reports each prefix quota's usage against its
+/// limits, one `prefix* keys=used/max bytes=used/max` line per rule.
+pub(crate) fn info(state: &mut StoreState, quotas: bool) -> CommandResult {
+    if quotas {
+        if state.quotas.is_empty() {
+            return Ok("(no prefix quotas)".to_string());
+        }
+        return Ok(state
+            .quotas
+            .rules()
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join("\n"));
+    }
+    let mut info = format!(
+        "keys:{}\ncapacity:{}\nin_flight:{}\nmax_in_flight:{}\nidempotency_window:{}\nidempotency_hits:{}\nidempotency_memory_bytes:{}\ntombstones:{}\ntombstone_memory_bytes:{}",
+        state.store.len(),
+        state.store.capacity(),
+        state.in_flight.current(),
+        state.in_flight.max(),
+        state.idempotency.capacity(),
+        state.idempotency.hits(),
+        state.idempotency.memory_bytes(),
+        state.tombstones.len(),
+        tombstone_memory_bytes(state)
+    );
+    for rule in state.validation.rules() {
+        info.push_str(&format!(
+            "\nvalidation_rejected[{}]:{}",
+            rule,
+            rule.rejected()
+        ));
+    }
+    Ok(info)
+}
+
+/// The build and its backends as `field:value` lines: crate version, git
+/// commit, compiled-in features, build profile, store backend, and whether
+/// snapshots are configured
+pub(crate) fn version(state: &mut StoreState) -> CommandResult {
+    Ok(version::fields(state.snapshot_path.is_some())
+        .into_iter()
+        .map(|(field, value)| format!("{}:{}", field, value))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Approximate bytes held by tombstones: keys, values, and timestamps
+fn tombstone_memory_bytes(state: &StoreState) -> usize {
+    state
+        .tombstones
+        .iter()
+        .map(|(key, tombstone)| key.len() + tombstone.value.byte_len() + size_of::<u64>())
+        .sum()
+}
+
+pub(crate) fn stats(state: &mut StoreState) -> CommandResult {
+    Ok(state.stats.to_string())
+}
+
+/// Read and reset in one step so interval scrapes never miss a command
+pub(crate) fn statsreset(state: &mut StoreState) -> CommandResult {
+    Ok(std::mem::take(&mut state.stats).to_string())
+}
+
+/// The most touched keys with their approximate command counts, one
+/// `key count` per line
+pub(crate) fn hotkeys(state: &mut StoreState, count: usize) -> CommandResult {
+    if !state.hot_keys.is_enabled() {
+        return Ok("(hot-key tracking disabled)".to_string());
+    }
+    let top = state.hot_keys.top(count);
+    if top.is_empty() {
+        return Ok("(empty)".to_string());
+    }
+    let lines: Vec<String> = top
+        .into_iter()
+        .map(|(key, count)| format!("{} {}", key, count))
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+/// Lines parsed into each command keyword, one `keyword count` per line,
+/// most parsed first
+pub(crate) fn parsestats(state: &mut StoreState) -> CommandResult {
+    let counts = state.parse_stats.counts();
+    if counts.is_empty() {
+        return Ok("(empty)".to_string());
+    }
+    let lines: Vec<String> = counts
+        .into_iter()
+        .map(|(name, count)| format!("{} {}", name, count))
+        .collect();
+    Ok(lines.join("\n"))
+}
+
+/// A number bumped by every command that changes the store, so a client
+/// can tell whether anything changed since it last asked
+pub(crate) fn snapshotid(state: &mut StoreState) -> CommandResult {
+    Ok(state.snapshot_id.to_string())
+}
+
+/// A hash of every key and value in the store, as 16 hex digits, for
+/// checking that two stores hold the same data
+///
+/// Each pair is hashed on its own and the hashes are XORed together, so the
+/// result doesn't depend on insertion order or on the map's iteration
+/// order. Values of different types never hash alike, even when they read
+/// back the same (`SET n 1` and `SETINT n 1`). Soft-deleted keys are left
+/// out. An empty store fingerprints to all zeros.
+pub(crate) fn fingerprint(state: &mut StoreState) -> CommandResult {
+    let fingerprint = state
+        .store
+        .iter()
+        .fold(0, |acc, (key, value)| acc ^ pair_hash(key, value));
+    Ok(format!("{:016x}", fingerprint))
+}
+
+/// FNV-1a over the key and a tagged, length-prefixed encoding of the value,
+/// mixed so that XORing many of them doesn't cancel out low bits
+fn pair_hash(key: &str, value: &Value) -> u64 {
+    fn feed(hash: u64, bytes: &[u8]) -> u64 {
+        bytes.iter().fold(hash, |hash, &byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+    fn feed_str(hash: u64, s: &str) -> u64 {
+        feed(feed(hash, &(s.len() as u64).to_le_bytes()), s.as_bytes())
+    }
+
+    let mut hash = feed_str(0xcbf2_9ce4_8422_2325, key);
+    hash = match value {
+        Value::Str(value) => feed_str(feed(hash, b"s"), value),
+        Value::Int(value) => feed(feed(hash, b"i"), &value.to_le_bytes()),
+        Value::Checksummed { value, crc } => {
+            feed(feed_str(feed(hash, b"c"), value), &crc.to_le_bytes())
+        }
+        Value::List(items) => {
+            let hash = feed(feed(hash, b"l"), &(items.len() as u64).to_le_bytes());
+            items.iter().fold(hash, |hash, item| feed_str(hash, item))
+        }
+    };
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^ (hash >> 33)
+}
+
+/// Look for inconsistent state: checksummed values that no longer match
+/// their CRC (reported, since the original is lost) and idempotency window
+/// entries missing from one of its indexes (removed)
+pub(crate) fn check(state: &mut StoreState) -> CommandResult {
+    let mut mismatches: Vec<&str> = state
+        .store
+        .iter()
+        .filter(|(_, value)| {
+            matches!(value, Value::Checksummed { value, crc } if crc32(value.as_bytes()) != *crc)
+        })
+        .map(|(key, _)| key.as_str())
+        .collect();
+    mismatches.sort_unstable();
+    let repaired = state.idempotency.repair();
+
+    let mut report = format!(
+        "keys_checked:{}\nchecksum_mismatches:{}\nidempotency_entries_removed:{}",
+        state.store.len(),
+        mismatches.len(),
+        repaired
+    );
+    for key in mismatches {
+        report.push_str(&format!("\nmismatch:{}", key));
+    }
+    Ok(report)
+}
+
+/// Save the store if asked; stopping is up to the main thread, which stops
+/// applying commands once a SHUTDOWN succeeds
+///
+/// A failed save fails the command, so the caller can fix the problem and
+/// try again before anything is lost.
+pub(crate) fn shutdown(state: &mut StoreState, save: bool) -> CommandResult {
+    if save {
+        let keys = save_snapshot(state)?;
+        return Ok(format!("OK (saved {} keys)", keys));
+    }
+    Ok("OK".to_string())
+}
+
+/// Write the store into `dir` as part files, one per worker thread, plus a
+/// manifest that `SNAPSHOT_PATH` can point at to load them back
+///
+/// The workers read the store in place, so the main thread waits for them,
+/// but encoding and writing run on every worker at once.
+pub(crate) fn dump_parallel(
+    state: &mut StoreState,
+    dir: String,
+    threads: Option<usize>,
+) -> CommandResult {
+    let threads =
+        threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, NonZero::get));
+    let dump = snapshot::save_parallel(&state.store, Path::new(&dir), threads)
+        .map_err(|err| HandlerError::DumpFailed(err.to_string()))?;
+    Ok(format!(
+        "dumped:{}\nparts:{}\nmanifest:{}",
+        dump.keys,
+        dump.parts,
+        dump.manifest.display()
+    ))
+}
+
+/// Parse `line` without running it and describe what it would do
+///
+/// A line that doesn't parse is still a successful EXPLAIN: the parse error
+/// is the answer.
+pub(crate) fn explain(state: &mut StoreState, line: String) -> CommandResult {
+    match Command::parse_with(&line, state.parse_options()) {
+        Ok(command) => {
+            let mut description = command.command_type.describe();
+            if let Some(token) = command.idempotency_key {
+                description.push_str(&format!(" id={}", token));
+            }
+            Ok(description)
+        }
+        Err(err) => {
+            let keyword = line.split_whitespace().next().unwrap_or("");
+            let hint = if lookup(keyword).is_some() {
+                format!(" ({} is a known command; check its arguments)", keyword)
+            } else {
+                String::new()
+            };
+            Ok(format!("parse error: {}{}", err, hint))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::run;
+    use super::*;
+    use crate::hotkeys::HotKeys;
+    use crate::idempotency::IdempotencyWindow;
+    use crate::quota::PrefixQuotas;
+    use crate::validate::ValidationRules;
+
+    #[test]
+    fn test_shutdown_save_needs_a_snapshot_path() {
+        let mut state = StoreState::default();
+        run(&mut state, "SET a 1").unwrap();
+
+        let err = run(&mut state, "SHUTDOWN SAVE").unwrap_err();
+        assert_eq!(err.to_string(), "save failed: no SNAPSHOT_PATH configured");
+        assert_eq!(run(&mut state, "SHUTDOWN").unwrap(), "OK");
+
+        let path =
+            std::env::temp_dir().join(format!("crabby_shutdown_save_{}.crkvs", std::process::id()));
+        state.snapshot_path = Some(path.clone());
+        assert_eq!(
+            run(&mut state, "SHUTDOWN SAVE").unwrap(),
+            "OK (saved 1 keys)"
+        );
+        let restored = crate::snapshot::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(restored, state.store);
+    }
+
+    #[test]
+    fn test_info_quotas_reports_usage_per_prefix() {
+        let mut state = StoreState::default();
+        assert_eq!(
+            run(&mut state, "INFO quotas").unwrap(),
+            "(no prefix quotas)"
+        );
+
+        state.quotas = PrefixQuotas::compile([
+            ("user:*", Some(10), Some(1024)),
+            ("user:tmp:*", Some(2), None),
+        ])
+        .unwrap();
+        run(&mut state, "SET user:1 Ada").unwrap();
+        run(&mut state, "SET user:tmp:1 x").unwrap();
+        run(&mut state, "SET other 1").unwrap();
+        assert_eq!(
+            run(&mut state, "INFO quotas").unwrap(),
+            "user:* keys=1/10 bytes=9/1024\nuser:tmp:* keys=1/2 bytes=11/unlimited"
+        );
+    }
+
+    #[test]
+    fn test_info_counts_rejections_per_validation_rule() {
+        let mut state = StoreState {
+            validation: ValidationRules::compile([("n:*", "integer"), ("f:*", "bool")]).unwrap(),
+            ..StoreState::default()
+        };
+        run(&mut state, "SET n:1 one").unwrap_err();
+        run(&mut state, "SET n:1 two").unwrap_err();
+        run(&mut state, "SET n:1 3").unwrap();
+
+        let info = run(&mut state, "INFO").unwrap();
+        assert!(
+            info.ends_with("\nvalidation_rejected[f:*=bool]:0\nvalidation_rejected[n:*=integer]:2"),
+            "{}",
+            info
+        );
+    }
+
+    #[test]
+    fn test_hotkeys_lists_the_most_touched_keys() {
+        let mut state = StoreState::default();
+        assert_eq!(
+            run(&mut state, "HOTKEYS").unwrap(),
+            "(hot-key tracking disabled)"
+        );
+
+        state.hot_keys = HotKeys::new(8);
+        assert_eq!(run(&mut state, "HOTKEYS").unwrap(), "(empty)");
+        for key in ["a", "b", "a", "c", "a", "b"] {
+            state.hot_keys.record(key);
+        }
+        assert_eq!(run(&mut state, "HOTKEYS 2").unwrap(), "a 3\nb 2");
+    }
+
+    #[test]
+    fn test_check_reports_mismatches_and_removes_dangling_entries() {
+        let mut state = StoreState {
+            idempotency: IdempotencyWindow::new(4),
+            ..StoreState::default()
+        };
+        state.idempotency.insert("req-1");
+        run(&mut state, "SETCRC a one").unwrap();
+        run(&mut state, "SETCRC b two").unwrap();
+        assert_eq!(
+            run(&mut state, "CHECK").unwrap(),
+            "keys_checked:2\nchecksum_mismatches:0\nidempotency_entries_removed:0"
+        );
+
+        if let Some(Value::Checksummed { value, .. }) = state.store.get_mut("b") {
+            value.push('!');
+        }
+        state.idempotency.insert_dangling(99, "ghost");
+        assert_eq!(
+            run(&mut state, "CHECK").unwrap(),
+            "keys_checked:2\nchecksum_mismatches:1\nidempotency_entries_removed:1\nmismatch:b"
+        );
+
+        // The dangling entry is gone; the mismatch can only be reported
+        let report = run(&mut state, "CHECK").unwrap();
+        assert!(
+            report.contains("idempotency_entries_removed:0"),
+            "{}",
+            report
+        );
+        assert!(report.ends_with("mismatch:b"), "{}", report);
+        assert!(state.idempotency.check("req-1"));
+    }
+
+    #[test]
+    fn test_dump_parallel_writes_loadable_parts() {
+        let mut state = StoreState::default();
+        for i in 0..10 {
+            run(&mut state, &format!("SET key:{} {}", i, i)).unwrap();
+        }
+        let dir = std::env::temp_dir().join(format!("crabby_dump_parallel_{}", std::process::id()));
+
+        let response = run(&mut state, &format!("DUMP PARALLEL {} 3", dir.display())).unwrap();
+        let manifest = dir.join(crate::snapshot::MANIFEST_NAME);
+        assert_eq!(
+            response,
+            format!("dumped:10\nparts:3\nmanifest:{}", manifest.display())
+        );
+        let restored = crate::snapshot::load(&manifest).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(restored, state.store);
+
+        let err = run(&mut state, "DUMP PARALLEL /proc/crabby/dump 2").unwrap_err();
+        assert!(matches!(err, HandlerError::DumpFailed(_)), "{:?}", err);
+    }
+
+    #[test]
+    fn test_explain_valid_and_invalid_lines() {
+        let mut state = StoreState {
+            idempotency: IdempotencyWindow::new(4),
+            ..StoreState::default()
+        };
+        assert_eq!(
+            run(&mut state, "EXPLAIN SET foo bar ID req-1").unwrap(),
+            "SET key=foo value=bar id=req-1"
+        );
+        // Explaining never runs the command
+        assert!(state.store.is_empty());
+
+        assert_eq!(
+            run(&mut state, "EXPLAIN LTRIM log zero 5").unwrap(),
+            "parse error: Invalid index 'zero': expected an integer (LTRIM is a known command; check its arguments)"
+        );
+        assert_eq!(
+            run(&mut state, "EXPLAIN FROB x").unwrap(),
+            "parse error: Invalid command: FROB x"
+        );
+    }
+
+    #[test]
+    fn test_version_reports_build_and_persistence() {
+        let mut state = StoreState::default();
+        let report = run(&mut state, "VERSION").unwrap();
+        assert!(
+            report.starts_with(&format!("version:{}\ngit_hash:", env!("CARGO_PKG_VERSION"))),
+            "{}",
+            report
+        );
+        assert!(report.contains("\nstore:hashmap\n"), "{}", report);
+        assert!(report.ends_with("\npersistence:off"), "{}", report);
+
+        state.snapshot_path = Some(std::path::PathBuf::from("store.crkvs"));
+        let report = run(&mut state, "VERSION").unwrap();
+        assert!(report.ends_with("\npersistence:snapshot"), "{}", report);
+    }
+
+    #[test]
+    fn test_fingerprint_ignores_insertion_order() {
+        let mut forward = StoreState::default();
+        let mut backward = StoreState::default();
+        let lines = [
+            "SET user:1 Ada",
+            "SETINT hits 3",
+            "SETCRC note hi",
+            "SET user:2 Grace",
+        ];
+        for line in lines {
+            run(&mut forward, line).unwrap();
+        }
+        for line in lines.iter().rev() {
+            run(&mut backward, line).unwrap();
+        }
+        let fingerprint = run(&mut forward, "FINGERPRINT").unwrap();
+        assert_eq!(fingerprint.len(), 16);
+        assert_eq!(run(&mut backward, "FINGERPRINT").unwrap(), fingerprint);
+
+        // Any difference in the data shows, including a value's type
+        run(&mut backward, "SET hits 3").unwrap();
+        assert_ne!(run(&mut backward, "FINGERPRINT").unwrap(), fingerprint);
+        run(&mut backward, "SETINT hits 3").unwrap();
+        assert_eq!(run(&mut backward, "FINGERPRINT").unwrap(), fingerprint);
+        run(&mut backward, "DELETE user:2").unwrap();
+        assert_ne!(run(&mut backward, "FINGERPRINT").unwrap(), fingerprint);
+
+        assert_eq!(
+            run(&mut StoreState::default(), "FINGERPRINT").unwrap(),
+            "0000000000000000"
+        );
+    }
+
+    #[test]
+    fn test_snapshotid_moves_only_when_the_store_changes() {
+        let mut state = StoreState::default();
+        let id = |state: &mut StoreState| run(state, "SNAPSHOTID").unwrap();
+        assert_eq!(id(&mut state), "0");
+
+        run(&mut state, "SET a 1").unwrap();
+        let after_set = id(&mut state);
+        assert_eq!(after_set, "1");
+        for _ in 0..3 {
+            run(&mut state, "GET a").unwrap();
+        }
+        run(&mut state, "GET missing").unwrap_err();
+        assert_eq!(id(&mut state), after_set);
+
+        // A write that changes nothing leaves it alone too
+        run(&mut state, "DELETE missing").unwrap_err();
+        run(&mut state, "RENAMENX missing b").unwrap_err();
+        assert_eq!(id(&mut state), after_set);
+
+        run(&mut state, "RENAMENX a b").unwrap();
+        run(&mut state, "DELETE b").unwrap();
+        assert_eq!(id(&mut state), "3");
+    }
+
+    #[test]
+    fn test_info_counts_tombstones_separately() {
+        let mut state = StoreState {
+            soft_delete: true,
+            ..StoreState::default()
+        };
+        run(&mut state, "SET a 12345").unwrap();
+        run(&mut state, "SET b 1").unwrap();
+        run(&mut state, "DELETE a").unwrap();
+
+        let info = run(&mut state, "INFO").unwrap();
+        assert!(info.starts_with("keys:1\n"), "{}", info);
+        assert!(info.contains("\ntombstones:1\n"), "{}", info);
+        assert!(info.ends_with("\ntombstone_memory_bytes:14"), "{}", info);
+    }
+
+    #[test]
+    fn test_with_capacity_reported_by_info() {
+        let mut state = StoreState::with_capacity(1000);
+        run(&mut state, "SET a 1").unwrap();
+
+        let info = run(&mut state, "INFO").unwrap();
+        assert!(info.contains("keys:1"));
+        let capacity: usize = info
+            .lines()
+            .find_map(|line| line.strip_prefix("capacity:"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(capacity >= 1000);
+    }
+}
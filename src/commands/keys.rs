@@ -0,0 +1,849 @@
+//! Keyspace commands: DELETE, UNDELETE, PURGE, RENAMENX, RENAMEPREFIX, SCAN,
+//! SORTBYVALUE, GETMATCHING, WAITKEY, VALUELENGTHSTATS, PREFIXHISTOGRAM,
+//! INSPECT, KEYSLOT, SLOTSTATS
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+use super::{CommandResult, StoreState, admit, insert, note_change, remove, unix_now};
+use crate::cluster;
+use crate::glob::glob_match;
+use crate::handler::{HandlerError, Store, Tombstone, Value};
+use crate::parser::encode_scan_cursor;
+
+/// Remove `key`, keeping its value as a tombstone under `SOFT_DELETE`
+pub(crate) fn delete(state: &mut StoreState, key: String) -> CommandResult {
+    let Some(value) = remove(state, &key) else {
+        return Err(HandlerError::not_found(key));
+    };
+    let response = format!("DELETED {} (was: {})", key, value);
+    if state.soft_delete {
+        let deleted_at = unix_now();
+        state
+            .tombstones
+            .insert(key, Tombstone { value, deleted_at });
+    }
+    Ok(response)
+}
+
+/// Bring back a soft-deleted key: `1` if it was restored, `0` if there is
+/// no tombstone for it or the key has been set again since (the tombstone
+/// is then kept)
+pub(crate) fn undelete(state: &mut StoreState, key: String) -> CommandResult {
+    if state.store.contains_key(&key) {
+        return Ok("0".to_string());
+    }
+    let Some(bytes) = state.tombstones.get(&key).map(|t| t.value.byte_len()) else {
+        return Ok("0".to_string());
+    };
+    admit(state, [(key.as_str(), Some(bytes))])?;
+    if let Some(tombstone) = state.tombstones.remove(&key) {
+        insert(state, key, tombstone.value);
+    }
+    Ok("1".to_string())
+}
+
+/// Free the tombstones whose keys match a glob, returning how many; live
+/// keys are never touched
+pub(crate) fn purge(state: &mut StoreState, pattern: String) -> CommandResult {
+    let before = state.tombstones.len();
+    state.tombstones.retain(|key, _| !glob_match(&pattern, key));
+    Ok((before - state.tombstones.len()).to_string())
+}
+
+pub(crate) fn renamenx(state: &mut StoreState, src: String, dst: String) -> CommandResult {
+    if !state.store.contains_key(&src) {
+        return Err(HandlerError::not_found(src));
+    }
+    // Also covers src == dst: the destination is taken
+    if state.store.contains_key(&dst) {
+        return Ok("0".to_string());
+    }
+
+    let bytes = state.store.get(&src).map(Value::byte_len);
+    admit(state, [(src.as_str(), None), (dst.as_str(), bytes)])?;
+    note_change(state, &src);
+    note_change(state, &dst);
+    if let Some(value) = state.store.remove(&src) {
+        state.access.renamed([(src.as_str(), dst.as_str())]);
+        state.history.renamed([(src.as_str(), dst.as_str())]);
+        state.store.insert(dst, value);
+    }
+    Ok("1".to_string())
+}
+
+/// Move every key starting with `old` to the same key starting with `new`,
+/// returning how many moved
+///
+/// All or nothing: if any target already exists (and isn't itself one of
+/// the keys being moved), nothing is renamed.
+pub(crate) fn renameprefix(state: &mut StoreState, old: String, new: String) -> CommandResult {
+    let mut sources: Vec<String> = state
+        .store
+        .keys()
+        .filter(|key| key.starts_with(&old))
+        .cloned()
+        .collect();
+    // Report the same collision however the map happens to be ordered
+    sources.sort();
+    let renamed: Vec<String> = sources
+        .iter()
+        .map(|key| format!("{}{}", new, &key[old.len()..]))
+        .collect();
+    if let Some(taken) = renamed
+        .iter()
+        .find(|dst| !dst.starts_with(&old) && state.store.contains_key(*dst))
+    {
+        return Err(HandlerError::KeyExists(taken.clone()));
+    }
+    admit(
+        state,
+        sources.iter().map(|key| (key.as_str(), None)).chain(
+            renamed
+                .iter()
+                .zip(&sources)
+                .map(|(dst, src)| (dst.as_str(), state.store.get(src).map(Value::byte_len))),
+        ),
+    )?;
+    for key in sources.iter().chain(&renamed) {
+        note_change(state, key);
+    }
+
+    let values: Vec<Value> = sources
+        .iter()
+        .filter_map(|key| state.store.remove(key))
+        .collect();
+    let count = values.len();
+    let moves = || {
+        sources
+            .iter()
+            .map(String::as_str)
+            .zip(renamed.iter().map(String::as_str))
+    };
+    state.access.renamed(moves());
+    state.history.renamed(moves());
+    state.store.extend(renamed.into_iter().zip(values));
+    Ok(count.to_string())
+}
+
+/// Return the next `count` keys in key order after the cursor key
+///
+/// The cursor is the last key returned rather than a position in the map,
+/// so rehashing and mutations between calls can't make a scan skip or
+/// repeat keys: a key present for the whole scan is returned exactly once,
+/// a key absent for the whole scan never is, and a key added or removed
+/// mid-scan may or may not appear. Each call walks the map but only holds
+/// `count` keys, trading CPU for not snapshotting the store.
+pub(crate) fn scan(state: &mut StoreState, after: Option<String>, count: usize) -> CommandResult {
+    // Max-heap holding the `count` smallest keys past the cursor
+    let mut batch: BinaryHeap<&String> = BinaryHeap::with_capacity(count + 1);
+    for key in state.store.keys() {
+        if after.as_ref().is_some_and(|after| key <= after) {
+            continue;
+        }
+        if batch.len() < count {
+            batch.push(key);
+        } else if batch.peek().is_some_and(|largest| key < *largest) {
+            batch.pop();
+            batch.push(key);
+        }
+    }
+
+    let keys = batch.into_sorted_vec();
+    let cursor = match keys.last() {
+        Some(last) if keys.len() == count => encode_scan_cursor(last),
+        _ => "0".to_string(),
+    };
+
+    let mut lines = vec![cursor];
+    lines.extend(keys.into_iter().cloned());
+    Ok(lines.join("\n"))
+}
+
+/// List every live key matching `pattern` as `key value` lines, in key
+/// order
+///
+/// Keys holding lists show `(list)` in place of their value, and SETCRC
+/// values, readable only through GETCRC, show `(checksummed)`; integers
+/// show their value.
+pub(crate) fn getmatching(state: &mut StoreState, pattern: String) -> CommandResult {
+    getmatching_in(&state.store, &pattern)
+}
+
+/// GETMATCHING against the store alone, so a read snapshot can answer it too
+pub(crate) fn getmatching_in(store: &Store, pattern: &str) -> CommandResult {
+    let mut entries: Vec<(&String, &Value)> = store
+        .iter()
+        .filter(|(key, _)| glob_match(pattern, key))
+        .collect();
+    if entries.is_empty() {
+        return Ok("(empty)".to_string());
+    }
+    entries.sort_unstable_by_key(|(key, _)| *key);
+    Ok(entries
+        .iter()
+        .map(|(key, value)| match value {
+            Value::Str(value) => format!("{} {}", key, value),
+            Value::Int(value) => format!("{} {}", key, value),
+            Value::List(_) => format!("{} (list)", key),
+            Value::Checksummed { .. } => format!("{} (checksummed)", key),
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// List string and integer keys matching `pattern` as `key value` lines,
+/// ordered by value
+///
+/// Ascending order puts numeric values first (compared as numbers), then
+/// the rest lexically by bytes; descending is the exact reverse. Equal
+/// values are ordered by key. Keys holding lists or checksummed strings are
+/// skipped.
+pub(crate) fn sortbyvalue(
+    state: &mut StoreState,
+    pattern: String,
+    ascending: bool,
+) -> CommandResult {
+    let mut entries: Vec<(&String, Cow<'_, str>, Option<f64>)> = state
+        .store
+        .iter()
+        .filter(|(key, _)| glob_match(&pattern, key))
+        .filter_map(|(key, value)| match value {
+            Value::Str(value) => {
+                let number = value.parse::<f64>().ok().filter(|n| n.is_finite());
+                Some((key, Cow::Borrowed(value.as_str()), number))
+            }
+            Value::Int(value) => Some((key, Cow::Owned(value.to_string()), Some(*value as f64))),
+            _ => None,
+        })
+        .collect();
+
+    entries.sort_by(|(a_key, a, a_num), (b_key, b, b_num)| {
+        let by_value = match (a_num, b_num) {
+            (Some(a), Some(b)) => a.total_cmp(b),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => a.cmp(b),
+        };
+        by_value.then_with(|| a_key.cmp(b_key))
+    });
+    if !ascending {
+        entries.reverse();
+    }
+
+    if entries.is_empty() {
+        return Ok("(empty)".to_string());
+    }
+    Ok(entries
+        .iter()
+        .map(|(key, value, _)| format!("{} {}", key, value))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// Answer whether `key` exists right now: `1` if it does, `0` if not
+///
+/// The waiting itself happens in the main thread, which parks a `0` with a
+/// non-zero timeout until a write creates the key or the timeout passes.
+pub(crate) fn waitkey(state: &mut StoreState, key: String, _timeout_ms: u64) -> CommandResult {
+    let present = state.store.contains_key(&key);
+    Ok(if present { "1" } else { "0" }.to_string())
+}
+
+/// Byte lengths of the string values (checksummed ones included): how many
+/// there are, the shortest and longest with the keys holding them, and the
+/// mean
+///
+/// Ties for an extreme go to the lowest key, so the answer doesn't depend on
+/// iteration order. Lists and integers are skipped.
+pub(crate) fn valuelengthstats(state: &mut StoreState) -> CommandResult {
+    let mut count = 0usize;
+    let mut total = 0usize;
+    let mut min: Option<(usize, &String)> = None;
+    let mut max: Option<(usize, &String)> = None;
+    for (key, value) in &state.store {
+        let len = match value {
+            Value::Str(value) | Value::Checksummed { value, .. } => value.len(),
+            Value::List(_) | Value::Int(_) => continue,
+        };
+        count += 1;
+        total += len;
+        if min.is_none_or(|(min_len, min_key)| (len, key) < (min_len, min_key)) {
+            min = Some((len, key));
+        }
+        if max.is_none_or(|(max_len, max_key)| len > max_len || (len == max_len && key < max_key)) {
+            max = Some((len, key));
+        }
+    }
+
+    let (Some((min_len, min_key)), Some((max_len, max_key))) = (min, max) else {
+        return Ok("strings:0".to_string());
+    };
+    Ok(format!(
+        "strings:{}\nmin:{}\nmin_key:{}\nmax:{}\nmax_key:{}\navg:{:.2}",
+        count,
+        min_len,
+        min_key,
+        max_len,
+        max_key,
+        total as f64 / count as f64
+    ))
+}
+
+/// The group PREFIXHISTOGRAM puts keys without the delimiter in
+const NO_PREFIX: &str = "(none)";
+
+/// Group keys by the part before the first `delimiter` and return a
+/// `prefix count` line per group, most keys first (ties by prefix)
+///
+/// Keys without the delimiter are counted together under `(none)`.
+pub(crate) fn prefixhistogram(state: &mut StoreState, delimiter: char) -> CommandResult {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for key in state.store.keys() {
+        let prefix = key
+            .split_once(delimiter)
+            .map_or(NO_PREFIX, |(prefix, _)| prefix);
+        *counts.entry(prefix).or_insert(0) += 1;
+    }
+    if counts.is_empty() {
+        return Ok("(empty)".to_string());
+    }
+
+    let mut groups: Vec<(&str, usize)> = counts.into_iter().collect();
+    groups.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| a.cmp(b)));
+    Ok(groups
+        .iter()
+        .map(|(prefix, count)| format!("{} {}", prefix, count))
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+/// The value of `key` with what the access log knows about it, as
+/// labeled lines: insertion age and last-read age in milliseconds, and the
+/// number of reads
+///
+/// The insertion age is `unknown` for keys the log didn't see created, and
+/// the last-read age is `never` for keys not read since. With value history
+/// on, the value is followed by the key's current version, as GETAT counts.
+pub(crate) fn inspect(state: &mut StoreState, key: String) -> CommandResult {
+    let Some(value) = state.store.get(&key) else {
+        return Err(HandlerError::not_found(key));
+    };
+    let value = if state.history.is_enabled() {
+        format!("{}\nversion:{}", value, state.history.version(&key))
+    } else {
+        value.to_string()
+    };
+    if !state.access.is_enabled() {
+        return Ok(format!("value:{}\n(key access tracking disabled)", value));
+    }
+
+    let now = Instant::now();
+    let access = state.access.get(&key);
+    let age = |age: Option<Duration>, missing: &str| {
+        age.map_or(missing.to_string(), |age| age.as_millis().to_string())
+    };
+    Ok(format!(
+        "value:{}\ninserted_age_ms:{}\nlast_access_age_ms:{}\naccess_count:{}",
+        value,
+        age(
+            access.and_then(|access| access.inserted_age(now)),
+            "unknown"
+        ),
+        age(
+            access.and_then(|access| access.last_access_age(now)),
+            "never"
+        ),
+        access.map_or(0, |access| access.accesses)
+    ))
+}
+
+/// The cluster slot `key` hashes to, whether or not it exists
+pub(crate) fn keyslot(_state: &mut StoreState, key: String) -> CommandResult {
+    Ok(cluster::key_slot(&key).to_string())
+}
+
+/// Split the slots into `ranges` near-equal ranges and count the keys in
+/// each, one `first-last count` line per range, empty ranges included
+pub(crate) fn slotstats(state: &mut StoreState, ranges: usize) -> CommandResult {
+    let mut counts = vec![0usize; ranges];
+    for key in state.store.keys() {
+        counts[cluster::slot_range_index(cluster::key_slot(key), ranges)] += 1;
+    }
+    Ok(counts
+        .iter()
+        .enumerate()
+        .map(|(index, count)| {
+            let (first, last) = cluster::slot_range(index, ranges);
+            format!("{}-{} {}", first, last, count)
+        })
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{list, run, strings, sweep_tombstones};
+    use super::*;
+    use crate::quota::PrefixQuotas;
+
+    fn set_str(state: &mut StoreState, key: &str, value: &str) {
+        strings::set(state, key.to_string(), value.to_string()).unwrap();
+    }
+
+    fn usage(state: &StoreState) -> Vec<(String, u64, u64)> {
+        state
+            .quotas
+            .rules()
+            .iter()
+            .map(|rule| (rule.pattern(), rule.keys(), rule.bytes()))
+            .collect()
+    }
+
+    #[test]
+    fn test_delete_and_rename_move_prefix_quota_usage() {
+        let mut state = StoreState {
+            quotas: PrefixQuotas::compile([
+                ("session:*", Some(2), None),
+                ("session:admin:*", Some(1), None),
+            ])
+            .unwrap(),
+            soft_delete: true,
+            ..StoreState::default()
+        };
+        let usage_of = |state: &StoreState| -> Vec<u64> {
+            usage(state).into_iter().map(|(_, keys, _)| keys).collect()
+        };
+
+        run(&mut state, "SET session:admin:1 x").unwrap();
+        run(&mut state, "SET session:1 x").unwrap();
+        // The admin key counts against the longer prefix only
+        assert_eq!(usage_of(&state), [1, 1]);
+        assert_eq!(
+            run(&mut state, "SET session:admin:2 x"),
+            Err(HandlerError::QuotaExceeded(
+                "'session:admin:*' allows at most 1 keys".to_string()
+            ))
+        );
+
+        run(&mut state, "DELETE session:admin:1").unwrap();
+        assert_eq!(usage_of(&state), [1, 0]);
+        run(&mut state, "SET session:admin:2 x").unwrap();
+        // The tombstone would take the admin prefix past its limit again
+        assert!(matches!(
+            run(&mut state, "UNDELETE session:admin:1"),
+            Err(HandlerError::QuotaExceeded(_))
+        ));
+
+        // Renaming moves the key from one quota to the other
+        assert_eq!(
+            run(&mut state, "RENAMENX session:admin:2 session:2").unwrap(),
+            "1"
+        );
+        assert_eq!(usage_of(&state), [2, 0]);
+        assert_eq!(run(&mut state, "UNDELETE session:admin:1").unwrap(), "1");
+        assert_eq!(usage_of(&state), [2, 1]);
+        assert!(matches!(
+            run(&mut state, "RENAMEPREFIX session:admin: session:moved:"),
+            Err(HandlerError::QuotaExceeded(_))
+        ));
+        assert_eq!(usage_of(&state), [2, 1]);
+    }
+
+    #[test]
+    fn test_list_writes_keep_quota_bytes_in_step() {
+        let mut state = StoreState {
+            quotas: PrefixQuotas::compile([("a:*", None, Some(100)), ("b:*", None, Some(5))])
+                .unwrap(),
+            ..StoreState::default()
+        };
+        state.store.insert("a:1".to_string(), list(&["xy", "z"]));
+        super::super::recount_quotas(&mut state);
+        assert_eq!(usage(&state)[0], ("a:*".to_string(), 1, 6));
+
+        assert_eq!(run(&mut state, "LMOVE a:1 b:1 RIGHT LEFT").unwrap(), "z");
+        assert_eq!(
+            usage(&state),
+            [("a:*".to_string(), 1, 5), ("b:*".to_string(), 1, 4)]
+        );
+        // b:1 holding "xy" too would be 6 bytes of key and values, one over
+        assert!(matches!(
+            run(&mut state, "LMOVE a:1 b:1 RIGHT LEFT"),
+            Err(HandlerError::QuotaExceeded(_))
+        ));
+        run(&mut state, "LTRIM b:1 1 0").unwrap();
+        assert_eq!(run(&mut state, "LMOVE a:1 b:1 RIGHT LEFT").unwrap(), "xy");
+        assert_eq!(
+            usage(&state),
+            [("a:*".to_string(), 0, 0), ("b:*".to_string(), 1, 5)]
+        );
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut state = StoreState::default();
+        run(&mut state, "SET user:1001 John").unwrap();
+
+        let result = run(&mut state, "DELETE user:1001");
+        assert!(result.is_ok());
+
+        // Verify key is deleted
+        let result = run(&mut state, "GET user:1001");
+        assert!(result.is_err());
+    }
+
+    fn soft_delete_state() -> StoreState {
+        let mut state = StoreState {
+            soft_delete: true,
+            ..StoreState::default()
+        };
+        for key in ["user:1", "user:2", "order:1"] {
+            set_str(&mut state, key, "v");
+        }
+        state
+    }
+
+    #[test]
+    fn test_soft_delete_hides_and_restores() {
+        let mut state = soft_delete_state();
+        assert_eq!(
+            run(&mut state, "DELETE user:1").unwrap(),
+            "DELETED user:1 (was: v)"
+        );
+        assert!(run(&mut state, "GET user:1").is_err());
+        assert_eq!(run(&mut state, "WAITKEY user:1 0").unwrap(), "0");
+        assert_eq!(run(&mut state, "SCAN 0").unwrap(), "0\norder:1\nuser:2");
+        assert!(run(&mut state, "INFO").unwrap().starts_with("keys:2\n"));
+        assert!(state.tombstones.contains_key("user:1"));
+
+        assert_eq!(run(&mut state, "UNDELETE user:1").unwrap(), "1");
+        assert_eq!(run(&mut state, "GET user:1").unwrap(), "GET user:1 = v");
+        assert_eq!(run(&mut state, "UNDELETE user:1").unwrap(), "0");
+
+        // A key set again after its delete keeps the tombstone
+        run(&mut state, "DELETE user:2").unwrap();
+        set_str(&mut state, "user:2", "new");
+        assert_eq!(run(&mut state, "UNDELETE user:2").unwrap(), "0");
+        assert_eq!(run(&mut state, "GET user:2").unwrap(), "GET user:2 = new");
+        assert!(state.tombstones.contains_key("user:2"));
+    }
+
+    #[test]
+    fn test_purge_frees_matching_tombstones() {
+        let mut state = soft_delete_state();
+        for key in ["user:1", "user:2", "order:1"] {
+            run(&mut state, &format!("DELETE {}", key)).unwrap();
+        }
+        set_str(&mut state, "user:3", "live");
+
+        assert_eq!(run(&mut state, "PURGE user:*").unwrap(), "2");
+        assert_eq!(run(&mut state, "UNDELETE user:1").unwrap(), "0");
+        assert_eq!(run(&mut state, "GET user:3").unwrap(), "GET user:3 = live");
+        assert_eq!(run(&mut state, "PURGE order:1").unwrap(), "1");
+        assert!(state.tombstones.is_empty());
+
+        // Without SOFT_DELETE nothing is kept to purge or restore
+        state.soft_delete = false;
+        run(&mut state, "DELETE user:3").unwrap();
+        assert_eq!(run(&mut state, "UNDELETE user:3").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_sweep_purges_expired_tombstones() {
+        let mut state = soft_delete_state();
+        state.tombstone_ttl_secs = Some(60);
+        for (key, deleted_at) in [("user:1", 1_000), ("user:2", 1_050)] {
+            let value = state.store.remove(key).unwrap();
+            state
+                .tombstones
+                .insert(key.to_string(), Tombstone { value, deleted_at });
+        }
+
+        assert_eq!(sweep_tombstones(&mut state, 1_059), 0);
+        assert_eq!(sweep_tombstones(&mut state, 1_060), 1);
+        // At most one sweep per second
+        state.tombstones.get_mut("user:2").unwrap().deleted_at = 0;
+        assert_eq!(sweep_tombstones(&mut state, 1_060), 0);
+        assert_eq!(sweep_tombstones(&mut state, 1_061), 1);
+        assert!(state.tombstones.is_empty());
+
+        state.tombstone_ttl_secs = None;
+        run(&mut state, "DELETE order:1").unwrap();
+        assert_eq!(sweep_tombstones(&mut state, u64::MAX), 0);
+    }
+
+    #[test]
+    fn test_prefixhistogram_groups_by_namespace() {
+        let mut state = StoreState::default();
+        assert_eq!(run(&mut state, "PREFIXHISTOGRAM :").unwrap(), "(empty)");
+        for key in ["user:1", "user:2", "user:3:cart", "session:1", "config"] {
+            set_str(&mut state, key, "v");
+        }
+
+        assert_eq!(
+            run(&mut state, "PREFIXHISTOGRAM :").unwrap(),
+            "user 3\n(none) 1\nsession 1"
+        );
+        assert_eq!(
+            run(&mut state, "PREFIXHISTOGRAM 1").unwrap(),
+            "(none) 3\nsession: 1\nuser: 1"
+        );
+    }
+
+    #[test]
+    fn test_keyslot_and_slotstats() {
+        let mut state = StoreState::default();
+        assert_eq!(run(&mut state, "KEYSLOT foo").unwrap(), "12182");
+        assert_eq!(run(&mut state, "KEYSLOT {foo}:bar").unwrap(), "12182");
+        assert!(state.store.is_empty());
+
+        // Slots 866 (hello), 5061 (bar), 12182 (foo and {foo}:bar)
+        for key in ["hello", "bar", "foo", "{foo}:bar"] {
+            set_str(&mut state, key, "v");
+        }
+        assert_eq!(
+            run(&mut state, "SLOTSTATS 4").unwrap(),
+            "0-4095 1\n4096-8191 1\n8192-12287 2\n12288-16383 0"
+        );
+        assert_eq!(run(&mut state, "SLOTSTATS 1").unwrap(), "0-16383 4");
+    }
+
+    #[test]
+    fn test_waitkey_reports_presence() {
+        let mut state = StoreState::default();
+        assert_eq!(run(&mut state, "WAITKEY job 0").unwrap(), "0");
+        set_str(&mut state, "job", "done");
+        assert_eq!(run(&mut state, "WAITKEY job 1000").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_valuelengthstats() {
+        let mut state = StoreState::default();
+        assert_eq!(run(&mut state, "VALUELENGTHSTATS").unwrap(), "strings:0");
+
+        set_str(&mut state, "b", "xx");
+        set_str(&mut state, "a", "xx");
+        set_str(&mut state, "long", "0123456789");
+        run(&mut state, "SETCRC crc abcd").unwrap();
+        state
+            .store
+            .insert("queue".to_string(), list(&["a much longer item"]));
+        assert_eq!(
+            run(&mut state, "VALUELENGTHSTATS").unwrap(),
+            "strings:4\nmin:2\nmin_key:a\nmax:10\nmax_key:long\navg:4.50"
+        );
+    }
+
+    #[test]
+    fn test_renamenx_moves_value() {
+        let mut state = StoreState::default();
+        state.store.insert("old".to_string(), list(&["a", "b"]));
+
+        assert_eq!(run(&mut state, "RENAMENX old new").unwrap(), "1");
+        assert!(!state.store.contains_key("old"));
+        assert_eq!(state.store.get("new"), Some(&list(&["a", "b"])));
+    }
+
+    #[test]
+    fn test_renamenx_existing_destination_is_noop() {
+        let mut state = StoreState::default();
+        set_str(&mut state, "a", "1");
+        set_str(&mut state, "b", "2");
+
+        assert_eq!(run(&mut state, "RENAMENX a b").unwrap(), "0");
+        assert_eq!(state.store.get("a"), Some(&Value::Str("1".to_string())));
+        assert_eq!(state.store.get("b"), Some(&Value::Str("2".to_string())));
+    }
+
+    #[test]
+    fn test_renameprefix_moves_matching_keys() {
+        let mut state = StoreState::default();
+        for key in ["v1:a", "v1:b", "v1:c:d", "v10", "other"] {
+            set_str(&mut state, key, key);
+        }
+
+        assert_eq!(run(&mut state, "RENAMEPREFIX v1: v2:").unwrap(), "3");
+        assert_eq!(
+            run(&mut state, "GET v2:c:d").unwrap(),
+            "GET v2:c:d = v1:c:d"
+        );
+        let mut keys: Vec<&String> = state.store.keys().collect();
+        keys.sort();
+        assert_eq!(keys, ["other", "v10", "v2:a", "v2:b", "v2:c:d"]);
+        assert_eq!(run(&mut state, "RENAMEPREFIX v1: v2:").unwrap(), "0");
+    }
+
+    #[test]
+    fn test_renameprefix_collision_changes_nothing() {
+        let mut state = StoreState::default();
+        for key in ["v1:a", "v1:b", "v2:b"] {
+            set_str(&mut state, key, key);
+        }
+        let before = state.store.clone();
+
+        assert_eq!(
+            run(&mut state, "RENAMEPREFIX v1: v2:"),
+            Err(HandlerError::KeyExists("v2:b".to_string()))
+        );
+        assert_eq!(state.store, before);
+
+        // A target that is itself being moved away isn't a collision
+        assert_eq!(run(&mut state, "RENAMEPREFIX v v1").unwrap(), "3");
+        assert_eq!(run(&mut state, "GET v11:b").unwrap(), "GET v11:b = v1:b");
+        assert_eq!(run(&mut state, "GET v12:b").unwrap(), "GET v12:b = v2:b");
+    }
+
+    #[test]
+    fn test_renamenx_missing_source() {
+        let mut state = StoreState::default();
+
+        assert_eq!(
+            run(&mut state, "RENAMENX ghost new"),
+            Err(HandlerError::not_found("ghost"))
+        );
+        assert!(!state.store.contains_key("new"));
+    }
+
+    /// Run one SCAN call, returning the next cursor and the batch
+    fn scan_batch(state: &mut StoreState, cursor: &str, count: usize) -> (String, Vec<String>) {
+        let response = run(state, &format!("SCAN {} COUNT {}", cursor, count)).unwrap();
+        let mut lines = response.lines().map(str::to_string);
+        (lines.next().unwrap(), lines.collect())
+    }
+
+    #[test]
+    fn test_scan_walks_all_keys_in_batches() {
+        let mut state = StoreState::default();
+        for i in 0..25 {
+            set_str(&mut state, &format!("key:{:02}", i), &i.to_string());
+        }
+
+        let mut cursor = "0".to_string();
+        let mut seen = Vec::new();
+        loop {
+            let (next, keys) = scan_batch(&mut state, &cursor, 10);
+            assert!(keys.len() <= 10);
+            seen.extend(keys);
+            if next == "0" {
+                break;
+            }
+            cursor = next;
+        }
+
+        let expected: Vec<String> = (0..25).map(|i| format!("key:{:02}", i)).collect();
+        assert_eq!(seen, expected);
+    }
+
+    #[test]
+    fn test_scan_guarantee_under_mutation_storm() {
+        use rand::Rng;
+        use std::collections::HashSet;
+
+        let mut state = StoreState::default();
+        let mut rng = rand::thread_rng();
+        let stable: HashSet<String> = (0..2000).map(|i| format!("stable:{}", i)).collect();
+        for key in &stable {
+            set_str(&mut state, key, "v");
+        }
+        // Every key that existed at any point during the scan
+        let mut ever_present = stable.clone();
+
+        let mut cursor = "0".to_string();
+        let mut returned = Vec::new();
+        loop {
+            let (next, keys) = scan_batch(&mut state, &cursor, 37);
+            returned.extend(keys);
+
+            // Storm: enough inserts to force rehashing, plus deletes
+            for _ in 0..200 {
+                let key = format!("volatile:{}", rng.gen_range(0..5000));
+                if rng.gen_bool(0.6) {
+                    set_str(&mut state, &key, "v");
+                    ever_present.insert(key);
+                } else {
+                    state.store.remove(&key);
+                }
+            }
+
+            if next == "0" {
+                break;
+            }
+            cursor = next;
+        }
+
+        let returned_set: HashSet<&String> = returned.iter().collect();
+        assert_eq!(returned_set.len(), returned.len(), "a key was repeated");
+        for key in &stable {
+            assert!(returned_set.contains(key), "stable key {} was skipped", key);
+        }
+        for key in &returned {
+            assert!(ever_present.contains(key), "{} never existed", key);
+        }
+    }
+
+    #[test]
+    fn test_getmatching_lists_keys_under_a_prefix() {
+        let mut state = StoreState::default();
+        for (key, value) in [
+            ("user:2", "Grace Hopper"),
+            ("user:1", "Ada"),
+            ("user:10", "Linus"),
+            ("users", "3"),
+            ("session:1", "tok"),
+        ] {
+            set_str(&mut state, key, value);
+        }
+        state
+            .store
+            .insert("user:tags".to_string(), list(&["admin", "ops"]));
+
+        assert_eq!(
+            run(&mut state, "GETMATCHING user:*").unwrap(),
+            "user:1 Ada\nuser:10 Linus\nuser:2 Grace Hopper\nuser:tags (list)"
+        );
+        assert_eq!(
+            run(&mut state, "GETMATCHING user:?").unwrap(),
+            "user:1 Ada\nuser:2 Grace Hopper"
+        );
+        assert_eq!(run(&mut state, "GETMATCHING order:*").unwrap(), "(empty)");
+
+        // Soft-deleted keys aren't live
+        state.soft_delete = true;
+        run(&mut state, "DELETE user:10").unwrap();
+        assert_eq!(
+            run(&mut state, "GETMATCHING user:1*").unwrap(),
+            "user:1 Ada"
+        );
+    }
+
+    #[test]
+    fn test_sortbyvalue_numeric_order() {
+        let mut state = StoreState::default();
+        for (key, value) in [
+            ("score:alice", "42"),
+            ("score:bob", "7"),
+            ("score:carol", "100"),
+            ("score:dave", "-3.5"),
+            ("score:eve", "n/a"),
+            ("other", "1"),
+        ] {
+            set_str(&mut state, key, value);
+        }
+
+        // 100 sorts after 42 numerically even though "100" < "42" lexically
+        assert_eq!(
+            run(&mut state, "SORTBYVALUE score:* asc").unwrap(),
+            "score:dave -3.5\nscore:bob 7\nscore:alice 42\nscore:carol 100\nscore:eve n/a"
+        );
+        assert_eq!(
+            run(&mut state, "SORTBYVALUE score:* desc").unwrap(),
+            "score:eve n/a\nscore:carol 100\nscore:alice 42\nscore:bob 7\nscore:dave -3.5"
+        );
+        assert_eq!(
+            run(&mut state, "SORTBYVALUE missing:* asc").unwrap(),
+            "(empty)"
+        );
+    }
+}
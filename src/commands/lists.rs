@@ -0,0 +1,237 @@
+//! List commands: LMOVE, LTRIM, LINDEX
+
+use std::collections::VecDeque;
+
+use super::{CommandResult, StoreState, admit, note_write, remove};
+use crate::handler::{HandlerError, Value};
+
+pub(crate) fn lmove(
+    state: &mut StoreState,
+    src: String,
+    dst: String,
+    from: String,
+    to: String,
+) -> CommandResult {
+    // Type-check both keys up front so a failed move never leaves src popped
+    for key in [&src, &dst] {
+        if let Some(value) = state.store.get(key)
+            && !matches!(value, Value::List(_))
+        {
+            return Err(HandlerError::WrongType);
+        }
+    }
+
+    // The element may cross from one quota to another; rotating a list in
+    // place changes nothing
+    if !state.quotas.is_empty()
+        && src != dst
+        && let Some(Value::List(list)) = state.store.get(&src)
+        && let Some(element) = if from == "LEFT" {
+            list.front()
+        } else {
+            list.back()
+        }
+    {
+        let src_bytes = (list.len() > 1).then(|| state.store[&src].byte_len() - element.len());
+        let dst_bytes = state.store.get(&dst).map_or(0, Value::byte_len) + element.len();
+        admit(
+            state,
+            [(src.as_str(), src_bytes), (dst.as_str(), Some(dst_bytes))],
+        )?;
+    }
+    if state.store.contains_key(&src) {
+        note_write(state, &src);
+    }
+    let element = match state.store.get_mut(&src) {
+        Some(Value::List(list)) => {
+            let element = if from == "LEFT" {
+                list.pop_front()
+            } else {
+                list.pop_back()
+            };
+            if list.is_empty() {
+                remove(state, &src);
+            }
+            element
+        }
+        _ => None,
+    };
+
+    let Some(element) = element else {
+        return Ok("(nil)".to_string());
+    };
+
+    note_write(state, &dst);
+    if let Value::List(list) = state
+        .store
+        .entry(dst)
+        .or_insert_with(|| Value::List(VecDeque::new()))
+    {
+        if to == "LEFT" {
+            list.push_front(element.clone());
+        } else {
+            list.push_back(element.clone());
+        }
+    }
+
+    Ok(element)
+}
+
+pub(crate) fn ltrim(state: &mut StoreState, key: String, start: i64, stop: i64) -> CommandResult {
+    let len = match state.store.get(&key) {
+        Some(Value::List(list)) => list.len(),
+        Some(_) => return Err(HandlerError::WrongType),
+        None => return Ok("OK".to_string()),
+    };
+
+    match normalize_range(len, start, stop) {
+        Some((start, stop)) => {
+            note_write(state, &key);
+            if let Some(Value::List(list)) = state.store.get_mut(&key) {
+                list.truncate(stop + 1);
+                list.drain(..start);
+            }
+        }
+        None => {
+            remove(state, &key);
+        }
+    }
+
+    Ok("OK".to_string())
+}
+
+pub(crate) fn lindex(state: &mut StoreState, key: String, index: i64) -> CommandResult {
+    let list = match state.store.get(&key) {
+        Some(Value::List(list)) => list,
+        Some(_) => return Err(HandlerError::WrongType),
+        None => return Ok("(nil)".to_string()),
+    };
+
+    let index = if index < 0 {
+        index + list.len() as i64
+    } else {
+        index
+    };
+    let element = usize::try_from(index).ok().and_then(|i| list.get(i));
+
+    Ok(element.cloned().unwrap_or_else(|| "(nil)".to_string()))
+}
+
+/// Resolve Redis-style (possibly negative) inclusive range bounds against a
+/// list of length `len`, clamping out-of-range bounds
+///
+/// Returns `None` when the range selects no elements.
+fn normalize_range(len: usize, start: i64, stop: i64) -> Option<(usize, usize)> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (start + len).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        stop + len
+    } else {
+        stop.min(len - 1)
+    };
+
+    if start > stop || start >= len {
+        return None;
+    }
+    Some((start as usize, stop as usize))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{list, run};
+    use super::*;
+
+    #[test]
+    fn test_lmove_directions() {
+        let mut state = StoreState::default();
+        state
+            .store
+            .insert("src".to_string(), list(&["a", "b", "c"]));
+        state.store.insert("dst".to_string(), list(&["x"]));
+
+        let cases = [
+            ("LMOVE src dst LEFT LEFT", "a", list(&["a", "x"])),
+            ("LMOVE src dst RIGHT RIGHT", "c", list(&["a", "x", "c"])),
+            ("LMOVE src dst LEFT RIGHT", "b", list(&["a", "x", "c", "b"])),
+        ];
+        for (line, moved, expected_dst) in cases {
+            assert_eq!(run(&mut state, line).unwrap(), moved);
+            assert_eq!(state.store.get("dst"), Some(&expected_dst));
+        }
+
+        // Source was drained, so the key is gone
+        assert!(!state.store.contains_key("src"));
+
+        assert_eq!(run(&mut state, "LMOVE dst dst RIGHT LEFT").unwrap(), "b");
+        assert_eq!(state.store.get("dst"), Some(&list(&["b", "a", "x", "c"])));
+    }
+
+    #[test]
+    fn test_lmove_empty_source() {
+        let mut state = StoreState::default();
+        assert_eq!(
+            run(&mut state, "LMOVE missing dst LEFT RIGHT").unwrap(),
+            "(nil)"
+        );
+        assert!(!state.store.contains_key("dst"));
+    }
+
+    #[test]
+    fn test_lmove_wrong_type() {
+        let mut state = StoreState::default();
+        state.store.insert("src".to_string(), list(&["a"]));
+        run(&mut state, "SET dst plain").unwrap();
+
+        assert_eq!(
+            run(&mut state, "LMOVE src dst LEFT RIGHT").unwrap_err(),
+            HandlerError::WrongType
+        );
+        // Source must be untouched after a rejected move
+        assert_eq!(state.store.get("src"), Some(&list(&["a"])));
+    }
+
+    #[test]
+    fn test_ltrim_ranges() {
+        let cases = [
+            ("LTRIM log 1 2", Some(list(&["b", "c"]))),
+            ("LTRIM log -2 -1", Some(list(&["d", "e"]))),
+            ("LTRIM log -100 100", Some(list(&["a", "b", "c", "d", "e"]))),
+            ("LTRIM log 3 1", None),
+            ("LTRIM log 10 20", None),
+        ];
+        for (line, expected) in cases {
+            let mut state = StoreState::default();
+            state
+                .store
+                .insert("log".to_string(), list(&["a", "b", "c", "d", "e"]));
+
+            assert_eq!(run(&mut state, line).unwrap(), "OK");
+            assert_eq!(state.store.get("log"), expected.as_ref(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_lindex() {
+        let mut state = StoreState::default();
+        state
+            .store
+            .insert("list".to_string(), list(&["a", "b", "c"]));
+
+        let cases = [
+            ("LINDEX list 0", "a"),
+            ("LINDEX list 2", "c"),
+            ("LINDEX list -1", "c"),
+            ("LINDEX list -3", "a"),
+            ("LINDEX list 3", "(nil)"),
+            ("LINDEX list -4", "(nil)"),
+            ("LINDEX missing 0", "(nil)"),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(run(&mut state, line).unwrap(), expected, "{}", line);
+        }
+    }
+}
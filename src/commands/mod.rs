@@ -0,0 +1,465 @@
+//! Command implementations, grouped by family, and the table that routes
+//! each parsed command to its handler function
+//!
+//! Every handler takes the `StoreState` plus the command's parsed arguments.
+//! Adding a command means writing its handler in the matching family module
+//! and registering it in `COMMANDS`; `CommandHandler` only looks commands up
+//! here and never needs to change. Commands defined outside the crate are
+//! registered at runtime instead, in the handler's `CommandRegistry`.
+
+mod admin;
+mod keys;
+mod lists;
+mod procedures;
+mod strings;
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::access::KeyAccessLog;
+use crate::config::{SetDuplicatePolicy, ValueCase};
+use crate::custom::{CommandRegistry, CustomStore};
+use crate::handler::{HandlerError, HandlerStats, Store, Tombstones, Value};
+use crate::history::ValueHistory;
+use crate::hotkeys::HotKeys;
+use crate::idempotency::IdempotencyWindow;
+use crate::import_report::ImportTracker;
+use crate::parser::{CommandType, ParseOptions, ParseStats};
+use crate::procedure::Procedure;
+use crate::quota::PrefixQuotas;
+use crate::thread::InFlight;
+use crate::validate::ValidationRules;
+
+/// What every command handler returns
+pub(crate) type CommandResult = Result<String, HandlerError>;
+
+/// Everything a command can read or modify
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StoreState {
+    pub(crate) store: HashMap<String, Value>,
+    pub(crate) idempotency: IdempotencyWindow,
+    pub(crate) stats: HandlerStats,
+    /// Where SHUTDOWN SAVE writes the store
+    pub(crate) snapshot_path: Option<PathBuf>,
+    /// Case folding SET applies to values
+    pub(crate) value_case: ValueCase,
+    /// What SET does to a key that already exists
+    pub(crate) set_duplicate_policy: SetDuplicatePolicy,
+    /// Whether DELETE keeps a tombstone instead of erasing the key
+    pub(crate) soft_delete: bool,
+    /// Deleted keys kept under `soft_delete`; never visible to other commands
+    pub(crate) tombstones: Tombstones,
+    /// Seconds a tombstone is kept before the sweep purges it (`None` keeps
+    /// it until PURGE)
+    pub(crate) tombstone_ttl_secs: Option<u64>,
+    /// When the tombstone sweep last ran, in Unix seconds
+    pub(crate) last_tombstone_sweep: u64,
+    /// Approximate per-key command counts (disabled unless `TRACK_HOT_KEYS`)
+    pub(crate) hot_keys: HotKeys,
+    /// Rules values must meet before they are written (`VALIDATE_*`)
+    pub(crate) validation: ValidationRules,
+    /// Key and byte limits per key prefix, with their usage
+    /// (`LIMIT_PREFIX_*`)
+    pub(crate) quotas: PrefixQuotas,
+    /// Insertion times and read counts for INSPECT (`TRACK_KEY_ACCESS`)
+    pub(crate) access: KeyAccessLog,
+    /// Versions and earlier values for GETAT (`VALUE_HISTORY_DEPTH`)
+    pub(crate) history: ValueHistory,
+    /// Created/identical/changed counts for `--import-report`
+    pub(crate) imports: ImportTracker,
+    /// Procedures CALL can run, from `PROCEDURES_FILE` and DEFINE
+    pub(crate) procedures: HashMap<String, Procedure>,
+    /// Commands registered at runtime, shared with whoever parses input
+    pub(crate) custom: CommandRegistry,
+    /// Successful parses per keyword, shared with the IO threads
+    pub(crate) parse_stats: Arc<ParseStats>,
+    /// Commands sent to the main thread and not yet answered, for INFO
+    pub(crate) in_flight: Arc<InFlight>,
+    /// Bumped once for every command that changed the store, for SNAPSHOTID
+    pub(crate) snapshot_id: u64,
+    /// Whether the command being dispatched has changed the store
+    pub(crate) changed: bool,
+}
+
+impl StoreState {
+    /// State whose store has room for at least `capacity` keys
+    pub(crate) fn with_capacity(capacity: usize) -> Self {
+        Self {
+            store: HashMap::with_capacity(capacity),
+            ..Self::default()
+        }
+    }
+
+    /// How lines this state runs (APPLY, CALL, EXPLAIN) are parsed: with
+    /// its custom commands, and with `ID` clauses only if its idempotency
+    /// window is on
+    pub(crate) fn parse_options(&self) -> ParseOptions<'_> {
+        ParseOptions {
+            custom: Some(&self.custom),
+            id_clause: self.idempotency.is_enabled(),
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, the clock tombstones are stamped with
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+/// Purge tombstones older than the TTL as of `now`, returning how many
+///
+/// Runs before every command but does the work at most once a second.
+pub(crate) fn sweep_tombstones(state: &mut StoreState, now: u64) -> usize {
+    let Some(ttl) = state.tombstone_ttl_secs else {
+        return 0;
+    };
+    if state.tombstones.is_empty() || state.last_tombstone_sweep == now {
+        return 0;
+    }
+    state.last_tombstone_sweep = now;
+    let before = state.tombstones.len();
+    state
+        .tombstones
+        .retain(|_, tombstone| tombstone.deleted_at.saturating_add(ttl) > now);
+    before - state.tombstones.len()
+}
+
+/// Note in the access log, value history and prefix quotas that `key` is
+/// about to be written
+pub(crate) fn note_write(state: &mut StoreState, key: &str) {
+    note_change(state, key);
+    if state.access.is_enabled() {
+        let existed = state.store.contains_key(key);
+        state.access.written(key, existed);
+    }
+    if state.history.is_enabled() {
+        state.history.written(key, state.store.get(key));
+    }
+}
+
+/// Store `value` at `key`, keeping the access log and history in step
+pub(crate) fn insert(state: &mut StoreState, key: String, value: Value) {
+    note_write(state, &key);
+    state.store.insert(key, value);
+}
+
+/// Store a whole value taken from the input (SET, SETCRC, LOADJSON,
+/// GETORSET), noting for the import report whether it creates or
+/// overwrites the key
+pub(crate) fn import_value(state: &mut StoreState, key: String, value: Value) {
+    if state.imports.is_enabled() {
+        state.imports.record(&key, state.store.get(&key), &value);
+    }
+    insert(state, key, value);
+}
+
+/// Take `key` out of the store, keeping the access log, history and prefix
+/// quotas in step
+pub(crate) fn remove(state: &mut StoreState, key: &str) -> Option<Value> {
+    if state.store.contains_key(key) {
+        note_change(state, key);
+    }
+    state.access.removed(key);
+    state.history.removed(key);
+    state.store.remove(key)
+}
+
+/// Check a string value about to be written at `key` against the
+/// validation rules
+pub(crate) fn validate(state: &mut StoreState, key: &str, value: &str) -> Result<(), HandlerError> {
+    state
+        .validation
+        .check(key, value)
+        .map_err(HandlerError::ValidationFailed)
+}
+
+/// Note that `key` is about to change; `dispatch` bumps the snapshot id
+/// and settles the prefix quotas' usage once the command is done
+pub(crate) fn note_change(state: &mut StoreState, key: &str) {
+    state.changed = true;
+    if !state.quotas.is_empty() {
+        let bytes = state.store.get(key).map(value_bytes);
+        state.quotas.touch(key, bytes);
+    }
+}
+
+/// Check writes about to be made against the prefix quotas, each a key and
+/// the value bytes it will hold (`None` for a key about to be removed); a
+/// key written twice counts once, with its last value
+pub(crate) fn admit<'a>(
+    state: &StoreState,
+    writes: impl IntoIterator<Item = (&'a str, Option<usize>)>,
+) -> Result<(), HandlerError> {
+    if state.quotas.is_empty() {
+        return Ok(());
+    }
+    let writes: HashMap<&str, Option<usize>> = writes.into_iter().collect();
+    state
+        .quotas
+        .check(writes.into_iter().map(|(key, after)| {
+            let before = state.store.get(key).map(value_bytes);
+            (key, before, after.map(|bytes| bytes as u64))
+        }))
+        .map_err(HandlerError::QuotaExceeded)
+}
+
+/// Recount the prefix quotas' usage from the whole store
+pub(crate) fn recount_quotas(state: &mut StoreState) {
+    state.quotas.recount(
+        state
+            .store
+            .iter()
+            .map(|(key, value)| (key.as_str(), value_bytes(value))),
+    );
+}
+
+fn value_bytes(value: &Value) -> u64 {
+    value.byte_len() as u64
+}
+
+/// Write the store (tombstones included) to the configured snapshot path,
+/// returning the number of live keys
+pub(crate) fn save_snapshot(state: &StoreState) -> Result<usize, HandlerError> {
+    let path = state
+        .snapshot_path
+        .as_ref()
+        .ok_or_else(|| HandlerError::SaveFailed("no SNAPSHOT_PATH configured".to_string()))?;
+    crate::snapshot::save_with_tombstones(&state.store, &state.tombstones, path)
+        .map_err(|err| HandlerError::SaveFailed(err.to_string()))?;
+    Ok(state.store.len())
+}
+
+/// One entry in the command table
+pub(crate) struct CommandSpec {
+    /// Keyword as returned by `CommandType::name`
+    pub(crate) name: &'static str,
+    /// Unpacks the command's arguments and calls its handler
+    run: fn(&mut StoreState, CommandType) -> CommandResult,
+}
+
+/// Build a `CommandSpec` whose `run` destructures one `CommandType` variant
+/// and passes its fields to the handler in order
+macro_rules! command {
+    ($name:literal, $variant:ident => $handler:path) => {
+        CommandSpec {
+            name: $name,
+            run: |state, command| match command {
+                CommandType::$variant => $handler(state),
+                other => unreachable!("{} dispatched to {}", other.name(), $name),
+            },
+        }
+    };
+    ($name:literal, $variant:ident($($arg:ident),+) => $handler:path) => {
+        CommandSpec {
+            name: $name,
+            run: |state, command| match command {
+                CommandType::$variant($($arg),+) => $handler(state, $($arg),+),
+                other => unreachable!("{} dispatched to {}", other.name(), $name),
+            },
+        }
+    };
+}
+
+/// The command table
+static COMMANDS: &[CommandSpec] = &[
+    // Strings
+    command!("SET", Set(key, value) => strings::set),
+    command!("SETINT", SetInt(key, value) => strings::setint),
+    command!("CONVERT", Convert(key) => strings::convert),
+    command!("GET", Get(key) => strings::get),
+    command!("GETWITHTTL", GetWithTtl(key) => strings::getwithttl),
+    command!("JGET", JGet(key, path) => strings::jget),
+    command!("APPENDCAP", AppendCap(key, max_len, value) => strings::appendcap),
+    command!("GETDEFAULT", GetDefault(key, default) => strings::getdefault),
+    command!("GETORSET", GetOrSet(key, default) => strings::getorset),
+    command!("INCRBOUNDED", IncrBounded(key, max) => strings::incrbounded),
+    command!("INCRIF", IncrIf(key, cond_key, cond_value) => strings::incrif),
+    command!("SETCRC", SetCrc(key, value) => strings::setcrc),
+    command!("GETCRC", GetCrc(key) => strings::getcrc),
+    command!("LOADJSON", LoadJson(path) => strings::loadjson),
+    command!("DUMPJSON", DumpJson(path) => strings::dumpjson),
+    command!("MERGE", Merge(dest, sources, strategy, strict) => strings::merge),
+    command!("GENSET", GenSet(prefix, count) => strings::genset),
+    // Keys
+    command!("DELETE", Delete(key) => keys::delete),
+    command!("UNDELETE", Undelete(key) => keys::undelete),
+    command!("PURGE", Purge(pattern) => keys::purge),
+    command!("RENAMENX", RenameNx(src, dst) => keys::renamenx),
+    command!("RENAMEPREFIX", RenamePrefix(old, new) => keys::renameprefix),
+    command!("SCAN", Scan(after, count) => keys::scan),
+    command!("SORTBYVALUE", SortByValue(pattern, ascending) => keys::sortbyvalue),
+    command!("GETMATCHING", GetMatching(pattern) => keys::getmatching),
+    command!("WAITKEY", WaitKey(key, timeout_ms) => keys::waitkey),
+    command!("VALUELENGTHSTATS", ValueLengthStats => keys::valuelengthstats),
+    command!("PREFIXHISTOGRAM", PrefixHistogram(delimiter) => keys::prefixhistogram),
+    command!("INSPECT", Inspect(key) => keys::inspect),
+    command!("GETAT", GetAt(key, version) => strings::getat),
+    command!("KEYSLOT", KeySlot(key) => keys::keyslot),
+    command!("SLOTSTATS", SlotStats(ranges) => keys::slotstats),
+    // Lists
+    command!("LMOVE", LMove(src, dst, from, to) => lists::lmove),
+    command!("LTRIM", LTrim(key, start, stop) => lists::ltrim),
+    command!("LINDEX", LIndex(key, index) => lists::lindex),
+    // Admin
+    command!("INFO", Info(quotas) => admin::info),
+    command!("VERSION", Version => admin::version),
+    command!("STATS", Stats => admin::stats),
+    command!("STATSRESET", StatsReset => admin::statsreset),
+    command!("HOTKEYS", HotKeys(count) => admin::hotkeys),
+    command!("CHECK", Check => admin::check),
+    command!("PARSESTATS", ParseStats => admin::parsestats),
+    command!("SNAPSHOTID", SnapshotId => admin::snapshotid),
+    command!("FINGERPRINT", Fingerprint => admin::fingerprint),
+    command!("SHUTDOWN", Shutdown(save) => admin::shutdown),
+    command!("DUMP", DumpParallel(dir, threads) => admin::dump_parallel),
+    command!("EXPLAIN", Explain(line) => admin::explain),
+    // Procedures
+    command!("DEFINE", Define(name, procedure) => procedures::define),
+    command!("CALL", Call(name, args) => procedures::call),
+    command!("APPLY", Apply(path) => procedures::apply),
+];
+
+/// Find the table entry for a command keyword
+pub(crate) fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    static BY_NAME: OnceLock<HashMap<&'static str, &'static CommandSpec>> = OnceLock::new();
+    BY_NAME
+        .get_or_init(|| COMMANDS.iter().map(|spec| (spec.name, spec)).collect())
+        .get(name)
+        .copied()
+}
+
+/// Every keyword in the command table, in table order
+pub(crate) fn names() -> impl Iterator<Item = &'static str> {
+    COMMANDS.iter().map(|spec| spec.name)
+}
+
+/// Run a parsed command against the state
+pub(crate) fn dispatch(state: &mut StoreState, command: CommandType) -> CommandResult {
+    let result = match command {
+        CommandType::Custom(name, args) => run_custom(state, name, &args),
+        command => {
+            let spec = lookup(command.name())
+                .unwrap_or_else(|| panic!("{} is missing from the command table", command.name()));
+            (spec.run)(state, command)
+        }
+    };
+    if std::mem::take(&mut state.changed) {
+        state.snapshot_id += 1;
+    }
+    let StoreState { store, quotas, .. } = state;
+    quotas.settle(|key| store.get(key).map(value_bytes));
+    result
+}
+
+/// Run a registered command's executor against a mediated view of the store
+fn run_custom(state: &mut StoreState, name: &str, args: &[String]) -> CommandResult {
+    let executor = state
+        .custom
+        .executor(name)
+        .ok_or_else(|| HandlerError::UnknownCommand(name.to_string()))?;
+    executor.execute(&mut CustomStore::new(state), args)
+}
+
+/// Answer a read from the store alone, as a read snapshot does off the
+/// main thread; `None` for commands that need the rest of the state
+pub(crate) fn read_only(store: &Store, command: &CommandType) -> Option<CommandResult> {
+    match command {
+        CommandType::Get(key) => Some(strings::get_in(store, key)),
+        CommandType::GetWithTtl(key) => Some(strings::getwithttl_in(store, key)),
+        CommandType::GetMatching(pattern) => Some(keys::getmatching_in(store, pattern)),
+        _ => None,
+    }
+}
+
+/// Parse `line` and dispatch it (test helper for the family modules)
+#[cfg(test)]
+pub(crate) fn run(state: &mut StoreState, line: &str) -> CommandResult {
+    let command: crate::parser::Command = line.parse().unwrap();
+    dispatch(state, command.command_type)
+}
+
+/// Build a list value (test helper for the family modules)
+#[cfg(test)]
+pub(crate) fn list(items: &[&str]) -> Value {
+    Value::List(items.iter().map(|s| s.to_string()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Command;
+
+    #[test]
+    fn test_every_command_is_registered() {
+        let examples = [
+            "SET k v",
+            "GET k",
+            "DELETE k",
+            "UNDELETE k",
+            "PURGE k*",
+            "LMOVE a b LEFT RIGHT",
+            "LTRIM k 0 1",
+            "LINDEX k 0",
+            "APPENDCAP k 4 v",
+            "GETDEFAULT k v",
+            "GETORSET k v",
+            "INCRBOUNDED k 5",
+            "INCRIF k c v",
+            "SETCRC k v",
+            "SETINT k 1",
+            "CONVERT k INT",
+            "GETCRC k",
+            "GETWITHTTL k",
+            "JGET k a.b[0]",
+            "RENAMENX a b",
+            "RENAMEPREFIX a b",
+            "SCAN 0",
+            "SORTBYVALUE * asc",
+            "GETMATCHING *",
+            "LOADJSON config.json",
+            "DUMPJSON backup.json",
+            "MERGE d a STRATEGY sum",
+            "GENSET k 3",
+            "WAITKEY k 100",
+            "VALUELENGTHSTATS",
+            "PREFIXHISTOGRAM :",
+            "INSPECT k",
+            "GETAT k 1",
+            "KEYSLOT k",
+            "SLOTSTATS 4",
+            "INFO",
+            "VERSION",
+            "STATS",
+            "STATSRESET",
+            "HOTKEYS 5",
+            "CHECK",
+            "PARSESTATS",
+            "SNAPSHOTID",
+            "FINGERPRINT",
+            "SHUTDOWN SAVE",
+            "DUMP PARALLEL dumps 2",
+            "EXPLAIN GET k",
+            "DEFINE p AS GET $1 END",
+            "CALL p k",
+            "APPLY p.patch",
+        ];
+        for line in examples {
+            let command: Command = line.parse().unwrap();
+            let name = command.command_type.name();
+            assert_eq!(lookup(name).map(|spec| spec.name), Some(name), "{}", line);
+        }
+        assert_eq!(COMMANDS.len(), examples.len());
+    }
+
+    #[test]
+    fn test_table_names_are_unique() {
+        let mut names: Vec<&str> = COMMANDS.iter().map(|spec| spec.name).collect();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), COMMANDS.len());
+    }
+}
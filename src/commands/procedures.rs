@@ -0,0 +1,246 @@
+//! Stored procedures and patch files: DEFINE, CALL, APPLY
+
+use super::{CommandResult, StoreState, dispatch};
+use crate::handler::HandlerError;
+use crate::parser::Command;
+use crate::procedure::Procedure;
+
+/// Keywords a patch line can't use: patches don't nest or run procedures,
+/// and a line can't stop or block the main thread
+const FORBIDDEN_IN_PATCH: [&str; 5] = ["APPLY", "CALL", "DEFINE", "SHUTDOWN", "WAITKEY"];
+
+/// Store a procedure under `name`, replacing any earlier definition
+pub(crate) fn define(state: &mut StoreState, name: String, procedure: Procedure) -> CommandResult {
+    state.procedures.insert(name, procedure);
+    Ok("OK".to_string())
+}
+
+/// Run every step of a procedure, one response line per step
+///
+/// The steps run back to back on the main thread, so no other command sees
+/// the store between them. A step that fails reports its error in its line
+/// and the rest still run; nothing is rolled back.
+pub(crate) fn call(state: &mut StoreState, name: String, args: Vec<String>) -> CommandResult {
+    let procedure = state
+        .procedures
+        .get(&name)
+        .ok_or_else(|| HandlerError::UnknownProcedure(name.clone()))?;
+    let steps = procedure
+        .expand(&name, &args, state.parse_options())
+        .map_err(HandlerError::CallFailed)?;
+
+    let responses: Vec<String> = steps
+        .into_iter()
+        .enumerate()
+        .map(|(i, step)| match dispatch(state, step) {
+            Ok(response) => format!("{}) {}", i + 1, response),
+            Err(err) => format!("{}) (error) {}", i + 1, err),
+        })
+        .collect();
+    Ok(responses.join("\n"))
+}
+
+/// Run every command in the patch file at `path`, all or nothing
+///
+/// Blank lines and lines starting with `#` are skipped. Every line is parsed
+/// before any runs, and lines that write files or can't run inside a patch
+/// are refused then. The store is copied before the first command, and the
+/// first command to fail puts the copy back, so a patch either applies in
+/// full or leaves no trace; the copy makes APPLY cost time and memory in
+/// proportion to the store.
+pub(crate) fn apply(state: &mut StoreState, path: String) -> CommandResult {
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|err| HandlerError::ApplyFailed(format!("{}: {}", path, err)))?;
+    let at = |line: usize, reason: String| {
+        HandlerError::ApplyFailed(format!("{}:{}: {}", path, line, reason))
+    };
+
+    let mut commands = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let command =
+            Command::parse_with(line, state.parse_options()).map_err(|err| at(i + 1, err))?;
+        let command_type = command.command_type;
+        if FORBIDDEN_IN_PATCH.contains(&command_type.name()) || command_type.is_external_write() {
+            let reason = format!("{} can't run inside a patch", command_type.name());
+            return Err(at(i + 1, reason));
+        }
+        if command.idempotency_key.is_some() {
+            return Err(at(
+                i + 1,
+                "an ID clause belongs on the APPLY, not a line".to_string(),
+            ));
+        }
+        commands.push((i + 1, command_type));
+    }
+
+    let saved = state.clone();
+    let applied = commands.len();
+    for (line, command_type) in commands {
+        let shown = command_type.to_string();
+        if let Err(err) = dispatch(state, command_type) {
+            *state = saved;
+            return Err(at(line, format!("{}: {}", shown, err)));
+        }
+    }
+    Ok(format!("applied:{}", applied))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::run;
+    use super::*;
+    use crate::handler::Value;
+    use crate::idempotency::IdempotencyWindow;
+
+    fn write_patch(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "crabby_apply_{}_{}.patch",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path.display().to_string()
+    }
+
+    #[test]
+    fn test_apply_runs_every_line() {
+        let mut state = StoreState::default();
+        run(&mut state, "SET counter 1").unwrap();
+        let path = write_patch(
+            "ok",
+            "# bump and copy\nINCRBOUNDED counter 10\n\nSET copy x\nRENAMENX copy moved\n",
+        );
+
+        assert_eq!(
+            run(&mut state, &format!("APPLY {}", path)).unwrap(),
+            "applied:3"
+        );
+        assert_eq!(
+            state.store.get("counter"),
+            Some(&Value::Str("2".to_string()))
+        );
+        assert_eq!(state.store.get("moved"), Some(&Value::Str("x".to_string())));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_apply_rolls_back_on_the_first_failing_line() {
+        let mut state = StoreState::default();
+        run(&mut state, "SET counter 1").unwrap();
+        run(&mut state, "SET name Ada").unwrap();
+        let path = write_patch(
+            "bad",
+            "SET counter 5\nDELETE name\nINCRBOUNDED missing_limit x\nSET never 1\n",
+        );
+
+        let err = run(&mut state, &format!("APPLY {}", path)).unwrap_err();
+        assert!(matches!(err, HandlerError::ApplyFailed(_)), "{}", err);
+        // The bad line is a parse error, so nothing ran at all
+        assert!(err.to_string().contains(&format!("{}:3:", path)), "{}", err);
+
+        std::fs::write(
+            &path,
+            "SET fresh 1\nDELETE counter\nINCRBOUNDED name 10\nSET never 1\n",
+        )
+        .unwrap();
+        let err = run(&mut state, &format!("APPLY {}", path)).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "apply failed, nothing applied: {}:3: INCRBOUNDED name 10: value is not an integer",
+                path
+            )
+        );
+        assert_eq!(
+            state.store.get("counter"),
+            Some(&Value::Str("1".to_string()))
+        );
+        assert!(!state.store.contains_key("fresh"));
+        assert!(!state.store.contains_key("never"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_apply_refuses_lines_that_cant_run_in_a_patch() {
+        let mut state = StoreState {
+            idempotency: IdempotencyWindow::new(4),
+            ..StoreState::default()
+        };
+        let path = write_patch("forbidden", "SET a 1\nDUMPJSON out.json\n");
+        let err = run(&mut state, &format!("APPLY {}", path)).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with(":2: DUMPJSON can't run inside a patch"),
+            "{}",
+            err
+        );
+
+        std::fs::write(&path, "SET a 1\nSET b 2 ID req-1\n").unwrap();
+        let err = run(&mut state, &format!("APPLY {}", path)).unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with(":2: an ID clause belongs on the APPLY, not a line")
+        );
+        assert!(state.store.is_empty());
+        std::fs::remove_file(path).unwrap();
+
+        let err = run(&mut state, "APPLY /nonexistent/crabby.patch").unwrap_err();
+        assert!(matches!(err, HandlerError::ApplyFailed(_)), "{}", err);
+    }
+
+    #[test]
+    fn test_call_runs_defined_steps_with_arguments() {
+        let mut state = StoreState::default();
+        assert_eq!(
+            run(
+                &mut state,
+                "DEFINE bump AS GETDEFAULT $1 0; INCRBOUNDED $1 $2; GET $1 END"
+            )
+            .unwrap(),
+            "OK"
+        );
+        assert_eq!(
+            run(&mut state, "CALL bump counter:x 5").unwrap(),
+            "1) 0\n2) 1\n3) GET counter:x = 1"
+        );
+        assert_eq!(
+            run(&mut state, "CALL bump counter:x 1").unwrap(),
+            "1) 1\n2) (error) limit reached\n3) GET counter:x = 1"
+        );
+        assert_eq!(
+            state.store.get("counter:x"),
+            Some(&Value::Str("1".to_string()))
+        );
+
+        // A later DEFINE replaces the procedure
+        run(&mut state, "DEFINE bump AS DELETE $1 END").unwrap();
+        assert_eq!(
+            run(&mut state, "CALL bump counter:x").unwrap(),
+            "1) DELETED counter:x (was: 1)"
+        );
+        assert!(state.store.is_empty());
+    }
+
+    #[test]
+    fn test_call_errors_run_no_steps() {
+        let mut state = StoreState::default();
+        assert_eq!(
+            run(&mut state, "CALL missing k"),
+            Err(HandlerError::UnknownProcedure("missing".to_string()))
+        );
+
+        run(&mut state, "DEFINE put AS SET $1 v; INCRBOUNDED $1 $2 END").unwrap();
+        let err = run(&mut state, "CALL put k").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "call failed: Procedure put expects 2 arguments, got 1"
+        );
+        let err = run(&mut state, "CALL put k ten").unwrap_err();
+        assert!(matches!(err, HandlerError::CallFailed(_)), "{}", err);
+        assert!(state.store.is_empty());
+    }
+}
@@ -0,0 +1,1242 @@
+//! String commands: SET, SETINT, CONVERT, GET, GETWITHTTL, GETAT, JGET,
+//! APPENDCAP, GETDEFAULT, GETORSET, INCRBOUNDED, INCRIF, SETCRC, GETCRC,
+//! LOADJSON, DUMPJSON, MERGE, GENSET
+
+use std::borrow::Cow;
+
+use super::{CommandResult, StoreState, admit, import_value, insert, note_write, validate};
+use crate::config::SetDuplicatePolicy;
+use crate::crc32::crc32;
+use crate::handler::{HandlerError, Store, Value};
+use crate::history::VersionError;
+use crate::json::{self, ExtractError, JsonPath, parse_flat_object, write_flat_object};
+use crate::parser::MergeStrategy;
+
+pub(crate) fn set(state: &mut StoreState, key: String, mut value: String) -> CommandResult {
+    if state.store.contains_key(&key) {
+        match state.set_duplicate_policy {
+            SetDuplicatePolicy::Overwrite => {}
+            SetDuplicatePolicy::FirstWins => return Ok("OK (unchanged)".to_string()),
+            SetDuplicatePolicy::Error => return Err(HandlerError::AlreadySet(key)),
+        }
+    }
+    state.value_case.apply(&mut value);
+    validate(state, &key, &value)?;
+    admit(state, [(key.as_str(), Some(value.len()))])?;
+    let response = format!("SET {} = {}", key, value);
+    import_value(state, key, Value::Str(value));
+    Ok(response)
+}
+
+/// Store `value` at `key` as an integer, which INCRBOUNDED and INCRIF then
+/// update without parsing and GET reads back as text
+///
+/// Follows `SET_DUPLICATE_POLICY` and the validation rules (against the
+/// decimal text) like SET.
+pub(crate) fn setint(state: &mut StoreState, key: String, value: i64) -> CommandResult {
+    if state.store.contains_key(&key) {
+        match state.set_duplicate_policy {
+            SetDuplicatePolicy::Overwrite => {}
+            SetDuplicatePolicy::FirstWins => return Ok("OK (unchanged)".to_string()),
+            SetDuplicatePolicy::Error => return Err(HandlerError::AlreadySet(key)),
+        }
+    }
+    validate(state, &key, &value.to_string())?;
+    admit(state, [(key.as_str(), Some(size_of::<i64>()))])?;
+    let response = format!("SETINT {} = {}", key, value);
+    import_value(state, key, Value::Int(value));
+    Ok(response)
+}
+
+/// Store the numeric string at `key` as an integer in place
+///
+/// An integer is left as it is; a string that isn't a 64-bit integer is an
+/// error and stays a string.
+pub(crate) fn convert(state: &mut StoreState, key: String) -> CommandResult {
+    let value = match state.store.get(&key) {
+        Some(Value::Str(value)) => value
+            .parse::<i64>()
+            .map_err(|_| HandlerError::NotAnInteger)?,
+        Some(Value::Int(_)) => return Ok("OK (unchanged)".to_string()),
+        Some(_) => return Err(HandlerError::WrongType),
+        None => return Err(HandlerError::not_found(key)),
+    };
+    admit(state, [(key.as_str(), Some(size_of::<i64>()))])?;
+    insert(state, key, Value::Int(value));
+    Ok("OK".to_string())
+}
+
+pub(crate) fn get(state: &mut StoreState, key: String) -> CommandResult {
+    get_in(&state.store, &key)
+}
+
+/// GET against the store alone, so a read snapshot can answer it too
+pub(crate) fn get_in(store: &Store, key: &str) -> CommandResult {
+    match store.get(key) {
+        Some(Value::Str(value)) => Ok(format!("GET {} = {}", key, value)),
+        Some(Value::Int(value)) => Ok(format!("GET {} = {}", key, value)),
+        Some(_) => Err(HandlerError::WrongType),
+        None => Err(HandlerError::not_found(key)),
+    }
+}
+
+/// The value and its remaining TTL in seconds, on two lines
+///
+/// Nothing in the store expires, so the TTL is always -1 (no expiry).
+pub(crate) fn getwithttl(state: &mut StoreState, key: String) -> CommandResult {
+    getwithttl_in(&state.store, &key)
+}
+
+/// GETWITHTTL against the store alone
+pub(crate) fn getwithttl_in(store: &Store, key: &str) -> CommandResult {
+    match store.get(key) {
+        Some(Value::Str(value)) => Ok(format!("{}\n-1", value)),
+        Some(Value::Int(value)) => Ok(format!("{}\n-1", value)),
+        Some(_) => Err(HandlerError::WrongType),
+        None => Ok("(nil)".to_string()),
+    }
+}
+
+/// The value `key` had at `version`, if the history still holds it
+pub(crate) fn getat(state: &mut StoreState, key: String, version: u64) -> CommandResult {
+    if !state.history.is_enabled() {
+        return Err(HandlerError::HistoryDisabled);
+    }
+    let Some(current) = state.store.get(&key) else {
+        return Err(HandlerError::not_found(key));
+    };
+    match state.history.value_at(&key, version, current) {
+        Ok(value) => Ok(value.to_string()),
+        Err(VersionError::Evicted { oldest }) => Err(HandlerError::VersionEvicted {
+            key,
+            version,
+            oldest,
+        }),
+        Err(VersionError::NeverExisted { latest }) => Err(HandlerError::VersionNeverExisted {
+            key,
+            version,
+            latest,
+        }),
+    }
+}
+
+/// The field at `path` in the key's value, parsed as a JSON document
+pub(crate) fn jget(state: &mut StoreState, key: String, path: JsonPath) -> CommandResult {
+    let value = match state.store.get(&key) {
+        Some(Value::Str(value)) => value,
+        Some(_) => return Err(HandlerError::WrongType),
+        None => return Err(HandlerError::not_found(key)),
+    };
+    json::extract(value, &path).map_err(|err| match err {
+        ExtractError::NotJson(err) => HandlerError::NotJson(err.to_string()),
+        ExtractError::PathNotFound => HandlerError::PathNotFound {
+            key,
+            path: path.to_string(),
+        },
+    })
+}
+
+pub(crate) fn appendcap(
+    state: &mut StoreState,
+    key: String,
+    max_len: usize,
+    value: String,
+) -> CommandResult {
+    let mut appended = match state.store.get(&key) {
+        Some(Value::Str(current)) => current.clone(),
+        Some(_) => return Err(HandlerError::WrongType),
+        None => String::new(),
+    };
+
+    appended.push_str(&value);
+    if appended.len() > max_len {
+        // Never split a multi-byte character
+        let mut cut = max_len;
+        while !appended.is_char_boundary(cut) {
+            cut -= 1;
+        }
+        appended.truncate(cut);
+    }
+
+    validate(state, &key, &appended)?;
+    admit(state, [(key.as_str(), Some(appended.len()))])?;
+    let len = appended.len();
+    insert(state, key, Value::Str(appended));
+    Ok(len.to_string())
+}
+
+pub(crate) fn getdefault(state: &mut StoreState, key: String, default: String) -> CommandResult {
+    match state.store.get(&key) {
+        Some(Value::Str(value)) => Ok(value.clone()),
+        Some(Value::Int(value)) => Ok(value.to_string()),
+        Some(_) => Err(HandlerError::WrongType),
+        None => Ok(default),
+    }
+}
+
+/// The value at `key`, or, if the key is missing, `default` after storing
+/// it there, in one step on the main thread
+///
+/// The stored default goes through `VALUE_CASE`, the validation rules, and
+/// prefix quotas like a SET value, and is returned as stored.
+pub(crate) fn getorset(state: &mut StoreState, key: String, mut default: String) -> CommandResult {
+    match state.store.get(&key) {
+        Some(Value::Str(value)) => return Ok(value.clone()),
+        Some(Value::Int(value)) => return Ok(value.to_string()),
+        Some(_) => return Err(HandlerError::WrongType),
+        None => {}
+    }
+    state.value_case.apply(&mut default);
+    validate(state, &key, &default)?;
+    admit(state, [(key.as_str(), Some(default.len()))])?;
+    import_value(state, key, Value::Str(default.clone()));
+    Ok(default)
+}
+
+pub(crate) fn incrbounded(state: &mut StoreState, key: String, max: i64) -> CommandResult {
+    let current = counter(state, &key)?;
+
+    // Overflowing i64 is necessarily past any max
+    match current.checked_add(1) {
+        Some(next) if next <= max => store_counter(state, key, next),
+        _ => Err(HandlerError::LimitReached),
+    }
+}
+
+/// The integer at `key` for an increment: an `Int` as it is, a string
+/// parsed, and 0 for a missing key
+fn counter(state: &StoreState, key: &str) -> Result<i64, HandlerError> {
+    match state.store.get(key) {
+        Some(Value::Int(value)) => Ok(*value),
+        Some(Value::Str(value)) => value.parse::<i64>().map_err(|_| HandlerError::NotAnInteger),
+        Some(_) => Err(HandlerError::WrongType),
+        None => Ok(0),
+    }
+}
+
+/// Store an incremented counter the way it was held: an `Int` stays one
+/// and is updated in place, anything else is written as a string
+fn store_counter(state: &mut StoreState, key: String, next: i64) -> CommandResult {
+    let text = next.to_string();
+    validate(state, &key, &text)?;
+    if let Some(Value::Int(_)) = state.store.get(&key) {
+        // Same size, so no quota can be crossed
+        note_write(state, &key);
+        if let Some(Value::Int(value)) = state.store.get_mut(&key) {
+            *value = next;
+        }
+        return Ok(text);
+    }
+    admit(state, [(key.as_str(), Some(text.len()))])?;
+    insert(state, key, Value::Str(text.clone()));
+    Ok(text)
+}
+
+/// Increment the integer at `key` by 1 if `cond_key` holds exactly
+/// `cond_value`, returning the new value
+///
+/// A missing condition key never matches. The check and the increment run
+/// as one command on the main thread, so nothing can change the condition
+/// in between.
+pub(crate) fn incrif(
+    state: &mut StoreState,
+    key: String,
+    cond_key: String,
+    cond_value: String,
+) -> CommandResult {
+    match state.store.get(&cond_key) {
+        Some(Value::Str(value)) if *value == cond_value => {}
+        Some(Value::Int(value)) if value.to_string() == cond_value => {}
+        // A SETCRC value is read only through GETCRC
+        Some(Value::List(_) | Value::Checksummed { .. }) => return Err(HandlerError::WrongType),
+        _ => return Err(HandlerError::ConditionNotMet),
+    }
+    let current = counter(state, &key)?;
+
+    // There is no bound to report, so overflow reads as a value that can't
+    // be incremented
+    let next = current.checked_add(1).ok_or(HandlerError::NotAnInteger)?;
+    store_counter(state, key, next)
+}
+
+pub(crate) fn setcrc(state: &mut StoreState, key: String, value: String) -> CommandResult {
+    validate(state, &key, &value)?;
+    admit(
+        state,
+        [(key.as_str(), Some(value.len() + size_of::<u32>()))],
+    )?;
+    let crc = crc32(value.as_bytes());
+    let response = format!("SETCRC {} = {} (crc32: {:08x})", key, value, crc);
+    import_value(state, key, Value::Checksummed { value, crc });
+    Ok(response)
+}
+
+pub(crate) fn getcrc(state: &mut StoreState, key: String) -> CommandResult {
+    match state.store.get(&key) {
+        Some(Value::Checksummed { value, crc }) if crc32(value.as_bytes()) == *crc => {
+            Ok(value.clone())
+        }
+        Some(Value::Checksummed { .. }) => Err(HandlerError::ChecksumMismatch),
+        Some(_) => Err(HandlerError::WrongType),
+        None => Err(HandlerError::not_found(key)),
+    }
+}
+
+/// Set every string member of the JSON object in the file at `path`
+///
+/// The file is read and parsed in full before anything is stored, so a
+/// malformed file, or a value a validation rule rejects, leaves the store
+/// untouched. Members with non-string values are skipped and counted rather
+/// than failing the load.
+pub(crate) fn loadjson(state: &mut StoreState, path: String) -> CommandResult {
+    let contents = std::fs::read_to_string(&path)
+        .map_err(|err| HandlerError::LoadFailed(format!("{}: {}", path, err)))?;
+    let object = parse_flat_object(&contents)
+        .map_err(|err| HandlerError::LoadFailed(format!("{}: {}", path, err)))?;
+
+    for (key, value) in &object.pairs {
+        validate(state, key, value)?;
+    }
+    admit(
+        state,
+        object
+            .pairs
+            .iter()
+            .map(|(key, value)| (key.as_str(), Some(value.len()))),
+    )?;
+    let loaded = object.pairs.len();
+    for (key, value) in object.pairs {
+        import_value(state, key, Value::Str(value));
+    }
+    Ok(format!("loaded:{}\nskipped:{}", loaded, object.skipped))
+}
+
+/// Write every plain string key to `path` as one flat JSON object
+///
+/// Keys are written in sorted order so dumps of the same data are identical.
+/// Integers are written as their decimal text (and load back as strings).
+/// Lists and checksummed values have no flat-JSON form and are skipped and
+/// counted.
+pub(crate) fn dumpjson(state: &mut StoreState, path: String) -> CommandResult {
+    let mut pairs: Vec<(&str, Cow<'_, str>)> = state
+        .store
+        .iter()
+        .filter_map(|(key, value)| match value {
+            Value::Str(value) => Some((key.as_str(), Cow::Borrowed(value.as_str()))),
+            Value::Int(value) => Some((key.as_str(), Cow::Owned(value.to_string()))),
+            _ => None,
+        })
+        .collect();
+    pairs.sort_unstable();
+    let dumped = pairs.len();
+    let skipped = state.store.len() - dumped;
+
+    let object = write_flat_object(pairs.iter().map(|(key, value)| (*key, value.as_ref())));
+    std::fs::write(&path, object)
+        .map_err(|err| HandlerError::DumpFailed(format!("{}: {}", path, err)))?;
+    Ok(format!("dumped:{}\nskipped:{}", dumped, skipped))
+}
+
+/// Combine the string values at `sources` and store the result at `dest`
+///
+/// Sources are read before `dest` is written, so `dest` may also be a
+/// source. Missing sources are skipped, or fail the merge when `strict`; if
+/// none exist the merge stores nothing and returns `(nil)`. Numeric
+/// strategies work on 64-bit floats, so integers beyond 2^53 lose precision.
+pub(crate) fn merge(
+    state: &mut StoreState,
+    dest: String,
+    sources: Vec<String>,
+    strategy: MergeStrategy,
+    strict: bool,
+) -> CommandResult {
+    let mut values = Vec::with_capacity(sources.len());
+    for key in &sources {
+        match state.store.get(key) {
+            Some(Value::Str(value)) => values.push((key, value)),
+            Some(_) => return Err(HandlerError::WrongType),
+            None if strict => return Err(HandlerError::not_found(key.clone())),
+            None => {}
+        }
+    }
+    if values.is_empty() {
+        return Ok("(nil)".to_string());
+    }
+
+    let merged = match strategy {
+        MergeStrategy::Concat(separator) => values
+            .iter()
+            .map(|(_, value)| value.as_str())
+            .collect::<Vec<_>>()
+            .join(&separator),
+        numeric => {
+            let mut numbers = Vec::with_capacity(values.len());
+            for (key, value) in &values {
+                match value.parse::<f64>() {
+                    Ok(number) if number.is_finite() => numbers.push(number),
+                    _ => return Err(HandlerError::NotNumeric(key.to_string())),
+                }
+            }
+            let result = match numeric {
+                MergeStrategy::Sum => numbers.iter().sum(),
+                MergeStrategy::Max => numbers.iter().copied().fold(f64::MIN, f64::max),
+                _ => numbers.iter().copied().fold(f64::MAX, f64::min),
+            };
+            result.to_string()
+        }
+    };
+
+    validate(state, &dest, &merged)?;
+    admit(state, [(dest.as_str(), Some(merged.len()))])?;
+    insert(state, dest, Value::Str(merged.clone()));
+    Ok(merged)
+}
+
+/// Value GENSET stores at every key it generates
+const GENSET_VALUE: &str = "value";
+
+/// Set `count` keys named `prefix0` to `prefix{count - 1}` to a dummy value,
+/// overwriting any that exist, and return how many were set
+///
+/// Meant for populating a store for benchmarks without a huge input file.
+/// With validation rules or prefix quotas, every key is checked before any
+/// is set.
+pub(crate) fn genset(state: &mut StoreState, prefix: String, count: usize) -> CommandResult {
+    if !state.validation.is_empty() {
+        for i in 0..count {
+            validate(state, &format!("{}{}", prefix, i), GENSET_VALUE)?;
+        }
+    }
+    if !state.quotas.is_empty() {
+        let keys: Vec<String> = (0..count).map(|i| format!("{}{}", prefix, i)).collect();
+        admit(
+            state,
+            keys.iter()
+                .map(|key| (key.as_str(), Some(GENSET_VALUE.len()))),
+        )?;
+    }
+    state.store.reserve(count);
+    for i in 0..count {
+        insert(
+            state,
+            format!("{}{}", prefix, i),
+            Value::Str(GENSET_VALUE.to_string()),
+        );
+    }
+    Ok(count.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::run;
+    use super::*;
+    use crate::config::ValueCase;
+    use crate::quota::PrefixQuotas;
+    use crate::validate::ValidationRules;
+
+    fn set_str(state: &mut StoreState, key: &str, value: &str) {
+        set(state, key.to_string(), value.to_string()).unwrap();
+    }
+
+    #[test]
+    fn test_set_and_get() {
+        let mut state = StoreState::default();
+
+        let result = run(&mut state, "SET user:1001 John");
+        assert!(result.is_ok());
+
+        let result = run(&mut state, "GET user:1001");
+        assert!(result.is_ok());
+        assert!(result.unwrap().contains("John"));
+    }
+
+    #[test]
+    fn test_value_case_folds_values_not_keys() {
+        let mut state = StoreState {
+            value_case: ValueCase::Lower,
+            ..StoreState::default()
+        };
+        run(&mut state, "SET MixedKey MixedCase").unwrap();
+        assert_eq!(
+            run(&mut state, "GET MixedKey").unwrap(),
+            "GET MixedKey = mixedcase"
+        );
+
+        state.value_case = ValueCase::Upper;
+        assert_eq!(run(&mut state, "SET k Straße").unwrap(), "SET k = STRASSE");
+    }
+
+    #[test]
+    fn test_set_duplicate_policies() {
+        let mut state = StoreState::default();
+        run(&mut state, "SET k first").unwrap();
+        assert_eq!(run(&mut state, "SET k second").unwrap(), "SET k = second");
+
+        state.set_duplicate_policy = SetDuplicatePolicy::FirstWins;
+        assert_eq!(run(&mut state, "SET k third").unwrap(), "OK (unchanged)");
+        assert_eq!(run(&mut state, "GET k").unwrap(), "GET k = second");
+        // New keys are still written
+        assert_eq!(run(&mut state, "SET j new").unwrap(), "SET j = new");
+
+        state.set_duplicate_policy = SetDuplicatePolicy::Error;
+        let err = run(&mut state, "SET k fourth").unwrap_err();
+        assert_eq!(err, HandlerError::AlreadySet("k".to_string()));
+        assert_eq!(err.to_string(), "key 'k' is already set, not overwritten");
+        assert!(!err.is_soft());
+        assert_eq!(run(&mut state, "GET k").unwrap(), "GET k = second");
+
+        // A deleted key can be set again
+        run(&mut state, "DELETE k").unwrap();
+        assert_eq!(run(&mut state, "SET k fifth").unwrap(), "SET k = fifth");
+    }
+
+    #[test]
+    fn test_get_nonexistent_key() {
+        let mut state = StoreState::default();
+        let result = run(&mut state, "GET nonexistent");
+        assert!(result.is_err());
+        assert!(result.unwrap_err().is_soft());
+    }
+
+    #[test]
+    fn test_appendcap_under_and_over_cap() {
+        let mut state = StoreState::default();
+
+        assert_eq!(run(&mut state, "APPENDCAP log 8 abc").unwrap(), "3");
+        assert_eq!(run(&mut state, "APPENDCAP log 8 defghijk").unwrap(), "8");
+        assert_eq!(
+            state.store.get("log"),
+            Some(&Value::Str("abcdefgh".to_string()))
+        );
+
+        // An existing value already past the cap is truncated too
+        set_str(&mut state, "long", "0123456789");
+        assert_eq!(run(&mut state, "APPENDCAP long 4 x").unwrap(), "4");
+        assert_eq!(
+            state.store.get("long"),
+            Some(&Value::Str("0123".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_prefix_quota_rejects_writes_without_applying_them() {
+        let mut state = StoreState {
+            quotas: PrefixQuotas::compile([("s:*", Some(2), None), ("b:*", None, Some(10))])
+                .unwrap(),
+            ..StoreState::default()
+        };
+        let exceeded = |limit: &str| Err(HandlerError::QuotaExceeded(limit.to_string()));
+
+        run(&mut state, "SET s:1 a").unwrap();
+        run(&mut state, "SET s:2 b").unwrap();
+        assert_eq!(
+            run(&mut state, "SET s:3 c"),
+            exceeded("'s:*' allows at most 2 keys")
+        );
+        // Overwriting an existing key adds no key
+        run(&mut state, "SET s:2 longer").unwrap();
+        assert_eq!(
+            run(&mut state, "GENSET s: 3"),
+            exceeded("'s:*' allows at most 2 keys")
+        );
+        assert!(!state.store.contains_key("s:0"));
+
+        // b:1 and its value take 6 of the 10 bytes
+        run(&mut state, "SET b:1 abc").unwrap();
+        assert_eq!(
+            run(&mut state, "APPENDCAP b:1 100 vwxyz"),
+            exceeded("'b:*' allows at most 10 bytes")
+        );
+        assert_eq!(
+            run(&mut state, "SETCRC b:1 abcd"),
+            exceeded("'b:*' allows at most 10 bytes")
+        );
+        assert_eq!(state.store.get("b:1"), Some(&Value::Str("abc".to_string())));
+        // Shrinking is always allowed
+        run(&mut state, "SET b:1 a").unwrap();
+
+        let rules: Vec<String> = state
+            .quotas
+            .rules()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        assert_eq!(
+            rules,
+            [
+                "b:* keys=1/unlimited bytes=4/10",
+                "s:* keys=2/2 bytes=13/unlimited"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loadjson_checks_quotas_for_the_whole_file() {
+        let path = std::env::temp_dir().join(format!("quota_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"s:1":"a","s:2":"b","s:3":"c"}"#).unwrap();
+        let mut state = StoreState {
+            quotas: PrefixQuotas::compile([("s:*", Some(2), None)]).unwrap(),
+            ..StoreState::default()
+        };
+
+        let result = run(&mut state, &format!("LOADJSON {}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            result,
+            Err(HandlerError::QuotaExceeded(
+                "'s:*' allows at most 2 keys".to_string()
+            ))
+        );
+        assert!(state.store.is_empty());
+        assert_eq!(state.quotas.rules()[0].keys(), 0);
+    }
+
+    #[test]
+    fn test_validation_rejects_writes_without_applying_them() {
+        let mut state = StoreState {
+            validation: ValidationRules::compile([("n:*", "integer"), ("f:*", "bool")]).unwrap(),
+            ..StoreState::default()
+        };
+        let rejected = |rule: &str| Err(HandlerError::ValidationFailed(rule.to_string()));
+
+        assert_eq!(run(&mut state, "SET n:1 12").unwrap(), "SET n:1 = 12");
+        assert_eq!(run(&mut state, "SET n:1 twelve"), rejected("n:*=integer"));
+        assert_eq!(
+            run(&mut state, "APPENDCAP n:1 8 x"),
+            rejected("n:*=integer")
+        );
+        assert_eq!(run(&mut state, "APPENDCAP n:1 8 3").unwrap(), "3");
+        assert_eq!(state.store.get("n:1"), Some(&Value::Str("123".to_string())));
+
+        assert_eq!(run(&mut state, "SETCRC f:on yes"), rejected("f:*=bool"));
+        assert_eq!(
+            run(&mut state, "MERGE f:on n:1 STRATEGY sum"),
+            rejected("f:*=bool")
+        );
+        assert_eq!(run(&mut state, "GENSET n: 2"), rejected("n:*=integer"));
+        assert_eq!(
+            run(&mut state, "INCRBOUNDED f:count 5"),
+            rejected("f:*=bool")
+        );
+        assert!(!state.store.contains_key("f:on"));
+        assert!(!state.store.contains_key("f:count"));
+        assert!(!state.store.contains_key("n:0"));
+
+        // Keys no rule covers take anything
+        assert_eq!(
+            run(&mut state, "SET other twelve").unwrap(),
+            "SET other = twelve"
+        );
+    }
+
+    #[test]
+    fn test_getwithttl_value_and_ttl() {
+        let mut state = StoreState::default();
+        set_str(&mut state, "name", "Ada Lovelace");
+        state
+            .store
+            .insert("queue".to_string(), Value::List(Default::default()));
+
+        assert_eq!(
+            run(&mut state, "GETWITHTTL name").unwrap(),
+            "Ada Lovelace\n-1"
+        );
+        assert_eq!(run(&mut state, "GETWITHTTL ghost").unwrap(), "(nil)");
+        assert_eq!(
+            run(&mut state, "GETWITHTTL queue"),
+            Err(HandlerError::WrongType)
+        );
+    }
+
+    #[test]
+    fn test_jget_errors_are_distinct() {
+        let mut state = StoreState::default();
+        run(
+            &mut state,
+            r#"SET profile {"user": {"addresses": [{"city": "Paris"}, {"city": "Oslo"}], "id": 7}}"#,
+        )
+        .unwrap();
+        set_str(&mut state, "plain", "hello world");
+        state
+            .store
+            .insert("queue".to_string(), Value::List(Default::default()));
+
+        assert_eq!(
+            run(&mut state, "JGET profile user.addresses[1].city").unwrap(),
+            "Oslo"
+        );
+        assert_eq!(run(&mut state, "JGET profile user.id").unwrap(), "7");
+        assert_eq!(
+            run(&mut state, "JGET profile user.addresses[2].city"),
+            Err(HandlerError::PathNotFound {
+                key: "profile".to_string(),
+                path: "user.addresses[2].city".to_string(),
+            })
+        );
+        let err = run(&mut state, "JGET plain user").unwrap_err();
+        assert!(matches!(err, HandlerError::NotJson(_)), "{:?}", err);
+        assert_eq!(
+            err.to_string(),
+            "value is not JSON: expected a value at byte 0"
+        );
+        assert_eq!(
+            run(&mut state, "JGET ghost user"),
+            Err(HandlerError::not_found("ghost"))
+        );
+        assert_eq!(
+            run(&mut state, "JGET queue user"),
+            Err(HandlerError::WrongType)
+        );
+    }
+
+    #[test]
+    fn test_getdefault_present_and_absent() {
+        let mut state = StoreState::default();
+        set_str(&mut state, "color", "blue");
+
+        assert_eq!(run(&mut state, "GETDEFAULT color red").unwrap(), "blue");
+        assert_eq!(
+            run(&mut state, "GETDEFAULT shade light grey").unwrap(),
+            "light grey"
+        );
+        // The default is never stored
+        assert!(!state.store.contains_key("shade"));
+    }
+
+    #[test]
+    fn test_getorset_returns_an_existing_value() {
+        let mut state = StoreState::default();
+        run(&mut state, "SET color blue").unwrap();
+        run(&mut state, "SETINT hits 7").unwrap();
+
+        assert_eq!(run(&mut state, "GETORSET color red").unwrap(), "blue");
+        assert_eq!(run(&mut state, "GETORSET hits 0").unwrap(), "7");
+        assert_eq!(
+            state.store.get("color"),
+            Some(&Value::Str("blue".to_string()))
+        );
+        assert_eq!(state.snapshot_id, 2);
+
+        state
+            .store
+            .insert("list".to_string(), Value::List(["a".to_string()].into()));
+        assert_eq!(
+            run(&mut state, "GETORSET list x"),
+            Err(HandlerError::WrongType)
+        );
+    }
+
+    #[test]
+    fn test_getorset_stores_and_returns_the_default() {
+        let mut state = StoreState::default();
+
+        assert_eq!(
+            run(&mut state, "GETORSET shade light grey").unwrap(),
+            "light grey"
+        );
+        assert_eq!(
+            state.store.get("shade"),
+            Some(&Value::Str("light grey".to_string()))
+        );
+        assert_eq!(state.snapshot_id, 1);
+        // Later calls get the stored value, not their own default
+        assert_eq!(
+            run(&mut state, "GETORSET shade dark").unwrap(),
+            "light grey"
+        );
+
+        state.value_case = ValueCase::Upper;
+        assert_eq!(run(&mut state, "GETORSET tone warm").unwrap(), "WARM");
+    }
+
+    #[test]
+    fn test_incrbounded_under_limit() {
+        let mut state = StoreState::default();
+
+        assert_eq!(run(&mut state, "INCRBOUNDED hits 2").unwrap(), "1");
+        assert_eq!(run(&mut state, "INCRBOUNDED hits 2").unwrap(), "2");
+        assert_eq!(state.store.get("hits"), Some(&Value::Str("2".to_string())));
+    }
+
+    #[test]
+    fn test_incrbounded_rejects_at_limit() {
+        let mut state = StoreState::default();
+        set_str(&mut state, "hits", "5");
+
+        let err = run(&mut state, "INCRBOUNDED hits 5").unwrap_err();
+        assert_eq!(err, HandlerError::LimitReached);
+        assert_eq!(err.to_string(), "limit reached");
+        assert!(!err.is_soft());
+        // The value is left unchanged
+        assert_eq!(state.store.get("hits"), Some(&Value::Str("5".to_string())));
+
+        // A missing key starts at 0, so a max below 1 rejects it outright
+        assert_eq!(
+            run(&mut state, "INCRBOUNDED fresh 0"),
+            Err(HandlerError::LimitReached)
+        );
+        assert!(!state.store.contains_key("fresh"));
+    }
+
+    #[test]
+    fn test_incrbounded_non_integer_value() {
+        let mut state = StoreState::default();
+        set_str(&mut state, "name", "alice");
+
+        assert_eq!(
+            run(&mut state, "INCRBOUNDED name 10"),
+            Err(HandlerError::NotAnInteger)
+        );
+    }
+
+    #[test]
+    fn test_incrif_increments_when_condition_is_met() {
+        let mut state = StoreState::default();
+        set_str(&mut state, "flag:beta", "on");
+
+        assert_eq!(run(&mut state, "INCRIF hits flag:beta on").unwrap(), "1");
+        assert_eq!(run(&mut state, "INCRIF hits flag:beta on").unwrap(), "2");
+        assert_eq!(state.store.get("hits"), Some(&Value::Str("2".to_string())));
+    }
+
+    #[test]
+    fn test_incrif_leaves_counter_when_condition_is_not_met() {
+        let mut state = StoreState::default();
+        set_str(&mut state, "hits", "7");
+
+        // A missing condition key never matches
+        let err = run(&mut state, "INCRIF hits flag:beta on").unwrap_err();
+        assert_eq!(err, HandlerError::ConditionNotMet);
+        assert_eq!(err.to_string(), "condition not met");
+
+        set_str(&mut state, "flag:beta", "off");
+        assert_eq!(
+            run(&mut state, "INCRIF hits flag:beta on"),
+            Err(HandlerError::ConditionNotMet)
+        );
+        assert_eq!(
+            run(&mut state, "INCRIF fresh flag:beta on"),
+            Err(HandlerError::ConditionNotMet)
+        );
+        assert_eq!(state.store.get("hits"), Some(&Value::Str("7".to_string())));
+        assert!(!state.store.contains_key("fresh"));
+    }
+
+    #[test]
+    fn test_setcrc_getcrc_round_trip() {
+        let mut state = StoreState::default();
+
+        assert_eq!(
+            run(&mut state, "SETCRC doc hello world").unwrap(),
+            "SETCRC doc = hello world (crc32: 0d4a1185)"
+        );
+        assert_eq!(run(&mut state, "GETCRC doc").unwrap(), "hello world");
+
+        // Plain string values carry no checksum
+        set_str(&mut state, "plain", "x");
+        assert_eq!(
+            run(&mut state, "GETCRC plain"),
+            Err(HandlerError::WrongType)
+        );
+    }
+
+    #[test]
+    fn test_getcrc_detects_corruption() {
+        let mut state = StoreState::default();
+        run(&mut state, "SETCRC doc hello world").unwrap();
+
+        // Flip the stored bytes behind the checksum's back
+        if let Some(Value::Checksummed { value, .. }) = state.store.get_mut("doc") {
+            value.replace_range(0..1, "j");
+        }
+
+        let err = run(&mut state, "GETCRC doc").unwrap_err();
+        assert_eq!(err, HandlerError::ChecksumMismatch);
+        assert_eq!(err.to_string(), "checksum mismatch");
+        assert!(!err.is_soft());
+    }
+
+    #[test]
+    fn test_checksummed_values_are_read_only_through_getcrc() {
+        let mut state = StoreState::default();
+        run(&mut state, "SETCRC doc hello").unwrap();
+
+        for line in [
+            "GET doc",
+            "GETWITHTTL doc",
+            "GETDEFAULT doc x",
+            "GETORSET doc x",
+            "JGET doc a",
+            "INCRIF hits doc hello",
+        ] {
+            assert_eq!(
+                run(&mut state, line),
+                Err(HandlerError::WrongType),
+                "{}",
+                line
+            );
+        }
+        assert_eq!(
+            run(&mut state, "GETMATCHING d*").unwrap(),
+            "doc (checksummed)"
+        );
+        assert_eq!(run(&mut state, "GETCRC doc").unwrap(), "hello");
+    }
+
+    #[test]
+    fn test_loadjson_sets_string_members() {
+        let path =
+            std::env::temp_dir().join(format!("crabby_loadjson_{}.json", std::process::id()));
+        std::fs::write(
+            &path,
+            r#"{"db.host": "localhost", "db.port": 5432, "db.pool": {"size": 4}, "mode": "dev"}"#,
+        )
+        .unwrap();
+
+        let mut state = StoreState::default();
+        set_str(&mut state, "mode", "prod");
+        let result = run(&mut state, &format!("LOADJSON {}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap(), "loaded:2\nskipped:2");
+        assert_eq!(
+            state.store.get("db.host"),
+            Some(&Value::Str("localhost".to_string()))
+        );
+        // Loaded keys overwrite existing ones
+        assert_eq!(
+            state.store.get("mode"),
+            Some(&Value::Str("dev".to_string()))
+        );
+        assert!(!state.store.contains_key("db.port"));
+        assert!(!state.store.contains_key("db.pool"));
+    }
+
+    #[test]
+    fn test_loadjson_failures_leave_store_untouched() {
+        let mut state = StoreState::default();
+        let err = run(&mut state, "LOADJSON /nonexistent/crabby.json").unwrap_err();
+        assert!(matches!(err, HandlerError::LoadFailed(_)));
+        assert!(!err.is_soft());
+
+        let path =
+            std::env::temp_dir().join(format!("crabby_loadjson_bad_{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"a": "1", "b": }"#).unwrap();
+        let result = run(&mut state, &format!("LOADJSON {}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(result, Err(HandlerError::LoadFailed(_))));
+        assert!(state.store.is_empty());
+    }
+
+    #[test]
+    fn test_dumpjson_round_trips_through_loadjson() {
+        let path =
+            std::env::temp_dir().join(format!("crabby_dumpjson_{}.json", std::process::id()));
+        let mut state = StoreState::default();
+        let values = [
+            ("greeting", "say \"hi\""),
+            ("path", "C:\\temp"),
+            ("lines", "one\ntwo\tthree\u{7}"),
+            ("emoji", "😀"),
+        ];
+        for (key, value) in values {
+            set_str(&mut state, key, value);
+        }
+        state
+            .store
+            .insert("queue".to_string(), super::super::list(&["a"]));
+        run(&mut state, "SETCRC doc checked").unwrap();
+
+        let result = run(&mut state, &format!("DUMPJSON {}", path.display()));
+        assert_eq!(result.unwrap(), "dumped:4\nskipped:2");
+
+        // There is no FLUSHALL command yet, so empty the store directly
+        state.store.clear();
+        let result = run(&mut state, &format!("LOADJSON {}", path.display()));
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap(), "loaded:4\nskipped:0");
+        assert_eq!(state.store.len(), values.len());
+        for (key, value) in values {
+            assert_eq!(state.store.get(key), Some(&Value::Str(value.to_string())));
+        }
+    }
+
+    #[test]
+    fn test_dumpjson_writes_integers_as_text() {
+        let path =
+            std::env::temp_dir().join(format!("crabby_dumpjson_int_{}.json", std::process::id()));
+        let mut state = StoreState::default();
+        run(&mut state, "SETINT total -12").unwrap();
+
+        let result = run(&mut state, &format!("DUMPJSON {}", path.display()));
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(result.unwrap(), "dumped:1\nskipped:0");
+        assert_eq!(written, r#"{"total":"-12"}"#);
+    }
+
+    #[test]
+    fn test_dumpjson_unwritable_path() {
+        let mut state = StoreState::default();
+        let err = run(&mut state, "DUMPJSON /nonexistent/dir/out.json").unwrap_err();
+        assert!(matches!(err, HandlerError::DumpFailed(_)));
+        assert!(
+            err.to_string()
+                .starts_with("dump failed: /nonexistent/dir/out.json")
+        );
+    }
+
+    #[test]
+    fn test_genset_populates_the_store() {
+        let mut state = StoreState::default();
+        set_str(&mut state, "gen:7", "kept?");
+
+        assert_eq!(run(&mut state, "GENSET gen: 100").unwrap(), "100");
+        assert_eq!(state.store.len(), 100);
+        assert!(run(&mut state, "INFO").unwrap().starts_with("keys:100\n"));
+        assert_eq!(run(&mut state, "GET gen:0").unwrap(), "GET gen:0 = value");
+        assert_eq!(run(&mut state, "GET gen:7").unwrap(), "GET gen:7 = value");
+        assert!(run(&mut state, "GET gen:100").is_err());
+        assert_eq!(run(&mut state, "GENSET none: 0").unwrap(), "0");
+    }
+
+    fn merge_state() -> StoreState {
+        let mut state = StoreState::default();
+        for (key, value) in [("s1", "10"), ("s2", "2.5"), ("s3", "-4"), ("name", "eu")] {
+            set_str(&mut state, key, value);
+        }
+        state
+    }
+
+    #[test]
+    fn test_merge_concat() {
+        let mut state = merge_state();
+
+        assert_eq!(
+            run(&mut state, "MERGE all name s1 STRATEGY concat SEPARATOR |").unwrap(),
+            "eu|10"
+        );
+        assert_eq!(
+            state.store.get("all"),
+            Some(&Value::Str("eu|10".to_string()))
+        );
+        // Sources are untouched
+        assert_eq!(state.store.get("name"), Some(&Value::Str("eu".to_string())));
+
+        // The separator defaults to nothing
+        assert_eq!(
+            run(&mut state, "MERGE all name s1 STRATEGY concat").unwrap(),
+            "eu10"
+        );
+    }
+
+    #[test]
+    fn test_merge_sum() {
+        let mut state = merge_state();
+        assert_eq!(
+            run(&mut state, "MERGE total s1 s2 s3 STRATEGY sum").unwrap(),
+            "8.5"
+        );
+        assert_eq!(
+            state.store.get("total"),
+            Some(&Value::Str("8.5".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_merge_max_and_min() {
+        let mut state = merge_state();
+        assert_eq!(
+            run(&mut state, "MERGE top s1 s2 s3 STRATEGY max").unwrap(),
+            "10"
+        );
+        assert_eq!(
+            run(&mut state, "MERGE low s1 s2 s3 STRATEGY min").unwrap(),
+            "-4"
+        );
+    }
+
+    #[test]
+    fn test_merge_numeric_error_names_source() {
+        let mut state = merge_state();
+        let err = run(&mut state, "MERGE total s1 name STRATEGY sum").unwrap_err();
+        assert_eq!(err, HandlerError::NotNumeric("name".to_string()));
+        assert_eq!(err.to_string(), "value at 'name' is not a number");
+        assert!(!state.store.contains_key("total"));
+    }
+
+    #[test]
+    fn test_merge_missing_sources() {
+        let mut state = merge_state();
+
+        // Skipped unless STRICT
+        assert_eq!(
+            run(&mut state, "MERGE total s1 gone s3 STRATEGY sum").unwrap(),
+            "6"
+        );
+        assert_eq!(
+            run(&mut state, "MERGE total s1 gone s3 STRATEGY sum STRICT"),
+            Err(HandlerError::not_found("gone"))
+        );
+        assert_eq!(state.store.get("total"), Some(&Value::Str("6".to_string())));
+
+        // Nothing to merge leaves dest alone
+        assert_eq!(
+            run(&mut state, "MERGE total gone lost STRATEGY max").unwrap(),
+            "(nil)"
+        );
+        assert_eq!(state.store.get("total"), Some(&Value::Str("6".to_string())));
+    }
+
+    #[test]
+    fn test_merge_into_a_source() {
+        let mut state = merge_state();
+        state
+            .store
+            .insert("list".to_string(), super::super::list(&["x"]));
+
+        assert_eq!(run(&mut state, "MERGE s1 s1 s3 STRATEGY sum").unwrap(), "6");
+        assert_eq!(
+            run(&mut state, "MERGE s1 s1 list STRATEGY concat"),
+            Err(HandlerError::WrongType)
+        );
+    }
+
+    #[test]
+    fn test_setint_reads_back_as_text() {
+        let mut state = StoreState::default();
+        assert_eq!(
+            run(&mut state, "SETINT hits -5").unwrap(),
+            "SETINT hits = -5"
+        );
+        assert_eq!(state.store.get("hits"), Some(&Value::Int(-5)));
+        assert_eq!(run(&mut state, "GET hits").unwrap(), "GET hits = -5");
+        assert_eq!(run(&mut state, "GETWITHTTL hits").unwrap(), "-5\n-1");
+        assert_eq!(run(&mut state, "GETDEFAULT hits 0").unwrap(), "-5");
+        assert_eq!(
+            run(&mut state, "APPENDCAP hits 10 x"),
+            Err(HandlerError::WrongType)
+        );
+
+        // Increments stay integers; legacy strings stay strings
+        assert_eq!(run(&mut state, "INCRBOUNDED hits 10").unwrap(), "-4");
+        assert_eq!(state.store.get("hits"), Some(&Value::Int(-4)));
+        run(&mut state, "SET flag on").unwrap();
+        assert_eq!(run(&mut state, "INCRIF hits flag on").unwrap(), "-3");
+        assert_eq!(state.store.get("hits"), Some(&Value::Int(-3)));
+        run(&mut state, "SET legacy 7").unwrap();
+        assert_eq!(run(&mut state, "INCRBOUNDED legacy 10").unwrap(), "8");
+        assert_eq!(
+            state.store.get("legacy"),
+            Some(&Value::Str("8".to_string()))
+        );
+
+        // An integer condition matches its text
+        assert_eq!(run(&mut state, "INCRIF legacy hits -3").unwrap(), "9");
+    }
+
+    #[test]
+    fn test_convert_migrates_numeric_strings_only() {
+        let mut state = StoreState::default();
+        run(&mut state, "SET n 0042").unwrap();
+        assert_eq!(run(&mut state, "CONVERT n INT").unwrap(), "OK");
+        assert_eq!(state.store.get("n"), Some(&Value::Int(42)));
+        assert_eq!(run(&mut state, "GET n").unwrap(), "GET n = 42");
+        assert_eq!(run(&mut state, "CONVERT n INT").unwrap(), "OK (unchanged)");
+
+        for value in ["4.5", "abc", "", "9223372036854775808"] {
+            state
+                .store
+                .insert("bad".to_string(), Value::Str(value.to_string()));
+            assert_eq!(
+                run(&mut state, "CONVERT bad INT"),
+                Err(HandlerError::NotAnInteger),
+                "{:?}",
+                value
+            );
+            assert_eq!(state.store.get("bad"), Some(&Value::Str(value.to_string())));
+        }
+        run(&mut state, "SETCRC doc 1").unwrap();
+        assert_eq!(
+            run(&mut state, "CONVERT doc INT"),
+            Err(HandlerError::WrongType)
+        );
+        assert_eq!(
+            run(&mut state, "CONVERT missing INT"),
+            Err(HandlerError::not_found("missing"))
+        );
+    }
+
+    #[test]
+    fn test_integers_count_eight_bytes_against_quotas() {
+        let mut state = StoreState {
+            quotas: PrefixQuotas::compile([("c:*", None, Some(20))]).unwrap(),
+            ..StoreState::default()
+        };
+        run(&mut state, "SETINT c:1 123456789").unwrap();
+        run(&mut state, "SET c:2 7").unwrap();
+        // Keys count too: c:1 takes 11 bytes and c:2 4, and growing "7" to
+        // 8 bytes would take 22 of the 20
+        assert_eq!(
+            run(&mut state, "CONVERT c:2 INT"),
+            Err(HandlerError::QuotaExceeded(
+                "'c:*' allows at most 20 bytes".to_string()
+            ))
+        );
+        // Incrementing an integer never changes its size
+        assert_eq!(
+            run(&mut state, "INCRBOUNDED c:1 999999999").unwrap(),
+            "123456790"
+        );
+    }
+
+    /// Compares INCRBOUNDED on a string counter against an integer one
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_incr_int_vs_string`
+    #[test]
+    #[ignore]
+    fn bench_incr_int_vs_string() {
+        const INCREMENTS: i64 = 1_000_000;
+
+        for (label, line) in [("string", "SET hits 0"), ("int", "SETINT hits 0")] {
+            let mut state = StoreState::default();
+            run(&mut state, line).unwrap();
+            let start = std::time::Instant::now();
+            for _ in 0..INCREMENTS {
+                incrbounded(&mut state, "hits".to_string(), i64::MAX).unwrap();
+            }
+            println!(
+                "[Bench] {} increments of a {} counter: {:?}",
+                INCREMENTS,
+                label,
+                start.elapsed()
+            );
+        }
+    }
+
+    /// Compares a cold import against a pre-sized one
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_presized_import`
+    #[test]
+    #[ignore]
+    fn bench_presized_import() {
+        const KEYS: usize = 5_000_000;
+
+        for capacity in [0, KEYS] {
+            let mut state = StoreState::with_capacity(capacity);
+            let start = std::time::Instant::now();
+            for i in 0..KEYS {
+                set(&mut state, format!("key:{}", i), "value".to_string()).unwrap();
+            }
+            println!(
+                "[Bench] {} keys with initial capacity {}: {:?}",
+                KEYS,
+                capacity,
+                start.elapsed()
+            );
+        }
+    }
+}
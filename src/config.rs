@@ -25,13 +25,44 @@
 //! - New fields can be added to TestConfig as needed
 //! - Type-safe: compiler ensures correct usage
 
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
+
+use crate::macros::Macros;
+use crate::procedure::{self, Procedure};
+use crate::quota::PrefixQuotas;
+use crate::status;
+use crate::validate::ValidationRules;
 
 #[cfg(debug_assertions)]
 use rand::Rng;
 
 #[cfg(debug_assertions)]
-use std::time::Duration;
+use std::fmt;
+
+/// Retries for transient command failures when `COMMAND_MAX_RETRIES` is unset
+pub const DEFAULT_COMMAND_MAX_RETRIES: u32 = 3;
+
+/// Lines ordered output buffers before skipping a missing one, when
+/// `OUTPUT_ORDER_MAX_BUFFERED` is unset
+pub const DEFAULT_OUTPUT_ORDER_MAX_BUFFERED: usize = 10_000;
+
+/// How long ordered output waits for a missing line, when
+/// `OUTPUT_ORDER_MAX_WAIT_MS` is unset
+pub const DEFAULT_OUTPUT_ORDER_MAX_WAIT: Duration = Duration::from_secs(5);
+
+/// Parsed commands queued for the main thread when
+/// `COMMAND_QUEUE_CAPACITY` is unset
+pub const DEFAULT_COMMAND_QUEUE_CAPACITY: usize = 10_000;
+
+/// Keys the hot-key sketch tracks when `HOT_KEYS_CAPACITY` is unset
+pub const DEFAULT_HOT_KEYS_CAPACITY: usize = 1000;
+
+/// Lines the parse-error rate is measured over when `PARSE_ERROR_WINDOW` is
+/// unset
+pub const DEFAULT_PARSE_ERROR_WINDOW: usize = 1000;
 
 /// Main configuration struct that is passed throughout the application
 ///
@@ -39,6 +70,47 @@ use std::time::Duration;
 /// This struct is meant to be wrapped in Arc<Config> and shared across threads.
 /// All fields are immutable after creation.
 ///
+/// # Environment Variables
+///
+/// - `STORE_INITIAL_CAPACITY`: Number of keys to pre-allocate in the store (default: 0)
+/// - `DEDUPE_CONSECUTIVE`: Skip input lines identical to the previous line (true/1 to enable)
+/// - `GZIP_INPUT`: Decompress the input file even without a `.gz` extension (true/1 to enable)
+/// - `PRODUCER_RATE`: Input lines sent to the IO threads per second (default: unset, unthrottled)
+/// - `ABORT_IF_PARSE_ERROR_RATE`: Stop the run once more than this share of the last `PARSE_ERROR_WINDOW` lines failed to parse, from 0 up to 1 (default: unset, never)
+/// - `PARSE_ERROR_WINDOW`: Lines the parse-error rate is measured over (default: 1000)
+/// - `INPUT_FILE`: Comma-separated input files, read in order (default: input.txt)
+/// - `LINE_NUMBER_MODE`: Line numbers across input files: `continue` or `per_file` (default: continue)
+/// - `VALUE_CASE`: Case-fold values stored by SET: `none`, `lower`, or `upper` (default: none)
+/// - `SET_DUPLICATE_POLICY`: What SET does to a key that exists: `overwrite`, `first-wins`, or `error` (default: overwrite)
+/// - `SOFT_ERROR_SINK`: Where soft errors such as GET misses go: `stdout`, `stderr`, or `quiet` (default: stderr)
+/// - `SOFT_ERRORS_FAIL`: Count soft errors toward a failing exit code (true/1 to enable)
+/// - `COMMAND_MAX_RETRIES`: Retries for a command failing with a transient error (default: 3)
+/// - `IDEMPOTENCY_WINDOW`: Recent `ID <token>` clauses remembered to skip retried commands (default: 0, disabled)
+/// - `SNAPSHOT_PATH`: Snapshot file loaded at startup and written by `SHUTDOWN SAVE` (default: unset)
+/// - `PER_KEY_ORDERING`: Apply commands for the same key in input order (true/1 to enable)
+/// - `READ_SNAPSHOT_MAX_AGE_MS`: Serve GET, GETWITHTTL, and GETMATCHING off the main thread from a copy of the store at most this old (default: unset, every read goes through the main thread)
+/// - `COMMAND_QUEUE_CAPACITY`: Parsed commands queued for the main thread before IO threads wait (default: 10000)
+/// - `OUTPUT_ORDERED`: Emit responses and errors in line-number order (true/1 to enable)
+/// - `OUTPUT_ORDER_MAX_BUFFERED`: Lines buffered before ordered output skips a missing one (default: 10000)
+/// - `OUTPUT_ORDER_MAX_WAIT_MS`: How long ordered output waits for a missing line (default: 5000)
+/// - `CATCH_HANDLER_PANICS`: Report a panicking command as an error and keep going (true/1 to enable)
+/// - `DISTRIBUTION`: How input lines reach IO threads: `shared`, `round_robin`, or `key_hash` (default: shared)
+/// - `STRIP_PREFIX`: Glob for a leading prefix removed from input lines before parsing (default: unset)
+/// - `STRIP_PREFIX_DROP_UNMATCHED`: Drop input lines that don't start with `STRIP_PREFIX` (true/1 to enable)
+/// - `LOWERCASE_KEYS`: Lower-case the keys of input lines before parsing (true/1 to enable)
+/// - `MAX_TOTAL_COMMANDS`: Commands the main thread processes before it stops accepting more (default: unset, no cap)
+/// - `ON_OUTPUT_ERROR`: What to do once output can't be written: `continue` or `stop` (default: continue)
+/// - `HALT_ON_ERROR`: Stop applying commands at the first one that fails (true/1 to enable)
+/// - `SOFT_DELETE`: Keep deleted keys as tombstones that UNDELETE can restore (true/1 to enable)
+/// - `TOMBSTONE_TTL_SECS`: Seconds a tombstone is kept before it is purged (default: unset, kept until PURGE)
+/// - `TRACK_HOT_KEYS`: Count commands per key approximately, for HOTKEYS and the run summary (true/1 to enable)
+/// - `HOT_KEYS_CAPACITY`: Keys the hot-key sketch tracks at once (default: 1000)
+/// - `TRACK_KEY_ACCESS`: Record each key's insertion time and reads, for INSPECT (true/1 to enable)
+/// - `VALUE_HISTORY_DEPTH`: Earlier values kept per key for GETAT (default: 0, history off)
+/// - `VALIDATE_<glob>`: Type values written at keys matching the glob must have: `integer`, `bool`, or `json` (any number of rules)
+/// - `MACRO_<NAME>`: Command line template that input lines starting with `NAME` expand to, with `$1`, `$2`, ... replaced by their arguments (any number of macros)
+/// - `PROCEDURES_FILE`: File of `DEFINE` lines loaded as stored procedures at startup (default: unset)
+///
 /// # Example
 /// ```no_run
 /// use multi_threader::config::Config;
@@ -46,13 +118,366 @@ use std::time::Duration;
 /// let config = Config::from_env();
 /// // Pass config to thread pool, handlers, etc.
 /// ```
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct Config {
+    /// Number of keys the store pre-allocates room for
+    ///
+    /// Sizing the map up front avoids repeated rehashing (and the main thread
+    /// stalls that come with it) when loading a large number of keys.
+    pub store_initial_capacity: usize,
+
+    /// Skip an input line when it is identical to the line just before it
+    pub dedupe_consecutive: bool,
+
+    /// Treat the input file as gzip-compressed regardless of its name
+    ///
+    /// Files ending in `.gz` are always decompressed; this covers compressed
+    /// dumps saved under another name.
+    pub gzip_input: bool,
+
+    /// Lines per second the binary sends to the IO threads
+    ///
+    /// `None` (the default) sends as fast as the channel accepts them. Set to
+    /// simulate slow clients or to avoid overwhelming downstream consumers.
+    pub producer_rate: Option<f64>,
+
+    /// Stop reading input once more than this share of the last
+    /// `parse_error_window` parsed lines failed to parse
+    ///
+    /// `None` (the default) never stops. Meant for input in the wrong
+    /// format, which would otherwise fail line after line to the end.
+    pub abort_if_parse_error_rate: Option<f64>,
+
+    /// Parsed lines `abort_if_parse_error_rate` is measured over (at least 1)
+    pub parse_error_window: usize,
+
+    /// Input files the binary reads, one after another
+    ///
+    /// Empty (the default) means `input.txt`. A file that can't be opened is
+    /// reported and skipped; the rest are still read.
+    pub input_files: Vec<PathBuf>,
+
+    /// Whether line numbers run on across input files or restart at 1 in
+    /// each one
+    pub line_number_mode: LineNumberMode,
+
+    /// Case folding applied to values stored by SET (keys are never folded)
+    pub value_case: ValueCase,
+
+    /// What SET does when its key already exists
+    pub set_duplicate_policy: SetDuplicatePolicy,
+
+    /// Where soft errors (expected misses) are reported
+    pub soft_error_sink: SoftErrorSink,
+
+    /// Whether soft errors make the run exit with a failure code
+    ///
+    /// By default only hard errors do, so replays where misses are expected
+    /// don't trip exit-code based alerting.
+    pub soft_errors_fail: bool,
+
+    /// How many times a command failing with a transient error is retried
+    ///
+    /// Retries back off briefly between attempts; a command still failing
+    /// after the last retry is reported as a hard error. Permanent errors are
+    /// never retried.
+    pub command_max_retries: u32,
+
+    /// How many recent idempotency tokens the main thread remembers
+    ///
+    /// A command tagged `ID <token>` whose token is still in the window is
+    /// acknowledged without being applied again. 0 disables the check, and
+    /// `ID` is then read as ordinary text rather than a clause.
+    pub idempotency_window: usize,
+
+    /// Snapshot file restored when the main thread starts (if it exists) and
+    /// written by `SHUTDOWN SAVE` and `--save-on-exit`
+    ///
+    /// Unset means the store starts empty and cannot be saved.
+    pub snapshot_path: Option<PathBuf>,
+
+    /// How input lines are spread across IO threads
+    pub distribution: Distribution,
+
+    /// Apply commands for the same key in input order
+    ///
+    /// IO threads stamp each line with its key's sequence number and the main
+    /// thread holds back a command until its key's earlier commands have been
+    /// applied. Commands for different keys can still overtake each other.
+    pub per_key_ordering: bool,
+
+    /// Serve reads off the main thread from a published copy of the store,
+    /// which is at most this old when a read uses it
+    ///
+    /// `None` (the default) sends every read through the main thread. The
+    /// copy is republished as writes come in, at most twice per max age.
+    pub read_snapshot_max_age: Option<Duration>,
+
+    /// Parsed commands that can wait for the main thread at once
+    ///
+    /// An IO thread with a command for a full queue blocks until the main
+    /// thread takes one, so a slow handler holds back parsing rather than
+    /// letting commands pile up in memory. 0, as in `Config::default()`,
+    /// queues nothing: each command is handed straight to the main thread.
+    pub command_queue_capacity: usize,
+
+    /// Emit responses and errors in line-number order rather than in the
+    /// order commands finish
+    pub output_ordered: bool,
+
+    /// Buffered lines at which ordered output gives up on a missing line
+    pub output_order_max_buffered: usize,
+
+    /// How long ordered output waits for a missing line
+    pub output_order_max_wait: Duration,
+
+    /// Catch a panic in a command handler instead of letting it take down
+    /// the main thread
+    ///
+    /// The command is reported as a hard error and processing continues. A
+    /// handler that panicked mid-update may leave that command partly
+    /// applied, which is why this is opt-in.
+    pub catch_handler_panics: bool,
+
+    /// Glob for a leading prefix (such as a syslog header) stripped from
+    /// each input line before it is parsed
+    ///
+    /// The shortest leading run that matches is removed; unset leaves lines
+    /// alone.
+    pub strip_prefix: Option<String>,
+
+    /// Drop input lines that don't start with `strip_prefix` instead of
+    /// parsing them as they are
+    pub strip_prefix_drop_unmatched: bool,
+
+    /// Lower-case the key arguments of input lines before they are parsed
+    pub lowercase_keys: bool,
+
+    /// Commands the main thread processes before it stops accepting more
+    ///
+    /// A safety cap for runaway inputs: once it is hit the main thread logs
+    /// it and exits its loop, and anything still queued is never applied.
+    /// Unset (or 0) means no cap.
+    pub max_total_commands: Option<usize>,
+
+    /// What the main thread does once writing output fails (for example a
+    /// downstream `head` exiting)
+    ///
+    /// Output stops either way and the run is reported as truncated.
+    pub on_output_error: OutputErrorPolicy,
+
+    /// Whether the main thread stops at the first command that fails, soft
+    /// errors such as GET misses included
+    ///
+    /// Like a SHUTDOWN, everything still queued is answered with a
+    /// shutting-down error instead of being applied.
+    pub halt_on_error: bool,
+
+    /// Whether DELETE keeps the value as a tombstone that UNDELETE can bring
+    /// back, until PURGE frees it
+    pub soft_delete: bool,
+
+    /// Seconds after deletion a tombstone is purged by the expiry sweep
+    ///
+    /// Unset (or 0) keeps tombstones until an explicit PURGE.
+    pub tombstone_ttl_secs: Option<u64>,
+
+    /// Whether the main thread keeps approximate per-key command counts,
+    /// reported by HOTKEYS and in the run summary
+    pub track_hot_keys: bool,
+
+    /// Keys the hot-key sketch tracks; a key touched more than
+    /// 1/`hot_keys_capacity` of the time is always among them
+    pub hot_keys_capacity: usize,
+
+    /// Whether the main thread records when each key was created and how
+    /// often it is read, reported by INSPECT
+    pub track_key_access: bool,
+
+    /// Earlier values GETAT can read per key; 0 turns version tracking off
+    pub value_history_depth: usize,
+
+    /// Value rules from `VALIDATE_<glob>` variables, checked when commands
+    /// write string values
+    pub validation_rules: ValidationRules,
+
+    /// Why the `VALIDATE_*` rules couldn't be compiled (an unknown type);
+    /// the binary refuses to start rather than run unvalidated
+    pub validation_error: Option<String>,
+
+    /// Key and byte limits per key prefix, from `LIMIT_PREFIX_<prefix>*_MAX_KEYS`
+    /// and `_MAX_BYTES` variables
+    pub prefix_quotas: PrefixQuotas,
+
+    /// Why the `LIMIT_PREFIX_*` variables were rejected (a pattern that
+    /// isn't a plain prefix, or a limit that isn't a count); the binary
+    /// refuses to start rather than run unlimited
+    pub quota_error: Option<String>,
+
+    /// Command macros from `MACRO_<NAME>` variables, expanded by IO threads
+    /// before parsing
+    pub macros: Macros,
+
+    /// Why the `MACRO_*` definitions were rejected (a bad template, or a
+    /// name shadowing a built-in); the binary refuses to start
+    pub macro_error: Option<String>,
+
+    /// File of DEFINE lines loaded when the config is read
+    pub procedures_file: Option<PathBuf>,
+
+    /// Procedures defined in `procedures_file`, available to CALL from the
+    /// first line
+    pub procedures: HashMap<String, Procedure>,
+
+    /// Why `procedures_file` couldn't be loaded (unreadable, or a line that
+    /// isn't a valid DEFINE); the binary refuses to start
+    pub procedures_error: Option<String>,
+
     /// Testing configuration (only available in debug builds)
     #[cfg(debug_assertions)]
     pub test: TestConfig,
 }
 
+/// Destination for soft handler errors
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SoftErrorSink {
+    /// Alongside successful responses
+    Stdout,
+    /// Alongside hard errors (the default)
+    #[default]
+    Stderr,
+    /// Not reported at all (still counted in the summary)
+    Quiet,
+}
+
+impl SoftErrorSink {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "stdout" => Some(Self::Stdout),
+            "stderr" => Some(Self::Stderr),
+            "quiet" => Some(Self::Quiet),
+            _ => None,
+        }
+    }
+}
+
+/// Case folding for values stored by SET
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum ValueCase {
+    /// Store values as given (the default)
+    #[default]
+    None,
+    Lower,
+    Upper,
+}
+
+impl ValueCase {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "none" => Some(Self::None),
+            "lower" => Some(Self::Lower),
+            "upper" => Some(Self::Upper),
+            _ => None,
+        }
+    }
+
+    /// Fold `value` in place
+    pub fn apply(self, value: &mut String) {
+        match self {
+            ValueCase::None => {}
+            ValueCase::Lower => *value = value.to_lowercase(),
+            ValueCase::Upper => *value = value.to_uppercase(),
+        }
+    }
+}
+
+/// What SET does when its key already exists
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum SetDuplicatePolicy {
+    /// Replace the value, last write wins (the default)
+    #[default]
+    Overwrite,
+    /// Keep the value already there and answer `OK (unchanged)`
+    FirstWins,
+    /// Keep the value already there and fail the SET
+    Error,
+}
+
+impl SetDuplicatePolicy {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "overwrite" => Some(Self::Overwrite),
+            "first-wins" => Some(Self::FirstWins),
+            "error" => Some(Self::Error),
+            _ => None,
+        }
+    }
+}
+
+/// How input lines are numbered when there is more than one input file
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LineNumberMode {
+    /// Number lines across all files as one input (the default)
+    #[default]
+    Continue,
+    /// Restart at 1 for each file, so numbers match each file's own lines
+    PerFile,
+}
+
+impl LineNumberMode {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "continue" => Some(Self::Continue),
+            "per_file" => Some(Self::PerFile),
+            _ => None,
+        }
+    }
+}
+
+/// What happens to processing once an output stream can't be written
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OutputErrorPolicy {
+    /// Keep applying commands without output (the default)
+    #[default]
+    Continue,
+    /// Stop accepting commands, as if input had ended
+    Stop,
+}
+
+impl OutputErrorPolicy {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "continue" => Some(Self::Continue),
+            "stop" => Some(Self::Stop),
+            _ => None,
+        }
+    }
+}
+
+/// How input lines are handed to IO threads
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Distribution {
+    /// One channel all IO threads take from (work stealing; the default)
+    #[default]
+    Shared,
+    /// A channel per IO thread, fed in rotation
+    RoundRobin,
+    /// A channel per IO thread, chosen by hashing the line's key, so every
+    /// command for a key is parsed by the same thread and arrives in order
+    KeyHash,
+}
+
+impl Distribution {
+    pub fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "shared" => Some(Self::Shared),
+            "round_robin" => Some(Self::RoundRobin),
+            "key_hash" => Some(Self::KeyHash),
+            _ => None,
+        }
+    }
+}
+
 /// Testing configuration for injecting controlled chaos and delays
 ///
 /// This struct is only compiled in debug builds. In release builds (--release),
@@ -70,6 +495,10 @@ pub struct Config {
 /// - `TEST_RANDOM_SLEEP_IO`: Enable random delays in IO threads (true/1 to enable)
 /// - `TEST_IO_SLEEP_MIN_MS`: Minimum sleep duration in milliseconds (default: 500)
 /// - `TEST_IO_SLEEP_MAX_MS`: Maximum sleep duration in milliseconds (default: 2000)
+/// - `TEST_PANIC_IO_ON_LINE`: Panic the IO thread that receives this line number
+///   while it still holds the shared receiver lock (default: unset)
+/// - `TEST_DELAY_RULES`: Targeted per-command delays, e.g. `after:SET:50ms;before:GET:20ms`
+///   (see `DelayRules`)
 ///
 /// # Example Usage
 ///
@@ -98,6 +527,169 @@ pub struct TestConfig {
 
     /// Maximum sleep duration in milliseconds (default: 2000)
     pub io_sleep_max_ms: u64,
+
+    /// Panic the IO thread that receives this line number
+    ///
+    /// The panic happens while the thread still holds the shared receiver
+    /// lock, poisoning the mutex. This exercises the poison recovery path in
+    /// `IoThread::run` - the remaining threads must keep draining the channel.
+    pub panic_io_thread_on_line: Option<usize>,
+
+    /// Delays attached to specific command types
+    ///
+    /// Unlike the random IO sleep these only hit the named commands, so a
+    /// particular interleaving (a DELETE overtaking a SET) can be reproduced
+    /// on demand.
+    pub delay_rules: DelayRules,
+
+    /// Fail every Nth write attempt with a transient error
+    ///
+    /// Stands in for a flaky persistence layer so the main thread's retry
+    /// policy can be exercised; the failing attempt is not applied.
+    pub fail_write_every: Option<u64>,
+
+    /// Panic inside the handler whenever this command keyword is applied
+    ///
+    /// Stands in for a handler bug so `CATCH_HANDLER_PANICS` can be
+    /// exercised without shipping a command that really panics.
+    pub panic_handler_on: Option<String>,
+}
+
+#[cfg(debug_assertions)]
+impl Default for TestConfig {
+    fn default() -> Self {
+        Self {
+            random_sleep_io_thread: false,
+            io_sleep_min_ms: 500,
+            io_sleep_max_ms: 2000,
+            panic_io_thread_on_line: None,
+            delay_rules: DelayRules::default(),
+            fail_write_every: None,
+            panic_handler_on: None,
+        }
+    }
+}
+
+/// Where a targeted delay is applied relative to its command
+#[cfg(debug_assertions)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DelayPosition {
+    /// In the IO thread, after parsing and before forwarding to the main
+    /// thread, so commands on other IO threads can overtake it
+    After,
+    /// In the main thread, before the command is applied, holding up
+    /// everything queued behind it
+    Before,
+}
+
+/// One `position:COMMAND:duration` rule
+#[cfg(debug_assertions)]
+#[derive(Debug, Clone, PartialEq)]
+pub struct DelayRule {
+    pub position: DelayPosition,
+    pub command: String,
+    pub delay: Duration,
+}
+
+#[cfg(debug_assertions)]
+impl fmt::Display for DelayRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let position = match self.position {
+            DelayPosition::After => "after",
+            DelayPosition::Before => "before",
+        };
+        write!(
+            f,
+            "{}:{}:{}ms",
+            position,
+            self.command,
+            self.delay.as_millis()
+        )
+    }
+}
+
+/// Targeted delay rules parsed from `TEST_DELAY_RULES`
+///
+/// Rules are separated by `;` and written `after|before:COMMAND:duration`,
+/// where the duration is a whole number of `ms` or `s`. Command names are
+/// matched case-insensitively against the input keyword; several rules for
+/// the same command and position add up.
+#[cfg(debug_assertions)]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DelayRules {
+    rules: Vec<DelayRule>,
+}
+
+#[cfg(debug_assertions)]
+impl DelayRules {
+    /// Parse a rule list, failing on the first malformed rule
+    pub fn parse(raw: &str) -> Result<Self, String> {
+        let rules = raw
+            .split(';')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(Self::parse_rule)
+            .collect::<Result<_, _>>()?;
+        Ok(Self { rules })
+    }
+
+    fn parse_rule(rule: &str) -> Result<DelayRule, String> {
+        let [position, command, delay] = rule.split(':').collect::<Vec<_>>()[..] else {
+            return Err(format!(
+                "'{}': expected after|before:COMMAND:duration",
+                rule
+            ));
+        };
+
+        let position = match position {
+            "after" => DelayPosition::After,
+            "before" => DelayPosition::Before,
+            _ => {
+                return Err(format!("'{}': position must be 'after' or 'before'", rule));
+            }
+        };
+
+        if command.is_empty() || !command.chars().all(|c| c.is_ascii_alphabetic()) {
+            return Err(format!("'{}': invalid command name '{}'", rule, command));
+        }
+
+        let delay = if let Some(ms) = delay.strip_suffix("ms") {
+            ms.parse().ok().map(Duration::from_millis)
+        } else if let Some(secs) = delay.strip_suffix('s') {
+            secs.parse().ok().map(Duration::from_secs)
+        } else {
+            None
+        }
+        .ok_or_else(|| {
+            format!(
+                "'{}': invalid duration '{}' (use e.g. 50ms or 2s)",
+                rule, delay
+            )
+        })?;
+
+        Ok(DelayRule {
+            position,
+            command: command.to_ascii_uppercase(),
+            delay,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn rules(&self) -> &[DelayRule] {
+        &self.rules
+    }
+
+    /// Total delay configured for `command` at `position`
+    pub fn delay_for(&self, position: DelayPosition, command: &str) -> Duration {
+        self.rules
+            .iter()
+            .filter(|rule| rule.position == position && rule.command == command)
+            .map(|rule| rule.delay)
+            .sum()
+    }
 }
 
 impl Config {
@@ -119,7 +711,328 @@ impl Config {
     /// let config_clone = config.clone();
     /// ```
     pub fn from_env() -> Arc<Self> {
+        let store_initial_capacity = std::env::var("STORE_INITIAL_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+
+        let dedupe_consecutive = std::env::var("DEDUPE_CONSECUTIVE")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let gzip_input = std::env::var("GZIP_INPUT")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let producer_rate = match std::env::var("PRODUCER_RATE") {
+            Ok(raw) => match raw.parse::<f64>() {
+                Ok(rate) if rate > 0.0 && rate.is_finite() => Some(rate),
+                _ => {
+                    eprintln!(
+                        "[Config] Warning: invalid PRODUCER_RATE '{}', expected a positive number of lines per second; not throttling",
+                        raw
+                    );
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        let abort_if_parse_error_rate = match std::env::var("ABORT_IF_PARSE_ERROR_RATE") {
+            Ok(raw) => match raw.parse::<f64>() {
+                Ok(rate) if (0.0..1.0).contains(&rate) => Some(rate),
+                _ => {
+                    eprintln!(
+                        "[Config] Warning: invalid ABORT_IF_PARSE_ERROR_RATE '{}', expected a share of lines from 0 up to 1; not aborting on parse errors",
+                        raw
+                    );
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        let parse_error_window = match std::env::var("PARSE_ERROR_WINDOW") {
+            Ok(raw) => match raw.parse::<usize>() {
+                Ok(window) if window > 0 => window,
+                _ => {
+                    eprintln!(
+                        "[Config] Warning: invalid PARSE_ERROR_WINDOW '{}', expected a positive number of lines; using {}",
+                        raw, DEFAULT_PARSE_ERROR_WINDOW
+                    );
+                    DEFAULT_PARSE_ERROR_WINDOW
+                }
+            },
+            Err(_) => DEFAULT_PARSE_ERROR_WINDOW,
+        };
+
+        let input_files = std::env::var("INPUT_FILE")
+            .map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|path| !path.is_empty())
+                    .map(PathBuf::from)
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let line_number_mode = match std::env::var("LINE_NUMBER_MODE") {
+            Ok(raw) => LineNumberMode::parse(&raw).unwrap_or_else(|| {
+                eprintln!(
+                    "[Config] Warning: invalid LINE_NUMBER_MODE '{}', expected continue|per_file; using continue",
+                    raw
+                );
+                LineNumberMode::default()
+            }),
+            Err(_) => LineNumberMode::default(),
+        };
+
+        let value_case = match std::env::var("VALUE_CASE") {
+            Ok(raw) => ValueCase::parse(&raw).unwrap_or_else(|| {
+                eprintln!(
+                    "[Config] Warning: invalid VALUE_CASE '{}', expected none|lower|upper; using none",
+                    raw
+                );
+                ValueCase::default()
+            }),
+            Err(_) => ValueCase::default(),
+        };
+
+        let set_duplicate_policy = match std::env::var("SET_DUPLICATE_POLICY") {
+            Ok(raw) => SetDuplicatePolicy::parse(&raw).unwrap_or_else(|| {
+                eprintln!(
+                    "[Config] Warning: invalid SET_DUPLICATE_POLICY '{}', expected overwrite|first-wins|error; using overwrite",
+                    raw
+                );
+                SetDuplicatePolicy::default()
+            }),
+            Err(_) => SetDuplicatePolicy::default(),
+        };
+
+        let soft_error_sink = match std::env::var("SOFT_ERROR_SINK") {
+            Ok(raw) => SoftErrorSink::parse(&raw).unwrap_or_else(|| {
+                eprintln!(
+                    "[Config] Warning: invalid SOFT_ERROR_SINK '{}', expected stdout|stderr|quiet; using stderr",
+                    raw
+                );
+                SoftErrorSink::default()
+            }),
+            Err(_) => SoftErrorSink::default(),
+        };
+
+        let soft_errors_fail = std::env::var("SOFT_ERRORS_FAIL")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let command_max_retries = std::env::var("COMMAND_MAX_RETRIES")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_COMMAND_MAX_RETRIES);
+
+        let idempotency_window = std::env::var("IDEMPOTENCY_WINDOW")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+
+        let snapshot_path = std::env::var("SNAPSHOT_PATH")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from);
+
+        let distribution = match std::env::var("DISTRIBUTION") {
+            Ok(raw) => Distribution::parse(&raw).unwrap_or_else(|| {
+                eprintln!(
+                    "[Config] Warning: invalid DISTRIBUTION '{}', expected shared|round_robin|key_hash; using shared",
+                    raw
+                );
+                Distribution::default()
+            }),
+            Err(_) => Distribution::default(),
+        };
+
+        let per_key_ordering = std::env::var("PER_KEY_ORDERING")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let output_ordered = std::env::var("OUTPUT_ORDERED")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let command_queue_capacity = std::env::var("COMMAND_QUEUE_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_COMMAND_QUEUE_CAPACITY);
+
+        let read_snapshot_max_age = match std::env::var("READ_SNAPSHOT_MAX_AGE_MS") {
+            Ok(raw) => match raw.parse::<u64>() {
+                Ok(ms) => Some(Duration::from_millis(ms)),
+                Err(_) => {
+                    eprintln!(
+                        "[Config] Warning: invalid READ_SNAPSHOT_MAX_AGE_MS '{}', expected milliseconds; reading through the main thread",
+                        raw
+                    );
+                    None
+                }
+            },
+            Err(_) => None,
+        };
+
+        let output_order_max_buffered = std::env::var("OUTPUT_ORDER_MAX_BUFFERED")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_OUTPUT_ORDER_MAX_BUFFERED);
+
+        let output_order_max_wait = std::env::var("OUTPUT_ORDER_MAX_WAIT_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_OUTPUT_ORDER_MAX_WAIT);
+
+        let catch_handler_panics = std::env::var("CATCH_HANDLER_PANICS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let strip_prefix = std::env::var("STRIP_PREFIX").ok().filter(|v| !v.is_empty());
+
+        let strip_prefix_drop_unmatched = std::env::var("STRIP_PREFIX_DROP_UNMATCHED")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let lowercase_keys = std::env::var("LOWERCASE_KEYS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let max_total_commands = std::env::var("MAX_TOTAL_COMMANDS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0);
+
+        let on_output_error = match std::env::var("ON_OUTPUT_ERROR") {
+            Ok(raw) => OutputErrorPolicy::parse(&raw).unwrap_or_else(|| {
+                eprintln!(
+                    "[Config] Warning: invalid ON_OUTPUT_ERROR '{}', expected continue|stop; using continue",
+                    raw
+                );
+                OutputErrorPolicy::default()
+            }),
+            Err(_) => OutputErrorPolicy::default(),
+        };
+
+        // Ordered output needs every line number to be unique
+        let line_number_mode = if output_ordered && line_number_mode == LineNumberMode::PerFile {
+            eprintln!(
+                "[Config] Warning: LINE_NUMBER_MODE=per_file repeats line numbers, which OUTPUT_ORDERED can't order; using continue"
+            );
+            LineNumberMode::Continue
+        } else {
+            line_number_mode
+        };
+
+        let halt_on_error = std::env::var("HALT_ON_ERROR")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let soft_delete = std::env::var("SOFT_DELETE")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let tombstone_ttl_secs = std::env::var("TOMBSTONE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&secs| secs > 0);
+
+        let track_hot_keys = std::env::var("TRACK_HOT_KEYS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let track_key_access = std::env::var("TRACK_KEY_ACCESS")
+            .map(|v| v == "true" || v == "1")
+            .unwrap_or(false);
+
+        let value_history_depth = std::env::var("VALUE_HISTORY_DEPTH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(0);
+
+        let hot_keys_capacity = std::env::var("HOT_KEYS_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0)
+            .unwrap_or(DEFAULT_HOT_KEYS_CAPACITY);
+
+        let (validation_rules, validation_error) = match ValidationRules::from_vars(std::env::vars())
+        {
+            Ok(rules) => (rules, None),
+            Err(err) => (ValidationRules::default(), Some(err)),
+        };
+
+        let (prefix_quotas, quota_error) = match PrefixQuotas::from_vars(std::env::vars()) {
+            Ok(quotas) => (quotas, None),
+            Err(err) => (PrefixQuotas::default(), Some(err)),
+        };
+
+        let (macros, macro_error) = match Macros::from_vars(std::env::vars()) {
+            Ok(macros) => (macros, None),
+            Err(err) => (Macros::default(), Some(err)),
+        };
+
+        let procedures_file = std::env::var("PROCEDURES_FILE")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from);
+        let (procedures, procedures_error) = match procedures_file.as_deref().map(procedure::load) {
+            Some(Ok(procedures)) => (procedures, None),
+            Some(Err(err)) => (HashMap::new(), Some(format!("PROCEDURES_FILE: {}", err))),
+            None => (HashMap::new(), None),
+        };
+
         Arc::new(Self {
+            store_initial_capacity,
+            dedupe_consecutive,
+            gzip_input,
+            producer_rate,
+            abort_if_parse_error_rate,
+            parse_error_window,
+            input_files,
+            line_number_mode,
+            value_case,
+            set_duplicate_policy,
+            soft_error_sink,
+            soft_errors_fail,
+            command_max_retries,
+            idempotency_window,
+            snapshot_path,
+            distribution,
+            per_key_ordering,
+            read_snapshot_max_age,
+            command_queue_capacity,
+            output_ordered,
+            output_order_max_buffered,
+            output_order_max_wait,
+            catch_handler_panics,
+            strip_prefix,
+            strip_prefix_drop_unmatched,
+            lowercase_keys,
+            max_total_commands,
+            on_output_error,
+            halt_on_error,
+            soft_delete,
+            tombstone_ttl_secs,
+            track_hot_keys,
+            hot_keys_capacity,
+            track_key_access,
+            value_history_depth,
+            validation_rules,
+            validation_error,
+            prefix_quotas,
+            quota_error,
+            macros,
+            macro_error,
+            procedures_file,
+            procedures,
+            procedures_error,
             #[cfg(debug_assertions)]
             test: TestConfig::from_env(),
         })
@@ -131,28 +1044,164 @@ impl Config {
     /// In release builds, this only prints a minimal message since test
     /// config is compiled out.
     pub fn print_config(&self) {
-        println!("[Config] Configuration loaded:");
+        status!("[Config] Configuration loaded:");
+        status!("  Store initial capacity: {}", self.store_initial_capacity);
+        status!("  Dedupe consecutive lines: {}", self.dedupe_consecutive);
+        status!("  Gzip input: {}", self.gzip_input);
+        match self.producer_rate {
+            Some(rate) => status!("  Producer rate: {} lines/s", rate),
+            None => status!("  Producer rate: unthrottled"),
+        }
+        match self.abort_if_parse_error_rate {
+            Some(rate) => status!(
+                "  Abort if parse error rate: over {} of the last {} lines",
+                rate,
+                self.parse_error_window
+            ),
+            None => status!("  Abort if parse error rate: never"),
+        }
+        if self.input_files.is_empty() {
+            status!("  Input files: input.txt");
+        } else {
+            let files: Vec<String> = self
+                .input_files
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            status!("  Input files: {}", files.join(", "));
+        }
+        status!("  Line number mode: {:?}", self.line_number_mode);
+        status!("  Value case: {:?}", self.value_case);
+        status!("  SET duplicate policy: {:?}", self.set_duplicate_policy);
+        status!("  Soft error sink: {:?}", self.soft_error_sink);
+        status!("  Soft errors fail run: {}", self.soft_errors_fail);
+        status!("  Command max retries: {}", self.command_max_retries);
+        status!("  Idempotency window: {}", self.idempotency_window);
+        match &self.snapshot_path {
+            Some(path) => status!("  Snapshot path: {}", path.display()),
+            None => status!("  Snapshot path: (none)"),
+        }
+        status!("  Distribution: {:?}", self.distribution);
+        status!("  Per-key ordering: {}", self.per_key_ordering);
+        match self.read_snapshot_max_age {
+            Some(max_age) => status!("  Read snapshot max age: {:?}", max_age),
+            None => status!("  Read snapshot: off"),
+        }
+        status!("  Command queue capacity: {}", self.command_queue_capacity);
+        if self.output_ordered {
+            status!(
+                "  Output ordered: true (max {} buffered, max wait {:?})",
+                self.output_order_max_buffered,
+                self.output_order_max_wait
+            );
+        } else {
+            status!("  Output ordered: false");
+        }
+        status!("  Catch handler panics: {}", self.catch_handler_panics);
+        match &self.strip_prefix {
+            Some(pattern) => status!(
+                "  Strip prefix: '{}' (drop unmatched: {})",
+                pattern,
+                self.strip_prefix_drop_unmatched
+            ),
+            None => status!("  Strip prefix: (none)"),
+        }
+        status!("  Lowercase keys: {}", self.lowercase_keys);
+        match self.max_total_commands {
+            Some(cap) => status!("  Max total commands: {}", cap),
+            None => status!("  Max total commands: (no cap)"),
+        }
+        status!("  On output error: {:?}", self.on_output_error);
+        status!("  Halt on error: {}", self.halt_on_error);
+        status!("  Soft delete: {}", self.soft_delete);
+        match self.tombstone_ttl_secs {
+            Some(secs) => status!("  Tombstone TTL: {}s", secs),
+            None => status!("  Tombstone TTL: (kept until PURGE)"),
+        }
+        if self.track_hot_keys {
+            status!(
+                "  Track hot keys: true (capacity {})",
+                self.hot_keys_capacity
+            );
+        } else {
+            status!("  Track hot keys: false");
+        }
+        status!("  Track key access: {}", self.track_key_access);
+        match self.value_history_depth {
+            0 => status!("  Value history: off"),
+            depth => status!("  Value history: {} earlier values per key", depth),
+        }
+        if self.validation_rules.is_empty() {
+            status!("  Validation rules: (none)");
+        } else {
+            let rules: Vec<String> = self
+                .validation_rules
+                .rules()
+                .iter()
+                .map(ToString::to_string)
+                .collect();
+            status!("  Validation rules: {}", rules.join(", "));
+        }
+        if self.prefix_quotas.is_empty() {
+            status!("  Prefix quotas: (none)");
+        } else {
+            status!("  Prefix quotas:");
+            for rule in self.prefix_quotas.rules() {
+                status!("    {}", rule);
+            }
+        }
+        if self.macros.is_empty() {
+            status!("  Macros: (none)");
+        } else {
+            status!("  Macros:");
+            for name in self.macros.names() {
+                if let Some(definition) = self.macros.get(name) {
+                    status!("    {} => {}", name, definition.template());
+                }
+            }
+        }
+        match &self.procedures_file {
+            Some(path) => status!(
+                "  Procedures file: {} ({} procedures)",
+                path.display(),
+                self.procedures.len()
+            ),
+            None => status!("  Procedures file: (none)"),
+        }
 
         #[cfg(debug_assertions)]
         {
-            println!(
+            status!(
                 "  [Test] Random IO sleep: {}",
                 self.test.random_sleep_io_thread
             );
             if self.test.random_sleep_io_thread {
-                println!(
+                status!(
                     "  [Test] IO sleep range: {}-{}ms",
-                    self.test.io_sleep_min_ms, self.test.io_sleep_max_ms
+                    self.test.io_sleep_min_ms,
+                    self.test.io_sleep_max_ms
                 );
             }
+            if let Some(line) = self.test.panic_io_thread_on_line {
+                status!("  [Test] Panic IO thread on line: {}", line);
+            }
+            if let Some(n) = self.test.fail_write_every {
+                status!("  [Test] Fail every {}th write attempt", n);
+            }
+            if let Some(command) = &self.test.panic_handler_on {
+                status!("  [Test] Panic handler on: {}", command);
+            }
+            for rule in self.test.delay_rules.rules() {
+                status!("  [Test] Delay rule: {}", rule);
+            }
         }
 
         #[cfg(not(debug_assertions))]
         {
-            println!("  [Production] All test hooks disabled (release build)");
+            status!("  [Production] All test hooks disabled (release build)");
         }
 
-        println!();
+        status!();
     }
 }
 
@@ -165,9 +1214,13 @@ impl TestConfig {
     /// - `TEST_RANDOM_SLEEP_IO`: "true" or "1" to enable
     /// - `TEST_IO_SLEEP_MIN_MS`: u64 value (default: 500)
     /// - `TEST_IO_SLEEP_MAX_MS`: u64 value (default: 2000)
+    /// - `TEST_PANIC_IO_ON_LINE`: usize line number (default: unset)
+    /// - `TEST_DELAY_RULES`: `;`-separated delay rules (default: none)
+    /// - `TEST_FAIL_WRITE_EVERY`: u64 N > 0 (default: unset)
     ///
     /// # Panics
-    /// Never panics - uses sensible defaults for invalid/missing values
+    /// Never panics - uses sensible defaults for invalid/missing values.
+    /// Malformed delay rules are reported and the whole rule set is ignored.
     fn from_env() -> Self {
         let random_sleep_io_thread = std::env::var("TEST_RANDOM_SLEEP_IO")
             .map(|v| v == "true" || v == "1")
@@ -194,14 +1247,44 @@ impl TestConfig {
             (io_sleep_min_ms, io_sleep_max_ms)
         };
 
+        let panic_io_thread_on_line = std::env::var("TEST_PANIC_IO_ON_LINE")
+            .ok()
+            .and_then(|v| v.parse().ok());
+
+        let delay_rules = match std::env::var("TEST_DELAY_RULES") {
+            Ok(raw) => DelayRules::parse(&raw).unwrap_or_else(|err| {
+                eprintln!(
+                    "[Config] Warning: invalid TEST_DELAY_RULES {}; ignoring all delay rules",
+                    err
+                );
+                DelayRules::default()
+            }),
+            Err(_) => DelayRules::default(),
+        };
+
+        let fail_write_every = std::env::var("TEST_FAIL_WRITE_EVERY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|&n| n > 0);
+
+        let panic_handler_on = std::env::var("TEST_PANIC_HANDLER_ON")
+            .ok()
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_uppercase());
+
         Self {
             random_sleep_io_thread,
             io_sleep_min_ms,
             io_sleep_max_ms,
+            panic_io_thread_on_line,
+            delay_rules,
+            fail_write_every,
+            panic_handler_on,
         }
     }
 
-    /// Maybe sleep the IO thread for a random duration
+    /// Maybe sleep the IO thread for a random duration, returning how long
+    /// it slept
     ///
     /// This method is called from IO threads after they receive work from the
     /// shared channel. If `random_sleep_io_thread` is enabled, it will sleep
@@ -226,16 +1309,64 @@ impl TestConfig {
     ///
     /// In release builds, this entire method is compiled out due to
     /// #[cfg(debug_assertions)] guards, resulting in zero overhead.
-    pub fn maybe_sleep_io_thread(&self) {
-        if self.random_sleep_io_thread {
-            let sleep_ms =
-                rand::thread_rng().gen_range(self.io_sleep_min_ms..=self.io_sleep_max_ms);
-            println!(
-                "[Test] IO thread {:?} sleeping for {}ms",
-                std::thread::current().id(),
-                sleep_ms
+    pub fn maybe_sleep_io_thread(&self) -> Duration {
+        if !self.random_sleep_io_thread {
+            return Duration::ZERO;
+        }
+        let sleep_ms = rand::thread_rng().gen_range(self.io_sleep_min_ms..=self.io_sleep_max_ms);
+        status!(
+            "[Test] IO thread {:?} sleeping for {}ms",
+            std::thread::current().id(),
+            sleep_ms
+        );
+        let sleep = Duration::from_millis(sleep_ms);
+        std::thread::sleep(sleep);
+        sleep
+    }
+
+    /// Sleep for any delay rules matching `command` at `position`, returning
+    /// the delay (zero when no rule matched)
+    ///
+    /// Called by IO threads after parsing (`After`) and by the main thread
+    /// before applying a command (`Before`).
+    pub fn maybe_delay_command(&self, position: DelayPosition, command: &str) -> Duration {
+        let delay = self.delay_rules.delay_for(position, command);
+        if !delay.is_zero() {
+            status!(
+                "[Test] {:?} delay of {:?} for {} on thread {:?}",
+                position,
+                delay,
+                command,
+                std::thread::current().id()
+            );
+            std::thread::sleep(delay);
+        }
+        delay
+    }
+
+    /// Whether write attempt number `attempt` (counting from 1) should fail
+    pub fn should_fail_write(&self, attempt: u64) -> bool {
+        self.fail_write_every
+            .is_some_and(|every| attempt.is_multiple_of(every))
+    }
+
+    /// Maybe panic while applying the command named `command`
+    pub fn maybe_panic_handler(&self, command: &str) {
+        if self.panic_handler_on.as_deref() == Some(command) {
+            panic!("[Test] Deliberate handler panic on {}", command);
+        }
+    }
+
+    /// Maybe panic the IO thread that received `line_number`
+    ///
+    /// Must be called while the shared receiver guard is still held so the
+    /// panic poisons the mutex, which is the scenario this hook reproduces.
+    pub fn maybe_panic_io_thread(&self, line_number: usize) {
+        if self.panic_io_thread_on_line == Some(line_number) {
+            panic!(
+                "[Test] Deliberate IO thread panic at line {} while holding the receiver lock",
+                line_number
             );
-            std::thread::sleep(Duration::from_millis(sleep_ms));
         }
     }
 }
@@ -319,6 +1450,10 @@ mod tests {
             random_sleep_io_thread: false,
             io_sleep_min_ms: 500,
             io_sleep_max_ms: 2000,
+            panic_io_thread_on_line: None,
+            delay_rules: DelayRules::default(),
+            fail_write_every: None,
+            panic_handler_on: None,
         };
 
         let start = std::time::Instant::now();
@@ -328,4 +1463,33 @@ mod tests {
         // Should return immediately (< 10ms to be generous)
         assert!(elapsed.as_millis() < 10);
     }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_delay_rules_parse() {
+        let rules = DelayRules::parse("after:SET:50ms; before:get:2s;after:SET:10ms").unwrap();
+        assert_eq!(rules.rules().len(), 3);
+        assert_eq!(
+            rules.delay_for(DelayPosition::After, "SET"),
+            Duration::from_millis(60)
+        );
+        assert_eq!(
+            rules.delay_for(DelayPosition::Before, "GET"),
+            Duration::from_secs(2)
+        );
+        assert!(rules.delay_for(DelayPosition::Before, "SET").is_zero());
+        assert_eq!(rules.rules()[1].to_string(), "before:GET:2000ms");
+
+        assert!(DelayRules::parse("").unwrap().is_empty());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_delay_rules_reject_malformed() {
+        assert!(DelayRules::parse("after:SET").is_err());
+        assert!(DelayRules::parse("during:SET:5ms").is_err());
+        assert!(DelayRules::parse("after:SE T:5ms").is_err());
+        assert!(DelayRules::parse("after:SET:5").is_err());
+        assert!(DelayRules::parse("after:SET:fastms").is_err());
+    }
 }
@@ -0,0 +1,371 @@
+//! Commands defined outside the crate, and the store handle they run against
+//!
+//! A `CommandRegistry` belongs to the handler that runs its commands; clones
+//! share it, which is how IO threads (or `KvEngine::execute`) parse the
+//! keywords their handler will run. A command registered with one handler is
+//! unknown to every other.
+//!
+//! Executors never touch the store directly. `CustomStore` writes through
+//! the same path as the built-in commands, so a custom write is validated
+//! (`VALIDATE_*`), admitted by the prefix quotas, kept in the value history,
+//! access log and import report, and tombstoned under `SOFT_DELETE`. Only a
+//! custom command that changed something moves SNAPSHOTID.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use crate::commands::{self, StoreState};
+use crate::handler::{CommandExecutor, HandlerError, Tombstone, Value};
+
+/// Why a command couldn't be registered
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegisterError {
+    /// The name isn't a single word of upper-case letters and underscores
+    InvalidName(String),
+    /// A built-in command already uses the name; so does the `ID` clause
+    BuiltIn(&'static str),
+    /// The registry already holds a command by this name
+    AlreadyRegistered(&'static str),
+}
+
+impl fmt::Display for RegisterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegisterError::InvalidName(name) => write!(
+                f,
+                "Invalid command name '{}': expected upper-case letters and underscores",
+                name
+            ),
+            RegisterError::BuiltIn(name) => write!(f, "{} is a built-in command", name),
+            RegisterError::AlreadyRegistered(name) => write!(f, "{} is already registered", name),
+        }
+    }
+}
+
+impl std::error::Error for RegisterError {}
+
+type Executors = HashMap<&'static str, Arc<dyn CommandExecutor>>;
+
+/// The custom commands one handler runs, by keyword
+///
+/// Clones share one registry, so a command registered through any clone is
+/// parsed and run by all of them, even after the pool has started.
+#[derive(Clone, Default)]
+pub struct CommandRegistry {
+    executors: Arc<RwLock<Executors>>,
+}
+
+impl fmt::Debug for CommandRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CommandRegistry")
+            .field(&self.names())
+            .finish()
+    }
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run `executor` for lines starting with `name`
+    ///
+    /// Fails if `name` isn't a single upper-case word, is a built-in
+    /// command, or is already registered.
+    pub fn register(
+        &self,
+        name: &'static str,
+        executor: impl CommandExecutor + 'static,
+    ) -> Result<(), RegisterError> {
+        if name.is_empty() || !name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_') {
+            return Err(RegisterError::InvalidName(name.to_string()));
+        }
+        // ID would be read as an idempotency clause, never as a command
+        if commands::lookup(name).is_some() || name == "ID" {
+            return Err(RegisterError::BuiltIn(name));
+        }
+        let mut executors = self
+            .executors
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        if executors.contains_key(name) {
+            return Err(RegisterError::AlreadyRegistered(name));
+        }
+        executors.insert(name, Arc::new(executor));
+        Ok(())
+    }
+
+    /// Remove a command, returning whether it was registered
+    ///
+    /// Lines already parsed as the command fail with `unknown command`.
+    pub fn unregister(&self, name: &str) -> bool {
+        self.executors
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(name)
+            .is_some()
+    }
+
+    /// The registered keywords, in alphabetical order
+    pub fn names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = self
+            .executors
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .keys()
+            .copied()
+            .collect();
+        names.sort_unstable();
+        names
+    }
+
+    /// The registered spelling of `keyword`, if it names a command
+    pub(crate) fn name(&self, keyword: &str) -> Option<&'static str> {
+        self.executors
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get_key_value(keyword)
+            .map(|(name, _)| *name)
+    }
+
+    pub(crate) fn executor(&self, name: &str) -> Option<Arc<dyn CommandExecutor>> {
+        self.executors
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(name)
+            .cloned()
+    }
+}
+
+/// The store as a custom command sees it
+///
+/// Reads see the live keys. `set` and `remove` do the bookkeeping SET and
+/// DELETE do, and a write a rule refuses changes nothing.
+pub struct CustomStore<'a> {
+    state: &'a mut StoreState,
+}
+
+impl<'a> CustomStore<'a> {
+    pub(crate) fn new(state: &'a mut StoreState) -> Self {
+        Self { state }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.state.store.get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.state.store.contains_key(key)
+    }
+
+    /// Number of live keys
+    pub fn len(&self) -> usize {
+        self.state.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.store.is_empty()
+    }
+
+    /// Every live key with its value, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.state
+            .store
+            .iter()
+            .map(|(key, value)| (key.as_str(), value))
+    }
+
+    /// Store `value` at `key`, as SET would
+    ///
+    /// Fails without writing if a string value breaks the key's validation
+    /// rule or the value would take its prefix past a quota.
+    pub fn set(&mut self, key: &str, value: Value) -> Result<(), HandlerError> {
+        if !matches!(value, Value::List(_)) {
+            commands::validate(self.state, key, &value.to_string())?;
+        }
+        commands::admit(self.state, [(key, Some(value.byte_len()))])?;
+        commands::import_value(self.state, key.to_string(), value);
+        Ok(())
+    }
+
+    /// Take `key` out of the store, as DELETE would, returning its value
+    ///
+    /// Under `SOFT_DELETE` the value is kept as a tombstone for UNDELETE.
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        let value = commands::remove(self.state, key)?;
+        if self.state.soft_delete {
+            self.state.tombstones.insert(
+                key.to_string(),
+                Tombstone {
+                    value: value.clone(),
+                    deleted_at: commands::unix_now(),
+                },
+            );
+        }
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::CommandHandler;
+    use crate::parser::{Command, CommandType, ParseOptions};
+    use crate::quota::PrefixQuotas;
+    use crate::validate::ValidationRules;
+
+    /// COUNTPREFIX [prefix]: count the keys starting with prefix
+    fn count_prefix(store: &mut CustomStore, args: &[String]) -> Result<String, HandlerError> {
+        let prefix = args.first().map(String::as_str).unwrap_or("");
+        let count = store
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .count();
+        Ok(count.to_string())
+    }
+
+    /// PUT key value...: store a string
+    fn put(store: &mut CustomStore, args: &[String]) -> Result<String, HandlerError> {
+        let (key, value) = args.split_first().unwrap();
+        store.set(key, Value::Str(value.join(" ")))?;
+        Ok("OK".to_string())
+    }
+
+    /// TAKE key: remove a key, returning what it held
+    fn take(store: &mut CustomStore, args: &[String]) -> Result<String, HandlerError> {
+        let key = &args[0];
+        match store.remove(key) {
+            Some(value) => Ok(value.to_string()),
+            None => Err(HandlerError::not_found(key.as_str())),
+        }
+    }
+
+    fn run(handler: &mut CommandHandler, line: &str) -> Result<String, HandlerError> {
+        let command = Command::parse_with(line, options(&handler.commands())).unwrap();
+        handler.process_command(command)
+    }
+
+    fn options(commands: &CommandRegistry) -> ParseOptions<'_> {
+        ParseOptions {
+            custom: Some(commands),
+            id_clause: true,
+        }
+    }
+
+    #[test]
+    fn test_custom_command_executor() {
+        let mut handler = CommandHandler::new().with_idempotency_window(4);
+        handler
+            .register_command("COUNTPREFIX", count_prefix)
+            .unwrap();
+        for line in ["SET user:1 a", "SET user:2 b", "SET order:1 c"] {
+            run(&mut handler, line).unwrap();
+        }
+        let command =
+            Command::parse_with("COUNTPREFIX user: ID req-1", options(&handler.commands()))
+                .unwrap();
+        assert_eq!(
+            command.command_type,
+            CommandType::Custom("COUNTPREFIX", vec!["user:".to_string()])
+        );
+        assert_eq!(handler.process_command(command.clone()).unwrap(), "2");
+        assert_eq!(
+            handler.process_command(command).unwrap(),
+            "duplicate, already applied"
+        );
+        assert_eq!(run(&mut handler, "COUNTPREFIX").unwrap(), "3");
+        // Only the three SETs changed the store
+        assert_eq!(handler.snapshot_id(), 3);
+
+        let unregistered = Command::new(CommandType::Custom("NOPE", Vec::new()));
+        assert_eq!(
+            handler.process_command(unregistered),
+            Err(HandlerError::UnknownCommand("NOPE".to_string()))
+        );
+        assert!("COUNTPREFIX user:".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_custom_writes_follow_the_store_rules() {
+        let mut handler = CommandHandler::new()
+            .with_validation(ValidationRules::compile([("n:*", "integer")]).unwrap())
+            .with_quotas(PrefixQuotas::compile([("q:*", Some(1), None)]).unwrap())
+            .with_value_history(4)
+            .with_soft_delete(true, None);
+        handler.register_command("PUT", put).unwrap();
+        handler.register_command("TAKE", take).unwrap();
+
+        assert!(matches!(
+            run(&mut handler, "PUT n:1 abc"),
+            Err(HandlerError::ValidationFailed(_))
+        ));
+        run(&mut handler, "PUT q:1 a").unwrap();
+        assert!(matches!(
+            run(&mut handler, "PUT q:2 b"),
+            Err(HandlerError::QuotaExceeded(_))
+        ));
+        assert!(!handler.contains_key("q:2"));
+        assert_eq!(handler.snapshot_id(), 1);
+
+        run(&mut handler, "PUT h first").unwrap();
+        run(&mut handler, "PUT h second").unwrap();
+        assert_eq!(run(&mut handler, "GETAT h 1").unwrap(), "first");
+        assert_eq!(run(&mut handler, "TAKE h").unwrap(), "second");
+        assert_eq!(
+            run(&mut handler, "TAKE h"),
+            Err(HandlerError::not_found("h"))
+        );
+        assert_eq!(handler.snapshot_id(), 4);
+        // The removal left a tombstone, as DELETE would
+        assert_eq!(run(&mut handler, "UNDELETE h").unwrap(), "1");
+    }
+
+    #[test]
+    fn test_registry_belongs_to_its_handler() {
+        let handler = CommandHandler::new();
+        let mut other = CommandHandler::new();
+        handler
+            .register_command("COUNTPREFIX", count_prefix)
+            .unwrap();
+
+        assert!(Command::parse_with("COUNTPREFIX", options(&other.commands())).is_err());
+        let command = Command::new(CommandType::Custom("COUNTPREFIX", Vec::new()));
+        assert_eq!(
+            other.process_command(command),
+            Err(HandlerError::UnknownCommand("COUNTPREFIX".to_string()))
+        );
+        other.register_command("COUNTPREFIX", count_prefix).unwrap();
+        assert_eq!(run(&mut other, "COUNTPREFIX").unwrap(), "0");
+
+        let commands = handler.commands();
+        assert_eq!(commands.names(), ["COUNTPREFIX"]);
+        assert!(commands.unregister("COUNTPREFIX"));
+        assert!(!commands.unregister("COUNTPREFIX"));
+        assert!(handler.commands().names().is_empty());
+    }
+
+    #[test]
+    fn test_register_rejects_taken_and_malformed_names() {
+        let registry = CommandRegistry::new();
+        let noop = |_: &mut CustomStore, _: &[String]| Ok(String::new());
+        assert_eq!(
+            registry.register("GET", noop),
+            Err(RegisterError::BuiltIn("GET"))
+        );
+        assert_eq!(
+            registry.register("ID", noop),
+            Err(RegisterError::BuiltIn("ID"))
+        );
+        assert_eq!(
+            registry.register("lower", noop),
+            Err(RegisterError::InvalidName("lower".to_string()))
+        );
+        assert!(registry.register("", noop).is_err());
+        registry.register("NOOP_TWICE", noop).unwrap();
+        assert_eq!(
+            registry.register("NOOP_TWICE", noop),
+            Err(RegisterError::AlreadyRegistered("NOOP_TWICE"))
+        );
+    }
+}
@@ -0,0 +1,200 @@
+//! Embed-friendly facade over the thread pool
+//!
+//! `KvEngine` wires up a `ThreadPool`, its senders, and the main thread's join
+//! handle so applications can run commands from code without managing any of
+//! that themselves. The underlying pieces stay public for anyone who needs
+//! more control.
+//!
+//! Commands given to `execute` are parsed on the calling thread (the work an
+//! IO thread would otherwise do) and handed straight to the main thread,
+//! which applies them in order against the single store and replies on a
+//! per-call channel. With `READ_SNAPSHOT_MAX_AGE_MS` set, reads the read
+//! snapshot can answer are answered on the calling thread instead, so
+//! concurrent callers don't queue behind each other's writes.
+
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::custom::{CommandRegistry, RegisterError};
+use crate::handler::{CommandExecutor, HandlerError};
+use crate::output::OutputSinks;
+use crate::parser::{Command, ParseOptions, ParseStats};
+use crate::thread::{
+    CommandMessage, DIRECT_SUBMISSION, RunSummary, SnapshotReader, ThreadPool, ThreadPoolBuilder,
+};
+
+/// Options for starting a `KvEngine`
+#[derive(Debug, Clone)]
+pub struct EngineOptions {
+    pub config: Arc<Config>,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self {
+            config: Arc::new(Config::default()),
+        }
+    }
+}
+
+/// Why a command run through the engine failed
+#[derive(Debug, Clone, PartialEq)]
+pub enum EngineError {
+    /// The line isn't a valid command
+    Parse(String),
+    /// The handler rejected the command
+    Handler(HandlerError),
+    /// The engine's main thread is no longer running
+    Stopped,
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::Parse(err) => write!(f, "Parse error: {}", err),
+            EngineError::Handler(err) => write!(f, "{}", err),
+            EngineError::Stopped => write!(f, "Engine is not running"),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+/// A running key-value engine
+///
+/// # Example
+/// ```
+/// use multi_threader::engine::{EngineOptions, KvEngine};
+///
+/// let engine = KvEngine::start(EngineOptions::default());
+/// engine.execute("SET user:1001 John").unwrap();
+/// assert_eq!(engine.execute("GET user:1001").unwrap(), "GET user:1001 = John");
+///
+/// let summary = engine.shutdown();
+/// assert_eq!(summary.processed, 2);
+/// ```
+pub struct KvEngine {
+    // The pool still carries the (already taken) main thread slot, whose
+    // receiver isn't `Sync`; the mutex lets callers share the engine by reference
+    pool: Mutex<ThreadPool>,
+    main_handle: JoinHandle<RunSummary>,
+    command_sender: SyncSender<CommandMessage>,
+    /// Answers reads without the main thread, when the config enables it
+    reader: Option<SnapshotReader>,
+    next_line: AtomicUsize,
+    /// Lines `execute` parsed, shared with the pool for PARSESTATS
+    parse_stats: Arc<ParseStats>,
+    /// Custom commands, shared with the main thread's handler
+    commands: CommandRegistry,
+    /// Whether lines may end in an `ID <token>` clause
+    /// (`IDEMPOTENCY_WINDOW` is on)
+    id_clause: bool,
+}
+
+impl KvEngine {
+    /// Build the pool and start the main thread
+    ///
+    /// Output sinks are discarded: results come back from `execute`.
+    pub fn start(options: EngineOptions) -> Self {
+        let parse_stats = Arc::new(ParseStats::new());
+        let commands = CommandRegistry::new();
+        let id_clause = options.config.idempotency_window > 0;
+        let mut pool = ThreadPoolBuilder::new(options.config)
+            .io_threads(0)
+            .output(OutputSinks::discard())
+            .parse_stats(Arc::clone(&parse_stats))
+            .commands(commands.clone())
+            .build();
+        let main_handle = pool.start_main_thread();
+        let command_sender = pool.get_command_sender();
+        let reader = pool.snapshot_reader();
+
+        Self {
+            pool: Mutex::new(pool),
+            main_handle,
+            command_sender,
+            reader,
+            next_line: AtomicUsize::new(1),
+            parse_stats,
+            commands,
+            id_clause,
+        }
+    }
+
+    /// Run `executor` for lines starting with `name`; see
+    /// `CommandRegistry::register`
+    pub fn register_command(
+        &self,
+        name: &'static str,
+        executor: impl CommandExecutor + 'static,
+    ) -> Result<(), RegisterError> {
+        self.commands.register(name, executor)
+    }
+
+    /// Parse and run one command line, blocking until the main thread replies
+    pub fn execute(&self, line: &str) -> Result<String, EngineError> {
+        let options = ParseOptions {
+            custom: Some(&self.commands),
+            id_clause: self.id_clause,
+        };
+        let command = Command::parse_with(line, options).map_err(EngineError::Parse)?;
+        self.parse_stats.record(command.command_type.name());
+        self.execute_command(command)
+    }
+
+    /// Run an already-parsed command, blocking until the main thread replies
+    /// unless the read snapshot answers it first
+    pub fn execute_command(&self, command: Command) -> Result<String, EngineError> {
+        if let Some(result) = self
+            .reader
+            .as_ref()
+            .and_then(|reader| reader.serve(&command))
+        {
+            return result.map_err(EngineError::Handler);
+        }
+
+        let (reply_tx, reply_rx) = mpsc::channel();
+        let msg = CommandMessage {
+            command,
+            line_number: self.next_line.fetch_add(1, Ordering::Relaxed),
+            io_thread_id: DIRECT_SUBMISSION,
+            reply: Some(reply_tx),
+            sequence: None,
+            enqueued_at: Instant::now(),
+            trace_id: None,
+            answer: None,
+        };
+
+        self.command_sender
+            .send(msg)
+            .map_err(|_| EngineError::Stopped)?;
+
+        match reply_rx.recv() {
+            Ok(result) => result.map_err(EngineError::Handler),
+            Err(_) => Err(EngineError::Stopped),
+        }
+    }
+
+    /// Counters for the commands processed so far
+    pub fn stats(&self) -> RunSummary {
+        self.pool
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .stats()
+    }
+
+    /// Stop the engine and return the final counters
+    pub fn shutdown(self) -> RunSummary {
+        drop(self.command_sender);
+        self.pool
+            .into_inner()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .shutdown();
+        self.main_handle.join().expect("Main thread panicked")
+    }
+}
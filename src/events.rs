@@ -0,0 +1,313 @@
+//! Structured run events for external collectors
+//!
+//! A run can report what happened to it as newline-delimited JSON, one flat
+//! object per event with a `ts_ms` timestamp (milliseconds since the Unix
+//! epoch) and an `event` type, followed by that event's own fields:
+//!
+//! ```text
+//! {"ts_ms":"1760000000000","event":"startup","version":"0.1.0",...}
+//! {"ts_ms":"1760000000004","event":"snapshot_restored","keys":"12",...}
+//! {"ts_ms":"1760000000950","event":"shutdown","processed":"40",...}
+//! ```
+//!
+//! Events are low-frequency (startup, snapshots, chaos injections, the
+//! shutdown summary), but they are raised on the main thread, which must
+//! never wait on a slow collector. `EventLog::emit` only queues the event on
+//! a bounded channel; a writer thread drains it into the `EventSink`. When
+//! the queue is full the event is dropped and counted instead.
+
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::json;
+
+/// Events held for the writer thread before new ones are dropped
+pub const EVENT_QUEUE_CAPACITY: usize = 1024;
+
+/// Destination for rendered events, one JSON object per call
+pub trait EventSink: Send {
+    /// Write one event line (without its trailing newline)
+    fn write_event(&mut self, line: &str) -> io::Result<()>;
+
+    /// Push buffered events out, called whenever the queue runs dry
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Appends events to a file as newline-delimited JSON
+pub struct FileEventSink {
+    writer: BufWriter<File>,
+}
+
+impl FileEventSink {
+    /// Create (or truncate) `path` for events
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+        })
+    }
+}
+
+impl EventSink for FileEventSink {
+    fn write_event(&mut self, line: &str) -> io::Result<()> {
+        writeln!(self.writer, "{}", line)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// One event: its type and fields, in the order they were added
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    kind: &'static str,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Event {
+    /// Start an event of type `kind`
+    pub fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            fields: Vec::new(),
+        }
+    }
+
+    /// Add a field, rendered with `Display`
+    pub fn field(mut self, name: &'static str, value: impl Display) -> Self {
+        self.fields.push((name, value.to_string()));
+        self
+    }
+
+    /// The event type
+    pub fn kind(&self) -> &str {
+        self.kind
+    }
+
+    /// Render as one JSON line stamped with `ts_ms`
+    pub fn to_json(&self, ts_ms: u128) -> String {
+        let ts = ts_ms.to_string();
+        json::write_flat_object(
+            [("ts_ms", ts.as_str()), ("event", self.kind)]
+                .into_iter()
+                .chain(
+                    self.fields
+                        .iter()
+                        .map(|(name, value)| (*name, value.as_str())),
+                ),
+        )
+    }
+}
+
+enum Queued {
+    Event(String),
+    Close,
+}
+
+struct Queue {
+    sender: SyncSender<Queued>,
+    dropped: AtomicU64,
+    writer: Mutex<Option<JoinHandle<io::Result<()>>>>,
+}
+
+/// Cheap handle for raising events; the default handle discards them
+#[derive(Clone, Default)]
+pub struct EventLog {
+    queue: Option<Arc<Queue>>,
+}
+
+impl EventLog {
+    /// Start a writer thread draining events into `sink`, holding at most
+    /// `capacity` events waiting to be written
+    pub fn start(sink: Box<dyn EventSink>, capacity: usize) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Queued>(capacity);
+        let writer = thread::spawn(move || {
+            let mut sink = sink;
+            'drain: while let Ok(mut queued) = receiver.recv() {
+                // Flush in bursts rather than per event
+                loop {
+                    match queued {
+                        Queued::Event(line) => sink.write_event(&line)?,
+                        Queued::Close => break 'drain,
+                    }
+                    match receiver.try_recv() {
+                        Ok(next) => queued = next,
+                        Err(_) => break,
+                    }
+                }
+                sink.flush()?;
+            }
+            sink.flush()
+        });
+        Self {
+            queue: Some(Arc::new(Queue {
+                sender,
+                dropped: AtomicU64::new(0),
+                writer: Mutex::new(Some(writer)),
+            })),
+        }
+    }
+
+    /// Whether events go anywhere
+    pub fn is_enabled(&self) -> bool {
+        self.queue.is_some()
+    }
+
+    /// Queue `event` without waiting, counting it as dropped if the queue
+    /// is full or already closed
+    pub fn emit(&self, event: Event) {
+        let Some(queue) = &self.queue else {
+            return;
+        };
+        let ts_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis());
+        match queue.sender.try_send(Queued::Event(event.to_json(ts_ms))) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_) | TrySendError::Disconnected(_)) => {
+                queue.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Events dropped so far
+    pub fn dropped(&self) -> u64 {
+        self.queue
+            .as_ref()
+            .map_or(0, |queue| queue.dropped.load(Ordering::Relaxed))
+    }
+
+    /// Write everything queued so far and stop the writer thread; events
+    /// emitted afterwards are dropped
+    ///
+    /// Only the first call waits for the writer; it reports a failed write
+    /// or a writer that panicked.
+    pub fn close(&self) -> io::Result<()> {
+        let Some(queue) = &self.queue else {
+            return Ok(());
+        };
+        let Some(writer) = queue
+            .writer
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+        else {
+            return Ok(());
+        };
+        // Blocking is fine here: the run is over. A writer that already
+        // failed has dropped the receiver, so the send fails instead
+        let _ = queue.sender.send(Queued::Close);
+        writer
+            .join()
+            .unwrap_or_else(|_| Err(io::Error::other("event writer panicked")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Collects lines in memory, optionally stalling until released
+    struct MemorySink {
+        lines: Arc<Mutex<Vec<String>>>,
+        gate: Option<mpsc::Receiver<()>>,
+    }
+
+    impl EventSink for MemorySink {
+        fn write_event(&mut self, line: &str) -> io::Result<()> {
+            if let Some(gate) = self.gate.take() {
+                let _ = gate.recv();
+            }
+            self.lines.lock().unwrap().push(line.to_string());
+            Ok(())
+        }
+    }
+
+    fn memory_log(
+        capacity: usize,
+        gated: bool,
+    ) -> (EventLog, Arc<Mutex<Vec<String>>>, mpsc::Sender<()>) {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let (release, gate) = mpsc::channel();
+        let sink = MemorySink {
+            lines: Arc::clone(&lines),
+            gate: gated.then_some(gate),
+        };
+        (EventLog::start(Box::new(sink), capacity), lines, release)
+    }
+
+    #[test]
+    fn test_event_renders_type_and_fields_in_order() {
+        let event = Event::new("snapshot_saved")
+            .field("trigger", "exit")
+            .field("keys", 3);
+        assert_eq!(
+            event.to_json(1500),
+            r#"{"ts_ms":"1500","event":"snapshot_saved","trigger":"exit","keys":"3"}"#
+        );
+        assert_eq!(event.kind(), "snapshot_saved");
+    }
+
+    #[test]
+    fn test_events_are_written_in_emit_order() {
+        let (log, lines, _release) = memory_log(16, false);
+        log.emit(Event::new("startup"));
+        log.emit(Event::new("chaos").field("kind", "fail_write"));
+        log.emit(Event::new("shutdown"));
+        log.close().unwrap();
+
+        let kinds: Vec<String> = lines
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|line| json::parse_flat_object(line).unwrap().pairs[1].1.clone())
+            .collect();
+        assert_eq!(kinds, ["startup", "chaos", "shutdown"]);
+        assert_eq!(log.dropped(), 0);
+    }
+
+    #[test]
+    fn test_full_queue_drops_instead_of_blocking() {
+        let (log, lines, release) = memory_log(2, true);
+        // The stalled writer holds at most one event and the queue two more,
+        // so most of the burst is dropped without emit ever waiting
+        for _ in 0..10 {
+            log.emit(Event::new("burst"));
+        }
+        let dropped = log.dropped();
+        assert!(dropped >= 7, "dropped {}", dropped);
+        release.send(()).unwrap();
+        log.close().unwrap();
+        assert_eq!(lines.lock().unwrap().len() as u64, 10 - dropped);
+    }
+
+    #[test]
+    fn test_emit_after_close_is_dropped() {
+        let (log, lines, _release) = memory_log(4, false);
+        log.emit(Event::new("startup"));
+        log.close().unwrap();
+        log.emit(Event::new("late"));
+        assert_eq!(log.dropped(), 1);
+        assert_eq!(lines.lock().unwrap().len(), 1);
+        // Closing twice is harmless
+        log.close().unwrap();
+    }
+
+    #[test]
+    fn test_disabled_log_ignores_events() {
+        let log = EventLog::default();
+        log.emit(Event::new("startup"));
+        assert!(!log.is_enabled());
+        assert_eq!(log.dropped(), 0);
+        log.close().unwrap();
+    }
+}
@@ -0,0 +1,177 @@
+//! C-compatible API over `KvEngine` (enabled with the `ffi` feature)
+//!
+//! The declarations live in `include/crabby.h`; keep the two in sync. Every
+//! entry point runs inside `catch_unwind` so a panic is turned into
+//! `CRABBY_ERR_PANIC` instead of unwinding into C. Output is always copied
+//! into a caller-provided buffer, so the only allocation C ever owns is the
+//! engine itself, released by `crabby_close`.
+
+use std::ffi::{CStr, c_char};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::sync::Arc;
+
+use crate::config::Config;
+use crate::engine::{EngineError, EngineOptions, KvEngine};
+
+pub const CRABBY_OK: i32 = 0;
+pub const CRABBY_ERR_NULL_ARG: i32 = -1;
+pub const CRABBY_ERR_INVALID_UTF8: i32 = -2;
+pub const CRABBY_ERR_PARSE: i32 = -3;
+pub const CRABBY_ERR_SOFT: i32 = -4;
+pub const CRABBY_ERR_HARD: i32 = -5;
+pub const CRABBY_ERR_BUFFER_TOO_SMALL: i32 = -6;
+pub const CRABBY_ERR_STOPPED: i32 = -7;
+pub const CRABBY_ERR_PANIC: i32 = -8;
+
+/// Options passed to `crabby_open`
+#[repr(C)]
+pub struct CrabbyOptions {
+    pub store_initial_capacity: usize,
+}
+
+/// Opaque engine handle (`CrabbyEngine` in C)
+pub struct CrabbyEngine {
+    engine: KvEngine,
+}
+
+/// Start an engine, or return NULL if it couldn't be started
+///
+/// # Safety
+/// `options` must be NULL or point to a valid `CrabbyOptions`.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn crabby_open(options: *const CrabbyOptions) -> *mut CrabbyEngine {
+    let result = panic::catch_unwind(|| {
+        let mut config = Config::default();
+        // SAFETY: the caller guarantees `options` is NULL or valid
+        if let Some(options) = unsafe { options.as_ref() } {
+            config.store_initial_capacity = options.store_initial_capacity;
+        }
+
+        let engine = KvEngine::start(EngineOptions {
+            config: Arc::new(config),
+        });
+        Box::into_raw(Box::new(CrabbyEngine { engine }))
+    });
+    result.unwrap_or(ptr::null_mut())
+}
+
+/// Run one command line and copy its response or error into `out_buf`
+///
+/// # Safety
+/// `engine` must come from `crabby_open` and not have been closed, `line`
+/// must be a NUL-terminated string, and `out_buf` must be NULL or point to
+/// at least `out_len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn crabby_execute(
+    engine: *mut CrabbyEngine,
+    line: *const c_char,
+    out_buf: *mut c_char,
+    out_len: usize,
+) -> i32 {
+    if engine.is_null() || line.is_null() {
+        return CRABBY_ERR_NULL_ARG;
+    }
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        // SAFETY: both pointers were checked for NULL and the caller
+        // guarantees they are otherwise valid
+        let (engine, line) = unsafe { (&(*engine).engine, CStr::from_ptr(line)) };
+        let Ok(line) = line.to_str() else {
+            return (CRABBY_ERR_INVALID_UTF8, String::new());
+        };
+
+        match engine.execute(line) {
+            Ok(response) => (CRABBY_OK, response),
+            Err(EngineError::Parse(err)) => (CRABBY_ERR_PARSE, err),
+            Err(EngineError::Handler(err)) if err.is_soft() => (CRABBY_ERR_SOFT, err.to_string()),
+            Err(EngineError::Handler(err)) => (CRABBY_ERR_HARD, err.to_string()),
+            Err(EngineError::Stopped) => (CRABBY_ERR_STOPPED, String::new()),
+        }
+    }));
+
+    let (status, text) = result.unwrap_or((CRABBY_ERR_PANIC, String::new()));
+    // SAFETY: the caller guarantees `out_buf` holds `out_len` bytes
+    let fits = unsafe { write_out(&text, out_buf, out_len) };
+    if status == CRABBY_OK && !fits {
+        CRABBY_ERR_BUFFER_TOO_SMALL
+    } else {
+        status
+    }
+}
+
+/// Stop and free an engine; NULL is a no-op
+///
+/// # Safety
+/// `engine` must be NULL or come from `crabby_open`, and must not be used
+/// again after this call.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn crabby_close(engine: *mut CrabbyEngine) {
+    if engine.is_null() {
+        return;
+    }
+
+    // SAFETY: the caller hands back ownership of a pointer from `crabby_open`
+    let engine = unsafe { Box::from_raw(engine) };
+    // A main thread panic surfaces here; it must not unwind into C
+    let _ = panic::catch_unwind(AssertUnwindSafe(|| engine.engine.shutdown()));
+}
+
+/// Copy `text` into `out_buf` as a NUL-terminated string, truncating it to
+/// fit; returns whether the whole text fit
+///
+/// # Safety
+/// `out_buf` must be NULL or point to at least `out_len` writable bytes.
+unsafe fn write_out(text: &str, out_buf: *mut c_char, out_len: usize) -> bool {
+    if out_buf.is_null() || out_len == 0 {
+        return text.is_empty();
+    }
+
+    let len = text.len().min(out_len - 1);
+    // SAFETY: `len + 1 <= out_len`, which the caller guarantees is writable
+    unsafe {
+        ptr::copy_nonoverlapping(text.as_ptr().cast::<c_char>(), out_buf, len);
+        *out_buf.add(len) = 0;
+    }
+    len == text.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn execute(engine: *mut CrabbyEngine, line: &str, buf: &mut [u8]) -> (i32, String) {
+        let line = std::ffi::CString::new(line).unwrap();
+        let status =
+            unsafe { crabby_execute(engine, line.as_ptr(), buf.as_mut_ptr().cast(), buf.len()) };
+        let text = CStr::from_bytes_until_nul(buf).unwrap().to_str().unwrap();
+        (status, text.to_string())
+    }
+
+    #[test]
+    fn test_status_codes() {
+        let engine = unsafe { crabby_open(ptr::null()) };
+        assert!(!engine.is_null());
+        let mut buf = [0u8; 64];
+
+        assert_eq!(execute(engine, "SET a 1", &mut buf).0, CRABBY_OK);
+        assert_eq!(
+            execute(engine, "GET missing", &mut buf),
+            (CRABBY_ERR_SOFT, "Key 'missing' not found".to_string())
+        );
+        assert_eq!(execute(engine, "LTRIM a 0 1", &mut buf).0, CRABBY_ERR_HARD);
+        assert_eq!(execute(engine, "BOGUS", &mut buf).0, CRABBY_ERR_PARSE);
+
+        let mut small = [0u8; 4];
+        assert_eq!(
+            execute(engine, "GET a", &mut small),
+            (CRABBY_ERR_BUFFER_TOO_SMALL, "GET".to_string())
+        );
+
+        let status =
+            unsafe { crabby_execute(ptr::null_mut(), c"GET a".as_ptr(), ptr::null_mut(), 0) };
+        assert_eq!(status, CRABBY_ERR_NULL_ARG);
+
+        unsafe { crabby_close(engine) };
+    }
+}
@@ -0,0 +1,119 @@
+//! Command-type filters applied by IO threads (`--only`, `--skip`)
+//!
+//! A line whose command type is filtered out is still parsed, then counted
+//! by type and dropped without reaching the main thread, so a mixed command
+//! log can be replayed as just its reads or just its writes. Names are
+//! checked against the command table when the filter is built, so a typo
+//! fails at startup instead of silently filtering nothing.
+
+use std::collections::HashSet;
+
+use crate::commands;
+use crate::custom::CommandRegistry;
+use crate::parser::CommandType;
+
+/// Which command types IO threads pass on to the main thread
+///
+/// The default passes everything. With `only`, just the listed types pass;
+/// `skip` then removes types from whatever would otherwise pass.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandFilter {
+    only: Option<HashSet<&'static str>>,
+    skip: HashSet<&'static str>,
+    /// Custom commands `only` and `skip` may name
+    custom: HashSet<&'static str>,
+}
+
+impl CommandFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A filter that can also name the commands registered in `custom` so
+    /// far
+    pub fn with_commands(custom: &CommandRegistry) -> Self {
+        Self {
+            custom: custom.names().into_iter().collect(),
+            ..Self::default()
+        }
+    }
+
+    /// Pass only the command types in `spec`, a comma-separated list such as
+    /// `SET,DELETE` (repeating it widens the list)
+    pub fn only(mut self, spec: &str) -> Result<Self, String> {
+        let names = parse_names("--only", spec, &self.custom)?;
+        self.only.get_or_insert_with(HashSet::new).extend(names);
+        Ok(self)
+    }
+
+    /// Drop the command types in `spec`, a comma-separated list such as `GET`
+    pub fn skip(mut self, spec: &str) -> Result<Self, String> {
+        let names = parse_names("--skip", spec, &self.custom)?;
+        self.skip.extend(names);
+        Ok(self)
+    }
+
+    /// Whether commands of this type reach the main thread
+    pub fn allows(&self, command_type: &CommandType) -> bool {
+        let name = command_type.name();
+        self.only.as_ref().is_none_or(|only| only.contains(name)) && !self.skip.contains(name)
+    }
+}
+
+/// The registered spelling of every name in a comma-separated list
+fn parse_names(
+    flag: &str,
+    spec: &str,
+    custom: &HashSet<&'static str>,
+) -> Result<HashSet<&'static str>, String> {
+    let names = spec
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let upper = name.to_ascii_uppercase();
+            commands::lookup(&upper)
+                .map(|spec| spec.name)
+                .or_else(|| custom.get(upper.as_str()).copied())
+                .ok_or_else(|| format!("Unknown command '{}' in {}", name, flag))
+        })
+        .collect::<Result<HashSet<_>, _>>()?;
+    if names.is_empty() {
+        return Err(format!(
+            "{} requires a comma-separated list of commands",
+            flag
+        ));
+    }
+    Ok(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_type(line: &str) -> CommandType {
+        line.parse::<crate::parser::Command>().unwrap().command_type
+    }
+
+    #[test]
+    fn test_only_and_skip_combine() {
+        let filter = CommandFilter::new()
+            .only("set, delete,GET")
+            .unwrap()
+            .skip("get")
+            .unwrap();
+        assert!(filter.allows(&command_type("SET k v")));
+        assert!(filter.allows(&command_type("DELETE k")));
+        assert!(!filter.allows(&command_type("GET k")));
+        assert!(!filter.allows(&command_type("INFO")));
+
+        assert!(CommandFilter::new().allows(&command_type("INFO")));
+    }
+
+    #[test]
+    fn test_unknown_names_are_rejected() {
+        let err = CommandFilter::new().skip("GET,SETT").unwrap_err();
+        assert_eq!(err, "Unknown command 'SETT' in --skip");
+        assert!(CommandFilter::new().only(" , ").is_err());
+    }
+}
@@ -0,0 +1,106 @@
+//! Redis-style glob matching for key patterns
+//!
+//! Supports `*` (any run of characters), `?` (any single character),
+//! `[abc]` / `[a-z]` / `[^abc]` character classes, and `\` to escape the
+//! next character.
+
+/// Whether `text` matches the glob `pattern` in full
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Iterative matcher: on a mismatch, retry from the most recent `*`
+    // consuming one more character
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match_class(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+
+        match step {
+            Some(len) => {
+                p += len;
+                t += 1;
+            }
+            None => match backtrack {
+                Some((star, consumed)) => {
+                    p = star + 1;
+                    t = consumed + 1;
+                    backtrack = Some((star, consumed + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Match `c` against the class starting at `pattern[0] == '['`, returning
+/// the class's length in pattern characters if it matches
+///
+/// An unterminated `[` is treated as a literal.
+fn match_class(pattern: &[char], c: char) -> Option<usize> {
+    let Some(close) = pattern.iter().skip(1).position(|&ch| ch == ']') else {
+        return (c == '[').then_some(1);
+    };
+    let close = close + 1;
+
+    let (negated, body) = match pattern[1..close].split_first() {
+        Some(('^', rest)) => (true, rest),
+        _ => (false, &pattern[1..close]),
+    };
+
+    let mut matched = false;
+    let mut i = 0;
+    while i < body.len() {
+        if i + 2 < body.len() && body[i + 1] == '-' {
+            matched |= (body[i]..=body[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= body[i] == c;
+            i += 1;
+        }
+    }
+
+    (matched != negated).then_some(close + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_wildcards() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("user:*", "user:1001"));
+        assert!(glob_match("*:score", "alice:score"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("user:100?", "user:1001"));
+        assert!(!glob_match("user:100?", "user:100"));
+    }
+
+    #[test]
+    fn test_glob_classes_and_escapes() {
+        assert!(glob_match("h[ae]llo", "hello"));
+        assert!(!glob_match("h[ae]llo", "hillo"));
+        assert!(glob_match("key:[0-9]", "key:7"));
+        assert!(glob_match("key:[^0-9]", "key:x"));
+        assert!(!glob_match("key:[^0-9]", "key:7"));
+        assert!(glob_match("a\\*", "a*"));
+        assert!(!glob_match("a\\*", "ab"));
+        assert!(glob_match("[", "["));
+    }
+}
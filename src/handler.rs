@@ -1,86 +1,845 @@
-use crate::parser::{Command, CommandType};
-use std::collections::HashMap;
+use crate::access::KeyAccessLog;
+use crate::commands::{self, StoreState};
+use crate::config::{SetDuplicatePolicy, ValueCase};
+use crate::custom::{CommandRegistry, CustomStore, RegisterError};
+use crate::history::ValueHistory;
+use crate::hotkeys::HotKeys;
+use crate::idempotency::IdempotencyWindow;
+use crate::import_report::{ImportReport, ImportTracker};
+use crate::parser::{Command, CommandType, ParseStats};
+use crate::procedure::Procedure;
+use crate::quota::PrefixQuotas;
+use crate::thread::InFlight;
+use crate::validate::ValidationRules;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
 
+/// Errors produced while executing a command
+#[derive(Debug, Clone, PartialEq)]
+pub enum HandlerError {
+    /// The key does not exist; `line` is the input line that asked for it,
+    /// once the processor has tagged the error
+    NotFound { key: String, line: Option<usize> },
+    /// The key holds a value of a different type than the command expects
+    WrongType,
+    /// The stored value is not a base-10 integer
+    NotAnInteger,
+    /// A numeric merge found a non-numeric value at this key
+    NotNumeric(String),
+    /// A JSON command found a stored value that isn't valid JSON
+    NotJson(String),
+    /// A JSON path leads nowhere in the key's (valid) JSON value
+    PathNotFound { key: String, path: String },
+    /// A bounded increment would exceed its maximum
+    LimitReached,
+    /// A conditional increment's condition key doesn't hold the expected
+    /// value
+    ConditionNotMet,
+    /// GETAT asked for a version whose value the history has dropped;
+    /// `oldest` is the earliest version still kept
+    VersionEvicted {
+        key: String,
+        version: u64,
+        oldest: u64,
+    },
+    /// GETAT asked for a version the key has never had
+    VersionNeverExisted {
+        key: String,
+        version: u64,
+        latest: u64,
+    },
+    /// GETAT needs value history, which `VALUE_HISTORY_DEPTH` leaves off
+    HistoryDisabled,
+    /// A rename would overwrite this key, which already exists
+    KeyExists(String),
+    /// SET found this key already set under `SET_DUPLICATE_POLICY=error`
+    AlreadySet(String),
+    /// A checksummed value no longer matches its stored CRC
+    ChecksumMismatch,
+    /// The value doesn't conform to the validation rule for its key, named
+    /// as `glob=type`; nothing was written
+    ValidationFailed(String),
+    /// The write would take a key prefix past its `LIMIT_PREFIX_*` quota,
+    /// described as the limit it would exceed; nothing was written
+    QuotaExceeded(String),
+    /// A failure that may succeed if the command is tried again (a briefly
+    /// full disk or replica buffer); the command was not applied
+    Transient(String),
+    /// A bulk load could not read or parse its source
+    LoadFailed(String),
+    /// A dump could not write its destination
+    DumpFailed(String),
+    /// A snapshot could not be written
+    SaveFailed(String),
+    /// The main thread has shut down and no longer applies commands
+    ShuttingDown,
+    /// A command naming no registered executor, built directly rather than
+    /// parsed
+    UnknownCommand(String),
+    /// CALL named a procedure that was never defined
+    UnknownProcedure(String),
+    /// CALL passed arguments its procedure can't run with; no step ran
+    CallFailed(String),
+    /// APPLY couldn't read its patch, or a line of it failed; the store is
+    /// as it was before the APPLY
+    ApplyFailed(String),
+    /// The command's handler panicked (only reported when
+    /// `CATCH_HANDLER_PANICS` is on; the command may be partly applied)
+    Panicked(String),
+}
+
+impl HandlerError {
+    /// Soft errors are expected outcomes (a GET miss during a replay, input
+    /// still queued behind a SHUTDOWN) rather than failures, and are reported
+    /// and counted separately
+    pub fn is_soft(&self) -> bool {
+        matches!(
+            self,
+            HandlerError::NotFound { .. } | HandlerError::ShuttingDown
+        )
+    }
+
+    /// A `NotFound` for `key`, not yet tied to an input line
+    pub fn not_found(key: impl Into<String>) -> Self {
+        HandlerError::NotFound {
+            key: key.into(),
+            line: None,
+        }
+    }
+
+    /// Name the input line in errors whose message refers to one
+    pub fn at_line(self, line_number: usize) -> Self {
+        match self {
+            HandlerError::NotFound { key, .. } => HandlerError::NotFound {
+                key,
+                line: Some(line_number),
+            },
+            err => err,
+        }
+    }
+
+    /// Transient errors are retried by the main thread before being reported
+    pub fn is_transient(&self) -> bool {
+        matches!(self, HandlerError::Transient(_))
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandlerError::NotFound { key, line: None } => write!(f, "Key '{}' not found", key),
+            HandlerError::NotFound {
+                key,
+                line: Some(line),
+            } => write!(f, "Key '{}' not found (line {})", key, line),
+            HandlerError::WrongType => write!(
+                f,
+                "WRONGTYPE Operation against a key holding the wrong kind of value"
+            ),
+            HandlerError::NotAnInteger => write!(f, "value is not an integer"),
+            HandlerError::NotNumeric(key) => write!(f, "value at '{}' is not a number", key),
+            HandlerError::NotJson(reason) => write!(f, "value is not JSON: {}", reason),
+            HandlerError::PathNotFound { key, path } => {
+                write!(f, "path '{}' not found in '{}'", path, key)
+            }
+            HandlerError::LimitReached => write!(f, "limit reached"),
+            HandlerError::ConditionNotMet => write!(f, "condition not met"),
+            HandlerError::VersionEvicted {
+                key,
+                version,
+                oldest,
+            } => write!(
+                f,
+                "version {} of '{}' is too old, history evicted (oldest kept: {})",
+                version, key, oldest
+            ),
+            HandlerError::VersionNeverExisted {
+                key,
+                version,
+                latest,
+            } => write!(
+                f,
+                "version {} of '{}' never existed (latest: {})",
+                version, key, latest
+            ),
+            HandlerError::HistoryDisabled => {
+                write!(f, "value history is disabled (set VALUE_HISTORY_DEPTH)")
+            }
+            HandlerError::KeyExists(key) => write!(f, "target key '{}' already exists", key),
+            HandlerError::AlreadySet(key) => {
+                write!(f, "key '{}' is already set, not overwritten", key)
+            }
+            HandlerError::ChecksumMismatch => write!(f, "checksum mismatch"),
+            HandlerError::ValidationFailed(rule) => {
+                write!(f, "value rejected by validation rule '{}'", rule)
+            }
+            HandlerError::QuotaExceeded(limit) => write!(f, "prefix quota exceeded: {}", limit),
+            HandlerError::Transient(reason) => write!(f, "transient failure: {}", reason),
+            HandlerError::LoadFailed(reason) => write!(f, "load failed: {}", reason),
+            HandlerError::DumpFailed(reason) => write!(f, "dump failed: {}", reason),
+            HandlerError::SaveFailed(reason) => write!(f, "save failed: {}", reason),
+            HandlerError::ShuttingDown => write!(f, "shutting down, command not applied"),
+            HandlerError::UnknownCommand(name) => write!(f, "unknown command '{}'", name),
+            HandlerError::UnknownProcedure(name) => write!(f, "unknown procedure '{}'", name),
+            HandlerError::CallFailed(reason) => write!(f, "call failed: {}", reason),
+            HandlerError::ApplyFailed(reason) => {
+                write!(f, "apply failed, nothing applied: {}", reason)
+            }
+            HandlerError::Panicked(reason) => write!(f, "handler panicked: {}", reason),
+        }
+    }
+}
+
+/// A value held in the store
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    List(VecDeque<String>),
+    /// A string stored with the CRC-32 it had when written (SETCRC)
+    Checksummed {
+        value: String,
+        crc: u32,
+    },
+    /// A counter kept as a number (SETINT, CONVERT), read back as its
+    /// decimal text
+    Int(i64),
+}
+
+impl Value {
+    /// Bytes of data the value holds: the string, every list element, the
+    /// string and its CRC, or the integer
+    pub fn byte_len(&self) -> usize {
+        match self {
+            Value::Str(value) => value.len(),
+            Value::List(items) => items.iter().map(String::len).sum(),
+            Value::Checksummed { value, .. } => value.len() + size_of::<u32>(),
+            Value::Int(_) => size_of::<i64>(),
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Str(s) | Value::Checksummed { value: s, .. } => write!(f, "{}", s),
+            Value::Int(n) => write!(f, "{}", n),
+            Value::List(items) => {
+                let items: Vec<&str> = items.iter().map(String::as_str).collect();
+                write!(f, "[{}]", items.join(", "))
+            }
+        }
+    }
+}
+
+/// The keys and values in a handler's store
+pub type Store = HashMap<String, Value>;
+
+/// A value DELETE kept back under `SOFT_DELETE`, hidden from every command
+/// until UNDELETE restores it or PURGE (or the TTL sweep) frees it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tombstone {
+    pub value: Value,
+    /// When the key was deleted, in seconds since the Unix epoch
+    pub deleted_at: u64,
+}
+
+/// Soft-deleted keys and what they held
+pub type Tombstones = HashMap<String, Tombstone>;
+
+/// Runs a command defined outside this crate
+///
+/// Executors are registered by keyword in a handler's `CommandRegistry`: a
+/// line starting with the keyword parses as `CommandType::Custom` with the
+/// remaining tokens as its arguments, and the handler passes those to
+/// `execute` along with a `CustomStore`. Custom commands get the same
+/// idempotency and stats handling as built-in ones.
+///
+/// Any function or closure with the signature
+/// `fn(&mut CustomStore, &[String]) -> Result<String, HandlerError>` is an
+/// executor.
+pub trait CommandExecutor: Send + Sync {
+    fn execute(&self, store: &mut CustomStore<'_>, args: &[String])
+    -> Result<String, HandlerError>;
+}
+
+impl<F> CommandExecutor for F
+where
+    F: Fn(&mut CustomStore<'_>, &[String]) -> Result<String, HandlerError> + Send + Sync,
+{
+    fn execute(
+        &self,
+        store: &mut CustomStore<'_>,
+        args: &[String],
+    ) -> Result<String, HandlerError> {
+        self(store, args)
+    }
+}
+
+/// Command outcome counters since the handler started or the last STATSRESET
+///
+/// STATS and STATSRESET themselves are not counted, so a scrape never shows
+/// up in the numbers it reads.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct HandlerStats {
+    pub commands: u64,
+    pub succeeded: u64,
+    pub soft_errors: u64,
+    pub hard_errors: u64,
+}
+
+impl HandlerStats {
+    pub(crate) fn record(&mut self, result: &Result<String, HandlerError>) {
+        self.commands += 1;
+        match result {
+            Ok(_) => self.succeeded += 1,
+            Err(err) if err.is_soft() => self.soft_errors += 1,
+            Err(_) => self.hard_errors += 1,
+        }
+    }
+}
+
+impl fmt::Display for HandlerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "commands:{}\nsucceeded:{}\nsoft_errors:{}\nhard_errors:{}",
+            self.commands, self.succeeded, self.soft_errors, self.hard_errors
+        )
+    }
+}
+
+/// Applies parsed commands to the store
+///
+/// The commands themselves live in the `commands` module tree; the handler
+/// owns the state and wraps each dispatch with idempotency and stats.
 pub struct CommandHandler {
-    store: HashMap<String, String>,
+    state: StoreState,
+}
+
+impl Default for CommandHandler {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl CommandHandler {
     pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    /// Create a handler whose store has room for at least `capacity` keys
+    /// before it needs to reallocate
+    pub fn with_capacity(capacity: usize) -> Self {
         Self {
-            store: HashMap::new(),
+            state: StoreState::with_capacity(capacity),
         }
     }
 
-    pub fn process_command(&mut self, command: Command) -> Result<String, String> {
-        match command.command_type {
-            CommandType::Set(key, value) => self.handle_set(key, value),
-            CommandType::Get(key) => self.handle_get(&key),
-            CommandType::Delete(key) => self.handle_delete(key),
+    /// Remember the last `size` idempotency tokens so retried commands
+    /// tagged with `ID <token>` are acknowledged instead of re-applied
+    pub fn with_idempotency_window(mut self, size: usize) -> Self {
+        self.state.idempotency = IdempotencyWindow::new(size);
+        self
+    }
+
+    /// Where `SHUTDOWN SAVE` and `save_snapshot` write the store
+    pub fn with_snapshot_path(mut self, path: Option<PathBuf>) -> Self {
+        self.state.snapshot_path = path;
+        self
+    }
+
+    /// Case-fold values stored by SET
+    pub fn with_value_case(mut self, value_case: ValueCase) -> Self {
+        self.state.value_case = value_case;
+        self
+    }
+
+    /// Keep or reject SETs to keys that already exist instead of
+    /// overwriting them
+    pub fn with_set_duplicate_policy(mut self, policy: SetDuplicatePolicy) -> Self {
+        self.state.set_duplicate_policy = policy;
+        self
+    }
+
+    /// Keep deleted keys as tombstones instead of erasing them, purging
+    /// tombstones `ttl_secs` after deletion if set
+    pub fn with_soft_delete(mut self, enabled: bool, ttl_secs: Option<u64>) -> Self {
+        self.state.soft_delete = enabled;
+        self.state.tombstone_ttl_secs = ttl_secs;
+        self
+    }
+
+    /// Procedures CALL can run before any DEFINE, as loaded from
+    /// `PROCEDURES_FILE`
+    pub fn with_procedures(mut self, procedures: HashMap<String, Procedure>) -> Self {
+        self.state.procedures = procedures;
+        self
+    }
+
+    /// Run the custom commands registered in `registry`
+    pub fn with_commands(mut self, registry: CommandRegistry) -> Self {
+        self.state.custom = registry;
+        self
+    }
+
+    /// Check values written by SET and friends against these rules
+    pub fn with_validation(mut self, rules: ValidationRules) -> Self {
+        self.state.validation = rules;
+        self
+    }
+
+    /// Cap the keys and bytes under each key prefix
+    pub fn with_quotas(mut self, quotas: PrefixQuotas) -> Self {
+        self.state.quotas = quotas;
+        self
+    }
+
+    /// Track when each key was created and how often it is read, for
+    /// INSPECT
+    pub fn with_key_access(mut self, enabled: bool) -> Self {
+        self.state.access = KeyAccessLog::new(enabled);
+        self
+    }
+
+    /// Number each key's versions and keep up to `depth` earlier values of
+    /// each, for GETAT; 0 disables the history
+    pub fn with_value_history(mut self, depth: usize) -> Self {
+        self.state.history = ValueHistory::new(depth);
+        self
+    }
+
+    /// Answer PARSESTATS from these counts, which the pool's IO threads
+    /// fill in as they parse
+    pub fn with_parse_stats(mut self, parse_stats: Arc<ParseStats>) -> Self {
+        self.state.parse_stats = parse_stats;
+        self
+    }
+
+    /// Report this gauge of commands waiting on the main thread in INFO
+    pub fn with_in_flight(mut self, in_flight: Arc<InFlight>) -> Self {
+        self.state.in_flight = in_flight;
+        self
+    }
+
+    /// Track the `capacity` most touched keys for HOTKEYS; 0 disables
+    /// tracking
+    pub fn with_hot_keys(mut self, capacity: usize) -> Self {
+        self.state.hot_keys = HotKeys::new(capacity);
+        self
+    }
+
+    /// Class each SET, SETCRC, and LOADJSON write as a create, an identical
+    /// overwrite, or a changed one, counting overwrites of up to
+    /// `overwrite_keys` keys at once; 0 disables the report
+    pub fn with_import_report(mut self, overwrite_keys: usize) -> Self {
+        self.state.imports = ImportTracker::new(overwrite_keys);
+        self
+    }
+
+    /// The import report so far, or `None` unless it is on
+    pub fn import_report(&self) -> Option<ImportReport> {
+        self.state.imports.report()
+    }
+
+    /// The `n` most touched keys with their approximate command counts,
+    /// empty unless hot-key tracking is on
+    pub fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        self.state.hot_keys.top(n)
+    }
+
+    /// Replace the store's contents with keys restored from a snapshot
+    ///
+    /// Snapshots hold no value history, so every restored key is back at
+    /// version 1.
+    pub fn restore(&mut self, store: HashMap<String, Value>) {
+        self.state.store = store;
+        self.state.access.clear();
+        self.state.history.clear();
+        commands::recount_quotas(&mut self.state);
+    }
+
+    /// Replace the tombstones with ones restored from a snapshot
+    pub fn restore_tombstones(&mut self, tombstones: Tombstones) {
+        self.state.tombstones = tombstones;
+    }
+
+    /// Write the store to the snapshot path, returning the number of keys
+    pub fn save_snapshot(&self) -> Result<usize, HandlerError> {
+        commands::save_snapshot(&self.state)
+    }
+
+    /// An independent copy of the handler's current state
+    ///
+    /// Commands applied to the copy never reach the original, which is how
+    /// dry runs see the effects of earlier lines without keeping them.
+    pub fn snapshot(&self) -> CommandHandler {
+        Self {
+            state: self.state.clone(),
         }
     }
 
-    fn handle_set(&mut self, key: String, value: String) -> Result<String, String> {
-        self.store.insert(key.clone(), value.clone());
-        Ok(format!("SET {} = {}", key, value))
+    /// Number of keys currently in the store
+    pub fn key_count(&self) -> usize {
+        self.state.store.len()
+    }
+
+    /// Whether `key` is currently in the store
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.state.store.contains_key(key)
     }
 
-    fn handle_get(&self, key: &str) -> Result<String, String> {
-        match self.store.get(key) {
-            Some(value) => Ok(format!("GET {} = {}", key, value)),
-            None => Err(format!("Key '{}' not found", key)),
+    /// Commands that have changed the store so far, as SNAPSHOTID reports
+    pub fn snapshot_id(&self) -> u64 {
+        self.state.snapshot_id
+    }
+
+    /// A copy of the live keys and their values
+    pub fn store_copy(&self) -> Store {
+        self.state.store.clone()
+    }
+
+    /// Run `executor` for lines starting with `name`; see
+    /// `CommandRegistry::register`
+    ///
+    /// Only this handler (and its snapshots) runs the command, and only
+    /// input parsed with `commands()` recognises it.
+    pub fn register_command(
+        &self,
+        name: &'static str,
+        executor: impl CommandExecutor + 'static,
+    ) -> Result<(), RegisterError> {
+        self.state.custom.register(name, executor)
+    }
+
+    /// The handler's custom commands, for parsing its input with
+    /// `Command::parse_with`
+    pub fn commands(&self) -> CommandRegistry {
+        self.state.custom.clone()
+    }
+
+    pub fn process_command(&mut self, command: Command) -> Result<String, HandlerError> {
+        commands::sweep_tombstones(&mut self.state, commands::unix_now());
+        if let Some(key) = command.command_type.primary_key() {
+            self.state.hot_keys.record(key);
+        }
+        let counted = !matches!(
+            command.command_type,
+            CommandType::Stats | CommandType::StatsReset
+        );
+        // INSPECT reports reads rather than being one
+        let read_key = match &command.command_type {
+            CommandType::Inspect(_) => None,
+            command_type if self.state.access.is_enabled() && !command_type.is_write() => {
+                command_type.primary_key().map(str::to_string)
+            }
+            _ => None,
+        };
+        let result = self.process_tagged(command);
+        if counted {
+            self.state.stats.record(&result);
         }
+        if let (Ok(_), Some(key)) = (&result, read_key) {
+            self.state.access.read(&key);
+        }
+        result
     }
 
-    fn handle_delete(&mut self, key: String) -> Result<String, String> {
-        match self.store.remove(&key) {
-            Some(value) => Ok(format!("DELETED {} (was: {})", key, value)),
-            None => Err(format!("Key '{}' not found", key)),
+    /// Apply a command unless its idempotency token shows it's a retry
+    fn process_tagged(&mut self, command: Command) -> Result<String, HandlerError> {
+        let Some(token) = command
+            .idempotency_key
+            .filter(|_| self.state.idempotency.is_enabled())
+        else {
+            return commands::dispatch(&mut self.state, command.command_type);
+        };
+
+        if self.state.idempotency.check(&token) {
+            return Ok("duplicate, already applied".to_string());
+        }
+        // Failed commands didn't apply, so a retry should get another chance
+        let result = commands::dispatch(&mut self.state, command.command_type);
+        if result.is_ok() {
+            self.state.idempotency.insert(&token);
         }
+        result
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::parser::Command;
+    use crate::parser::ParseOptions;
+
+    #[test]
+    fn test_idempotent_retry_applies_once() {
+        let mut handler = CommandHandler::new().with_idempotency_window(16);
+
+        let cmd: Command = "INCRBOUNDED hits 10 ID req-1".parse().unwrap();
+        assert_eq!(handler.process_command(cmd).unwrap(), "1");
+        let cmd: Command = "INCRBOUNDED hits 10 ID req-1".parse().unwrap();
+        assert_eq!(
+            handler.process_command(cmd).unwrap(),
+            "duplicate, already applied"
+        );
+        assert_eq!(
+            handler.state.store.get("hits"),
+            Some(&Value::Str("1".to_string()))
+        );
+
+        let cmd: Command = "INCRBOUNDED hits 10 ID req-2".parse().unwrap();
+        assert_eq!(handler.process_command(cmd).unwrap(), "2");
+
+        let info = handler.process_command("INFO".parse().unwrap()).unwrap();
+        assert!(info.contains("idempotency_window:16"));
+        assert!(info.contains("idempotency_hits:1"));
+    }
 
     #[test]
-    fn test_set_and_get() {
+    fn test_evicted_token_applies_again() {
+        let mut handler = CommandHandler::new().with_idempotency_window(1);
+
+        for line in [
+            "INCRBOUNDED hits 10 ID a",
+            "INCRBOUNDED hits 10 ID b",
+            "INCRBOUNDED hits 10 ID a",
+        ] {
+            handler.process_command(line.parse().unwrap()).unwrap();
+        }
+        // `a` was pushed out of the one-token window by `b`
+        assert_eq!(
+            handler.state.store.get("hits"),
+            Some(&Value::Str("3".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_id_is_plain_text_when_disabled() {
         let mut handler = CommandHandler::new();
+        let options = ParseOptions {
+            custom: None,
+            id_clause: false,
+        };
 
-        let set_cmd: Command = "SET user:1001 John".parse().unwrap();
-        let result = handler.process_command(set_cmd);
-        assert!(result.is_ok());
+        for line in ["SET k my ID 7", "SET ID x"] {
+            let cmd = Command::parse_with(line, options).unwrap();
+            handler.process_command(cmd).unwrap();
+        }
+        assert_eq!(
+            handler.state.store.get("k"),
+            Some(&Value::Str("my ID 7".to_string()))
+        );
+        assert_eq!(
+            handler.state.store.get("ID"),
+            Some(&Value::Str("x".to_string()))
+        );
+        assert!(Command::parse_with("INCRBOUNDED hits 10 ID a", options).is_err());
 
-        let get_cmd: Command = "GET user:1001".parse().unwrap();
-        let result = handler.process_command(get_cmd);
-        assert!(result.is_ok());
-        assert!(result.unwrap().contains("John"));
+        // A key set on the command directly is ignored too
+        for _ in 0..2 {
+            let cmd: Command = "INCRBOUNDED hits 10 ID a".parse().unwrap();
+            handler.process_command(cmd).unwrap();
+        }
+        assert_eq!(
+            handler.state.store.get("hits"),
+            Some(&Value::Str("2".to_string()))
+        );
     }
 
     #[test]
-    fn test_get_nonexistent_key() {
+    fn test_snapshot_is_independent() {
         let mut handler = CommandHandler::new();
-        let get_cmd: Command = "GET nonexistent".parse().unwrap();
-        let result = handler.process_command(get_cmd);
-        assert!(result.is_err());
+        handler.process_command("SET a 1".parse().unwrap()).unwrap();
+
+        let mut shadow = handler.snapshot();
+        shadow.process_command("SET b 2".parse().unwrap()).unwrap();
+        shadow.process_command("DELETE a".parse().unwrap()).unwrap();
+
+        assert_eq!(shadow.key_count(), 1);
+        assert_eq!(handler.key_count(), 1);
+        assert!(handler.state.store.contains_key("a"));
+        assert!(!handler.state.store.contains_key("b"));
     }
 
     #[test]
-    fn test_delete() {
+    fn test_statsreset_returns_and_clears_counters() {
         let mut handler = CommandHandler::new();
+        for line in ["SET a 1", "GET a", "GET missing", "LTRIM a 0 1"] {
+            let _ = handler.process_command(line.parse().unwrap());
+        }
+
+        let stats = handler
+            .process_command("STATSRESET".parse().unwrap())
+            .unwrap();
+        assert_eq!(
+            stats,
+            "commands:4\nsucceeded:2\nsoft_errors:1\nhard_errors:1"
+        );
+
+        let stats = handler.process_command("STATS".parse().unwrap()).unwrap();
+        assert_eq!(
+            stats,
+            "commands:0\nsucceeded:0\nsoft_errors:0\nhard_errors:0"
+        );
+        // Resetting the counters leaves the data alone
+        assert!(handler.state.store.contains_key("a"));
+    }
+
+    #[test]
+    fn test_inspect_counts_reads_of_a_key() {
+        let mut handler = CommandHandler::new().with_key_access(true);
+        let mut run = |line: &str| handler.process_command(line.parse().unwrap());
+        let field = |report: &str, name: &str| {
+            report
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .unwrap()
+                .to_string()
+        };
+
+        run("SET session:9 tok").unwrap();
+        let report = run("INSPECT session:9").unwrap();
+        assert_eq!(field(&report, "value"), "tok");
+        assert!(field(&report, "inserted_age_ms").parse::<u128>().is_ok());
+        assert_eq!(field(&report, "last_access_age_ms"), "never");
+        assert_eq!(field(&report, "access_count"), "0");
+
+        for _ in 0..3 {
+            run("GET session:9").unwrap();
+        }
+        // Writes, misses, and INSPECT itself aren't reads of the key
+        run("SET session:9 tok2").unwrap();
+        run("GET other").unwrap_err();
+        run("INSPECT session:9").unwrap();
+        let report = run("INSPECT session:9").unwrap();
+        assert_eq!(field(&report, "value"), "tok2");
+        assert!(field(&report, "last_access_age_ms").parse::<u128>().is_ok());
+        assert_eq!(field(&report, "access_count"), "3");
 
-        let set_cmd: Command = "SET user:1001 John".parse().unwrap();
-        handler.process_command(set_cmd).unwrap();
+        // A rename carries the history; deleting the key ends it
+        run("RENAMENX session:9 session:10").unwrap();
+        let report = run("INSPECT session:10").unwrap();
+        assert_eq!(field(&report, "access_count"), "3");
+        run("DELETE session:10").unwrap();
+        assert_eq!(
+            run("INSPECT session:10"),
+            Err(HandlerError::not_found("session:10"))
+        );
+        run("SET session:10 fresh").unwrap();
+        let report = run("INSPECT session:10").unwrap();
+        assert_eq!(field(&report, "access_count"), "0");
+    }
+
+    #[test]
+    fn test_getat_reads_earlier_versions() {
+        let mut handler = CommandHandler::new().with_value_history(2);
+        let mut run = |line: &str| handler.process_command(line.parse().unwrap());
+
+        for value in ["a", "b", "c", "d"] {
+            run(&format!("SET k {}", value)).unwrap();
+        }
+        assert_eq!(run("GETAT k 4").unwrap(), "d");
+        assert_eq!(run("GETAT k 3").unwrap(), "c");
+        assert_eq!(run("GETAT k 2").unwrap(), "b");
+        let err = run("GETAT k 1").unwrap_err();
+        assert_eq!(
+            err,
+            HandlerError::VersionEvicted {
+                key: "k".to_string(),
+                version: 1,
+                oldest: 2
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "version 1 of 'k' is too old, history evicted (oldest kept: 2)"
+        );
+        let err = run("GETAT k 5").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "version 5 of 'k' never existed (latest: 4)"
+        );
+        assert!(matches!(err, HandlerError::VersionNeverExisted { .. }));
+        assert!(
+            run("INSPECT k")
+                .unwrap()
+                .starts_with("value:d\nversion:4\n")
+        );
+
+        // A rename keeps the versions
+        run("RENAMENX k moved").unwrap();
+        assert_eq!(run("GETAT moved 3").unwrap(), "c");
+
+        // Deleting ends the history; the key starts over when set again
+        run("DELETE moved").unwrap();
+        run("SET moved e").unwrap();
+        assert_eq!(
+            run("GETAT moved 2"),
+            Err(HandlerError::VersionNeverExisted {
+                key: "moved".to_string(),
+                version: 2,
+                latest: 1
+            })
+        );
+        assert_eq!(run("GETAT gone 1"), Err(HandlerError::not_found("gone")));
+    }
+
+    #[test]
+    fn test_getat_versions_list_writes() {
+        let mut handler = CommandHandler::new().with_value_history(4);
+        handler.restore(HashMap::from([(
+            "l".to_string(),
+            Value::List(["a", "b", "c"].map(String::from).into()),
+        )]));
+        let mut run = |line: &str| handler.process_command(line.parse().unwrap());
+
+        run("LTRIM l 1 -1").unwrap();
+        run("LMOVE l dst LEFT RIGHT").unwrap();
+        assert_eq!(run("GETAT l 3").unwrap(), "[c]");
+        assert_eq!(run("GETAT l 2").unwrap(), "[b, c]");
+        assert_eq!(run("GETAT l 1").unwrap(), "[a, b, c]");
+        assert_eq!(run("GETAT dst 1").unwrap(), "[b]");
+    }
 
-        let delete_cmd: Command = "DELETE user:1001".parse().unwrap();
-        let result = handler.process_command(delete_cmd);
-        assert!(result.is_ok());
+    #[test]
+    fn test_value_history_is_not_kept_across_snapshots() {
+        let mut handler = CommandHandler::new().with_value_history(4);
+        for line in ["SET k a", "SET k b"] {
+            handler.process_command(line.parse().unwrap()).unwrap();
+        }
+        let store = handler.state.store.clone();
+        handler.restore(store);
 
-        // Verify key is deleted
-        let get_cmd: Command = "GET user:1001".parse().unwrap();
-        let result = handler.process_command(get_cmd);
-        assert!(result.is_err());
+        // The restored key is back at version 1, holding its latest value
+        let mut run = |line: &str| handler.process_command(line.parse().unwrap());
+        assert_eq!(run("GETAT k 1").unwrap(), "b");
+        assert!(matches!(
+            run("GETAT k 2"),
+            Err(HandlerError::VersionNeverExisted { latest: 1, .. })
+        ));
+        run("SET k c").unwrap();
+        assert_eq!(run("GETAT k 1").unwrap(), "b");
+    }
+
+    #[test]
+    fn test_getat_requires_value_history() {
+        let mut handler = CommandHandler::new();
+        handler.process_command("SET k a".parse().unwrap()).unwrap();
+        let err = handler
+            .process_command("GETAT k 1".parse().unwrap())
+            .unwrap_err();
+        assert_eq!(err, HandlerError::HistoryDisabled);
+        assert_eq!(
+            err.to_string(),
+            "value history is disabled (set VALUE_HISTORY_DEPTH)"
+        );
+        // INSPECT only reports versions when they are counted
+        assert_eq!(
+            handler
+                .process_command("INSPECT k".parse().unwrap())
+                .unwrap(),
+            "value:a\n(key access tracking disabled)"
+        );
     }
 }
@@ -0,0 +1,230 @@
+//! Per-key version numbers and recent earlier values, for GETAT
+//!
+//! A key is at version 1 when it is created, and every write to it moves it
+//! to the next version. The value at the current version is the one in the
+//! store; the history keeps a copy of each value a write replaced, stamped
+//! with its version, up to `depth` of them per key, dropping the oldest.
+//! Renaming a key moves its history and deleting it ends it, so a key set
+//! again after a DELETE starts over at version 1.
+//!
+//! History is in memory only: snapshots don't save it, and a key restored
+//! from one (or written by a custom command) is taken to be at version 1 on
+//! its next GETAT or write. It copies every overwritten value, so it is off
+//! unless `VALUE_HISTORY_DEPTH` asks for it, and every method is then a
+//! no-op.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::handler::Value;
+
+/// Why a version of a key can't be read
+#[derive(Debug, Clone, PartialEq)]
+pub enum VersionError {
+    /// The version existed, but its value has since been dropped from the
+    /// history; `oldest` is the earliest version still kept
+    Evicted { oldest: u64 },
+    /// The key has never reached this version; `latest` is its current one
+    NeverExisted { latest: u64 },
+}
+
+/// The versions of one key
+#[derive(Debug, Clone)]
+struct KeyHistory {
+    /// The version of the value in the store
+    version: u64,
+    /// Values earlier writes replaced, oldest first, each with its version
+    earlier: VecDeque<(u64, Value)>,
+}
+
+impl KeyHistory {
+    fn new() -> Self {
+        Self {
+            version: 1,
+            earlier: VecDeque::new(),
+        }
+    }
+}
+
+/// Version numbers and replaced values of every key, when enabled
+#[derive(Debug, Clone, Default)]
+pub struct ValueHistory {
+    /// Earlier values kept per key; 0 disables the history
+    depth: usize,
+    keys: HashMap<String, KeyHistory>,
+}
+
+impl ValueHistory {
+    /// A history keeping up to `depth` earlier values of each key
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth,
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.depth > 0
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Note a write to `key`, whose value before the write is `current`
+    ///
+    /// Call before the store changes: the value being replaced is kept as
+    /// the current version, and the key moves to the next one. A key that
+    /// didn't exist starts at version 1.
+    pub fn written(&mut self, key: &str, current: Option<&Value>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let Some(current) = current else {
+            self.keys.insert(key.to_string(), KeyHistory::new());
+            return;
+        };
+        let history = match self.keys.get_mut(key) {
+            Some(history) => history,
+            None => self
+                .keys
+                .entry(key.to_string())
+                .or_insert_with(KeyHistory::new),
+        };
+        if history.earlier.len() == self.depth {
+            history.earlier.pop_front();
+        }
+        history
+            .earlier
+            .push_back((history.version, current.clone()));
+        history.version += 1;
+    }
+
+    /// Note that `key` left the store
+    pub fn removed(&mut self, key: &str) {
+        if self.is_enabled() {
+            self.keys.remove(key);
+        }
+    }
+
+    /// Note values that moved, each `(from, to)`, keeping their history
+    ///
+    /// Every source is taken out before any destination is filled, so a
+    /// destination may be another move's source.
+    pub fn renamed<'a>(&mut self, moves: impl IntoIterator<Item = (&'a str, &'a str)>) {
+        if !self.is_enabled() {
+            return;
+        }
+        let moved: Vec<(&str, Option<KeyHistory>)> = moves
+            .into_iter()
+            .map(|(from, to)| (to, self.keys.remove(from)))
+            .collect();
+        for (to, history) in moved {
+            match history {
+                Some(history) => self.keys.insert(to.to_string(), history),
+                None => self.keys.remove(to),
+            };
+        }
+    }
+
+    /// The current version of `key`, which must be in the store
+    pub fn version(&self, key: &str) -> u64 {
+        self.keys.get(key).map_or(1, |history| history.version)
+    }
+
+    /// The value `key` had at `version`, given its value now
+    pub fn value_at<'a>(
+        &'a self,
+        key: &str,
+        version: u64,
+        current: &'a Value,
+    ) -> Result<&'a Value, VersionError> {
+        let Some(history) = self.keys.get(key) else {
+            return match version {
+                1 => Ok(current),
+                _ => Err(VersionError::NeverExisted { latest: 1 }),
+            };
+        };
+        if version == 0 || version > history.version {
+            return Err(VersionError::NeverExisted {
+                latest: history.version,
+            });
+        }
+        if version == history.version {
+            return Ok(current);
+        }
+        // Kept versions are consecutive, ending just before the current one
+        let oldest = history.version - history.earlier.len() as u64;
+        if version < oldest {
+            return Err(VersionError::Evicted { oldest });
+        }
+        Ok(&history.earlier[(version - oldest) as usize].1)
+    }
+
+    /// Forget every key, as when the store is replaced wholesale
+    pub fn clear(&mut self) {
+        self.keys.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(s: &str) -> Value {
+        Value::Str(s.to_string())
+    }
+
+    #[test]
+    fn test_history_keeps_the_latest_versions() {
+        let mut history = ValueHistory::new(2);
+        history.written("k", None);
+        // Versions 1 to 4 hold a, b, c, d; d is in the store
+        for old in ["a", "b", "c"] {
+            history.written("k", Some(&value(old)));
+        }
+        let current = value("d");
+        assert_eq!(history.version("k"), 4);
+        assert_eq!(history.value_at("k", 4, &current), Ok(&current));
+        assert_eq!(history.value_at("k", 3, &current), Ok(&value("c")));
+        assert_eq!(history.value_at("k", 2, &current), Ok(&value("b")));
+        assert_eq!(
+            history.value_at("k", 1, &current),
+            Err(VersionError::Evicted { oldest: 2 })
+        );
+        for version in [0, 5] {
+            assert_eq!(
+                history.value_at("k", version, &current),
+                Err(VersionError::NeverExisted { latest: 4 })
+            );
+        }
+    }
+
+    #[test]
+    fn test_history_follows_renames_and_ends_on_removal() {
+        let mut history = ValueHistory::new(4);
+        history.written("a", None);
+        history.written("a", Some(&value("1")));
+        history.renamed([("a", "b")]);
+        assert_eq!(history.version("b"), 2);
+        assert_eq!(history.value_at("b", 1, &value("2")), Ok(&value("1")));
+
+        history.removed("b");
+        history.written("b", None);
+        assert_eq!(history.version("b"), 1);
+
+        // A key the history never saw written is at version 1
+        let restored = value("r");
+        assert_eq!(history.value_at("r", 1, &restored), Ok(&restored));
+        history.written("r", Some(&restored));
+        assert_eq!(history.value_at("r", 1, &value("s")), Ok(&restored));
+    }
+
+    #[test]
+    fn test_disabled_history_records_nothing() {
+        let mut history = ValueHistory::default();
+        assert!(!history.is_enabled());
+        history.written("a", None);
+        history.written("a", Some(&value("1")));
+        assert_eq!(history.version("a"), 1);
+    }
+}
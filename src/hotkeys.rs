@@ -0,0 +1,403 @@
+//! Approximate per-key command counts for finding hot keys
+//!
+//! Counting every key exactly would take memory proportional to the key
+//! space. `HotKeys` is a space-saving sketch instead: it tracks at most
+//! `capacity` keys, and a key arriving when every slot is taken replaces the
+//! one with the lowest count, inheriting that count as its possible
+//! overestimate. Any key seen more than `total / capacity` times is
+//! guaranteed to be tracked, and a tracked key's count is never below its
+//! true count nor more than `error` above it.
+
+/// A space-saving sketch of the most frequently touched keys
+///
+/// Updates run on the main thread for every command, so they avoid
+/// allocating: tracked keys are looked up by a 64-bit hash of the key, an
+/// evicted key's buffer is reused for its replacement, and slots are ranked
+/// by count in blocks of equal counts, so bumping a count and finding the
+/// least counted key (always ranked last) take constant time. Two keys with
+/// the same hash can't both be tracked; the second is simply not counted.
+#[derive(Debug, Clone, Default)]
+pub struct HotKeys {
+    capacity: usize,
+    slots: Vec<Slot>,
+    // Open-addressed table of slot numbers, probed linearly from a key's
+    // hash; slots are reused when a key is evicted
+    index: Vec<usize>,
+    // Slots by count, highest first
+    ranked: Vec<Ranked>,
+    blocks: Vec<Block>,
+    free_blocks: Vec<usize>,
+    total: u64,
+}
+
+/// A tracked key
+#[derive(Debug, Clone)]
+struct Slot {
+    key: String,
+    hash: u64,
+    /// How much of the count may have been inherited from the key this one
+    /// evicted
+    error: u64,
+    /// Where the slot is in `ranked`
+    rank: usize,
+}
+
+/// An entry in the ranking: a slot and the block holding its count
+#[derive(Debug, Clone, Copy)]
+struct Ranked {
+    slot: usize,
+    block: usize,
+}
+
+/// A run of ranks whose slots share a count
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    count: u64,
+    /// The run's first rank; it ends where the next block starts
+    first: usize,
+}
+
+impl HotKeys {
+    /// A sketch tracking up to `capacity` keys; 0 disables it
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Number of updates seen since the sketch was created
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Count one command touching `key`
+    pub fn record(&mut self, key: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+        self.total += 1;
+
+        let hash = key_hash(key);
+        if let Some(slot) = self.find(hash) {
+            if self.slots[slot].key == key {
+                self.bump(slot);
+            }
+            return;
+        }
+        if self.slots.len() < self.capacity {
+            let slot = self.slots.len();
+            let rank = self.ranked.len();
+            let block = match self.ranked.last() {
+                Some(last) if self.blocks[last.block].count == 0 => last.block,
+                _ => self.new_block(0, rank),
+            };
+            self.slots.push(Slot {
+                key: key.to_string(),
+                hash,
+                error: 0,
+                rank,
+            });
+            self.ranked.push(Ranked { slot, block });
+            self.insert(hash, slot);
+            self.bump(slot);
+            return;
+        }
+
+        // Every slot is taken: the new key replaces the least counted one
+        let Some(&Ranked { slot, block }) = self.ranked.last() else {
+            return;
+        };
+        let min = self.blocks[block].count;
+        self.remove(self.slots[slot].hash);
+        self.insert(hash, slot);
+        let evicted = &mut self.slots[slot];
+        evicted.key.clear();
+        evicted.key.push_str(key);
+        evicted.hash = hash;
+        evicted.error = min;
+        self.bump(slot);
+    }
+
+    /// The slot tracking the key with this hash
+    fn find(&self, hash: u64) -> Option<usize> {
+        let mask = self.index.len().checked_sub(1)?;
+        let mut at = hash as usize & mask;
+        loop {
+            let slot = self.index[at];
+            if slot == EMPTY {
+                return None;
+            }
+            if self.slots[slot].hash == hash {
+                return Some(slot);
+            }
+            at = (at + 1) & mask;
+        }
+    }
+
+    fn insert(&mut self, hash: u64, slot: usize) {
+        if self.index.is_empty() {
+            // At most half full, so probe runs stay short
+            self.index = vec![EMPTY; (2 * self.capacity).next_power_of_two()];
+        }
+        let mask = self.index.len() - 1;
+        let mut at = hash as usize & mask;
+        while self.index[at] != EMPTY {
+            at = (at + 1) & mask;
+        }
+        self.index[at] = slot;
+    }
+
+    /// Drop the entry for `hash`, shifting later entries of its probe run
+    /// back so lookups never stop at the gap
+    fn remove(&mut self, hash: u64) {
+        let mask = self.index.len() - 1;
+        let mut gap = hash as usize & mask;
+        while self.slots[self.index[gap]].hash != hash {
+            gap = (gap + 1) & mask;
+        }
+        let mut at = gap;
+        loop {
+            at = (at + 1) & mask;
+            let slot = self.index[at];
+            if slot == EMPTY {
+                break;
+            }
+            // An entry may fill the gap unless its home lies after the gap
+            let home = self.slots[slot].hash as usize & mask;
+            if at.wrapping_sub(home) & mask >= at.wrapping_sub(gap) & mask {
+                self.index[gap] = slot;
+                gap = at;
+            }
+        }
+        self.index[gap] = EMPTY;
+    }
+
+    /// Add one to a slot's count, keeping `ranked` sorted: the slot swaps
+    /// to the front of its block, then that rank joins the block for the
+    /// next count, which is always just before it
+    fn bump(&mut self, slot: usize) {
+        let rank = self.slots[slot].rank;
+        let block = self.ranked[rank].block;
+        let Block { count, first } = self.blocks[block];
+
+        let displaced = self.ranked[first].slot;
+        self.ranked[rank].slot = displaced;
+        self.ranked[first].slot = slot;
+        self.slots[displaced].rank = rank;
+        self.slots[slot].rank = first;
+
+        let emptied = self
+            .ranked
+            .get(first + 1)
+            .is_none_or(|next| next.block != block);
+        let above = first.checked_sub(1).map(|before| self.ranked[before].block);
+        let next = match above {
+            Some(above) if self.blocks[above].count == count + 1 => above,
+            _ if emptied => {
+                self.blocks[block].count += 1;
+                return;
+            }
+            _ => self.new_block(count + 1, first),
+        };
+        self.ranked[first].block = next;
+        if emptied {
+            self.free_blocks.push(block);
+        } else {
+            self.blocks[block].first = first + 1;
+        }
+    }
+
+    fn new_block(&mut self, count: u64, first: usize) -> usize {
+        let block = Block { count, first };
+        match self.free_blocks.pop() {
+            Some(id) => {
+                self.blocks[id] = block;
+                id
+            }
+            None => {
+                self.blocks.push(block);
+                self.blocks.len() - 1
+            }
+        }
+    }
+
+    /// The `n` keys with the highest counts, highest first (ties by key)
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut top: Vec<(String, u64)> = self
+            .ranked
+            .iter()
+            .map(|ranked| {
+                let key = self.slots[ranked.slot].key.clone();
+                (key, self.blocks[ranked.block].count)
+            })
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    /// The tracked count of `key` and how much of it may be overestimated
+    pub fn estimate(&self, key: &str) -> Option<(u64, u64)> {
+        let slot = self.find(key_hash(key))?;
+        let tracked = &self.slots[slot];
+        (tracked.key == key).then(|| {
+            let count = self.blocks[self.ranked[tracked.rank].block].count;
+            (count, tracked.error)
+        })
+    }
+}
+
+/// Marks an unused entry in the index
+const EMPTY: usize = usize::MAX;
+
+/// FNV-1a over the key's bytes, then mixed so the low bits the index
+/// probes from depend on every byte
+fn key_hash(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.bytes() {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+
+    /// A Zipf-like stream over `keys` distinct keys: key i is drawn with
+    /// weight 1 / (i + 1)
+    fn skewed_stream(keys: usize, len: usize) -> Vec<String> {
+        let weights: Vec<f64> = (0..keys).map(|i| 1.0 / (i + 1) as f64).collect();
+        let total: f64 = weights.iter().sum();
+        let mut rng = StdRng::seed_from_u64(7);
+        (0..len)
+            .map(|_| {
+                let mut target = rng.r#gen::<f64>() * total;
+                let mut key = keys - 1;
+                for (i, weight) in weights.iter().enumerate() {
+                    if target < *weight {
+                        key = i;
+                        break;
+                    }
+                    target -= weight;
+                }
+                format!("key:{}", key)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sketch_matches_exact_counts_on_skewed_keys() {
+        const CAPACITY: usize = 50;
+        let stream = skewed_stream(2_000, 50_000);
+
+        let mut sketch = HotKeys::new(CAPACITY);
+        let mut exact: HashMap<&str, u64> = HashMap::new();
+        for key in &stream {
+            sketch.record(key);
+            *exact.entry(key).or_insert(0) += 1;
+        }
+        assert_eq!(sketch.total(), stream.len() as u64);
+
+        // Every key above total / capacity is tracked, and no tracked count
+        // strays outside [true, true + error]
+        let threshold = stream.len() as u64 / CAPACITY as u64;
+        for (key, &count) in &exact {
+            if count > threshold {
+                assert!(sketch.estimate(key).is_some(), "{} not tracked", key);
+            }
+        }
+        for (key, count) in sketch.top(CAPACITY) {
+            let (_, error) = sketch.estimate(&key).unwrap();
+            let truth = exact[key.as_str()];
+            assert!(count >= truth && count - error <= truth, "{}", key);
+        }
+
+        // The heaviest keys come out in the same order as the exact counts
+        let mut exact_top: Vec<(&str, u64)> = exact.into_iter().collect();
+        exact_top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let sketch_top: Vec<String> = sketch.top(5).into_iter().map(|(key, _)| key).collect();
+        let exact_top: Vec<&str> = exact_top[..5].iter().map(|(key, _)| *key).collect();
+        assert_eq!(sketch_top, exact_top);
+    }
+
+    #[test]
+    fn test_eviction_inherits_the_minimum_count() {
+        let mut sketch = HotKeys::new(2);
+        for key in ["a", "a", "a", "b", "c"] {
+            sketch.record(key);
+        }
+        assert_eq!(sketch.estimate("b"), None);
+        assert_eq!(sketch.estimate("c"), Some((2, 1)));
+        assert_eq!(sketch.top(10), [("a".to_string(), 3), ("c".to_string(), 2)]);
+    }
+
+    /// Compares an all-SET run through the pool with hot-key tracking off
+    /// and on
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_hot_key_tracking`
+    #[test]
+    #[ignore]
+    fn bench_hot_key_tracking() {
+        use crate::config::Config;
+        use crate::output::OutputSinks;
+        use crate::thread::ThreadPoolBuilder;
+        use std::sync::Arc;
+
+        const LINES: usize = 1_000_000;
+
+        for _ in 0..3 {
+            for track_hot_keys in [false, true] {
+                let config = Config {
+                    track_hot_keys,
+                    hot_keys_capacity: crate::config::DEFAULT_HOT_KEYS_CAPACITY,
+                    ..Config::default()
+                };
+                let mut pool = ThreadPoolBuilder::new(Arc::new(config))
+                    .io_threads(2)
+                    .output(OutputSinks::discard())
+                    .build();
+                let start = std::time::Instant::now();
+                let main_handle = pool.start_main_thread();
+                let sender = pool.get_string_sender();
+                for i in 0..LINES {
+                    sender
+                        .send((format!("SET key:{} value", i * 7919 % 100_000), i + 1))
+                        .unwrap();
+                }
+                drop(sender);
+                pool.shutdown();
+                main_handle.join().unwrap();
+                println!(
+                    "[Bench] {} SETs with hot-key tracking {}: {:?}",
+                    LINES,
+                    if track_hot_keys { "on" } else { "off" },
+                    start.elapsed()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_disabled_sketch_tracks_nothing() {
+        let mut sketch = HotKeys::new(0);
+        sketch.record("a");
+        assert_eq!(sketch.total(), 0);
+        assert!(sketch.top(10).is_empty());
+    }
+}
@@ -0,0 +1,153 @@
+//! Bounded window of recently applied idempotency tokens
+//!
+//! Producers that retry may send the same command twice. Tagging a command
+//! with `ID <token>` lets the main thread recognise the retry and acknowledge
+//! it without applying it again. Only the most recently used tokens are kept:
+//! once a token is evicted from the window, a command carrying it is applied
+//! as if it were new.
+
+use std::collections::{BTreeMap, HashMap};
+use std::mem::size_of;
+
+/// An LRU set of idempotency tokens
+#[derive(Debug, Clone, Default)]
+pub struct IdempotencyWindow {
+    capacity: usize,
+    // token -> tick of its last use, and the reverse for finding the oldest
+    last_used: HashMap<String, u64>,
+    by_age: BTreeMap<u64, String>,
+    tick: u64,
+    hits: u64,
+    token_bytes: usize,
+}
+
+impl IdempotencyWindow {
+    /// A window remembering up to `capacity` tokens; 0 disables it
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Whether `token` was applied recently; a hit refreshes the token
+    pub fn check(&mut self, token: &str) -> bool {
+        let Some(tick) = self.last_used.get(token).copied() else {
+            return false;
+        };
+
+        self.hits += 1;
+        self.touch(token.to_string(), Some(tick));
+        true
+    }
+
+    /// Remember `token` as applied, evicting the least recently used token
+    /// if the window is full
+    pub fn insert(&mut self, token: &str) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let previous = self.last_used.get(token).copied();
+        if previous.is_none() {
+            if self.last_used.len() == self.capacity
+                && let Some((_, oldest)) = self.by_age.pop_first()
+            {
+                self.last_used.remove(&oldest);
+                self.token_bytes -= oldest.len();
+            }
+            self.token_bytes += token.len();
+        }
+        self.touch(token.to_string(), previous);
+    }
+
+    fn touch(&mut self, token: String, previous: Option<u64>) {
+        if let Some(tick) = previous {
+            self.by_age.remove(&tick);
+        }
+        self.tick += 1;
+        self.by_age.insert(self.tick, token.clone());
+        self.last_used.insert(token, self.tick);
+    }
+
+    /// Drop entries only one of the two indexes has, returning how many
+    ///
+    /// Every token should be in both maps under the same tick. A token
+    /// missing from `by_age` is never evicted, and an age entry whose token
+    /// is gone would be evicted in place of a real one.
+    pub fn repair(&mut self) -> usize {
+        let before = self.last_used.len() + self.by_age.len();
+        let last_used = &self.last_used;
+        self.by_age
+            .retain(|tick, token| last_used.get(token) == Some(tick));
+        let by_age = &self.by_age;
+        self.last_used
+            .retain(|token, tick| by_age.get(tick) == Some(token));
+        self.token_bytes = self.last_used.keys().map(String::len).sum();
+        before - self.last_used.len() - self.by_age.len()
+    }
+
+    /// Add an age entry with no matching token (test helper for CHECK)
+    #[cfg(test)]
+    pub(crate) fn insert_dangling(&mut self, tick: u64, token: &str) {
+        self.by_age.insert(tick, token.to_string());
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.last_used.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.last_used.is_empty()
+    }
+
+    /// Duplicates recognised so far
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Approximate heap use: each token is stored twice, plus the entry
+    /// overhead of both maps (hash table and tree node slack is not counted)
+    pub fn memory_bytes(&self) -> usize {
+        let per_entry = 2 * (size_of::<String>() + size_of::<u64>());
+        2 * self.token_bytes + self.len() * per_entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_evicts_least_recently_used() {
+        let mut window = IdempotencyWindow::new(2);
+        window.insert("a");
+        window.insert("b");
+        // Refreshing `a` makes `b` the oldest
+        assert!(window.check("a"));
+        window.insert("c");
+
+        assert!(window.check("a"));
+        assert!(!window.check("b"));
+        assert!(window.check("c"));
+        assert_eq!(window.len(), 2);
+        assert_eq!(window.hits(), 3);
+    }
+
+    #[test]
+    fn test_disabled_window_remembers_nothing() {
+        let mut window = IdempotencyWindow::new(0);
+        window.insert("a");
+        assert!(!window.check("a"));
+        assert!(window.is_empty());
+        assert_eq!(window.memory_bytes(), 0);
+    }
+}
@@ -0,0 +1,118 @@
+//! How much overwriting an import did (`--import-report`)
+//!
+//! Each SET, SETCRC, or LOADJSON write is classed as creating a key,
+//! overwriting it with an identical value, or overwriting it with a
+//! different one. Overwrites are counted per key in a `HotKeys` sketch, so
+//! memory stays bounded however many keys the import touches: the counts
+//! are exact while fewer distinct keys are overwritten than the sketch
+//! tracks, and the most overwritten keys are still found past that.
+
+use crate::handler::Value;
+use crate::hotkeys::HotKeys;
+
+/// Most overwritten keys the run summary lists
+pub const REPORT_TOP_KEYS: usize = 20;
+
+/// Keys the overwrite sketch tracks when hot-key tracking doesn't set a
+/// capacity
+pub const DEFAULT_OVERWRITE_KEYS: usize = 10_000;
+
+/// The totals an import report ends with
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ImportReport {
+    /// Writes to keys that didn't exist
+    pub created: u64,
+    /// Writes that replaced a value with an equal one
+    pub identical: u64,
+    /// Writes that replaced a value with a different one
+    pub changed: u64,
+    /// The keys overwritten most often, with their (approximate) counts
+    pub most_overwritten: Vec<(String, u64)>,
+}
+
+/// Classifies SET-like writes as they happen, when enabled
+#[derive(Debug, Clone, Default)]
+pub struct ImportTracker {
+    created: u64,
+    identical: u64,
+    changed: u64,
+    overwrites: HotKeys,
+}
+
+impl ImportTracker {
+    /// A tracker counting overwrites of up to `overwrite_keys` keys at
+    /// once; 0 disables it
+    pub fn new(overwrite_keys: usize) -> Self {
+        Self {
+            overwrites: HotKeys::new(overwrite_keys),
+            ..Self::default()
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.overwrites.is_enabled()
+    }
+
+    /// Note that `value` is about to be written at `key`, which currently
+    /// holds `previous`
+    pub fn record(&mut self, key: &str, previous: Option<&Value>, value: &Value) {
+        if !self.is_enabled() {
+            return;
+        }
+        match previous {
+            None => self.created += 1,
+            Some(previous) => {
+                if previous == value {
+                    self.identical += 1;
+                } else {
+                    self.changed += 1;
+                }
+                self.overwrites.record(key);
+            }
+        }
+    }
+
+    /// The counts so far, or `None` if the tracker is off
+    pub fn report(&self) -> Option<ImportReport> {
+        self.is_enabled().then(|| ImportReport {
+            created: self.created,
+            identical: self.identical,
+            changed: self.changed,
+            most_overwritten: self.overwrites.top(REPORT_TOP_KEYS),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tracker_classifies_writes() {
+        let mut tracker = ImportTracker::new(8);
+        let value = |s: &str| Value::Str(s.to_string());
+        tracker.record("a", None, &value("1"));
+        tracker.record("a", Some(&value("1")), &value("1"));
+        tracker.record("a", Some(&value("1")), &value("2"));
+        tracker.record("b", None, &value("1"));
+        tracker.record("b", Some(&value("1")), &value("1"));
+
+        let report = tracker.report().unwrap();
+        assert_eq!(
+            report,
+            ImportReport {
+                created: 2,
+                identical: 2,
+                changed: 1,
+                most_overwritten: vec![("a".to_string(), 2), ("b".to_string(), 1)],
+            }
+        );
+    }
+
+    #[test]
+    fn test_disabled_tracker_reports_nothing() {
+        let mut tracker = ImportTracker::default();
+        tracker.record("a", None, &Value::Str("1".to_string()));
+        assert_eq!(tracker.report(), None);
+    }
+}
@@ -0,0 +1,692 @@
+//! Minimal JSON reader and writer for flat string-to-string objects
+//! (LOADJSON and DUMPJSON), and field extraction from JSON values (JGET)
+//!
+//! When reading, only the top level has to be an object. Its string members are returned;
+//! members holding numbers, booleans, null, arrays or objects are validated,
+//! skipped and counted.
+//!
+//! Extraction walks a document along a `JsonPath` such as
+//! `user.addresses[0].city` without building a tree, validating the whole
+//! document on the way.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// The string members of a top-level JSON object, in document order
+#[derive(Debug, Default, PartialEq)]
+pub struct FlatObject {
+    pub pairs: Vec<(String, String)>,
+    /// Members whose value was not a string
+    pub skipped: usize,
+}
+
+/// Malformed JSON, with the byte offset where parsing stopped
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for JsonError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at byte {}", self.message, self.offset)
+    }
+}
+
+/// Parse `input` as a single JSON object, keeping its string members
+pub fn parse_flat_object(input: &str) -> Result<FlatObject, JsonError> {
+    let mut parser = Parser {
+        bytes: input.as_bytes(),
+        pos: 0,
+    };
+    let mut object = FlatObject::default();
+
+    parser.skip_whitespace();
+    parser.expect(b'{')?;
+    parser.skip_whitespace();
+    if parser.peek() == Some(b'}') {
+        parser.pos += 1;
+    } else {
+        loop {
+            parser.skip_whitespace();
+            let key = parser.parse_string()?;
+            parser.skip_whitespace();
+            parser.expect(b':')?;
+            parser.skip_whitespace();
+            if parser.peek() == Some(b'"') {
+                object.pairs.push((key, parser.parse_string()?));
+            } else {
+                parser.skip_value()?;
+                object.skipped += 1;
+            }
+            parser.skip_whitespace();
+            match parser.peek() {
+                Some(b',') => parser.pos += 1,
+                Some(b'}') => {
+                    parser.pos += 1;
+                    break;
+                }
+                _ => return Err(parser.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    parser.skip_whitespace();
+    if parser.pos < parser.bytes.len() {
+        return Err(parser.error("trailing characters after object"));
+    }
+    Ok(object)
+}
+
+/// Render `pairs` as a single-line JSON object, in the order given
+pub fn write_flat_object<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+    let mut out = String::from("{");
+    for (i, (key, value)) in pairs.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_string(&mut out, key);
+        out.push(':');
+        write_string(&mut out, value);
+    }
+    out.push('}');
+    out
+}
+
+/// One step of a `JsonPath`
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    /// An object member, by name
+    Member(String),
+    /// An array element, by zero-based index
+    Index(usize),
+}
+
+/// A dotted/bracket path into a JSON document, such as
+/// `user.addresses[0].city`
+///
+/// A backslash makes the next character part of the member name, so
+/// `a\.b` names the single member `a.b`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath {
+    pub segments: Vec<PathSegment>,
+}
+
+impl FromStr for JsonPath {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        let mut segments = Vec::new();
+        let mut chars = raw.chars().peekable();
+        // Whether the next segment may be a bare member name: at the start
+        // and after a '.', but not straight after an index
+        let mut expect_member = true;
+
+        while let Some(&c) = chars.peek() {
+            if c == '[' {
+                if expect_member && !segments.is_empty() {
+                    return Err(format!("Invalid path '{}': empty member name", raw));
+                }
+                chars.next();
+                let mut digits = String::new();
+                while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                    digits.push(digit);
+                }
+                if chars.next() != Some(']') || digits.is_empty() {
+                    return Err(format!(
+                        "Invalid path '{}': '[' must hold an array index and be closed by ']'",
+                        raw
+                    ));
+                }
+                let index = digits.parse().map_err(|_| {
+                    format!("Invalid path '{}': index {} is too large", raw, digits)
+                })?;
+                segments.push(PathSegment::Index(index));
+                expect_member = false;
+            } else if c == '.' && !expect_member {
+                chars.next();
+                expect_member = true;
+            } else if expect_member {
+                let mut name = String::new();
+                while let Some(&c) = chars.peek() {
+                    match c {
+                        '.' | '[' => break,
+                        ']' => return Err(format!("Invalid path '{}': unmatched ']'", raw)),
+                        '\\' => {
+                            chars.next();
+                            name.push(chars.next().ok_or_else(|| {
+                                format!("Invalid path '{}': trailing backslash", raw)
+                            })?);
+                            continue;
+                        }
+                        c => name.push(c),
+                    }
+                    chars.next();
+                }
+                if name.is_empty() {
+                    return Err(format!("Invalid path '{}': empty member name", raw));
+                }
+                segments.push(PathSegment::Member(name));
+                expect_member = false;
+            } else {
+                return Err(format!(
+                    "Invalid path '{}': expected '.' or '[' after an index",
+                    raw
+                ));
+            }
+        }
+
+        if segments.is_empty() || expect_member {
+            return Err(format!("Invalid path '{}': empty member name", raw));
+        }
+        Ok(Self { segments })
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, segment) in self.segments.iter().enumerate() {
+            match segment {
+                PathSegment::Member(name) => {
+                    if i > 0 {
+                        write!(f, ".")?;
+                    }
+                    for c in name.chars() {
+                        if matches!(c, '.' | '[' | ']' | '\\') {
+                            write!(f, "\\")?;
+                        }
+                        write!(f, "{}", c)?;
+                    }
+                }
+                PathSegment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why a field couldn't be extracted from a document
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExtractError {
+    /// The document isn't valid JSON
+    NotJson(JsonError),
+    /// The document is valid but has nothing at the path
+    PathNotFound,
+}
+
+/// The value at `path` in the JSON document `input`
+///
+/// Strings are returned unquoted and unescaped; numbers, literals, arrays
+/// and objects are returned as their JSON text. If an object repeats a
+/// member name, the last one wins.
+pub fn extract(input: &str, path: &JsonPath) -> Result<String, ExtractError> {
+    let mut parser = Parser {
+        bytes: input.as_bytes(),
+        pos: 0,
+    };
+    parser.skip_whitespace();
+    let found = parser
+        .find(Some(&path.segments))
+        .map_err(ExtractError::NotJson)?;
+    parser.skip_whitespace();
+    if parser.pos < parser.bytes.len() {
+        return Err(ExtractError::NotJson(
+            parser.error("trailing characters after value"),
+        ));
+    }
+
+    let (start, end) = found.ok_or(ExtractError::PathNotFound)?;
+    let text = &input[start..end];
+    if text.starts_with('"') {
+        let mut value = Parser {
+            bytes: text.as_bytes(),
+            pos: 0,
+        };
+        // Already validated while walking the document
+        value.parse_string().map_err(ExtractError::NotJson)
+    } else {
+        Ok(text.to_string())
+    }
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Parser<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.bytes.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn error(&self, message: &str) -> JsonError {
+        JsonError {
+            offset: self.pos,
+            message: message.to_string(),
+        }
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<(), JsonError> {
+        if self.peek() == Some(expected) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", expected as char)))
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, JsonError> {
+        self.expect(b'"')?;
+        let mut out = String::new();
+        loop {
+            // Copy the run up to the next quote or escape in one go; both
+            // are ASCII, so the run always ends on a char boundary
+            let start = self.pos;
+            while !matches!(self.peek(), Some(b'"' | b'\\') | None) {
+                if self.bytes[self.pos] < 0x20 {
+                    return Err(self.error("control character in string"));
+                }
+                self.pos += 1;
+            }
+            // The input came from a &str, so the run is valid UTF-8
+            out.push_str(std::str::from_utf8(&self.bytes[start..self.pos]).unwrap());
+
+            match self.next() {
+                Some(b'"') => return Ok(out),
+                Some(b'\\') => out.push(self.parse_escape()?),
+                _ => return Err(self.error("unterminated string")),
+            }
+        }
+    }
+
+    fn parse_escape(&mut self) -> Result<char, JsonError> {
+        let c = match self.next() {
+            Some(b'"') => '"',
+            Some(b'\\') => '\\',
+            Some(b'/') => '/',
+            Some(b'b') => '\u{8}',
+            Some(b'f') => '\u{c}',
+            Some(b'n') => '\n',
+            Some(b'r') => '\r',
+            Some(b't') => '\t',
+            Some(b'u') => {
+                let unit = self.parse_hex4()?;
+                let code = if (0xD800..0xDC00).contains(&unit) {
+                    // High surrogate: must be followed by an escaped low one
+                    if self.next() != Some(b'\\') || self.next() != Some(b'u') {
+                        return Err(self.error("unpaired surrogate"));
+                    }
+                    let low = self.parse_hex4()?;
+                    if !(0xDC00..0xE000).contains(&low) {
+                        return Err(self.error("unpaired surrogate"));
+                    }
+                    0x10000 + ((unit - 0xD800) << 10) + (low - 0xDC00)
+                } else {
+                    unit
+                };
+                return char::from_u32(code).ok_or_else(|| self.error("unpaired surrogate"));
+            }
+            _ => return Err(self.error("invalid escape")),
+        };
+        Ok(c)
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, JsonError> {
+        let digits = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+
+    /// Consume one JSON value, returning the byte span of whatever `path`
+    /// leads to inside it (the value itself for an empty path)
+    ///
+    /// `None` for the path means nothing inside is wanted; the value is only
+    /// validated.
+    fn find(&mut self, path: Option<&[PathSegment]>) -> Result<Option<(usize, usize)>, JsonError> {
+        let start = self.pos;
+        let (step, rest) = match path {
+            None => return self.skip_value().map(|()| None),
+            Some([]) => {
+                self.skip_value()?;
+                return Ok(Some((start, self.pos)));
+            }
+            Some([step, rest @ ..]) => (step, rest),
+        };
+
+        let (open, close) = match (self.peek(), step) {
+            (Some(b'{'), PathSegment::Member(_)) => (b'{', b'}'),
+            (Some(b'['), PathSegment::Index(_)) => (b'[', b']'),
+            // Valid or not, nothing in this value matches the step
+            _ => return self.skip_value().map(|()| None),
+        };
+        self.pos += 1;
+        self.skip_whitespace();
+        if self.peek() == Some(close) {
+            self.pos += 1;
+            return Ok(None);
+        }
+
+        let mut found = None;
+        let mut index = 0;
+        loop {
+            self.skip_whitespace();
+            let wanted = if open == b'{' {
+                let name = self.parse_string()?;
+                self.skip_whitespace();
+                self.expect(b':')?;
+                self.skip_whitespace();
+                *step == PathSegment::Member(name)
+            } else {
+                index += 1;
+                *step == PathSegment::Index(index - 1)
+            };
+            match self.find(wanted.then_some(rest))? {
+                Some(span) => found = Some(span),
+                // A later duplicate member that lacks the rest of the path
+                // still replaces the earlier one
+                None if wanted => found = None,
+                None => {}
+            }
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    return Ok(found);
+                }
+                _ => return Err(self.error("expected ',' or closing bracket")),
+            }
+        }
+    }
+
+    /// Consume any JSON value without keeping it
+    fn skip_value(&mut self) -> Result<(), JsonError> {
+        match self.peek() {
+            Some(b'"') => self.parse_string().map(drop),
+            Some(open @ (b'{' | b'[')) => {
+                let close = if open == b'{' { b'}' } else { b']' };
+                self.pos += 1;
+                self.skip_whitespace();
+                if self.peek() == Some(close) {
+                    self.pos += 1;
+                    return Ok(());
+                }
+                loop {
+                    self.skip_whitespace();
+                    if open == b'{' {
+                        self.parse_string()?;
+                        self.skip_whitespace();
+                        self.expect(b':')?;
+                        self.skip_whitespace();
+                    }
+                    self.skip_value()?;
+                    self.skip_whitespace();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(c) if c == close => {
+                            self.pos += 1;
+                            return Ok(());
+                        }
+                        _ => return Err(self.error("expected ',' or closing bracket")),
+                    }
+                }
+            }
+            Some(b't') => self.skip_literal("true"),
+            Some(b'f') => self.skip_literal("false"),
+            Some(b'n') => self.skip_literal("null"),
+            Some(b'-' | b'0'..=b'9') => self.skip_number(),
+            _ => Err(self.error("expected a value")),
+        }
+    }
+
+    fn skip_literal(&mut self, literal: &str) -> Result<(), JsonError> {
+        if self.bytes[self.pos..].starts_with(literal.as_bytes()) {
+            self.pos += literal.len();
+            Ok(())
+        } else {
+            Err(self.error("invalid literal"))
+        }
+    }
+
+    fn skip_number(&mut self) -> Result<(), JsonError> {
+        let start = self.pos;
+        while matches!(
+            self.peek(),
+            Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9')
+        ) {
+            self.pos += 1;
+        }
+        // Let the float parser judge the JSON number grammar
+        std::str::from_utf8(&self.bytes[start..self.pos])
+            .ok()
+            .and_then(|number| number.parse::<f64>().ok())
+            .map(drop)
+            .ok_or_else(|| JsonError {
+                offset: start,
+                message: "invalid number".to_string(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(object: &FlatObject) -> Vec<(&str, &str)> {
+        object
+            .pairs
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_flat_object() {
+        let object = parse_flat_object(r#" { "host": "db1", "port" : "5432" } "#).unwrap();
+        assert_eq!(pairs(&object), vec![("host", "db1"), ("port", "5432")]);
+        assert_eq!(object.skipped, 0);
+
+        assert_eq!(parse_flat_object("{}").unwrap(), FlatObject::default());
+    }
+
+    #[test]
+    fn test_non_string_values_are_skipped() {
+        let object = parse_flat_object(
+            r#"{"a": "1", "n": -1.5e3, "t": true, "z": null,
+                "nested": {"x": ["y", {"z": 1}]}, "list": [], "b": "2"}"#,
+        )
+        .unwrap();
+        assert_eq!(pairs(&object), vec![("a", "1"), ("b", "2")]);
+        assert_eq!(object.skipped, 5);
+    }
+
+    #[test]
+    fn test_string_escapes() {
+        let object =
+            parse_flat_object(r#"{"k\"ey": "line\nbreak \u00e9 \ud83d\ude00 \/"}"#).unwrap();
+        assert_eq!(pairs(&object), vec![("k\"ey", "line\nbreak é 😀 /")]);
+    }
+
+    #[test]
+    fn test_write_escapes_and_round_trips() {
+        let members = [
+            ("plain", "value"),
+            ("quote\"key", "back\\slash"),
+            ("ctrl", "tab\tnew\nline\u{1}bell\u{1f}"),
+            ("unicode", "é 😀"),
+        ];
+        let json = write_flat_object(members);
+        assert_eq!(
+            json,
+            r#"{"plain":"value","quote\"key":"back\\slash","ctrl":"tab\tnew\nline\u0001bell\u001f","unicode":"é 😀"}"#
+        );
+
+        let object = parse_flat_object(&json).unwrap();
+        assert_eq!(pairs(&object), members.to_vec());
+        assert_eq!(write_flat_object([]), "{}");
+    }
+
+    const ACCOUNT: &str = r#"{
+        "user": {
+            "name": "Ada \"Countess\" Lovelace",
+            "addresses": [
+                {"city": "London", "zip": null},
+                {"city": "Marylebone", "tags": ["home", 2]}
+            ],
+            "a.b": {"c[0]": true},
+            "age": 36,
+            "empty": {}
+        },
+        "dup": 1,
+        "dup": {"x": 2}
+    }"#;
+
+    fn jget(input: &str, path: &str) -> Result<String, ExtractError> {
+        extract(input, &path.parse().unwrap())
+    }
+
+    #[test]
+    fn test_extract_nested_fields() {
+        let cases = [
+            ("user.name", r#"Ada "Countess" Lovelace"#),
+            ("user.addresses[0].city", "London"),
+            ("user.addresses[0].zip", "null"),
+            ("user.addresses[1].tags[1]", "2"),
+            ("user.addresses[1].tags", r#"["home", 2]"#),
+            (r"user.a\.b.c\[0\]", "true"),
+            ("user.age", "36"),
+            ("user.empty", "{}"),
+            ("dup.x", "2"),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(jget(ACCOUNT, path).as_deref(), Ok(expected), "{}", path);
+        }
+        assert_eq!(jget("[[1, 2], [3]]", "[1][0]").as_deref(), Ok("3"));
+    }
+
+    #[test]
+    fn test_extract_missing_paths() {
+        for path in [
+            "user.nickname",
+            "user.addresses[2]",
+            "user.addresses.city",
+            "user.age[0]",
+            "user.name.first",
+            "user.empty.x",
+            "dup.y",
+            "[0]",
+        ] {
+            assert_eq!(
+                jget(ACCOUNT, path),
+                Err(ExtractError::PathNotFound),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_rejects_invalid_documents() {
+        // Malformed content after the field still makes the value invalid
+        for input in [
+            "plain text",
+            "",
+            r#"{"a": 1} {"b": 2}"#,
+            r#"{"a": 1, "b": [1,}"#,
+            r#"{"a": 1"#,
+        ] {
+            assert!(
+                matches!(jget(input, "a"), Err(ExtractError::NotJson(_))),
+                "{:?}",
+                input
+            );
+        }
+    }
+
+    #[test]
+    fn test_path_parsing() {
+        let path: JsonPath = r"user.addresses[0].a\.b\\c".parse().unwrap();
+        assert_eq!(
+            path.segments,
+            [
+                PathSegment::Member("user".to_string()),
+                PathSegment::Member("addresses".to_string()),
+                PathSegment::Index(0),
+                PathSegment::Member(r"a.b\c".to_string()),
+            ]
+        );
+        assert_eq!(path.to_string(), r"user.addresses[0].a\.b\\c");
+        assert_eq!("[3][1]".parse::<JsonPath>().unwrap().to_string(), "[3][1]");
+
+        for raw in [
+            "",
+            ".",
+            "a.",
+            ".a",
+            "a..b",
+            "a.[0]",
+            "a[]",
+            "a[x]",
+            "a[0",
+            "a]",
+            "a[0]b",
+            "a\\",
+            "a[99999999999999999999999]",
+        ] {
+            assert!(raw.parse::<JsonPath>().is_err(), "{:?}", raw);
+        }
+    }
+
+    #[test]
+    fn test_malformed_input() {
+        for input in [
+            "",
+            "[]",
+            r#"{"a": "1",}"#,
+            r#"{"a" "1"}"#,
+            r#"{"a": "1"} extra"#,
+            r#"{"a": "unterminated}"#,
+            r#"{"a": tru}"#,
+            r#"{"a": "\ud83d"}"#,
+            r#"{"a": [1, 2}"#,
+        ] {
+            assert!(parse_flat_object(input).is_err(), "{:?}", input);
+        }
+
+        let err = parse_flat_object(r#"{"a": 1x}"#).unwrap_err();
+        assert_eq!(err.to_string(), "expected ',' or '}' at byte 7");
+    }
+}
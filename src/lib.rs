@@ -0,0 +1,43 @@
+//! Multi-threaded command processor for an in-memory key-value store
+//!
+//! IO threads parse raw command lines pulled from a shared work-stealing
+//! channel and forward them to a single main thread that owns the store.
+//! `engine::KvEngine` wraps the whole pipeline for embedding; the individual
+//! pieces are public for callers who want to wire them up themselves.
+
+pub mod access;
+pub mod breaker;
+pub mod cli;
+pub mod cluster;
+mod commands;
+pub mod config;
+pub mod crc32;
+pub mod custom;
+pub mod engine;
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod filter;
+pub mod glob;
+pub mod handler;
+pub mod history;
+pub mod hotkeys;
+pub mod idempotency;
+pub mod import_report;
+pub mod input;
+pub mod json;
+pub mod macros;
+pub mod ordering;
+pub mod output;
+pub mod parser;
+pub mod procedure;
+pub mod processor;
+pub mod quota;
+pub mod read_snapshot;
+pub mod record;
+pub mod selftest;
+pub mod snapshot;
+pub mod thread;
+pub mod transform;
+pub mod validate;
+pub mod version;
@@ -0,0 +1,401 @@
+//! User-defined command macros (`MACRO_<NAME>=<template>`)
+//!
+//! A macro gives a command line template a keyword of its own. IO threads
+//! expand a line whose keyword names a macro before parsing it, putting the
+//! line's arguments in place of `$1`, `$2`, ... in the template. Expansion
+//! happens once, so a template can't call another macro.
+//!
+//! Every macro is checked when the config is read: its name can't shadow a
+//! built-in command, its references must run from `$1` without gaps, and
+//! its template must parse with `1` standing in for each argument.
+
+use std::collections::HashMap;
+
+use crate::commands;
+use crate::parser::{Command, ParseOptions};
+
+/// Environment variable prefix of a macro; the rest of the name is its keyword
+pub const ENV_PREFIX: &str = "MACRO_";
+
+/// Argument every reference is replaced with when checking a template
+const PLACEHOLDER_ARG: &str = "1";
+
+/// A run of template text, or a reference to an argument
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Text(String),
+    /// Zero-based index of the argument (`$1` is 0)
+    Arg(usize),
+}
+
+/// A command line with `$1`, `$2`, ... standing for arguments, shared by
+/// macros and stored procedures
+///
+/// Errors don't say where the template came from; callers prefix that.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    text: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err("empty template".to_string());
+        }
+
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = text.char_indices();
+        while let Some((at, c)) = chars.next() {
+            let digits = if c == '$' { &text[at + 1..] } else { "" };
+            let len = digits.bytes().take_while(u8::is_ascii_digit).count();
+            if len == 0 {
+                literal.push(c);
+                continue;
+            }
+            let index: usize = digits[..len]
+                .parse()
+                .map_err(|_| format!("unknown positional reference ${}", &digits[..len]))?;
+            if index == 0 {
+                return Err("unknown positional reference $0 (arguments start at $1)".to_string());
+            }
+            for _ in 0..len {
+                chars.next();
+            }
+            if !literal.is_empty() {
+                parts.push(Part::Text(std::mem::take(&mut literal)));
+            }
+            parts.push(Part::Arg(index - 1));
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Text(literal));
+        }
+        Ok(Self {
+            text: text.to_string(),
+            parts,
+        })
+    }
+
+    /// The highest argument the template refers to
+    pub fn arity(&self) -> usize {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Arg(index) => Some(index + 1),
+                Part::Text(_) => None,
+            })
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// The template with `args` in place of its references
+    ///
+    /// Panics if there are fewer than `arity` arguments.
+    pub fn substitute(&self, args: &[&str]) -> String {
+        let mut line = String::with_capacity(self.text.len());
+        for part in &self.parts {
+            match part {
+                Part::Text(text) => line.push_str(text),
+                Part::Arg(index) => line.push_str(args[*index]),
+            }
+        }
+        line
+    }
+
+    /// The template as written
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    /// The keyword the template starts with
+    pub fn keyword(&self) -> &str {
+        self.text.split_whitespace().next().unwrap_or("")
+    }
+
+    /// Check the template parses as a command with `1` for each of `arity`
+    /// arguments, returning that command
+    pub fn check_parses(&self, arity: usize, options: ParseOptions<'_>) -> Result<Command, String> {
+        let line = self.substitute(&vec![PLACEHOLDER_ARG; arity]);
+        Command::parse_with(&line, options)
+            .map_err(|err| format!("'{}' doesn't parse: {}", line, err))
+    }
+}
+
+/// The number of arguments `templates` take together, checking their
+/// references run from `$1` without gaps
+pub fn arity_of(templates: &[Template]) -> Result<usize, String> {
+    let arity = templates.iter().map(Template::arity).max().unwrap_or(0);
+    let used = |index: usize| {
+        templates
+            .iter()
+            .any(|template| template.parts.contains(&Part::Arg(index)))
+    };
+    match (0..arity).find(|&index| !used(index)) {
+        Some(unused) => Err(format!(
+            "unknown positional reference ${} (${} is never used)",
+            arity,
+            unused + 1
+        )),
+        None => Ok(arity),
+    }
+}
+
+/// One compiled macro
+#[derive(Debug, Clone, PartialEq)]
+pub struct Macro {
+    template: Template,
+    /// Arguments a line must pass: the highest reference in the template
+    arity: usize,
+}
+
+impl Macro {
+    fn compile(name: &str, template: &str) -> Result<Self, String> {
+        let template =
+            Template::parse(template).map_err(|err| format!("{}{}: {}", ENV_PREFIX, name, err))?;
+        let arity = arity_of(std::slice::from_ref(&template))
+            .map_err(|err| format!("{}{}: {}", ENV_PREFIX, name, err))?;
+        Ok(Self { template, arity })
+    }
+
+    /// The template as written in the environment
+    pub fn template(&self) -> &str {
+        self.template.as_str()
+    }
+}
+
+/// Every macro, by keyword
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Macros {
+    by_name: HashMap<String, Macro>,
+}
+
+impl Macros {
+    /// Compile and check macros from `(name, template)` pairs
+    pub fn compile<'a>(
+        definitions: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self, String> {
+        let mut by_name = HashMap::new();
+        for (name, template) in definitions {
+            if name.is_empty() || !name.bytes().all(|b| b.is_ascii_uppercase() || b == b'_') {
+                return Err(format!(
+                    "{}{}: invalid macro name, expected upper-case letters and underscores",
+                    ENV_PREFIX, name
+                ));
+            }
+            // ID would be read as an idempotency clause, never as a keyword
+            if commands::lookup(name).is_some() || name == "ID" {
+                return Err(format!(
+                    "{}{}: {} is a built-in command",
+                    ENV_PREFIX, name, name
+                ));
+            }
+            by_name.insert(name.to_string(), Macro::compile(name, template)?);
+        }
+        let macros = Self { by_name };
+
+        for name in macros.names() {
+            let definition = &macros.by_name[name];
+            let keyword = definition.template.keyword();
+            if macros.by_name.contains_key(keyword) {
+                return Err(format!(
+                    "{}{}: calls macro {}, but macros can't call other macros",
+                    ENV_PREFIX, name, keyword
+                ));
+            }
+            definition
+                .template
+                .check_parses(definition.arity, ParseOptions::default())
+                .map_err(|err| format!("{}{}: {}", ENV_PREFIX, name, err))?;
+        }
+        Ok(macros)
+    }
+
+    /// Compile every `MACRO_<NAME>` variable in `vars`
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self, String> {
+        let definitions: Vec<(String, String)> = vars
+            .into_iter()
+            .filter_map(|(name, template)| {
+                Some((name.strip_prefix(ENV_PREFIX)?.to_string(), template))
+            })
+            .collect();
+        Self::compile(
+            definitions
+                .iter()
+                .map(|(name, template)| (name.as_str(), template.as_str())),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Macro keywords, sorted
+    pub fn names(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.by_name.keys().map(String::as_str).collect();
+        names.sort_unstable();
+        names
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Macro> {
+        self.by_name.get(name)
+    }
+
+    /// Expand `line` if its keyword names a macro, or `Ok(None)` if it doesn't
+    ///
+    /// With `id_clause` set, a trailing `ID <token>` clause isn't an
+    /// argument; it is carried over to the end of the expanded line. A line
+    /// passing the wrong number of arguments is an error naming the macro.
+    pub fn expand(&self, line: &str, id_clause: bool) -> Result<Option<String>, String> {
+        if self.by_name.is_empty() {
+            return Ok(None);
+        }
+        let mut tokens = line.split_whitespace();
+        let Some(definition) = tokens
+            .next()
+            .and_then(|keyword| self.by_name.get_key_value(keyword))
+        else {
+            return Ok(None);
+        };
+        let (name, definition) = definition;
+
+        let tokens: Vec<&str> = tokens.collect();
+        let (args, id_clause) = match tokens.as_slice() {
+            [args @ .., "ID", token] if id_clause => (args, Some(*token)),
+            args => (args, None),
+        };
+        if args.len() != definition.arity {
+            return Err(format!(
+                "Macro {} expects {} argument{}, got {}",
+                name,
+                definition.arity,
+                if definition.arity == 1 { "" } else { "s" },
+                args.len()
+            ));
+        }
+
+        let mut expanded = definition.template.substitute(args);
+        if let Some(token) = id_clause {
+            expanded.push_str(" ID ");
+            expanded.push_str(token);
+        }
+        Ok(Some(expanded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn macros(definitions: &[(&str, &str)]) -> Macros {
+        Macros::compile(definitions.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn test_expand_substitutes_positional_arguments() {
+        let macros = macros(&[
+            ("SETJ", "SET $1 $2 EX 3600"),
+            ("SWAP", "LMOVE $2 $1 LEFT RIGHT"),
+            ("TAG", "SET tag:$1 $1-$2"),
+            ("USERS", "SCAN 0 COUNT 5"),
+        ]);
+        let cases = [
+            ("SETJ session:9 tok", "SET session:9 tok EX 3600"),
+            ("SWAP a b", "LMOVE b a LEFT RIGHT"),
+            ("TAG x 1", "SET tag:x x-1"),
+            ("USERS", "SCAN 0 COUNT 5"),
+            ("SETJ  k   v  ID req-1", "SET k v EX 3600 ID req-1"),
+        ];
+        for (line, expected) in cases {
+            assert_eq!(
+                macros.expand(line, true),
+                Ok(Some(expected.to_string())),
+                "{}",
+                line
+            );
+        }
+
+        // Lines that don't start with a macro are left to the parser
+        assert_eq!(macros.expand("SET k v", true), Ok(None));
+        assert_eq!(macros.expand("setj k v", true), Ok(None));
+        assert_eq!(Macros::default().expand("SETJ k v", true), Ok(None));
+    }
+
+    #[test]
+    fn test_expand_rejects_arity_mismatch() {
+        let macros = macros(&[("SETJ", "SET $1 $2 EX 3600"), ("ONE", "GET $1")]);
+        assert_eq!(
+            macros.expand("SETJ session:9", true),
+            Err("Macro SETJ expects 2 arguments, got 1".to_string())
+        );
+        assert_eq!(
+            macros.expand("SETJ a b c", true),
+            Err("Macro SETJ expects 2 arguments, got 3".to_string())
+        );
+        assert_eq!(
+            macros.expand("ONE ID req-1", true),
+            Err("Macro ONE expects 1 argument, got 0".to_string())
+        );
+        // Without idempotency, ID is an argument like any other
+        assert_eq!(
+            macros.expand("ONE ID", false),
+            Ok(Some("GET ID".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_compile_rejects_bad_macros() {
+        let cases = [
+            (("GET", "GET $1"), "MACRO_GET: GET is a built-in command"),
+            (("DUMP", "INFO"), "MACRO_DUMP: DUMP is a built-in command"),
+            (("ID", "INFO"), "MACRO_ID: ID is a built-in command"),
+            (
+                ("setj", "SET $1 $2"),
+                "MACRO_setj: invalid macro name, expected upper-case letters and underscores",
+            ),
+            (("EMPTY", "  "), "MACRO_EMPTY: empty template"),
+            (
+                ("ZERO", "GET $0"),
+                "MACRO_ZERO: unknown positional reference $0 (arguments start at $1)",
+            ),
+            (
+                ("GAP", "SET $1 $3"),
+                "MACRO_GAP: unknown positional reference $3 ($2 is never used)",
+            ),
+            (
+                ("BAD", "INCRBOUNDED $1 many"),
+                "MACRO_BAD: 'INCRBOUNDED 1 many' doesn't parse: Invalid max 'many': expected an integer",
+            ),
+        ];
+        for ((name, template), expected) in cases {
+            assert_eq!(
+                Macros::compile([(name, template)]),
+                Err(expected.to_string()),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_macros_cannot_call_macros() {
+        let err = Macros::compile([("INNER", "GET $1"), ("OUTER", "INNER $1")]).unwrap_err();
+        assert_eq!(
+            err,
+            "MACRO_OUTER: calls macro INNER, but macros can't call other macros"
+        );
+    }
+
+    #[test]
+    fn test_from_vars_reads_only_macro_variables() {
+        let macros = Macros::from_vars([
+            ("MACRO_SETJ".to_string(), "SET $1 $2 EX 3600".to_string()),
+            ("MACRO_TOP".to_string(), "HOTKEYS 3".to_string()),
+            ("VALIDATE_n:*".to_string(), "integer".to_string()),
+        ])
+        .unwrap();
+        assert_eq!(macros.names(), ["SETJ", "TOP"]);
+        assert_eq!(macros.get("TOP").unwrap().template(), "HOTKEYS 3");
+    }
+}
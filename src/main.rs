@@ -1,30 +1,169 @@
-mod config;
-mod handler;
-mod parser;
-mod thread;
-
-use std::{
-    fs::File,
-    io::{BufRead, BufReader},
-    time::Instant,
+use std::{path::PathBuf, sync::Arc, time::Instant};
+
+use multi_threader::cli::{CliOptions, Mode};
+use multi_threader::config::Config;
+use multi_threader::events::{self, Event, EventLog, FileEventSink};
+use multi_threader::input::{
+    self, ChainedLines, ConsecutiveDedup, FileLines, ProducerPacer, StreamingLines,
 };
+use multi_threader::output::OutputSinks;
+use multi_threader::record::{self, Recorder};
+use multi_threader::selftest;
+use multi_threader::snapshot;
+use multi_threader::status;
+use multi_threader::thread;
+use multi_threader::version;
+
+/// Exit code for a run whose output could not all be written
+const EXIT_OUTPUT_TRUNCATED: i32 = 3;
+
+/// Exit code for a run whose main thread panicked, leaving the rest of the
+/// input unapplied
+const EXIT_MAIN_THREAD_PANICKED: i32 = 4;
 
-use config::Config;
+/// Exit code for a run stopped early because too much of its input failed
+/// to parse (`ABORT_IF_PARSE_ERROR_RATE`)
+const EXIT_PARSE_ERROR_ABORT: i32 = 5;
 
 fn main() {
+    let options = match CliOptions::parse(std::env::args().skip(1)) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(2);
+        }
+    };
+
+    if options.mode == Mode::Version {
+        println!("{}", version::banner());
+        return;
+    }
+
+    // Inspecting a snapshot runs nothing else
+    if let Mode::Inspect { path, keys, get } = &options.mode {
+        let result = snapshot::inspect(path, keys.as_deref(), get.as_deref(), |line| {
+            status!("{}", line)
+        });
+        if let Err(err) = result {
+            eprintln!("Error: {}", err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // So does the selftest, which builds its own pool and ignores the environment
+    if options.mode == Mode::Selftest {
+        let report = selftest::run_selftest();
+        status!("\n{}", report);
+        std::process::exit(if report.passed() { 0 } else { 1 });
+    }
+
+    // Open output destinations up front so a bad path fails before any work
+    let output = match OutputSinks::open(&options.output) {
+        Ok(output) => output,
+        Err(err) => {
+            eprintln!("Error: {}", err);
+            std::process::exit(2);
+        }
+    };
+
     let start_time = Instant::now();
-    println!("Starting multi-threaded command processor...\n");
+    status!("{}", version::banner());
+    status!("Starting multi-threaded command processor...\n");
 
     // Load configuration from environment variables
     let config = Config::from_env();
     config.print_config();
+    if let Some(err) = config
+        .validation_error
+        .as_ref()
+        .or(config.quota_error.as_ref())
+        .or(config.macro_error.as_ref())
+        .or(config.procedures_error.as_ref())
+    {
+        eprintln!("Error: {}", err);
+        std::process::exit(2);
+    }
+    if options.save_on_exit && config.snapshot_path.is_none() {
+        eprintln!("Error: --save-on-exit requires SNAPSHOT_PATH");
+        std::process::exit(2);
+    }
+
+    // A directory run lists its files up front, so a bad path fails before
+    // the pool starts
+    let dir_files = match &options.mode {
+        Mode::Dir { path, glob, .. } => match input::list_dir(path, glob) {
+            Ok(files) => Some((files, Arc::new(FileLines::new()))),
+            Err(err) => {
+                eprintln!("Error: can't list {}: {}", path.display(), err);
+                std::process::exit(2);
+            }
+        },
+        _ => None,
+    };
 
     // Create thread pool with 4 IO threads
     let num_io_threads = 4;
+
+    // Structured events start with the configuration the run will use
+    let events = match &options.events {
+        Some(path) => match FileEventSink::create(path) {
+            Ok(sink) => EventLog::start(Box::new(sink), events::EVENT_QUEUE_CAPACITY),
+            Err(err) => {
+                eprintln!(
+                    "Error: can't create events file {}: {}",
+                    path.display(),
+                    err
+                );
+                std::process::exit(2);
+            }
+        },
+        None => EventLog::default(),
+    };
+    let mode = match &options.mode {
+        Mode::Dir { .. } => "dir",
+        Mode::Replay { .. } => "replay",
+        _ => "run",
+    };
+    let mut startup = Event::new("startup");
+    for (name, value) in version::fields(config.snapshot_path.is_some()) {
+        startup = startup.field(name, value);
+    }
+    events.emit(
+        startup
+            .field("mode", mode)
+            .field("io_threads", num_io_threads)
+            .field("distribution", format!("{:?}", config.distribution))
+            .field("per_key_ordering", config.per_key_ordering)
+            .field("dry_run", options.dry_run)
+            .field("save_on_exit", options.save_on_exit),
+    );
+
     let pool_start = Instant::now();
-    let mut pool = thread::ThreadPool::new(num_io_threads, config);
+    let mut builder = thread::ThreadPoolBuilder::new(Arc::clone(&config))
+        .io_threads(num_io_threads)
+        .output(output)
+        .dry_run(options.dry_run)
+        .save_on_exit(options.save_on_exit)
+        .show_trace_ids(options.show_trace_ids)
+        .import_report(options.import_report)
+        .command_filter(options.filter)
+        .events(events.clone());
+    if let Some((_, files)) = &dir_files {
+        builder = builder.file_lines(Arc::clone(files));
+    }
+    if let Some(path) = &options.record {
+        match Recorder::create(path) {
+            Ok(recorder) => {
+                status!("[Main] Recording session to {}", path.display());
+                builder = builder.recorder(recorder);
+            }
+            Err(err) => panic!("Error creating session file {}: {}", path.display(), err),
+        }
+    }
+    let mut pool = builder.build();
     let pool_creation_time = pool_start.elapsed();
-    println!("[Timing] Thread pool created in {:?}\n", pool_creation_time);
+    status!("[Timing] Thread pool created in {:?}\n", pool_creation_time);
 
     // Get the single shared sender for all IO threads
     let sender = pool.get_string_sender();
@@ -32,74 +171,261 @@ fn main() {
     // Start the main processing thread
     let main_handle = pool.start_main_thread();
 
-    // Open and read the input file
+    // Read the input (or recorded session) and distribute it
     let file_read_start = Instant::now();
-    let f = File::open("input.txt");
     let file_read_time;
-    match f {
-        Ok(file) => {
-            let reader = BufReader::new(file);
-            let mut line_num = 0;
+    let mut unreadable_inputs = 0;
+    match &options.mode {
+        Mode::Run => {
+            let default_input = [PathBuf::from("input.txt")];
+            let paths = if config.input_files.is_empty() {
+                &default_input[..]
+            } else {
+                &config.input_files[..]
+            };
+            let mut lines = ChainedLines::new(
+                input::open_all(paths, config.gzip_input),
+                config.line_number_mode,
+            );
+            let mut sent = 0;
+            let mut dedup = config.dedupe_consecutive.then(ConsecutiveDedup::new);
+            let mut pacer = config.producer_rate.map(ProducerPacer::new);
 
             // Send all lines to the shared channel - IO threads will compete for work
-            for line_result in reader.lines() {
-                match line_result {
-                    Ok(line) => {
-                        line_num += 1;
-
-                        // Send raw string to shared channel (work-stealing pattern)
-                        if let Err(e) = sender.send((line, line_num)) {
-                            eprintln!("Failed to send line {} to IO threads: {}", line_num, e);
+            for (line, line_num) in lines.by_ref() {
+                // A SHUTDOWN stops the main thread taking new work
+                if pool.shutdown_requested() {
+                    status!("[Main] Shutdown requested, no more lines will be sent");
+                    break;
+                }
+
+                // Drop exact repeats of the previous line (idempotent loads)
+                if let Some(dedup) = dedup.as_mut()
+                    && !dedup.admit(&line)
+                {
+                    pool.skip_line(line_num);
+                    continue;
+                }
+
+                if let Some(pacer) = pacer.as_mut() {
+                    std::thread::sleep(pacer.delay(Instant::now()));
+                }
+
+                // Send raw string to shared channel (work-stealing pattern)
+                if let Err(e) = sender.send((line, line_num)) {
+                    eprintln!("Failed to send line {} to IO threads: {}", line_num, e);
+                    break;
+                }
+                sent += 1;
+            }
+
+            file_read_time = file_read_start.elapsed();
+            status!("\nSent {} lines to IO threads (work-stealing)", sent);
+            unreadable_inputs = lines.failed();
+            if unreadable_inputs > 0 {
+                status!("Skipped {} unreadable input files", unreadable_inputs);
+            }
+            if let Some(dedup) = &dedup {
+                status!("Skipped {} consecutive duplicate lines", dedup.skipped());
+            }
+            status!(
+                "[Timing] File reading and distribution took {:?}",
+                file_read_time
+            );
+        }
+        Mode::Dir {
+            path, fail_fast, ..
+        } => {
+            let (paths, files) = dir_files.as_ref().expect("listed before the pool started");
+            status!(
+                "[Main] Processing {} files from {}",
+                paths.len(),
+                path.display()
+            );
+            // One numbering across files; output names lines as file:line
+            let mut line_num = 0;
+            let mut dedup = config.dedupe_consecutive.then(ConsecutiveDedup::new);
+            let mut pacer = config.producer_rate.map(ProducerPacer::new);
+            'files: for file in paths {
+                let name = file.file_name().map_or_else(
+                    || file.display().to_string(),
+                    |name| name.to_string_lossy().into_owned(),
+                );
+                let reader = match input::open(file, config.gzip_input) {
+                    Ok(reader) => reader,
+                    Err(err) => {
+                        eprintln!("Error opening input file {}: {}; skipping it", name, err);
+                        unreadable_inputs += 1;
+                        continue;
+                    }
+                };
+                files.begin(name.as_str(), line_num + 1);
+                let hard_errors_before = pool.stats().hard_errors;
+                for line in StreamingLines::new(reader) {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(err) => {
+                            eprintln!("Error reading {}: {}; skipping the rest of it", name, err);
+                            unreadable_inputs += 1;
                             break;
                         }
+                    };
+                    if pool.shutdown_requested() {
+                        status!("[Main] Shutdown requested, no more lines will be sent");
+                        break 'files;
                     }
-                    Err(err) => {
-                        eprintln!("Error reading line: {}", err);
-                        break;
+                    line_num += 1;
+                    if let Some(dedup) = dedup.as_mut()
+                        && !dedup.admit(&line)
+                    {
+                        pool.skip_line(line_num);
+                        continue;
+                    }
+                    if let Some(pacer) = pacer.as_mut() {
+                        std::thread::sleep(pacer.delay(Instant::now()));
                     }
+                    if let Err(e) = sender.send((line, line_num)) {
+                        eprintln!("Failed to send line {} to IO threads: {}", line_num, e);
+                        break 'files;
+                    }
+                }
+
+                // Each file is finished before the next starts
+                if !pool.wait_for_lines(line_num, &main_handle) {
+                    break;
+                }
+                let hard_errors = pool.stats().hard_errors - hard_errors_before;
+                if *fail_fast && hard_errors > 0 {
+                    eprintln!(
+                        "Error: {} had {} hard errors; skipping the remaining files (--fail-fast)",
+                        name, hard_errors
+                    );
+                    break;
                 }
             }
 
             file_read_time = file_read_start.elapsed();
-            println!("\nSent {} lines to IO threads (work-stealing)", line_num);
-            println!(
+            status!(
+                "\nSent {} lines from {} to IO threads",
+                line_num,
+                path.display()
+            );
+            status!(
                 "[Timing] File reading and distribution took {:?}",
                 file_read_time
             );
         }
-        Err(err) => {
-            panic!("Error opening input file: {}", err);
+        Mode::Replay { path, pacing } => {
+            let mut line_num = 0;
+
+            // Session lines flow through the same channel as file input, paced
+            let result = record::replay(path, *pacing, |line| {
+                line_num += 1;
+                !pool.shutdown_requested() && sender.send((line, line_num)).is_ok()
+            });
+
+            file_read_time = file_read_start.elapsed();
+            match result {
+                Ok(count) => {
+                    status!("\nReplayed {} commands from {}", count, path.display())
+                }
+                Err(err) => eprintln!("Error replaying {}: {}", path.display(), err),
+            }
+            status!("[Timing] Session replay took {:?}", file_read_time);
+        }
+        Mode::Inspect { .. } | Mode::Selftest | Mode::Version => {
+            unreachable!("inspect, selftest, and --version exit before the pool starts")
         }
     }
 
     // Drop sender to signal IO threads that no more input is coming
     drop(sender);
-    println!("[Main] All lines sent, closing input channel\n");
+    status!("[Main] All lines sent, closing input channel\n");
 
     // Shutdown thread pool (this joins all IO threads after they receive disconnect signal)
     let shutdown_start = Instant::now();
-    pool.shutdown();
+    let shutdown_report = pool.shutdown();
     let shutdown_time = shutdown_start.elapsed();
-    println!("[Timing] IO thread shutdown took {:?}\n", shutdown_time);
+    status!("[Timing] IO thread shutdown took {:?}\n", shutdown_time);
 
     // Wait for main thread to finish processing all commands
-    println!("[Main] Waiting for main processing thread to finish...");
+    status!("[Main] Waiting for main processing thread to finish...");
     let processing_wait_start = Instant::now();
-    main_handle.join().expect("Main thread panicked");
+    // The IO threads have been joined above (their sends fail once the main
+    // thread is gone), so a panic here leaves nothing running
+    let summary = match main_handle.join() {
+        Ok(summary) => summary,
+        Err(payload) => {
+            eprintln!(
+                "Error: main processing thread panicked: {}",
+                thread::panic_message(payload.as_ref())
+            );
+            eprintln!(
+                "Commands after the panicking one were not applied and no summary is available"
+            );
+            let _ = events.close();
+            std::process::exit(EXIT_MAIN_THREAD_PANICKED);
+        }
+    };
     let processing_wait_time = processing_wait_start.elapsed();
-    println!("[Main] Main processing thread finished");
-    println!(
+    status!("[Main] Main processing thread finished");
+    if let Err(err) = events.close() {
+        eprintln!("Error writing events: {}", err);
+    }
+    if events.dropped() > 0 {
+        eprintln!(
+            "Warning: dropped {} events because the events file couldn't keep up",
+            events.dropped()
+        );
+    }
+    status!(
         "[Timing] Main thread completion took {:?}",
         processing_wait_time
     );
 
     let total_time = start_time.elapsed();
-    println!("\n=== All processing complete! ===");
-    println!("[Timing] Total execution time: {:?}", total_time);
-    println!("\n--- Timing Breakdown ---");
-    println!("  Pool creation:       {:?}", pool_creation_time);
-    println!("  File reading:        {:?}", file_read_time);
-    println!("  IO thread shutdown:  {:?}", shutdown_time);
-    println!("  Processing wait:     {:?}", processing_wait_time);
-    println!("  Total time:          {:?}", total_time);
+    status!("\n=== All processing complete! ===");
+    status!("[Timing] Total execution time: {:?}", total_time);
+    status!("\n--- Timing Breakdown ---");
+    status!("  Pool creation:       {:?}", pool_creation_time);
+    status!("  File reading:        {:?}", file_read_time);
+    status!("  IO thread shutdown:  {:?}", shutdown_time);
+    status!("  Processing wait:     {:?}", processing_wait_time);
+    status!("  Total time:          {:?}", total_time);
+
+    // Long receive waits with short parse times point at receiver lock
+    // contention; long send waits, at a main thread that can't keep up
+    status!("\n--- IO Thread Breakdown ---");
+    for timing in &shutdown_report.io_thread_timings {
+        status!(
+            "  IO thread {}: {} lines, receive wait {:?}, parse {:?}, send wait {:?}",
+            timing.id,
+            timing.lines,
+            timing.receive_wait,
+            timing.parse,
+            timing.send_wait
+        );
+    }
+
+    // Output that never reached its consumer (say, a `head` that exited)
+    // gets its own exit code, ahead of any command failures
+    if summary.output_truncated {
+        std::process::exit(EXIT_OUTPUT_TRUNCATED);
+    }
+
+    // Input in the wrong format stops the run before its end
+    if summary.parse_error_abort {
+        eprintln!(
+            "Error: stopped early, too many lines failed to parse; {} lines already read were not parsed",
+            summary.unparsed_lines
+        );
+        std::process::exit(EXIT_PARSE_ERROR_ABORT);
+    }
+
+    // Only hard errors fail the run unless SOFT_ERRORS_FAIL is set; a dry
+    // run fails on the errors the real run would hit. An input file that
+    // couldn't be read fails it too, even though the others were processed
+    if summary.is_failure(config.soft_errors_fail) || unreadable_inputs > 0 {
+        std::process::exit(1);
+    }
 }
@@ -0,0 +1,238 @@
+//! Per-key ordering for commands spread across IO threads (`PER_KEY_ORDERING`)
+//!
+//! Each line is stamped with its key's next sequence number at the one point
+//! where input order is still known: under the shared receiver lock, or in
+//! the dispatcher for per-thread channels. The main thread then holds back a
+//! command until every earlier command for the same key has been applied, so
+//! commands for one key apply in input order while commands for different
+//! keys can still overtake each other.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::mem;
+use std::sync::{Arc, Mutex, PoisonError};
+
+use crate::thread::CommandMessage;
+
+/// A line's place among the lines for its key
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeySequence {
+    pub key: String,
+    pub seq: u64,
+}
+
+/// The token a line is ordered and routed by: its first argument, which is
+/// the key for every keyed command (the source key for multi-key commands
+/// such as RENAMENX), or the keyword for keyless commands such as INFO
+pub fn routing_key(line: &str) -> &str {
+    let mut tokens = line.split_whitespace();
+    let keyword = tokens.next().unwrap_or("");
+    tokens.next().unwrap_or(keyword)
+}
+
+/// Hands out per-key sequence numbers, shared by whatever stamps lines and
+/// the main thread
+#[derive(Debug, Default)]
+pub struct KeySequencer {
+    next: Mutex<HashMap<String, u64>>,
+    /// Stamps whose line will never reach the main thread (it failed to
+    /// parse), waiting for the main thread to skip past them
+    abandoned: Mutex<Vec<KeySequence>>,
+}
+
+impl KeySequencer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stamp `line` with its key's next sequence number
+    ///
+    /// Blank lines are never forwarded, so they aren't stamped.
+    pub fn stamp(&self, line: &str) -> Option<KeySequence> {
+        let key = routing_key(line);
+        if key.is_empty() {
+            return None;
+        }
+        let mut next = self.next.lock().unwrap_or_else(PoisonError::into_inner);
+        let counter = next.entry(key.to_string()).or_insert(0);
+        let seq = *counter;
+        *counter += 1;
+        Some(KeySequence {
+            key: key.to_string(),
+            seq,
+        })
+    }
+
+    /// Give up a stamp whose line won't be forwarded, so later lines for the
+    /// key aren't held back waiting for it
+    pub fn abandon(&self, sequence: KeySequence) {
+        self.abandoned
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push(sequence);
+    }
+
+    fn take_abandoned(&self) -> Vec<KeySequence> {
+        mem::take(
+            &mut *self
+                .abandoned
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        )
+    }
+}
+
+/// The main thread's side: releases stamped commands in per-key order
+///
+/// Unstamped commands (direct submissions) are released immediately. The
+/// expected sequence number is kept for every key seen, so memory grows with
+/// the number of distinct keys.
+#[derive(Debug)]
+pub struct KeyOrder {
+    sequencer: Arc<KeySequencer>,
+    expected: HashMap<String, u64>,
+    held: HashMap<String, BTreeMap<u64, CommandMessage>>,
+    /// Abandoned stamps the main thread hasn't reached yet
+    skipped: HashMap<String, HashSet<u64>>,
+}
+
+impl KeyOrder {
+    pub fn new(sequencer: Arc<KeySequencer>) -> Self {
+        Self {
+            sequencer,
+            expected: HashMap::new(),
+            held: HashMap::new(),
+            skipped: HashMap::new(),
+        }
+    }
+
+    /// Take in a received command and return every command that may now be
+    /// applied, in order
+    pub fn admit(&mut self, msg: CommandMessage) -> Vec<CommandMessage> {
+        let mut ready = Vec::new();
+        for abandoned in self.sequencer.take_abandoned() {
+            let key = abandoned.key.clone();
+            self.skipped
+                .entry(abandoned.key)
+                .or_default()
+                .insert(abandoned.seq);
+            self.release(&key, &mut ready);
+        }
+
+        let Some(sequence) = msg.sequence.clone() else {
+            ready.push(msg);
+            return ready;
+        };
+        self.held
+            .entry(sequence.key.clone())
+            .or_default()
+            .insert(sequence.seq, msg);
+        self.release(&sequence.key, &mut ready);
+        ready
+    }
+
+    /// Every command still held back, in per-key order, for when no more
+    /// input can arrive
+    pub fn drain(&mut self) -> Vec<CommandMessage> {
+        self.held
+            .drain()
+            .flat_map(|(_, held)| held.into_values())
+            .collect()
+    }
+
+    /// Number of commands currently held back
+    pub fn held(&self) -> usize {
+        self.held.values().map(BTreeMap::len).sum()
+    }
+
+    fn release(&mut self, key: &str, ready: &mut Vec<CommandMessage>) {
+        let expected = self.expected.entry(key.to_string()).or_insert(0);
+        loop {
+            if let Some(msg) = self.held.get_mut(key).and_then(|h| h.remove(expected)) {
+                ready.push(msg);
+            } else if self
+                .skipped
+                .get_mut(key)
+                .is_some_and(|skipped| skipped.remove(expected))
+            {
+                // That line failed to parse; nothing to apply
+            } else {
+                break;
+            }
+            *expected += 1;
+        }
+        if self.held.get(key).is_some_and(BTreeMap::is_empty) {
+            self.held.remove(key);
+        }
+        if self.skipped.get(key).is_some_and(HashSet::is_empty) {
+            self.skipped.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(sequencer: &KeySequencer, line: &str, line_number: usize) -> CommandMessage {
+        CommandMessage {
+            command: line.parse().unwrap(),
+            line_number,
+            io_thread_id: 0,
+            reply: None,
+            sequence: sequencer.stamp(line),
+            enqueued_at: std::time::Instant::now(),
+            trace_id: None,
+            answer: None,
+        }
+    }
+
+    fn lines(ready: Vec<CommandMessage>) -> Vec<usize> {
+        ready.iter().map(|msg| msg.line_number).collect()
+    }
+
+    #[test]
+    fn test_holds_back_until_predecessor_arrives() {
+        let sequencer = Arc::new(KeySequencer::new());
+        let mut order = KeyOrder::new(Arc::clone(&sequencer));
+        let set_a = message(&sequencer, "SET a 1", 1);
+        let set_b = message(&sequencer, "SET b 1", 2);
+        let get_a = message(&sequencer, "GET a", 3);
+
+        // Line 3 overtook line 1; line 2 is another key and isn't held
+        assert!(order.admit(get_a).is_empty());
+        assert_eq!(lines(order.admit(set_b)), [2]);
+        assert_eq!(order.held(), 1);
+        assert_eq!(lines(order.admit(set_a)), [1, 3]);
+        assert_eq!(order.held(), 0);
+    }
+
+    #[test]
+    fn test_abandoned_stamp_is_skipped() {
+        let sequencer = Arc::new(KeySequencer::new());
+        let mut order = KeyOrder::new(Arc::clone(&sequencer));
+        let broken = sequencer.stamp("LTRIM a oops").unwrap();
+        let get_a = message(&sequencer, "GET a", 2);
+
+        assert!(order.admit(get_a).is_empty());
+        sequencer.abandon(broken);
+        let info = message(&sequencer, "INFO", 3);
+        assert_eq!(lines(order.admit(info)), [2, 3]);
+    }
+
+    #[test]
+    fn test_unstamped_and_drained_commands() {
+        let sequencer = Arc::new(KeySequencer::new());
+        let mut order = KeyOrder::new(Arc::clone(&sequencer));
+        sequencer.stamp("SET a 1");
+        let direct = CommandMessage {
+            sequence: None,
+            ..message(&sequencer, "SET a 2", 2)
+        };
+        let later = message(&sequencer, "SET a 3", 3);
+
+        assert_eq!(lines(order.admit(direct)), [2]);
+        assert!(order.admit(later).is_empty());
+        assert_eq!(lines(order.drain()), [3]);
+        assert!(sequencer.stamp("   ").is_none());
+    }
+}
@@ -0,0 +1,587 @@
+//! Output routing for command responses, handler errors, and the run summary
+//!
+//! Each stream goes to its own destination so operators don't have to
+//! untangle them from the thread chatter afterwards. A destination is either
+//! the process's standard stream (stdout for responses and the summary,
+//! stderr for errors) or a file written through a `BufWriter`.
+//!
+//! On the standard streams lines keep the console format
+//! (`[Line 3 | IO Thread 1] ...`). In files the nondeterministic IO thread id
+//! is dropped and every line starts with `[Line N]`, so output can be joined
+//! back to the input by line number.
+//!
+//! With `OUTPUT_ORDERED` the response and error lines are also emitted in
+//! line-number order: each is buffered until every earlier line has been
+//! reported (as a response, an error, or as producing nothing), so the
+//! output lines up with the input for diffing even when commands finish out
+//! of order.
+//!
+//! A stream that fails to write (stdout piped into a `head` that has exited,
+//! a full disk) is reported once on stderr and dropped from then on; the run
+//! is marked as truncated rather than panicking in the middle of a command.
+//! Progress chatter goes through `status!` for the same reason.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::config::SoftErrorSink;
+use crate::input::FileLines;
+use crate::thread::{CommandFailure, DIRECT_SUBMISSION};
+
+/// `println!` that never panics: with stdout closed, the line is lost
+/// instead of taking the thread down with it
+#[macro_export]
+macro_rules! status {
+    ($($arg:tt)*) => {{
+        use std::io::Write as _;
+        let _ = writeln!(std::io::stdout(), $($arg)*);
+    }};
+}
+
+/// Where each output stream should go; `None` means the standard stream
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct OutputOptions {
+    pub responses: Option<PathBuf>,
+    pub errors: Option<PathBuf>,
+    pub summary: Option<PathBuf>,
+}
+
+/// One output stream
+enum Sink {
+    Stdout,
+    Stderr,
+    File(BufWriter<File>),
+    Discard,
+    /// A stream whose last write failed; nothing more is written to it
+    Failed,
+}
+
+impl Sink {
+    fn open(path: &Option<PathBuf>, flag: &str, std_stream: Sink) -> Result<Self, String> {
+        match path {
+            Some(path) => {
+                let file = File::create(path)
+                    .map_err(|e| format!("Cannot open {} '{}': {}", flag, path.display(), e))?;
+                Ok(Sink::File(BufWriter::new(file)))
+            }
+            None => Ok(std_stream),
+        }
+    }
+
+    fn to_file(&self) -> bool {
+        matches!(self, Sink::File(_))
+    }
+
+    fn write_line(&mut self, text: &str) {
+        let result = match self {
+            Sink::Stdout => writeln!(io::stdout(), "{}", text),
+            Sink::Stderr => writeln!(io::stderr(), "{}", text),
+            Sink::File(writer) => writeln!(writer, "{}", text),
+            Sink::Discard | Sink::Failed => Ok(()),
+        };
+        self.check(result);
+    }
+
+    fn flush(&mut self) {
+        if let Sink::File(writer) = self {
+            let result = writer.flush();
+            self.check(result);
+        }
+    }
+
+    /// Give up on the stream after a failed write; command processing must
+    /// not stop because its output can't be delivered
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(err) = result {
+            let _ = writeln!(
+                io::stderr(),
+                "[Output] Write failed ({}), dropping further output",
+                err
+            );
+            *self = Sink::Failed;
+        }
+    }
+}
+
+/// The stream a buffered line is written to
+#[derive(Debug, Clone, Copy)]
+enum Stream {
+    Responses,
+    Errors,
+}
+
+/// Buffers lines until every earlier line number has been reported
+///
+/// A line that never arrives would hold back everything after it, so once
+/// `max_buffered` lines are waiting or the oldest gap is `max_wait` old, the
+/// gap is skipped with a marker and output continues from the next line that
+/// is ready. The wait is only checked when output arrives; whatever is still
+/// buffered when the run ends is flushed the same way.
+struct Reorder {
+    /// Next line number to emit
+    next: usize,
+    /// Reported lines that can't be emitted yet; `None` for lines with no output
+    pending: BTreeMap<usize, Option<(Stream, String)>>,
+    /// When the line at `next` started holding others back
+    stalled_since: Option<Instant>,
+    max_buffered: usize,
+    max_wait: Duration,
+}
+
+/// The response, error, and summary streams owned by the main thread
+pub struct OutputSinks {
+    responses: Sink,
+    errors: Sink,
+    summary: Sink,
+    reorder: Option<Reorder>,
+    /// Names lines as `file:line` in a multi-file run
+    file_lines: Option<Arc<FileLines>>,
+}
+
+impl OutputSinks {
+    /// Open every configured destination
+    ///
+    /// Fails if any file can't be created, so a bad path is reported at
+    /// startup rather than partway through a run.
+    pub fn open(options: &OutputOptions) -> Result<Self, String> {
+        Ok(Self {
+            responses: Sink::open(&options.responses, "--responses-out", Sink::Stdout)?,
+            errors: Sink::open(&options.errors, "--errors-out", Sink::Stderr)?,
+            summary: Sink::open(&options.summary, "--summary-out", Sink::Stdout)?,
+            reorder: None,
+            file_lines: None,
+        })
+    }
+
+    /// Emit response and error lines in line-number order, starting from
+    /// line 1
+    ///
+    /// Every line number must then be reported exactly once, through
+    /// `response`, `failure`, `parse_error` or `skip_line`. A missing line is
+    /// given up on after `max_buffered` later lines are waiting or after
+    /// `max_wait`.
+    pub fn ordered(mut self, max_buffered: usize, max_wait: Duration) -> Self {
+        self.reorder = Some(Reorder {
+            next: 1,
+            pending: BTreeMap::new(),
+            stalled_since: None,
+            max_buffered: max_buffered.max(1),
+            max_wait,
+        });
+        self
+    }
+
+    /// Name each line by its file and its number within that file, as
+    /// `file:line`
+    pub fn file_lines(mut self, files: Arc<FileLines>) -> Self {
+        self.file_lines = Some(files);
+        self
+    }
+
+    /// All streams on stdout/stderr (the default)
+    pub fn stdio() -> Self {
+        Self::open(&OutputOptions::default()).expect("standard streams always open")
+    }
+
+    /// Drop all output (for embedders that consume results directly)
+    pub fn discard() -> Self {
+        Self {
+            responses: Sink::Discard,
+            errors: Sink::Discard,
+            summary: Sink::Discard,
+            reorder: None,
+            file_lines: None,
+        }
+    }
+
+    /// Emit a successful command response
+    pub fn response(&mut self, line_number: usize, io_thread_id: usize, response: &str) {
+        let text = if self.responses.to_file() {
+            format!("[Line {}] {}", self.label(line_number), response)
+        } else {
+            format!(
+                "[Line {} | IO Thread {}] {}",
+                self.label(line_number),
+                io_thread_id,
+                response
+            )
+        };
+        self.emit(line_number, io_thread_id, Some((Stream::Responses, text)));
+    }
+
+    /// Emit a line that failed to parse (to the error stream)
+    pub fn parse_error(&mut self, line_number: usize, io_thread_id: usize, error: &str) {
+        let text = if self.errors.to_file() {
+            format!("[Line {}] Parse error: {}", self.label(line_number), error)
+        } else {
+            format!(
+                "[Line {} | IO Thread {}] Parse error: {}",
+                self.label(line_number),
+                io_thread_id,
+                error
+            )
+        };
+        self.emit(line_number, io_thread_id, Some((Stream::Errors, text)));
+    }
+
+    /// Record that a line produces no output (blank, or dropped as a
+    /// duplicate), so ordered output doesn't wait for it
+    pub fn skip_line(&mut self, line_number: usize) {
+        self.emit(line_number, 0, None);
+    }
+
+    /// Emit a handler failure
+    ///
+    /// Hard errors always go to the error stream with an `Error:` label. Soft
+    /// errors are labelled `Warning:` and routed by `soft_sink`, so expected
+    /// misses don't look like real failures to log-based alerting.
+    pub fn failure(&mut self, failure: &CommandFailure, soft_sink: SoftErrorSink) {
+        let (stream, label) = if !failure.error.is_soft() {
+            (Stream::Errors, "Error")
+        } else {
+            match soft_sink {
+                SoftErrorSink::Stdout => (Stream::Responses, "Warning"),
+                SoftErrorSink::Stderr => (Stream::Errors, "Warning"),
+                SoftErrorSink::Quiet => {
+                    return self.emit(failure.line_number, failure.io_thread_id, None);
+                }
+            }
+        };
+
+        let trace = failure
+            .trace_id
+            .as_ref()
+            .map_or_else(String::new, |id| format!("[trace:{}] ", id));
+        let line = self.label(failure.line_number);
+        let text = if self.sink(stream).to_file() {
+            format!("[Line {}] {}{}: {}", line, trace, label, failure.error)
+        } else {
+            format!(
+                "[Line {} | IO Thread {}] {}{}: {}",
+                line, failure.io_thread_id, trace, label, failure.error
+            )
+        };
+        self.emit(
+            failure.line_number,
+            failure.io_thread_id,
+            Some((stream, text)),
+        );
+    }
+
+    /// Emit one line of the end-of-run summary
+    pub fn summary(&mut self, text: &str) {
+        if self.summary.to_file() {
+            self.summary.write_line(text);
+        } else {
+            self.summary.write_line(&format!("[Summary] {}", text));
+        }
+    }
+
+    /// Whether any stream has failed, so some output was never delivered
+    pub fn truncated(&self) -> bool {
+        [&self.responses, &self.errors, &self.summary]
+            .iter()
+            .any(|sink| matches!(sink, Sink::Failed))
+    }
+
+    /// Flush all streams (called by the main thread on shutdown)
+    ///
+    /// Ordered output still waiting on a missing line is written out with a
+    /// gap marker, since no more lines can arrive.
+    pub fn flush(&mut self) {
+        while let Some(reorder) = &self.reorder
+            && !reorder.pending.is_empty()
+        {
+            self.skip_gap();
+        }
+        for sink in [&mut self.responses, &mut self.errors, &mut self.summary] {
+            sink.flush();
+        }
+    }
+
+    /// How output names `line_number`
+    fn label(&self, line_number: usize) -> String {
+        match &self.file_lines {
+            Some(files) => files.label(line_number),
+            None => line_number.to_string(),
+        }
+    }
+
+    fn sink(&mut self, stream: Stream) -> &mut Sink {
+        match stream {
+            Stream::Responses => &mut self.responses,
+            Stream::Errors => &mut self.errors,
+        }
+    }
+
+    /// Write a reported line now, or buffer it until its turn
+    fn emit(&mut self, line_number: usize, io_thread_id: usize, line: Option<(Stream, String)>) {
+        let Some(reorder) = &mut self.reorder else {
+            if let Some((stream, text)) = line {
+                self.sink(stream).write_line(&text);
+            }
+            return;
+        };
+        // Direct submissions have their own numbering, and a line older than
+        // `next` was already given up on; neither has a place to wait for
+        if io_thread_id == DIRECT_SUBMISSION || line_number < reorder.next {
+            if let Some((stream, text)) = line {
+                self.sink(stream).write_line(&text);
+            }
+            return;
+        }
+
+        reorder.pending.insert(line_number, line);
+        self.drain_ready();
+        if let Some(reorder) = &mut self.reorder
+            && !reorder.pending.is_empty()
+        {
+            let since = *reorder.stalled_since.get_or_insert_with(Instant::now);
+            if reorder.pending.len() >= reorder.max_buffered || since.elapsed() >= reorder.max_wait
+            {
+                self.skip_gap();
+            }
+        }
+    }
+
+    /// Write every buffered line whose predecessors have all been written
+    fn drain_ready(&mut self) {
+        let Some(reorder) = &mut self.reorder else {
+            return;
+        };
+        let mut ready = Vec::new();
+        while let Some(line) = reorder.pending.remove(&reorder.next) {
+            ready.extend(line);
+            reorder.next += 1;
+            reorder.stalled_since = None;
+        }
+        for (stream, text) in ready {
+            self.sink(stream).write_line(&text);
+        }
+    }
+
+    /// Give up on the missing line(s) before the first buffered one
+    fn skip_gap(&mut self) {
+        let Some(reorder) = &mut self.reorder else {
+            return;
+        };
+        let Some(&first) = reorder.pending.keys().next() else {
+            return;
+        };
+        let marker = if first - reorder.next == 1 {
+            format!("[Output] line {} missing, continuing", reorder.next)
+        } else {
+            format!(
+                "[Output] lines {}-{} missing, continuing",
+                reorder.next,
+                first - 1
+            )
+        };
+        reorder.next = first;
+        self.responses.write_line(&marker);
+        self.drain_ready();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::handler::HandlerError;
+    use crate::thread::ThreadPoolBuilder;
+    use std::sync::Arc;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("crabby_output_{}_{}.txt", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_open_fails_for_bad_path() {
+        let options = OutputOptions {
+            errors: Some(PathBuf::from("/nonexistent-dir/errors.txt")),
+            ..OutputOptions::default()
+        };
+        let err = OutputSinks::open(&options).err().unwrap();
+        assert!(err.contains("--errors-out"));
+    }
+
+    #[test]
+    fn test_streams_routed_to_separate_files() {
+        let options = OutputOptions {
+            responses: Some(temp_path("responses")),
+            errors: Some(temp_path("errors")),
+            summary: Some(temp_path("summary")),
+        };
+        let sinks = OutputSinks::open(&options).unwrap();
+
+        // A single IO thread keeps the arrival order deterministic
+        let mut pool = ThreadPoolBuilder::new(Config::from_env())
+            .io_threads(1)
+            .output(sinks)
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for (i, line) in ["SET a 1", "GET a", "GET missing", "LTRIM a 0 1", "DELETE a"]
+            .iter()
+            .enumerate()
+        {
+            sender.send((line.to_string(), i + 1)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        main_handle.join().unwrap();
+
+        let read = |path: &Option<PathBuf>| {
+            let path = path.as_ref().unwrap();
+            let contents = std::fs::read_to_string(path).unwrap();
+            std::fs::remove_file(path).unwrap();
+            contents
+        };
+        assert_eq!(
+            read(&options.responses),
+            "[Line 1] SET a = 1\n[Line 2] GET a = 1\n[Line 5] DELETED a (was: 1)\n"
+        );
+        assert_eq!(
+            read(&options.errors),
+            "[Line 3] Warning: Key 'missing' not found (line 3)\n\
+             [Line 4] Error: WRONGTYPE Operation against a key holding the wrong kind of value\n"
+        );
+        assert_eq!(
+            read(&options.summary),
+            "processed: 5\nsucceeded: 3\nsoft_errors: 1\nhard_errors: 1\nretries: 0\n"
+        );
+    }
+
+    #[test]
+    fn test_soft_errors_follow_configured_sink() {
+        let options = OutputOptions {
+            responses: Some(temp_path("soft_responses")),
+            errors: Some(temp_path("soft_errors")),
+            ..OutputOptions::default()
+        };
+        let failure = |error| CommandFailure {
+            line_number: 7,
+            io_thread_id: 0,
+            error,
+            trace_id: None,
+        };
+
+        let mut sinks = OutputSinks::open(&options).unwrap();
+        let miss = failure(HandlerError::not_found("k"));
+        sinks.failure(&miss, SoftErrorSink::Stdout);
+        sinks.failure(&miss, SoftErrorSink::Quiet);
+        sinks.failure(&failure(HandlerError::WrongType), SoftErrorSink::Quiet);
+        sinks.flush();
+
+        let responses = std::fs::read_to_string(options.responses.as_ref().unwrap()).unwrap();
+        let errors = std::fs::read_to_string(options.errors.as_ref().unwrap()).unwrap();
+        assert_eq!(responses, "[Line 7] Warning: Key 'k' not found\n");
+        assert!(errors.starts_with("[Line 7] Error: WRONGTYPE"));
+
+        std::fs::remove_file(options.responses.unwrap()).unwrap();
+        std::fs::remove_file(options.errors.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_ordered_output_fills_gaps_and_skips_stalls() {
+        let options = OutputOptions {
+            responses: Some(temp_path("ordered_responses")),
+            errors: Some(temp_path("ordered_errors")),
+            ..OutputOptions::default()
+        };
+        let mut sinks = OutputSinks::open(&options)
+            .unwrap()
+            .ordered(3, Duration::from_secs(60));
+
+        sinks.response(2, 0, "two");
+        sinks.parse_error(3, 0, "Invalid command: NOPE");
+        sinks.response(1, 0, "one");
+        sinks.skip_line(4);
+        // Line 5 is lost; the third buffered line gives up on it
+        sinks.response(6, 0, "six");
+        sinks.response(7, 0, "seven");
+        sinks.response(8, 0, "eight");
+        // Too late for its place, so it's written straight away
+        sinks.response(5, 0, "five");
+        sinks.response(10, 0, "ten");
+        sinks.flush();
+
+        let responses = std::fs::read_to_string(options.responses.as_ref().unwrap()).unwrap();
+        let errors = std::fs::read_to_string(options.errors.as_ref().unwrap()).unwrap();
+        assert_eq!(
+            responses,
+            "[Line 1] one\n[Line 2] two\n\
+             [Output] line 5 missing, continuing\n\
+             [Line 6] six\n[Line 7] seven\n[Line 8] eight\n[Line 5] five\n\
+             [Output] line 9 missing, continuing\n[Line 10] ten\n"
+        );
+        assert_eq!(errors, "[Line 3] Parse error: Invalid command: NOPE\n");
+
+        std::fs::remove_file(options.responses.unwrap()).unwrap();
+        std::fs::remove_file(options.errors.unwrap()).unwrap();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_parse_errors_hold_their_place_in_ordered_output() {
+        use crate::config::TestConfig;
+
+        let options = OutputOptions {
+            responses: Some(temp_path("ordered_pool_responses")),
+            errors: Some(temp_path("ordered_pool_errors")),
+            ..OutputOptions::default()
+        };
+        let config = Arc::new(Config {
+            output_ordered: true,
+            output_order_max_buffered: 10_000,
+            output_order_max_wait: Duration::from_secs(60),
+            test: TestConfig {
+                random_sleep_io_thread: true,
+                io_sleep_min_ms: 0,
+                io_sleep_max_ms: 2,
+                ..TestConfig::default()
+            },
+            ..Config::default()
+        });
+        let mut pool = ThreadPoolBuilder::new(config)
+            .io_threads(4)
+            .output(OutputSinks::open(&options).unwrap())
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+
+        let mut expected_responses = String::new();
+        let mut expected_errors = String::new();
+        for i in 1..=300 {
+            let line = if i % 7 == 0 {
+                expected_errors.push_str(&format!(
+                    "[Line {0}] Parse error: Invalid command: BOGUS {0} (line: 'BOGUS {0}')\n",
+                    i
+                ));
+                format!("BOGUS {}", i)
+            } else if i % 11 == 0 {
+                String::new()
+            } else {
+                expected_responses.push_str(&format!("[Line {0}] SET k{0} = v\n", i));
+                format!("SET k{} v", i)
+            };
+            sender.send((line, i)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        main_handle.join().unwrap();
+
+        // Without the parse errors and blank lines holding their numbers, the
+        // gaps would never close and every line after the first would stall
+        let responses = std::fs::read_to_string(options.responses.as_ref().unwrap()).unwrap();
+        let errors = std::fs::read_to_string(options.errors.as_ref().unwrap()).unwrap();
+        std::fs::remove_file(options.responses.unwrap()).unwrap();
+        std::fs::remove_file(options.errors.unwrap()).unwrap();
+        assert_eq!(responses, expected_responses);
+        assert_eq!(errors, expected_errors);
+    }
+}
@@ -1,20 +1,566 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock};
 
-#[derive(Debug, PartialEq)]
+use crate::cluster::SLOTS;
+use crate::custom::CommandRegistry;
+use crate::json::JsonPath;
+use crate::procedure::Procedure;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum CommandType {
-    Set(String, String), // key, value
-    Get(String),         // key
-    Delete(String),      // key
+    Set(String, String),                             // key, value
+    SetInt(String, i64),                             // key, value
+    Convert(String),                                 // key to store as an integer
+    Get(String),                                     // key
+    Delete(String),                                  // key
+    Undelete(String),                                // key
+    Purge(String),                                   // glob pattern of tombstoned keys
+    LMove(String, String, String, String),           // src, dst, LEFT|RIGHT, LEFT|RIGHT
+    LTrim(String, i64, i64),                         // key, start, stop
+    LIndex(String, i64),                             // key, index
+    AppendCap(String, usize, String),                // key, max length in bytes, value
+    GetDefault(String, String),                      // key, default
+    GetOrSet(String, String),                        // key, default
+    IncrBounded(String, i64),                        // key, max
+    IncrIf(String, String, String),                  // counter key, condition key, expected value
+    SetCrc(String, String),                          // key, value
+    GetCrc(String),                                  // key
+    GetWithTtl(String),                              // key
+    GetAt(String, u64),                              // key, version
+    Inspect(String),                                 // key
+    JGet(String, JsonPath),                          // key, path into the JSON value
+    RenameNx(String, String),                        // src, dst
+    RenamePrefix(String, String),                    // old key prefix, new key prefix
+    Scan(Option<String>, usize),                     // resume after this key (None = start), count
+    SortByValue(String, bool),                       // glob pattern, ascending
+    GetMatching(String),                             // glob pattern
+    PrefixHistogram(char),                           // delimiter ending a key's prefix
+    KeySlot(String),                                 // key
+    SlotStats(usize),                                // number of slot ranges
+    LoadJson(String),                                // path to a flat JSON object
+    DumpJson(String),                                // path to write a flat JSON object to
+    Merge(String, Vec<String>, MergeStrategy, bool), // dest, sources, strategy, strict
+    GenSet(String, usize),                           // key prefix, number of keys
+    WaitKey(String, u64),                            // key, timeout in milliseconds
+    DumpParallel(String, Option<usize>), // directory, worker threads (None = one per CPU)
+    Shutdown(bool),                      // save a snapshot first
+    Explain(String),                     // raw command line to parse
+    Define(String, Procedure),           // procedure name, compiled steps
+    Call(String, Vec<String>),           // procedure name, arguments
+    Apply(String),                       // path to a file of commands
+    Info(bool),                          // only the prefix quota section
+    Stats,
+    StatsReset,
+    ValueLengthStats,
+    HotKeys(usize), // number of keys to list
+    Check,
+    ParseStats,
+    Version,
+    SnapshotId,
+    Fingerprint,
+    Custom(&'static str, Vec<String>), // registered keyword, arguments
+}
+
+/// How MERGE combines its source values
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeStrategy {
+    /// Join the values with the separator between them
+    Concat(String),
+    Sum,
+    Max,
+    Min,
 }
 
-#[derive(Debug)]
+impl CommandType {
+    /// The command keyword as written in input (`SET`, `LMOVE`, ...)
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandType::Set(..) => "SET",
+            CommandType::Get(..) => "GET",
+            CommandType::Delete(..) => "DELETE",
+            CommandType::Undelete(..) => "UNDELETE",
+            CommandType::Purge(..) => "PURGE",
+            CommandType::LMove(..) => "LMOVE",
+            CommandType::LTrim(..) => "LTRIM",
+            CommandType::LIndex(..) => "LINDEX",
+            CommandType::AppendCap(..) => "APPENDCAP",
+            CommandType::GetDefault(..) => "GETDEFAULT",
+            CommandType::GetOrSet(..) => "GETORSET",
+            CommandType::IncrBounded(..) => "INCRBOUNDED",
+            CommandType::IncrIf(..) => "INCRIF",
+            CommandType::SetCrc(..) => "SETCRC",
+            CommandType::SetInt(..) => "SETINT",
+            CommandType::Convert(..) => "CONVERT",
+            CommandType::GetCrc(..) => "GETCRC",
+            CommandType::GetWithTtl(..) => "GETWITHTTL",
+            CommandType::Inspect(..) => "INSPECT",
+            CommandType::GetAt(..) => "GETAT",
+            CommandType::JGet(..) => "JGET",
+            CommandType::RenameNx(..) => "RENAMENX",
+            CommandType::RenamePrefix(..) => "RENAMEPREFIX",
+            CommandType::Scan(..) => "SCAN",
+            CommandType::SortByValue(..) => "SORTBYVALUE",
+            CommandType::GetMatching(..) => "GETMATCHING",
+            CommandType::PrefixHistogram(..) => "PREFIXHISTOGRAM",
+            CommandType::KeySlot(..) => "KEYSLOT",
+            CommandType::SlotStats(..) => "SLOTSTATS",
+            CommandType::LoadJson(..) => "LOADJSON",
+            CommandType::DumpJson(..) => "DUMPJSON",
+            CommandType::Merge(..) => "MERGE",
+            CommandType::GenSet(..) => "GENSET",
+            CommandType::WaitKey(..) => "WAITKEY",
+            CommandType::DumpParallel(..) => "DUMP",
+            CommandType::Shutdown(..) => "SHUTDOWN",
+            CommandType::Explain(..) => "EXPLAIN",
+            CommandType::Define(..) => "DEFINE",
+            CommandType::Call(..) => "CALL",
+            CommandType::Apply(..) => "APPLY",
+            CommandType::Info(..) => "INFO",
+            CommandType::Version => "VERSION",
+            CommandType::SnapshotId => "SNAPSHOTID",
+            CommandType::Fingerprint => "FINGERPRINT",
+            CommandType::Stats => "STATS",
+            CommandType::StatsReset => "STATSRESET",
+            CommandType::ValueLengthStats => "VALUELENGTHSTATS",
+            CommandType::HotKeys(..) => "HOTKEYS",
+            CommandType::Check => "CHECK",
+            CommandType::ParseStats => "PARSESTATS",
+            CommandType::Custom(name, _) => name,
+        }
+    }
+
+    /// The command with its parsed arguments, as `SET key=foo value=bar`
+    ///
+    /// Arguments that are empty or contain whitespace are quoted, so the
+    /// description shows exactly where each one starts and ends.
+    pub fn describe(&self) -> String {
+        let fields: Vec<(&str, String)> = match self {
+            CommandType::Set(key, value) | CommandType::SetCrc(key, value) => {
+                vec![("key", key.clone()), ("value", value.clone())]
+            }
+            CommandType::SetInt(key, value) => {
+                vec![("key", key.clone()), ("value", value.to_string())]
+            }
+            CommandType::Convert(key) => vec![("key", key.clone()), ("to", "INT".to_string())],
+            CommandType::Get(key)
+            | CommandType::Delete(key)
+            | CommandType::Undelete(key)
+            | CommandType::GetCrc(key)
+            | CommandType::GetWithTtl(key)
+            | CommandType::Inspect(key)
+            | CommandType::KeySlot(key) => vec![("key", key.clone())],
+            CommandType::JGet(key, path) => vec![("key", key.clone()), ("path", path.to_string())],
+            CommandType::GetAt(key, version) => {
+                vec![("key", key.clone()), ("version", version.to_string())]
+            }
+            CommandType::LMove(src, dst, from, to) => vec![
+                ("src", src.clone()),
+                ("dst", dst.clone()),
+                ("from", from.clone()),
+                ("to", to.clone()),
+            ],
+            CommandType::LTrim(key, start, stop) => vec![
+                ("key", key.clone()),
+                ("start", start.to_string()),
+                ("stop", stop.to_string()),
+            ],
+            CommandType::LIndex(key, index) => {
+                vec![("key", key.clone()), ("index", index.to_string())]
+            }
+            CommandType::AppendCap(key, max_len, value) => vec![
+                ("key", key.clone()),
+                ("max_len", max_len.to_string()),
+                ("value", value.clone()),
+            ],
+            CommandType::GetDefault(key, default) | CommandType::GetOrSet(key, default) => {
+                vec![("key", key.clone()), ("default", default.clone())]
+            }
+            CommandType::IncrBounded(key, max) => {
+                vec![("key", key.clone()), ("max", max.to_string())]
+            }
+            CommandType::IncrIf(key, cond_key, cond_value) => vec![
+                ("key", key.clone()),
+                ("cond_key", cond_key.clone()),
+                ("cond_value", cond_value.clone()),
+            ],
+            CommandType::RenameNx(src, dst) => vec![("src", src.clone()), ("dst", dst.clone())],
+            CommandType::RenamePrefix(old, new) => {
+                vec![("old_prefix", old.clone()), ("new_prefix", new.clone())]
+            }
+            CommandType::Scan(after, count) => vec![
+                (
+                    "after",
+                    after.clone().unwrap_or_else(|| "(start)".to_string()),
+                ),
+                ("count", count.to_string()),
+            ],
+            CommandType::Purge(pattern) | CommandType::GetMatching(pattern) => {
+                vec![("pattern", pattern.clone())]
+            }
+            CommandType::SortByValue(pattern, ascending) => vec![
+                ("pattern", pattern.clone()),
+                ("order", if *ascending { "asc" } else { "desc" }.to_string()),
+            ],
+            CommandType::PrefixHistogram(delimiter) => {
+                vec![("delimiter", delimiter.to_string())]
+            }
+            CommandType::LoadJson(path)
+            | CommandType::DumpJson(path)
+            | CommandType::Apply(path) => {
+                vec![("path", path.clone())]
+            }
+            CommandType::Merge(dest, sources, strategy, strict) => {
+                let mut fields = vec![("dest", dest.clone()), ("sources", sources.join(","))];
+                match strategy {
+                    MergeStrategy::Concat(separator) => {
+                        fields.push(("strategy", "concat".to_string()));
+                        fields.push(("separator", separator.clone()));
+                    }
+                    MergeStrategy::Sum => fields.push(("strategy", "sum".to_string())),
+                    MergeStrategy::Max => fields.push(("strategy", "max".to_string())),
+                    MergeStrategy::Min => fields.push(("strategy", "min".to_string())),
+                }
+                fields.push(("strict", strict.to_string()));
+                fields
+            }
+            CommandType::GenSet(prefix, count) => {
+                vec![("prefix", prefix.clone()), ("count", count.to_string())]
+            }
+            CommandType::WaitKey(key, timeout_ms) => {
+                vec![("key", key.clone()), ("timeout_ms", timeout_ms.to_string())]
+            }
+            CommandType::DumpParallel(dir, threads) => vec![
+                ("dir", dir.clone()),
+                (
+                    "threads",
+                    threads.map_or_else(|| "(per cpu)".to_string(), |n| n.to_string()),
+                ),
+            ],
+            CommandType::Shutdown(save) => vec![("save", save.to_string())],
+            CommandType::Info(true) => vec![("section", "quotas".to_string())],
+            CommandType::Explain(line) => vec![("line", line.clone())],
+            CommandType::Define(name, procedure) => vec![
+                ("name", name.clone()),
+                ("steps", procedure.steps().collect::<Vec<_>>().join("; ")),
+            ],
+            CommandType::Call(name, args) if args.is_empty() => vec![("name", name.clone())],
+            CommandType::Call(name, args) => {
+                vec![("name", name.clone()), ("args", args.join(" "))]
+            }
+            CommandType::HotKeys(count) => vec![("count", count.to_string())],
+            CommandType::SlotStats(ranges) => vec![("ranges", ranges.to_string())],
+            CommandType::Info(false)
+            | CommandType::Stats
+            | CommandType::StatsReset
+            | CommandType::ValueLengthStats
+            | CommandType::Check
+            | CommandType::ParseStats
+            | CommandType::Version
+            | CommandType::SnapshotId
+            | CommandType::Fingerprint => Vec::new(),
+            CommandType::Custom(_, args) if args.is_empty() => Vec::new(),
+            CommandType::Custom(_, args) => vec![("args", args.join(" "))],
+        };
+
+        let mut out = self.name().to_string();
+        for (name, value) in fields {
+            if value.is_empty() || value.contains(char::is_whitespace) {
+                out.push_str(&format!(" {}={:?}", name, value));
+            } else {
+                out.push_str(&format!(" {}={}", name, value));
+            }
+        }
+        out
+    }
+
+    /// Whether the command writes outside the store (to a file), which a dry
+    /// run's shadow copy can't contain
+    pub fn is_external_write(&self) -> bool {
+        matches!(
+            self,
+            CommandType::DumpJson(..) | CommandType::DumpParallel(..) | CommandType::Shutdown(true)
+        )
+    }
+
+    /// Whether the command can modify the store
+    ///
+    /// Custom commands and procedure calls might, so they always count as
+    /// writes.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            CommandType::Set(..)
+                | CommandType::Delete(..)
+                | CommandType::Undelete(..)
+                | CommandType::Purge(..)
+                | CommandType::LMove(..)
+                | CommandType::LTrim(..)
+                | CommandType::AppendCap(..)
+                | CommandType::GetOrSet(..)
+                | CommandType::IncrBounded(..)
+                | CommandType::IncrIf(..)
+                | CommandType::SetCrc(..)
+                | CommandType::SetInt(..)
+                | CommandType::Convert(..)
+                | CommandType::RenameNx(..)
+                | CommandType::RenamePrefix(..)
+                | CommandType::LoadJson(..)
+                | CommandType::Merge(..)
+                | CommandType::GenSet(..)
+                | CommandType::Custom(..)
+                | CommandType::Call(..)
+                | CommandType::Apply(..)
+        )
+    }
+
+    /// The single key the command is about, if it has one: the first key
+    /// for commands taking several, and none for patterns and prefixes
+    pub fn primary_key(&self) -> Option<&str> {
+        match self {
+            CommandType::Set(key, _)
+            | CommandType::Get(key)
+            | CommandType::Delete(key)
+            | CommandType::Undelete(key)
+            | CommandType::LMove(key, ..)
+            | CommandType::LTrim(key, ..)
+            | CommandType::LIndex(key, _)
+            | CommandType::AppendCap(key, ..)
+            | CommandType::GetDefault(key, _)
+            | CommandType::GetOrSet(key, _)
+            | CommandType::IncrBounded(key, _)
+            | CommandType::IncrIf(key, ..)
+            | CommandType::SetCrc(key, _)
+            | CommandType::SetInt(key, _)
+            | CommandType::Convert(key)
+            | CommandType::GetCrc(key)
+            | CommandType::GetWithTtl(key)
+            | CommandType::Inspect(key)
+            | CommandType::GetAt(key, _)
+            | CommandType::JGet(key, _)
+            | CommandType::RenameNx(key, _)
+            | CommandType::Merge(key, ..)
+            | CommandType::WaitKey(key, _) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// Characters of a value `Display` shows before cutting it off with `…`
+pub const DISPLAY_VALUE_CHARS: usize = 32;
+
+/// `value`, cut to `DISPLAY_VALUE_CHARS` characters plus an ellipsis
+fn shorten(value: &str) -> Cow<'_, str> {
+    match value.char_indices().nth(DISPLAY_VALUE_CHARS) {
+        Some((end, _)) => Cow::Owned(format!("{}…", &value[..end])),
+        None => Cow::Borrowed(value),
+    }
+}
+
+/// The command in its input syntax, for logs: keys, patterns and numbers
+/// are shown whole, while values and other free text are shortened to
+/// `DISPLAY_VALUE_CHARS` characters
+impl fmt::Display for CommandType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())?;
+        match self {
+            CommandType::Set(key, value) | CommandType::SetCrc(key, value) => {
+                write!(f, " {} {}", key, shorten(value))
+            }
+            CommandType::SetInt(key, value) => write!(f, " {} {}", key, value),
+            CommandType::Convert(key) => write!(f, " {} INT", key),
+            CommandType::Get(key)
+            | CommandType::Delete(key)
+            | CommandType::Undelete(key)
+            | CommandType::GetCrc(key)
+            | CommandType::GetWithTtl(key)
+            | CommandType::Inspect(key)
+            | CommandType::KeySlot(key) => write!(f, " {}", key),
+            CommandType::Purge(pattern) | CommandType::GetMatching(pattern) => {
+                write!(f, " {}", pattern)
+            }
+            CommandType::LMove(src, dst, from, to) => {
+                write!(f, " {} {} {} {}", src, dst, from, to)
+            }
+            CommandType::LTrim(key, start, stop) => write!(f, " {} {} {}", key, start, stop),
+            CommandType::LIndex(key, index) => write!(f, " {} {}", key, index),
+            CommandType::AppendCap(key, max_len, value) => {
+                write!(f, " {} {} {}", key, max_len, shorten(value))
+            }
+            CommandType::GetDefault(key, default) | CommandType::GetOrSet(key, default) => {
+                write!(f, " {} {}", key, shorten(default))
+            }
+            CommandType::IncrBounded(key, max) => write!(f, " {} {}", key, max),
+            CommandType::GetAt(key, version) => write!(f, " {} {}", key, version),
+            CommandType::IncrIf(key, cond_key, cond_value) => {
+                write!(f, " {} {} {}", key, cond_key, shorten(cond_value))
+            }
+            CommandType::JGet(key, path) => write!(f, " {} {}", key, path),
+            CommandType::RenameNx(src, dst) => write!(f, " {} {}", src, dst),
+            CommandType::RenamePrefix(old, new) => write!(f, " {} {}", old, new),
+            CommandType::Scan(after, count) => {
+                let cursor = after.as_deref().map_or("0".to_string(), encode_scan_cursor);
+                write!(f, " {} COUNT {}", shorten(&cursor), count)
+            }
+            CommandType::SortByValue(pattern, ascending) => {
+                write!(
+                    f,
+                    " {} {}",
+                    pattern,
+                    if *ascending { "asc" } else { "desc" }
+                )
+            }
+            CommandType::PrefixHistogram(delimiter) => write!(f, " {}", delimiter),
+            CommandType::LoadJson(path)
+            | CommandType::DumpJson(path)
+            | CommandType::Apply(path) => write!(f, " {}", path),
+            CommandType::Merge(dest, sources, strategy, strict) => {
+                write!(f, " {} {} STRATEGY ", dest, sources.join(" "))?;
+                match strategy {
+                    MergeStrategy::Concat(separator) if separator.is_empty() => {
+                        write!(f, "concat")?
+                    }
+                    MergeStrategy::Concat(separator) => {
+                        write!(f, "concat SEPARATOR {}", shorten(separator))?
+                    }
+                    MergeStrategy::Sum => write!(f, "sum")?,
+                    MergeStrategy::Max => write!(f, "max")?,
+                    MergeStrategy::Min => write!(f, "min")?,
+                }
+                if *strict {
+                    write!(f, " STRICT")?;
+                }
+                Ok(())
+            }
+            CommandType::GenSet(prefix, count) => write!(f, " {} {}", prefix, count),
+            CommandType::WaitKey(key, timeout_ms) => write!(f, " {} {}", key, timeout_ms),
+            CommandType::DumpParallel(dir, threads) => {
+                write!(f, " PARALLEL {}", dir)?;
+                match threads {
+                    Some(threads) => write!(f, " {}", threads),
+                    None => Ok(()),
+                }
+            }
+            CommandType::Shutdown(true) => write!(f, " SAVE"),
+            CommandType::Info(true) => write!(f, " quotas"),
+            CommandType::Explain(line) => write!(f, " {}", shorten(line)),
+            CommandType::Define(name, procedure) => {
+                let steps: Vec<&str> = procedure.steps().collect();
+                write!(f, " {} AS {} END", name, shorten(&steps.join("; ")))
+            }
+            CommandType::Call(name, args) => {
+                write!(f, " {}", name)?;
+                for arg in args {
+                    write!(f, " {}", shorten(arg))?;
+                }
+                Ok(())
+            }
+            CommandType::HotKeys(count) => write!(f, " {}", count),
+            CommandType::SlotStats(ranges) => write!(f, " {}", ranges),
+            CommandType::Custom(_, args) if !args.is_empty() => {
+                write!(f, " {}", shorten(&args.join(" ")))
+            }
+            CommandType::Shutdown(false)
+            | CommandType::Info(false)
+            | CommandType::Stats
+            | CommandType::StatsReset
+            | CommandType::ValueLengthStats
+            | CommandType::Check
+            | CommandType::ParseStats
+            | CommandType::Version
+            | CommandType::SnapshotId
+            | CommandType::Fingerprint
+            | CommandType::Custom(..) => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Command {
     pub command_type: CommandType,
+    /// Token from a trailing `ID <token>` clause, used to skip retried commands
+    pub idempotency_key: Option<String>,
+    /// Set by a trailing `FRESH` clause: the read goes through the main
+    /// thread, seeing every earlier write, even with a read snapshot on
+    pub fresh: bool,
 }
 
 impl Command {
     pub fn new(command_type: CommandType) -> Self {
-        Self { command_type }
+        Self {
+            command_type,
+            idempotency_key: None,
+            fresh: false,
+        }
+    }
+}
+
+/// What a line may hold beyond the built-in commands
+#[derive(Debug, Clone, Copy)]
+pub struct ParseOptions<'a> {
+    /// Keywords registered at runtime, read as custom commands
+    pub custom: Option<&'a CommandRegistry>,
+    /// Read a trailing `ID <token>` as an idempotency clause, as when
+    /// `IDEMPOTENCY_WINDOW` is on; otherwise the two tokens belong to the
+    /// command like any others
+    pub id_clause: bool,
+}
+
+/// What `str::parse` reads: the built-in commands, with `ID` clauses
+impl Default for ParseOptions<'_> {
+    fn default() -> Self {
+        Self {
+            custom: None,
+            id_clause: true,
+        }
+    }
+}
+
+/// Lines parsed into a command, per keyword, counted by the IO threads as
+/// they parse and read by PARSESTATS on the main thread
+#[derive(Debug, Default)]
+pub struct ParseStats {
+    counts: RwLock<HashMap<&'static str, AtomicU64>>,
+}
+
+impl ParseStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count one successful parse of a `name` command
+    ///
+    /// Only a keyword's first parse takes the write lock.
+    pub fn record(&self, name: &'static str) {
+        let counts = self.counts.read().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = counts.get(name) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        drop(counts);
+        self.counts
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(name)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Every keyword parsed so far with its count, most parsed first and
+    /// ties by keyword
+    pub fn counts(&self) -> Vec<(&'static str, u64)> {
+        let mut counts: Vec<(&'static str, u64)> = self
+            .counts
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(name, count)| (*name, count.load(Ordering::Relaxed)))
+            .collect();
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        counts
     }
 }
 
@@ -22,6 +568,16 @@ impl FromStr for Command {
     type Err = String;
 
     fn from_str(line: &str) -> Result<Self, Self::Err> {
+        Self::parse_with(line, ParseOptions::default())
+    }
+}
+
+impl Command {
+    /// Parse a line as `options` allow
+    pub fn parse_with(line: &str, options: ParseOptions<'_>) -> Result<Self, String> {
+        // A line split on `\n` alone keeps the `\r` of a CRLF ending; strip
+        // it first so it can never end up in a key or value
+        let line = line.trim_end_matches(['\r', '\n']);
         let trimmed = line.trim();
 
         // Skip empty lines
@@ -31,7 +587,34 @@ impl FromStr for Command {
 
         let parts: Vec<&str> = trimmed.split_whitespace().collect();
 
-        match parts.as_slice() {
+        // EXPLAIN takes the rest of the line verbatim, including any ID
+        // clause, which belongs to the line being explained
+        if let ["EXPLAIN", line @ ..] = parts.as_slice() {
+            if line.is_empty() {
+                return Err("EXPLAIN requires a command line".to_string());
+            }
+            return Ok(Command::new(CommandType::Explain(line.join(" "))));
+        }
+
+        // Where idempotency is on, a trailing `ID <token>` clause tags the
+        // command and is never part of the command itself
+        let (parts, idempotency_key) = match parts.as_slice() {
+            [rest @ .., "ID", token] if options.id_clause && !rest.is_empty() => {
+                (rest, Some(token.to_string()))
+            }
+            all => (all, None),
+        };
+
+        // So is a trailing `FRESH` on the reads a read snapshot can serve,
+        // whose arguments are single tokens, so it can't be part of one
+        let (parts, fresh) = match parts {
+            [rest @ .., "FRESH"] if matches!(rest, ["GET" | "GETWITHTTL" | "GETMATCHING", _]) => {
+                (rest, true)
+            }
+            all => (all, false),
+        };
+
+        let mut command = match parts {
             ["SET", key, value @ ..] if !value.is_empty() => {
                 // Join remaining parts as the value (handles values with spaces)
                 let value_str = value.join(" ");
@@ -39,9 +622,361 @@ impl FromStr for Command {
             }
             ["GET", key] => Ok(Command::new(CommandType::Get(key.to_string()))),
             ["DELETE", key] => Ok(Command::new(CommandType::Delete(key.to_string()))),
-            _ => Err(format!("Invalid command: {}", trimmed)),
+            ["UNDELETE", key] => Ok(Command::new(CommandType::Undelete(key.to_string()))),
+            ["PURGE", pattern] => Ok(Command::new(CommandType::Purge(pattern.to_string()))),
+            [
+                "LMOVE",
+                src,
+                dst,
+                from @ ("LEFT" | "RIGHT"),
+                to @ ("LEFT" | "RIGHT"),
+            ] => Ok(Command::new(CommandType::LMove(
+                src.to_string(),
+                dst.to_string(),
+                from.to_string(),
+                to.to_string(),
+            ))),
+            ["LTRIM", key, start, stop] => {
+                let start = parse_index(start)?;
+                let stop = parse_index(stop)?;
+                Ok(Command::new(CommandType::LTrim(
+                    key.to_string(),
+                    start,
+                    stop,
+                )))
+            }
+            ["LINDEX", key, index] => {
+                let index = parse_index(index)?;
+                Ok(Command::new(CommandType::LIndex(key.to_string(), index)))
+            }
+            ["APPENDCAP", key, max_len, value @ ..] if !value.is_empty() => {
+                let max_len = max_len
+                    .parse()
+                    .map_err(|_| format!("Invalid max length '{}'", max_len))?;
+                Ok(Command::new(CommandType::AppendCap(
+                    key.to_string(),
+                    max_len,
+                    value.join(" "),
+                )))
+            }
+            ["GETDEFAULT", key, default @ ..] if !default.is_empty() => Ok(Command::new(
+                CommandType::GetDefault(key.to_string(), default.join(" ")),
+            )),
+            ["GETORSET", key, default @ ..] if !default.is_empty() => Ok(Command::new(
+                CommandType::GetOrSet(key.to_string(), default.join(" ")),
+            )),
+            ["INCRBOUNDED", key, max] => {
+                let max = max
+                    .parse()
+                    .map_err(|_| format!("Invalid max '{}': expected an integer", max))?;
+                Ok(Command::new(CommandType::IncrBounded(key.to_string(), max)))
+            }
+            ["INCRIF", key, cond_key, cond_value @ ..] if !cond_value.is_empty() => {
+                Ok(Command::new(CommandType::IncrIf(
+                    key.to_string(),
+                    cond_key.to_string(),
+                    cond_value.join(" "),
+                )))
+            }
+            ["SETCRC", key, value @ ..] if !value.is_empty() => Ok(Command::new(
+                CommandType::SetCrc(key.to_string(), value.join(" ")),
+            )),
+            ["GETCRC", key] => Ok(Command::new(CommandType::GetCrc(key.to_string()))),
+            ["SETINT", key, value] => {
+                let value = value
+                    .parse()
+                    .map_err(|_| format!("Invalid value '{}': expected an integer", value))?;
+                Ok(Command::new(CommandType::SetInt(key.to_string(), value)))
+            }
+            ["CONVERT", key, target] if target.eq_ignore_ascii_case("INT") => {
+                Ok(Command::new(CommandType::Convert(key.to_string())))
+            }
+            ["CONVERT", _, target] => Err(format!(
+                "Unsupported CONVERT target '{}': expected INT",
+                target
+            )),
+            ["GETWITHTTL", key] => Ok(Command::new(CommandType::GetWithTtl(key.to_string()))),
+            ["INSPECT", key] => Ok(Command::new(CommandType::Inspect(key.to_string()))),
+            ["GETAT", key, version] => {
+                let version = version.parse().map_err(|_| {
+                    format!(
+                        "Invalid version '{}': expected a non-negative integer",
+                        version
+                    )
+                })?;
+                Ok(Command::new(CommandType::GetAt(key.to_string(), version)))
+            }
+            ["JGET", key, path] => Ok(Command::new(CommandType::JGet(
+                key.to_string(),
+                path.parse()?,
+            ))),
+            ["RENAMENX", src, dst] => Ok(Command::new(CommandType::RenameNx(
+                src.to_string(),
+                dst.to_string(),
+            ))),
+            ["RENAMEPREFIX", old, new] => Ok(Command::new(CommandType::RenamePrefix(
+                old.to_string(),
+                new.to_string(),
+            ))),
+            ["SCAN", cursor] => Ok(Command::new(CommandType::Scan(
+                decode_scan_cursor(cursor)?,
+                DEFAULT_SCAN_COUNT,
+            ))),
+            ["SCAN", cursor, "COUNT", count] => {
+                let count = match count.parse() {
+                    Ok(count) if count > 0 => count,
+                    _ => {
+                        return Err(format!(
+                            "Invalid COUNT '{}': expected a positive integer",
+                            count
+                        ));
+                    }
+                };
+                Ok(Command::new(CommandType::Scan(
+                    decode_scan_cursor(cursor)?,
+                    count,
+                )))
+            }
+            ["SORTBYVALUE", pattern, order @ ("asc" | "desc")] => Ok(Command::new(
+                CommandType::SortByValue(pattern.to_string(), *order == "asc"),
+            )),
+            ["GETMATCHING", pattern] => {
+                Ok(Command::new(CommandType::GetMatching(pattern.to_string())))
+            }
+            ["PREFIXHISTOGRAM", delimiter] => {
+                let mut chars = delimiter.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(delimiter), None) => {
+                        Ok(Command::new(CommandType::PrefixHistogram(delimiter)))
+                    }
+                    _ => Err(format!(
+                        "Invalid delimiter '{}': expected a single character",
+                        delimiter
+                    )),
+                }
+            }
+            ["LOADJSON", path @ ..] if !path.is_empty() => {
+                Ok(Command::new(CommandType::LoadJson(path.join(" "))))
+            }
+            ["DUMPJSON", path @ ..] if !path.is_empty() => {
+                Ok(Command::new(CommandType::DumpJson(path.join(" "))))
+            }
+            ["WAITKEY", key, timeout_ms] => {
+                let timeout_ms = timeout_ms.parse().map_err(|_| {
+                    format!(
+                        "Invalid timeout '{}': expected milliseconds as a non-negative integer",
+                        timeout_ms
+                    )
+                })?;
+                Ok(Command::new(CommandType::WaitKey(
+                    key.to_string(),
+                    timeout_ms,
+                )))
+            }
+            ["MERGE", args @ ..] => parse_merge(args).map(Command::new),
+            ["GENSET", prefix, count] => {
+                let count = count.parse().map_err(|_| {
+                    format!("Invalid count '{}': expected a non-negative integer", count)
+                })?;
+                Ok(Command::new(CommandType::GenSet(prefix.to_string(), count)))
+            }
+            ["KEYSLOT", key] => Ok(Command::new(CommandType::KeySlot(key.to_string()))),
+            ["SLOTSTATS"] => Ok(Command::new(CommandType::SlotStats(DEFAULT_SLOT_RANGES))),
+            ["SLOTSTATS", ranges] => match ranges.parse() {
+                Ok(ranges) if (1..=SLOTS as usize).contains(&ranges) => {
+                    Ok(Command::new(CommandType::SlotStats(ranges)))
+                }
+                _ => Err(format!(
+                    "Invalid range count '{}': expected 1 to {}",
+                    ranges, SLOTS
+                )),
+            },
+            ["INFO"] => Ok(Command::new(CommandType::Info(false))),
+            ["INFO", section] if section.eq_ignore_ascii_case("quotas") => {
+                Ok(Command::new(CommandType::Info(true)))
+            }
+            ["STATS"] => Ok(Command::new(CommandType::Stats)),
+            ["STATSRESET"] => Ok(Command::new(CommandType::StatsReset)),
+            ["VALUELENGTHSTATS"] => Ok(Command::new(CommandType::ValueLengthStats)),
+            ["CHECK"] => Ok(Command::new(CommandType::Check)),
+            ["PARSESTATS"] => Ok(Command::new(CommandType::ParseStats)),
+            ["VERSION"] => Ok(Command::new(CommandType::Version)),
+            ["SNAPSHOTID"] => Ok(Command::new(CommandType::SnapshotId)),
+            ["FINGERPRINT"] => Ok(Command::new(CommandType::Fingerprint)),
+            ["HOTKEYS"] => Ok(Command::new(CommandType::HotKeys(DEFAULT_HOT_KEYS_COUNT))),
+            ["HOTKEYS", count] => match count.parse() {
+                Ok(count) if count > 0 => Ok(Command::new(CommandType::HotKeys(count))),
+                _ => Err(format!(
+                    "Invalid count '{}': expected a positive integer",
+                    count
+                )),
+            },
+            ["DUMP", "PARALLEL", dir] => Ok(Command::new(CommandType::DumpParallel(
+                dir.to_string(),
+                None,
+            ))),
+            ["DUMP", "PARALLEL", dir, threads] => {
+                let threads = match threads.parse() {
+                    Ok(threads) if threads > 0 => threads,
+                    _ => {
+                        return Err(format!(
+                            "Invalid threads '{}': expected a positive integer",
+                            threads
+                        ));
+                    }
+                };
+                Ok(Command::new(CommandType::DumpParallel(
+                    dir.to_string(),
+                    Some(threads),
+                )))
+            }
+            ["SHUTDOWN"] => Ok(Command::new(CommandType::Shutdown(false))),
+            ["SHUTDOWN", "SAVE"] => Ok(Command::new(CommandType::Shutdown(true))),
+            ["DEFINE", name, "AS", body @ ..] => {
+                parse_define(name, body, options).map(Command::new)
+            }
+            ["DEFINE", ..] => Err("DEFINE requires a name and AS".to_string()),
+            ["CALL", name, args @ ..] => Ok(Command::new(CommandType::Call(
+                name.to_string(),
+                args.iter().map(|arg| arg.to_string()).collect(),
+            ))),
+            ["APPLY", path @ ..] if !path.is_empty() => {
+                Ok(Command::new(CommandType::Apply(path.join(" "))))
+            }
+            [keyword, args @ ..] => match options.custom.and_then(|custom| custom.name(keyword)) {
+                Some(name) => Ok(Command::new(CommandType::Custom(
+                    name,
+                    args.iter().map(|arg| arg.to_string()).collect(),
+                ))),
+                None => Err(format!("Invalid command: {}", trimmed)),
+            },
+            [] => Err(format!("Invalid command: {}", trimmed)),
+        }?;
+
+        command.idempotency_key = idempotency_key;
+        command.fresh = fresh;
+        Ok(command)
+    }
+}
+
+/// Parse the `step; step; ... END` body of `DEFINE name AS ...`
+fn parse_define(
+    name: &str,
+    body: &[&str],
+    options: ParseOptions<'_>,
+) -> Result<CommandType, String> {
+    let [steps @ .., "END"] = body else {
+        return Err("DEFINE requires steps ending in END".to_string());
+    };
+    let steps = steps.join(" ");
+    let steps: Vec<&str> = match steps.as_str() {
+        "" => Vec::new(),
+        steps => steps.split(';').collect(),
+    };
+    let procedure = Procedure::compile(&steps, options)
+        .map_err(|err| format!("Invalid procedure {}: {}", name, err))?;
+    Ok(CommandType::Define(name.to_string(), procedure))
+}
+
+/// Parse `dest src... STRATEGY <name> [SEPARATOR <sep>] [STRICT]`
+///
+/// The clauses after the strategy may come in either order.
+fn parse_merge(args: &[&str]) -> Result<CommandType, String> {
+    let strategy_at = args
+        .iter()
+        .position(|arg| *arg == "STRATEGY")
+        .ok_or("MERGE requires a STRATEGY clause")?;
+    let (keys, clauses) = args.split_at(strategy_at);
+    let [dest, sources @ ..] = keys else {
+        return Err("MERGE requires a destination key".to_string());
+    };
+    if sources.is_empty() {
+        return Err("MERGE requires at least one source key".to_string());
+    }
+
+    let mut strategy = match clauses.get(1) {
+        Some(&"concat") => MergeStrategy::Concat(String::new()),
+        Some(&"sum") => MergeStrategy::Sum,
+        Some(&"max") => MergeStrategy::Max,
+        Some(&"min") => MergeStrategy::Min,
+        Some(other) => {
+            return Err(format!(
+                "Invalid STRATEGY '{}': expected concat, sum, max or min",
+                other
+            ));
         }
+        None => return Err("STRATEGY requires a value".to_string()),
+    };
+
+    let mut strict = false;
+    let mut rest = &clauses[2..];
+    while !rest.is_empty() {
+        rest = match rest {
+            ["STRICT", tail @ ..] if !strict => {
+                strict = true;
+                tail
+            }
+            ["SEPARATOR", separator, tail @ ..] => match &mut strategy {
+                MergeStrategy::Concat(current) if current.is_empty() => {
+                    *current = separator.to_string();
+                    tail
+                }
+                MergeStrategy::Concat(_) => return Err("Duplicate SEPARATOR".to_string()),
+                _ => return Err("SEPARATOR only applies to STRATEGY concat".to_string()),
+            },
+            [other, ..] => return Err(format!("Unexpected MERGE argument '{}'", other)),
+            [] => unreachable!(),
+        };
     }
+
+    Ok(CommandType::Merge(
+        dest.to_string(),
+        sources.iter().map(|source| source.to_string()).collect(),
+        strategy,
+        strict,
+    ))
+}
+
+/// Keys returned per SCAN call when no COUNT is given
+pub const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// Keys listed by HOTKEYS when no count is given
+pub const DEFAULT_HOT_KEYS_COUNT: usize = 10;
+
+/// Slot ranges SLOTSTATS reports when no count is given
+pub const DEFAULT_SLOT_RANGES: usize = 16;
+
+/// Encode the last key of a SCAN batch as the cursor for the next call
+///
+/// Cursors are the key's bytes in hex, which can never collide with the
+/// `0` that starts (and ends) a scan.
+pub fn encode_scan_cursor(last_key: &str) -> String {
+    last_key.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode a SCAN cursor: `0` starts from the beginning
+fn decode_scan_cursor(cursor: &str) -> Result<Option<String>, String> {
+    if cursor == "0" {
+        return Ok(None);
+    }
+
+    let invalid = || format!("Invalid SCAN cursor '{}'", cursor);
+    if !cursor.len().is_multiple_of(2) {
+        return Err(invalid());
+    }
+    let bytes = (0..cursor.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(cursor.get(i..i + 2).ok_or_else(invalid)?, 16).map_err(|_| invalid())
+        })
+        .collect::<Result<Vec<u8>, String>>()?;
+    String::from_utf8(bytes).map(Some).map_err(|_| invalid())
+}
+
+/// Parse a (possibly negative) list index
+fn parse_index(raw: &str) -> Result<i64, String> {
+    raw.parse()
+        .map_err(|_| format!("Invalid index '{}': expected an integer", raw))
 }
 
 #[cfg(test)]
@@ -69,6 +1004,82 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_getwithttl_command() {
+        let cmd: Command = "GETWITHTTL session:9".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::GetWithTtl("session:9".to_string())
+        );
+        assert!("GETWITHTTL".parse::<Command>().is_err());
+        assert!("GETWITHTTL a b".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_inspect_command() {
+        let cmd: Command = "INSPECT session:9".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Inspect("session:9".to_string())
+        );
+        assert!(!cmd.command_type.is_write());
+        assert!("INSPECT".parse::<Command>().is_err());
+        assert!("INSPECT a b".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_getat_command() {
+        let cmd: Command = "GETAT session:9 3".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::GetAt("session:9".to_string(), 3)
+        );
+        assert!(!cmd.command_type.is_write());
+        assert_eq!(
+            "GETAT session:9 -1".parse::<Command>().unwrap_err(),
+            "Invalid version '-1': expected a non-negative integer"
+        );
+        assert!("GETAT session:9".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_keyslot_and_slotstats_commands() {
+        let cmd: Command = "KEYSLOT {user:1}:cart".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::KeySlot("{user:1}:cart".to_string())
+        );
+        assert!(!cmd.command_type.is_write());
+        // The key is only hashed, never read
+        assert_eq!(cmd.command_type.primary_key(), None);
+
+        let cmd: Command = "SLOTSTATS".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::SlotStats(DEFAULT_SLOT_RANGES)
+        );
+        let cmd: Command = "SLOTSTATS 16384".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::SlotStats(16384));
+        for bad in ["SLOTSTATS 0", "SLOTSTATS 16385", "SLOTSTATS many"] {
+            assert!(bad.parse::<Command>().is_err(), "{}", bad);
+        }
+        assert!("KEYSLOT".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_jget_command() {
+        let cmd: Command = "JGET profile:7 user.addresses[0].city".parse().unwrap();
+        assert_eq!(
+            cmd.command_type.describe(),
+            "JGET key=profile:7 path=user.addresses[0].city"
+        );
+        assert!("JGET profile:7".parse::<Command>().is_err());
+        assert!("JGET profile:7 a. b".parse::<Command>().is_err());
+
+        let err = "JGET k user..name".parse::<Command>().unwrap_err();
+        assert_eq!(err, "Invalid path 'user..name': empty member name");
+    }
+
     #[test]
     fn test_parse_delete_command() {
         let cmd: Command = "DELETE user:1001".parse().unwrap();
@@ -78,6 +1089,19 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_parse_undelete_and_purge() {
+        let cmd: Command = "UNDELETE user:1001".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Undelete("user:1001".to_string())
+        );
+        let cmd: Command = "PURGE user:*".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Purge("user:*".to_string()));
+        assert!(cmd.command_type.is_write());
+        assert!("PURGE".parse::<Command>().is_err());
+    }
+
     #[test]
     fn test_parse_empty_line() {
         let result: Result<Command, String> = "".parse();
@@ -101,4 +1125,704 @@ mod tests {
             _ => panic!("Expected Set command"),
         }
     }
+
+    #[test]
+    fn test_parse_lmove_command() {
+        let cmd: Command = "LMOVE src dst LEFT RIGHT".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::LMove(
+                "src".to_string(),
+                "dst".to_string(),
+                "LEFT".to_string(),
+                "RIGHT".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_parse_lmove_invalid_direction() {
+        let result: Result<Command, String> = "LMOVE src dst UP RIGHT".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_ltrim_command() {
+        let cmd: Command = "LTRIM log 0 -1".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::LTrim("log".to_string(), 0, -1)
+        );
+
+        let result: Result<Command, String> = "LTRIM log zero 5".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_appendcap_command() {
+        let cmd: Command = "APPENDCAP log 16 hello world".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::AppendCap("log".to_string(), 16, "hello world".to_string())
+        );
+
+        let result: Result<Command, String> = "APPENDCAP log -1 x".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_getdefault_multi_word_default() {
+        let cmd: Command = "GETDEFAULT greeting hello there".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::GetDefault("greeting".to_string(), "hello there".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_getorset() {
+        let cmd: Command = "GETORSET greeting hello there".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::GetOrSet("greeting".to_string(), "hello there".to_string())
+        );
+        assert!(cmd.command_type.is_write());
+        assert!("GETORSET greeting".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_incrbounded_command() {
+        let cmd: Command = "INCRBOUNDED hits 10".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::IncrBounded("hits".to_string(), 10)
+        );
+
+        let result: Result<Command, String> = "INCRBOUNDED hits ten".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_incrif_command() {
+        let cmd: Command = "INCRIF hits:beta flag:beta on for all".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::IncrIf(
+                "hits:beta".to_string(),
+                "flag:beta".to_string(),
+                "on for all".to_string()
+            )
+        );
+        assert_eq!(cmd.command_type.primary_key(), Some("hits:beta"));
+        assert!(cmd.command_type.is_write());
+
+        let result: Result<Command, String> = "INCRIF hits:beta flag:beta".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_setint_and_convert() {
+        let cmd: Command = "SETINT hits -42".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::SetInt("hits".to_string(), -42)
+        );
+        assert!(cmd.command_type.is_write());
+        assert!("SETINT hits 4.5".parse::<Command>().is_err());
+        assert!(
+            "SETINT hits 9223372036854775808"
+                .parse::<Command>()
+                .is_err()
+        );
+
+        let cmd: Command = "CONVERT hits int".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Convert("hits".to_string()));
+        assert_eq!(cmd.command_type.primary_key(), Some("hits"));
+        assert!(cmd.command_type.is_write());
+        let err = "CONVERT hits FLOAT".parse::<Command>().unwrap_err();
+        assert_eq!(err, "Unsupported CONVERT target 'FLOAT': expected INT");
+    }
+
+    #[test]
+    fn test_parse_setcrc_and_getcrc() {
+        let cmd: Command = "SETCRC doc hello world".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::SetCrc("doc".to_string(), "hello world".to_string())
+        );
+
+        let cmd: Command = "GETCRC doc".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::GetCrc("doc".to_string()));
+
+        let result: Result<Command, String> = "SETCRC doc".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_renamenx_command() {
+        let cmd: Command = "RENAMENX old new".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::RenameNx("old".to_string(), "new".to_string())
+        );
+
+        let result: Result<Command, String> = "RENAMENX old".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_renameprefix_command() {
+        let cmd: Command = "RENAMEPREFIX v1: v2:".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::RenamePrefix("v1:".to_string(), "v2:".to_string())
+        );
+        assert!("RENAMEPREFIX v1:".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_fresh_clause() {
+        let cmd: Command = "GET user:1 FRESH".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Get("user:1".to_string()));
+        assert!(cmd.fresh);
+        let cmd: Command = "GETMATCHING user:* FRESH ID req-1".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::GetMatching("user:*".to_string())
+        );
+        assert!(cmd.fresh);
+        assert!(!"GET user:1".parse::<Command>().unwrap().fresh);
+
+        // Anywhere else FRESH is an ordinary token
+        let cmd: Command = "SET mood FRESH".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Set("mood".to_string(), "FRESH".to_string())
+        );
+        assert!(!cmd.fresh);
+        let cmd: Command = "GET FRESH".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Get("FRESH".to_string()));
+        assert!("DELETE k FRESH".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_idempotency_clause() {
+        let cmd: Command = "SET greeting hello world ID req-42".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Set("greeting".to_string(), "hello world".to_string())
+        );
+        assert_eq!(cmd.idempotency_key.as_deref(), Some("req-42"));
+
+        let cmd: Command = "INFO".parse().unwrap();
+        assert_eq!(cmd.idempotency_key, None);
+
+        // The clause needs a command in front of it
+        let result: Result<Command, String> = "ID req-42".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_id_is_plain_text_without_the_clause() {
+        let options = ParseOptions {
+            custom: None,
+            id_clause: false,
+        };
+        let cmd = Command::parse_with("SET k my ID 7", options).unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Set("k".to_string(), "my ID 7".to_string())
+        );
+        assert_eq!(cmd.idempotency_key, None);
+
+        let cmd = Command::parse_with("SET ID x", options).unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Set("ID".to_string(), "x".to_string())
+        );
+        assert_eq!(cmd.idempotency_key, None);
+    }
+
+    #[test]
+    fn test_parse_version_command() {
+        let cmd: Command = "VERSION".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Version);
+        assert!(!cmd.command_type.is_write());
+        assert!("VERSION full".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_snapshotid() {
+        let cmd: Command = "SNAPSHOTID".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::SnapshotId);
+        assert!(!cmd.command_type.is_write());
+        assert!("SNAPSHOTID 3".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let cmd: Command = "FINGERPRINT".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Fingerprint);
+        assert!(!cmd.command_type.is_write());
+        assert!("FINGERPRINT user:*".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_info_sections() {
+        let cmd: Command = "INFO".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Info(false));
+        let cmd: Command = "INFO quotas".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Info(true));
+        let cmd: Command = "INFO QUOTAS".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Info(true));
+        assert!("INFO memory".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_stats_commands() {
+        let cmd: Command = "STATS".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Stats);
+        let cmd: Command = "STATSRESET".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::StatsReset);
+    }
+
+    #[test]
+    fn test_parse_valuelengthstats() {
+        let cmd: Command = "VALUELENGTHSTATS".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::ValueLengthStats);
+        assert!("VALUELENGTHSTATS user:*".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_hotkeys_command() {
+        let cmd: Command = "HOTKEYS".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::HotKeys(DEFAULT_HOT_KEYS_COUNT)
+        );
+        let cmd: Command = "HOTKEYS 3".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::HotKeys(3));
+        assert!("HOTKEYS 0".parse::<Command>().is_err());
+        assert!("HOTKEYS many".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_check_command() {
+        let cmd: Command = "CHECK".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Check);
+        assert!("CHECK user:*".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_scan_command() {
+        let cmd: Command = "SCAN 0".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Scan(None, DEFAULT_SCAN_COUNT)
+        );
+
+        let cursor = encode_scan_cursor("user:7");
+        let cmd: Command = format!("SCAN {} COUNT 50", cursor).parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Scan(Some("user:7".to_string()), 50)
+        );
+
+        for bad in ["SCAN xyz", "SCAN 123", "SCAN 0 COUNT 0", "SCAN 0 COUNT"] {
+            let result: Result<Command, String> = bad.parse();
+            assert!(result.is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_sortbyvalue_command() {
+        let cmd: Command = "SORTBYVALUE score:* desc".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::SortByValue("score:*".to_string(), false)
+        );
+
+        let result: Result<Command, String> = "SORTBYVALUE score:* up".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_getmatching_command() {
+        let cmd: Command = "GETMATCHING user:*".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::GetMatching("user:*".to_string())
+        );
+        assert!(!cmd.command_type.is_write());
+        assert!("GETMATCHING".parse::<Command>().is_err());
+        assert!("GETMATCHING a b".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_prefixhistogram_command() {
+        let cmd: Command = "PREFIXHISTOGRAM :".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::PrefixHistogram(':'));
+        assert!("PREFIXHISTOGRAM ::".parse::<Command>().is_err());
+        assert!("PREFIXHISTOGRAM".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_loadjson_command() {
+        let cmd: Command = "LOADJSON /etc/app config.json".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::LoadJson("/etc/app config.json".to_string())
+        );
+        assert!(cmd.command_type.is_write());
+
+        let result: Result<Command, String> = "LOADJSON".parse();
+        assert!(result.is_err());
+
+        let cmd: Command = "DUMPJSON backup.json".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::DumpJson("backup.json".to_string())
+        );
+        // Dumping reads the store but never changes it
+        assert!(!cmd.command_type.is_write());
+        assert!(cmd.command_type.is_external_write());
+    }
+
+    #[test]
+    fn test_parse_genset() {
+        let cmd: Command = "GENSET bench: 1000".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::GenSet("bench:".to_string(), 1000)
+        );
+        assert!("GENSET bench: -1".parse::<Command>().is_err());
+        assert!("GENSET bench:".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_merge_command() {
+        let cmd: Command = "MERGE total a b STRATEGY sum".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Merge(
+                "total".to_string(),
+                vec!["a".to_string(), "b".to_string()],
+                MergeStrategy::Sum,
+                false
+            )
+        );
+
+        let cmd: Command = "MERGE all a STRATEGY concat STRICT SEPARATOR ,"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Merge(
+                "all".to_string(),
+                vec!["a".to_string()],
+                MergeStrategy::Concat(",".to_string()),
+                true
+            )
+        );
+
+        for bad in [
+            "MERGE dest a b",
+            "MERGE dest STRATEGY sum",
+            "MERGE STRATEGY sum",
+            "MERGE dest a STRATEGY",
+            "MERGE dest a STRATEGY avg",
+            "MERGE dest a STRATEGY sum SEPARATOR ,",
+            "MERGE dest a STRATEGY concat SEPARATOR",
+            "MERGE dest a STRATEGY max STRICT STRICT",
+            "MERGE dest a STRATEGY min LOUD",
+        ] {
+            let result: Result<Command, String> = bad.parse();
+            assert!(result.is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_waitkey_command() {
+        let cmd: Command = "WAITKEY job:done 1500".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::WaitKey("job:done".to_string(), 1500)
+        );
+        assert!(!cmd.command_type.is_write());
+
+        for bad in [
+            "WAITKEY job:done",
+            "WAITKEY job:done -1",
+            "WAITKEY job:done soon",
+        ] {
+            let result: Result<Command, String> = bad.parse();
+            assert!(result.is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_explain_command() {
+        let cmd: Command = "EXPLAIN SET a  b ID req-1".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Explain("SET a b ID req-1".to_string())
+        );
+        assert_eq!(cmd.idempotency_key, None);
+
+        let result: Result<Command, String> = "EXPLAIN".parse();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_define_and_call_commands() {
+        let cmd: Command = "DEFINE bump AS GET $1;INCRBOUNDED $1 $2 ;  GET $1 END ID req-1"
+            .parse()
+            .unwrap();
+        let CommandType::Define(name, procedure) = &cmd.command_type else {
+            panic!("expected DEFINE, got {:?}", cmd.command_type);
+        };
+        assert_eq!(name, "bump");
+        assert_eq!(procedure.arity(), 2);
+        assert_eq!(
+            procedure.steps().collect::<Vec<_>>(),
+            ["GET $1", "INCRBOUNDED $1 $2", "GET $1"]
+        );
+        assert_eq!(cmd.idempotency_key.as_deref(), Some("req-1"));
+        assert!(!cmd.command_type.is_write());
+
+        let cmd: Command = "CALL bump counter:x 10".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Call(
+                "bump".to_string(),
+                vec!["counter:x".to_string(), "10".to_string()]
+            )
+        );
+        assert!(cmd.command_type.is_write());
+
+        let errors = [
+            ("DEFINE bump", "DEFINE requires a name and AS"),
+            (
+                "DEFINE bump AS GET $1",
+                "DEFINE requires steps ending in END",
+            ),
+            (
+                "DEFINE bump AS END",
+                "Invalid procedure bump: a procedure needs at least one step",
+            ),
+            (
+                "DEFINE bump AS GET $1; CALL bump $1 END",
+                "Invalid procedure bump: step 2: CALL can't run inside a procedure",
+            ),
+        ];
+        for (line, expected) in errors {
+            assert_eq!(
+                line.parse::<Command>().map(|cmd| cmd.command_type),
+                Err(expected.to_string()),
+                "{}",
+                line
+            );
+        }
+        assert!("CALL".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_carriage_returns_never_reach_tokens() {
+        let cmd: Command = "SET greeting hello world\r".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Set("greeting".to_string(), "hello world".to_string())
+        );
+        let cmd: Command = "GET greeting\r\n".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Get("greeting".to_string()));
+        let cmd: Command = "SET k v ID req-1\r".parse().unwrap();
+        assert_eq!(cmd.idempotency_key.as_deref(), Some("req-1"));
+        let cmd: Command = "EXPLAIN GET greeting\r\r".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Explain("GET greeting".to_string())
+        );
+        assert!("\r".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_parse_apply_command() {
+        let cmd: Command = "APPLY /tmp/my patches/1.patch".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::Apply("/tmp/my patches/1.patch".to_string())
+        );
+        assert!(cmd.command_type.is_write());
+        assert!(!cmd.command_type.is_external_write());
+        assert!("APPLY".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn test_describe_quotes_only_when_needed() {
+        let cmd: Command = "SET key hello world".parse().unwrap();
+        assert_eq!(
+            cmd.command_type.describe(),
+            r#"SET key=key value="hello world""#
+        );
+        let cmd: Command = "MERGE d a b STRATEGY concat".parse().unwrap();
+        assert_eq!(
+            cmd.command_type.describe(),
+            r#"MERGE dest=d sources=a,b strategy=concat separator="" strict=false"#
+        );
+        assert_eq!(CommandType::Info(false).describe(), "INFO");
+        assert_eq!(CommandType::Info(true).describe(), "INFO section=quotas");
+    }
+
+    #[test]
+    fn test_display_shows_every_command_in_input_syntax() {
+        let lines = [
+            "SET user:1 Ada",
+            "GET user:1",
+            "DELETE user:1",
+            "UNDELETE user:1",
+            "PURGE user:*",
+            "LMOVE src dst LEFT RIGHT",
+            "LTRIM list 0 -1",
+            "LINDEX list -2",
+            "APPENDCAP log 64 line two",
+            "GETDEFAULT user:1 (none)",
+            "GETORSET user:1 Ada",
+            "INCRBOUNDED hits 100",
+            "INCRIF hits flag:beta on",
+            "SETCRC user:1 Ada",
+            "GETCRC user:1",
+            "GETWITHTTL user:1",
+            "INSPECT user:1",
+            "GETAT user:1 2",
+            "JGET user:1 tags[0]",
+            "RENAMENX a b",
+            "RENAMEPREFIX user: member:",
+            "SCAN 0 COUNT 10",
+            "SCAN 6b6579 COUNT 5",
+            "SORTBYVALUE user:* desc",
+            "GETMATCHING user:*",
+            "PREFIXHISTOGRAM :",
+            "KEYSLOT {user:1}:cart",
+            "SLOTSTATS 4",
+            "LOADJSON data/in.json",
+            "DUMPJSON data/out.json",
+            "MERGE d a b STRATEGY concat",
+            "MERGE d a b STRATEGY concat SEPARATOR , STRICT",
+            "MERGE d a STRATEGY sum",
+            "MERGE d a STRATEGY max",
+            "MERGE d a STRATEGY min STRICT",
+            "GENSET bench: 1000",
+            "WAITKEY job:1 250",
+            "DUMP PARALLEL /var/dumps",
+            "DUMP PARALLEL /var/dumps 8",
+            "SHUTDOWN",
+            "SHUTDOWN SAVE",
+            "EXPLAIN SET a b ID req-1",
+            "DEFINE bump AS GET $1; INCRBOUNDED $1 9 END",
+            "CALL bump hits",
+            "CALL top",
+            "APPLY fixes.patch",
+            "INFO",
+            "INFO quotas",
+            "STATS",
+            "STATSRESET",
+            "VALUELENGTHSTATS",
+            "HOTKEYS 3",
+            "CHECK",
+            "PARSESTATS",
+            "VERSION",
+            "SNAPSHOTID",
+            "FINGERPRINT",
+            "SETINT hits -3",
+            "CONVERT hits INT",
+        ];
+        for line in lines {
+            let cmd: Command = line.parse().unwrap();
+            assert_eq!(cmd.command_type.to_string(), line);
+        }
+
+        let cmd: Command = "HOTKEYS".parse().unwrap();
+        assert_eq!(cmd.command_type.to_string(), "HOTKEYS 10");
+        let cmd: Command = "SET a 1 ID req-1".parse().unwrap();
+        assert_eq!(cmd.command_type.to_string(), "SET a 1");
+        let custom = CommandType::Custom("PING", vec!["hello".to_string(), "there".to_string()]);
+        assert_eq!(custom.to_string(), "PING hello there");
+        assert_eq!(CommandType::Custom("PING", Vec::new()).to_string(), "PING");
+    }
+
+    #[test]
+    fn test_display_shortens_long_values() {
+        let long = "x".repeat(100);
+        let shown = format!("{}…", "x".repeat(DISPLAY_VALUE_CHARS));
+        let exact = "y".repeat(DISPLAY_VALUE_CHARS);
+
+        let cases = [
+            (
+                CommandType::Set("k".to_string(), long.clone()),
+                format!("SET k {}", shown),
+            ),
+            (
+                CommandType::Set("k".to_string(), exact.clone()),
+                format!("SET k {}", exact),
+            ),
+            (
+                CommandType::SetCrc("k".to_string(), long.clone()),
+                format!("SETCRC k {}", shown),
+            ),
+            (
+                CommandType::AppendCap("k".to_string(), 500, long.clone()),
+                format!("APPENDCAP k 500 {}", shown),
+            ),
+            (
+                CommandType::GetDefault("k".to_string(), long.clone()),
+                format!("GETDEFAULT k {}", shown),
+            ),
+            (
+                CommandType::Explain(long.clone()),
+                format!("EXPLAIN {}", shown),
+            ),
+            (
+                CommandType::Custom("PING", vec![long.clone()]),
+                format!("PING {}", shown),
+            ),
+            // Keys are never cut, so logs still say which key a command hit
+            (CommandType::Get(long.clone()), format!("GET {}", long)),
+        ];
+        for (command, expected) in cases {
+            assert_eq!(command.to_string(), expected);
+        }
+
+        // Cuts land on character boundaries, not bytes
+        let accented = "é".repeat(DISPLAY_VALUE_CHARS + 1);
+        assert_eq!(
+            CommandType::Set("k".to_string(), accented).to_string(),
+            format!("SET k {}…", "é".repeat(DISPLAY_VALUE_CHARS))
+        );
+    }
+
+    #[test]
+    fn test_parse_shutdown_command() {
+        let cmd: Command = "DUMP PARALLEL /var/dumps 8".parse().unwrap();
+        assert_eq!(
+            cmd.command_type,
+            CommandType::DumpParallel("/var/dumps".to_string(), Some(8))
+        );
+        assert!(cmd.command_type.is_external_write());
+        let cmd: Command = "DUMP PARALLEL out".parse().unwrap();
+        assert_eq!(
+            cmd.command_type.describe(),
+            "DUMP dir=out threads=\"(per cpu)\""
+        );
+        assert!("DUMP PARALLEL out 0".parse::<Command>().is_err());
+        assert!("DUMP out".parse::<Command>().is_err());
+
+        let cmd: Command = "SHUTDOWN".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Shutdown(false));
+        let cmd: Command = "SHUTDOWN SAVE".parse().unwrap();
+        assert_eq!(cmd.command_type, CommandType::Shutdown(true));
+        assert!(cmd.command_type.is_external_write());
+
+        let result: Result<Command, String> = "SHUTDOWN NOW".parse();
+        assert!(result.is_err());
+    }
 }
@@ -0,0 +1,260 @@
+//! Stored procedures: named command sequences run as one (DEFINE, CALL)
+//!
+//! `DEFINE name AS step; step; ... END` keeps each step as a `Template`;
+//! `CALL name args...` puts the arguments into every step, parses them all,
+//! and only then runs them one after another on the main thread. No other
+//! command runs between the steps, so a CALL is atomic with respect to
+//! other traffic, but a failing step doesn't undo the steps before it.
+//!
+//! Definitions can also be loaded at startup from `PROCEDURES_FILE`, one
+//! DEFINE line each; a DEFINE at runtime lasts until the process exits.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::macros::{self, Template};
+use crate::parser::{Command, CommandType, ParseOptions};
+
+/// Most steps one procedure may have
+pub const MAX_STEPS: usize = 16;
+
+/// Keywords a step can't use: procedures don't nest, a step can't stop the
+/// main thread out from under the rest of the call, and a step has no way
+/// to block waiting for a key
+const FORBIDDEN_STEPS: [&str; 4] = ["CALL", "DEFINE", "SHUTDOWN", "WAITKEY"];
+
+/// A compiled procedure
+#[derive(Debug, Clone, PartialEq)]
+pub struct Procedure {
+    steps: Vec<Template>,
+    /// Arguments a CALL must pass: the highest reference in any step
+    arity: usize,
+}
+
+impl Procedure {
+    /// Compile `steps`, checking that each parses with `1` for every
+    /// argument and that there aren't too many
+    pub fn compile(steps: &[&str], options: ParseOptions<'_>) -> Result<Self, String> {
+        if steps.is_empty() {
+            return Err("a procedure needs at least one step".to_string());
+        }
+        if steps.len() > MAX_STEPS {
+            return Err(format!(
+                "{} steps is more than the limit of {}",
+                steps.len(),
+                MAX_STEPS
+            ));
+        }
+        let steps = steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                Template::parse(step).map_err(|err| format!("step {}: {}", i + 1, err))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let arity = macros::arity_of(&steps)?;
+
+        for (i, step) in steps.iter().enumerate() {
+            let keyword = step.keyword();
+            if FORBIDDEN_STEPS.contains(&keyword) {
+                return Err(format!(
+                    "step {}: {} can't run inside a procedure",
+                    i + 1,
+                    keyword
+                ));
+            }
+            let command = step
+                .check_parses(arity, options)
+                .map_err(|err| format!("step {}: {}", i + 1, err))?;
+            if command.idempotency_key.is_some() {
+                return Err(format!(
+                    "step {}: an ID clause belongs on the CALL, not a step",
+                    i + 1
+                ));
+            }
+        }
+        Ok(Self { steps, arity })
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    /// The steps as written
+    pub fn steps(&self) -> impl Iterator<Item = &str> {
+        self.steps.iter().map(Template::as_str)
+    }
+
+    /// The commands `CALL name args...` runs, or why it can't run
+    ///
+    /// Every step is parsed before any runs, so an argument that makes a
+    /// step invalid fails the whole call without applying anything.
+    pub fn expand(
+        &self,
+        name: &str,
+        args: &[String],
+        options: ParseOptions<'_>,
+    ) -> Result<Vec<CommandType>, String> {
+        if args.len() != self.arity {
+            return Err(format!(
+                "Procedure {} expects {} argument{}, got {}",
+                name,
+                self.arity,
+                if self.arity == 1 { "" } else { "s" },
+                args.len()
+            ));
+        }
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        self.steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                let line = step.substitute(&args);
+                match Command::parse_with(&line, options) {
+                    Ok(command) => Ok(command.command_type),
+                    Err(err) => Err(format!(
+                        "Procedure {} step {} '{}': {}",
+                        name,
+                        i + 1,
+                        line,
+                        err
+                    )),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Load the procedures defined in a file of DEFINE lines
+///
+/// Blank lines and lines starting with `#` are skipped. Any other line
+/// that isn't a valid DEFINE is an error naming it. The file is read with
+/// the config, before any handler exists, so its steps can use built-in
+/// commands only.
+pub fn load(path: &Path) -> Result<HashMap<String, Procedure>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+    let mut procedures = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let at = |err: String| format!("{}:{}: {}", path.display(), i + 1, err);
+        match line.parse::<Command>().map_err(at)?.command_type {
+            CommandType::Define(name, procedure) => {
+                procedures.insert(name, procedure);
+            }
+            other => return Err(at(format!("expected DEFINE, found {}", other.name()))),
+        }
+    }
+    Ok(procedures)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_substitutes_arguments_into_every_step() {
+        let procedure = Procedure::compile(
+            &["GET $1", "INCRBOUNDED $1 $2", "RENAMENX $1 $1:old"],
+            ParseOptions::default(),
+        )
+        .unwrap();
+        assert_eq!(procedure.arity(), 2);
+        let commands = procedure
+            .expand(
+                "bump",
+                &["counter:x".to_string(), "10".to_string()],
+                ParseOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(
+            commands,
+            [
+                CommandType::Get("counter:x".to_string()),
+                CommandType::IncrBounded("counter:x".to_string(), 10),
+                CommandType::RenameNx("counter:x".to_string(), "counter:x:old".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_expand_checks_arguments_before_running_anything() {
+        let procedure =
+            Procedure::compile(&["GET $1", "INCRBOUNDED $1 $2"], ParseOptions::default()).unwrap();
+        assert_eq!(
+            procedure.expand("bump", &["a".to_string()], ParseOptions::default()),
+            Err("Procedure bump expects 2 arguments, got 1".to_string())
+        );
+        assert_eq!(
+            procedure.expand(
+                "bump",
+                &["a".to_string(), "ten".to_string()],
+                ParseOptions::default()
+            ),
+            Err(
+                "Procedure bump step 2 'INCRBOUNDED a ten': Invalid max 'ten': expected an integer"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_compile_rejects_bad_steps() {
+        let too_many = vec!["INFO"; MAX_STEPS + 1];
+        let cases: [(&[&str], &str); 6] = [
+            (&[], "a procedure needs at least one step"),
+            (&too_many, "17 steps is more than the limit of 16"),
+            (
+                &["GET $1", "CALL other $1"],
+                "step 2: CALL can't run inside a procedure",
+            ),
+            (
+                &["SHUTDOWN"],
+                "step 1: SHUTDOWN can't run inside a procedure",
+            ),
+            (
+                &["GET $1", "GET $3"],
+                "unknown positional reference $3 ($2 is never used)",
+            ),
+            (
+                &["SET $1 v ID req-1"],
+                "step 1: an ID clause belongs on the CALL, not a step",
+            ),
+        ];
+        for (steps, expected) in cases {
+            assert_eq!(
+                Procedure::compile(steps, ParseOptions::default()),
+                Err(expected.to_string()),
+                "{:?}",
+                steps
+            );
+        }
+    }
+
+    #[test]
+    fn test_load_reads_define_lines() {
+        let path =
+            std::env::temp_dir().join(format!("crabby_procedures_{}.txt", std::process::id()));
+        std::fs::write(
+            &path,
+            "# counters\nDEFINE bump AS INCRBOUNDED $1 100; GET $1 END\n\nDEFINE top AS HOTKEYS 3 END\n",
+        )
+        .unwrap();
+        let procedures = load(&path).unwrap();
+        let mut names: Vec<&String> = procedures.keys().collect();
+        names.sort();
+        assert_eq!(names, ["bump", "top"]);
+        assert_eq!(
+            procedures["bump"].steps().collect::<Vec<_>>(),
+            ["INCRBOUNDED $1 100", "GET $1"]
+        );
+
+        std::fs::write(&path, "DEFINE ok AS INFO END\nGET a\n").unwrap();
+        let err = load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.ends_with(":2: expected DEFINE, found GET"), "{}", err);
+    }
+}
@@ -0,0 +1,327 @@
+//! What the main thread applies commands with
+//!
+//! `CommandHandler` is the default processor. A pool can be built with
+//! another one instead, for example one that forwards some key prefixes to
+//! an external system and hands the rest to a `CommandHandler`. Only
+//! `process` is required; the other methods back snapshots, dry runs,
+//! WAITKEY, and read snapshots, and default to a processor with no store of
+//! its own.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Instant;
+
+use crate::handler::{CommandHandler, HandlerError, Tombstones, Value};
+use crate::import_report::ImportReport;
+use crate::parser::Command;
+use crate::thread::DIRECT_SUBMISSION;
+
+/// Where a command came from, handed to the processor alongside it
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageContext {
+    /// The input line, or a per-engine call count for direct submissions
+    pub line_number: usize,
+    /// The IO thread that parsed the line, or `DIRECT_SUBMISSION`
+    pub io_thread_id: usize,
+    /// When the command was handed to the main thread
+    pub enqueued_at: Instant,
+    /// The command's trace id, if its line had one or trace ids are shown
+    pub trace_id: Option<String>,
+}
+
+impl MessageContext {
+    /// The input line the command came from, if it came from input at all
+    pub fn input_line(&self) -> Option<usize> {
+        (self.io_thread_id != DIRECT_SUBMISSION).then_some(self.line_number)
+    }
+}
+
+/// Applies commands on the main thread
+pub trait CommandProcessor: Send {
+    fn process(&mut self, command: Command, ctx: &MessageContext) -> Result<String, HandlerError>;
+
+    /// Number of keys in the store
+    fn key_count(&self) -> usize {
+        0
+    }
+
+    /// Whether `key` is in the store, checked to wake WAITKEY callers
+    fn contains_key(&self, _key: &str) -> bool {
+        false
+    }
+
+    /// Replace the store's contents with keys restored from a snapshot
+    fn restore(&mut self, _store: HashMap<String, Value>) {}
+
+    /// Replace the soft-deleted keys with ones restored from a snapshot
+    fn restore_tombstones(&mut self, _tombstones: Tombstones) {}
+
+    /// The `n` most touched keys with approximate command counts, for the
+    /// run summary
+    fn hot_keys(&self, _n: usize) -> Vec<(String, u64)> {
+        Vec::new()
+    }
+
+    /// How much the run's SET-like writes overwrote, when an import report
+    /// was asked for
+    fn import_report(&self) -> Option<ImportReport> {
+        None
+    }
+
+    /// Write the store to the snapshot path, returning the number of keys
+    fn save_snapshot(&self) -> Result<usize, HandlerError> {
+        Err(HandlerError::SaveFailed(
+            "this processor has no store to save".to_string(),
+        ))
+    }
+
+    /// An independent copy for a dry run to apply commands to, or `None` if
+    /// this processor can't run dry
+    fn shadow(&self) -> Option<Box<dyn CommandProcessor>> {
+        None
+    }
+
+    /// A counter that moves whenever the store changes, or `None` if reads
+    /// can't be served from a copy of this processor's store
+    fn snapshot_id(&self) -> Option<u64> {
+        None
+    }
+
+    /// A copy of the store for a read snapshot to serve reads from
+    fn store_copy(&self) -> Option<HashMap<String, Value>> {
+        None
+    }
+}
+
+impl CommandProcessor for CommandHandler {
+    fn process(&mut self, command: Command, ctx: &MessageContext) -> Result<String, HandlerError> {
+        let result = self.process_command(command);
+        match ctx.input_line() {
+            Some(line) => result.map_err(|err| err.at_line(line)),
+            None => result,
+        }
+    }
+
+    fn key_count(&self) -> usize {
+        CommandHandler::key_count(self)
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        CommandHandler::contains_key(self, key)
+    }
+
+    fn restore(&mut self, store: HashMap<String, Value>) {
+        CommandHandler::restore(self, store)
+    }
+
+    fn restore_tombstones(&mut self, tombstones: Tombstones) {
+        CommandHandler::restore_tombstones(self, tombstones)
+    }
+
+    fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        CommandHandler::hot_keys(self, n)
+    }
+
+    fn import_report(&self) -> Option<ImportReport> {
+        CommandHandler::import_report(self)
+    }
+
+    fn save_snapshot(&self) -> Result<usize, HandlerError> {
+        CommandHandler::save_snapshot(self)
+    }
+
+    fn shadow(&self) -> Option<Box<dyn CommandProcessor>> {
+        Some(Box::new(self.snapshot()))
+    }
+
+    fn snapshot_id(&self) -> Option<u64> {
+        Some(CommandHandler::snapshot_id(self))
+    }
+
+    fn store_copy(&self) -> Option<HashMap<String, Value>> {
+        Some(CommandHandler::store_copy(self))
+    }
+}
+
+impl<P: CommandProcessor + ?Sized> CommandProcessor for Box<P> {
+    fn process(&mut self, command: Command, ctx: &MessageContext) -> Result<String, HandlerError> {
+        (**self).process(command, ctx)
+    }
+
+    fn key_count(&self) -> usize {
+        (**self).key_count()
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        (**self).contains_key(key)
+    }
+
+    fn restore(&mut self, store: HashMap<String, Value>) {
+        (**self).restore(store)
+    }
+
+    fn restore_tombstones(&mut self, tombstones: Tombstones) {
+        (**self).restore_tombstones(tombstones)
+    }
+
+    fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        (**self).hot_keys(n)
+    }
+
+    fn import_report(&self) -> Option<ImportReport> {
+        (**self).import_report()
+    }
+
+    fn save_snapshot(&self) -> Result<usize, HandlerError> {
+        (**self).save_snapshot()
+    }
+
+    fn shadow(&self) -> Option<Box<dyn CommandProcessor>> {
+        (**self).shadow()
+    }
+
+    fn snapshot_id(&self) -> Option<u64> {
+        (**self).snapshot_id()
+    }
+
+    fn store_copy(&self) -> Option<HashMap<String, Value>> {
+        (**self).store_copy()
+    }
+}
+
+/// Every command a `RecordingProcessor` saw, with the context it came with
+pub type CommandLog = Arc<Mutex<Vec<(MessageContext, Command)>>>;
+
+/// Passes commands on to another processor, keeping a copy of each
+///
+/// Meant for tests: take `log()` before handing the processor to a pool and
+/// read it once the main thread has finished.
+pub struct RecordingProcessor<P = CommandHandler> {
+    inner: P,
+    log: CommandLog,
+}
+
+impl<P: CommandProcessor> RecordingProcessor<P> {
+    pub fn new(inner: P) -> Self {
+        Self {
+            inner,
+            log: CommandLog::default(),
+        }
+    }
+
+    /// Shared handle to the commands seen so far
+    pub fn log(&self) -> CommandLog {
+        Arc::clone(&self.log)
+    }
+}
+
+impl<P: CommandProcessor> CommandProcessor for RecordingProcessor<P> {
+    fn process(&mut self, command: Command, ctx: &MessageContext) -> Result<String, HandlerError> {
+        self.log
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((ctx.clone(), command.clone()));
+        self.inner.process(command, ctx)
+    }
+
+    fn key_count(&self) -> usize {
+        self.inner.key_count()
+    }
+
+    fn contains_key(&self, key: &str) -> bool {
+        self.inner.contains_key(key)
+    }
+
+    fn restore(&mut self, store: HashMap<String, Value>) {
+        self.inner.restore(store)
+    }
+
+    fn restore_tombstones(&mut self, tombstones: Tombstones) {
+        self.inner.restore_tombstones(tombstones)
+    }
+
+    fn hot_keys(&self, n: usize) -> Vec<(String, u64)> {
+        self.inner.hot_keys(n)
+    }
+
+    fn import_report(&self) -> Option<ImportReport> {
+        self.inner.import_report()
+    }
+
+    fn save_snapshot(&self) -> Result<usize, HandlerError> {
+        self.inner.save_snapshot()
+    }
+
+    /// A dry run records into the same log
+    fn shadow(&self) -> Option<Box<dyn CommandProcessor>> {
+        let inner = self.inner.shadow()?;
+        Some(Box::new(RecordingProcessor {
+            inner,
+            log: Arc::clone(&self.log),
+        }))
+    }
+
+    fn snapshot_id(&self) -> Option<u64> {
+        self.inner.snapshot_id()
+    }
+
+    fn store_copy(&self) -> Option<HashMap<String, Value>> {
+        self.inner.store_copy()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recording_processor_logs_and_delegates() {
+        let mut processor = RecordingProcessor::new(CommandHandler::new());
+        let log = processor.log();
+        let ctx = |line_number| MessageContext {
+            line_number,
+            io_thread_id: 0,
+            enqueued_at: Instant::now(),
+            trace_id: None,
+        };
+
+        let set = "SET a 1".parse().unwrap();
+        assert_eq!(processor.process(set, &ctx(1)).unwrap(), "SET a = 1");
+        let mut shadow = processor.shadow().unwrap();
+        let get = "GET a".parse().unwrap();
+        assert_eq!(shadow.process(get, &ctx(2)).unwrap(), "GET a = 1");
+        let del = "DELETE a".parse().unwrap();
+        shadow.process(del, &ctx(3)).unwrap();
+
+        // The shadow's delete never reached the original
+        assert!(processor.contains_key("a"));
+        let lines: Vec<usize> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ctx, _)| ctx.line_number)
+            .collect();
+        assert_eq!(lines, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_misses_name_their_input_line() {
+        let mut handler = CommandHandler::new();
+        let mut ctx = MessageContext {
+            line_number: 532,
+            io_thread_id: 1,
+            enqueued_at: Instant::now(),
+            trace_id: None,
+        };
+
+        let get: Command = "GET x".parse().unwrap();
+        let err = handler.process(get.clone(), &ctx).unwrap_err();
+        assert_eq!(err.to_string(), "Key 'x' not found (line 532)");
+        assert!(err.is_soft());
+
+        // A direct submission's count isn't an input line
+        ctx.io_thread_id = DIRECT_SUBMISSION;
+        let err = handler.process(get, &ctx).unwrap_err();
+        assert_eq!(err.to_string(), "Key 'x' not found");
+    }
+}
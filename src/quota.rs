@@ -0,0 +1,406 @@
+//! Per-prefix key-count and byte quotas (`LIMIT_PREFIX_<prefix>*_MAX_KEYS`,
+//! `LIMIT_PREFIX_<prefix>*_MAX_BYTES`)
+//!
+//! Each rule caps how many keys, and how many bytes of keys plus values,
+//! may live under one key prefix. A key counts against the rule with the
+//! longest prefix it starts with, found by walking a byte trie compiled at
+//! startup, and against no other. Commands check the writes they are about
+//! to make and fail without writing if one would push a rule past its
+//! limit; writes that shrink a rule's usage are always allowed, even over
+//! a limit.
+
+use std::collections::HashMap;
+use std::fmt;
+
+/// Environment variable prefix of a rule; the rest of the name is its
+/// pattern followed by one of the limit suffixes
+pub const ENV_PREFIX: &str = "LIMIT_PREFIX_";
+
+const MAX_KEYS_SUFFIX: &str = "_MAX_KEYS";
+const MAX_BYTES_SUFFIX: &str = "_MAX_BYTES";
+
+/// One `prefix*` rule: its limits and what its keys use now
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuotaRule {
+    prefix: String,
+    max_keys: Option<u64>,
+    max_bytes: Option<u64>,
+    keys: u64,
+    bytes: u64,
+}
+
+impl QuotaRule {
+    /// The rule's pattern as written in the environment, `prefix*`
+    pub fn pattern(&self) -> String {
+        format!("{}*", self.prefix)
+    }
+
+    pub fn max_keys(&self) -> Option<u64> {
+        self.max_keys
+    }
+
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+
+    /// Keys currently under the rule
+    pub fn keys(&self) -> u64 {
+        self.keys
+    }
+
+    /// Bytes of keys and values currently under the rule
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl fmt::Display for QuotaRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let limit =
+            |max: Option<u64>| max.map_or_else(|| "unlimited".to_string(), |n| n.to_string());
+        write!(
+            f,
+            "{} keys={}/{} bytes={}/{}",
+            self.pattern(),
+            self.keys,
+            limit(self.max_keys),
+            self.bytes,
+            limit(self.max_bytes)
+        )
+    }
+}
+
+/// A trie node: children by next key byte, and the rule whose prefix ends
+/// here
+#[derive(Debug, Clone, Default, PartialEq)]
+struct TrieNode {
+    children: Vec<(u8, usize)>,
+    rule: Option<usize>,
+}
+
+/// The compiled rules, their usage, and the keys a command has touched
+/// since usage was last settled
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PrefixQuotas {
+    /// Sorted by prefix
+    rules: Vec<QuotaRule>,
+    /// Node 0 is the root
+    trie: Vec<TrieNode>,
+    /// Touched keys and the bytes they held before the first touch
+    pending: HashMap<String, Option<u64>>,
+}
+
+impl PrefixQuotas {
+    /// Compile rules from `(pattern, max_keys, max_bytes)` triples
+    ///
+    /// A pattern must be a literal prefix followed by a single `*`; rules
+    /// are matched by prefix, not as globs.
+    pub fn compile<'a>(
+        rules: impl IntoIterator<Item = (&'a str, Option<u64>, Option<u64>)>,
+    ) -> Result<Self, String> {
+        let mut compiled = rules
+            .into_iter()
+            .map(|(pattern, max_keys, max_bytes)| {
+                let prefix = pattern
+                    .strip_suffix('*')
+                    .filter(|prefix| !prefix.contains(['*', '?', '[', '\\']))
+                    .ok_or_else(|| {
+                        format!(
+                            "Invalid quota pattern '{}' in {}{}: expected a literal prefix ending in '*'",
+                            pattern, ENV_PREFIX, pattern
+                        )
+                    })?;
+                Ok(QuotaRule {
+                    prefix: prefix.to_string(),
+                    max_keys,
+                    max_bytes,
+                    keys: 0,
+                    bytes: 0,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        compiled.sort_by(|a, b| a.prefix.cmp(&b.prefix));
+
+        let mut trie = vec![TrieNode::default()];
+        for (index, rule) in compiled.iter().enumerate() {
+            let mut node = 0;
+            for &byte in rule.prefix.as_bytes() {
+                node = match trie[node].children.iter().find(|(b, _)| *b == byte) {
+                    Some(&(_, child)) => child,
+                    None => {
+                        trie.push(TrieNode::default());
+                        let child = trie.len() - 1;
+                        trie[node].children.push((byte, child));
+                        child
+                    }
+                };
+            }
+            trie[node].rule = Some(index);
+        }
+        Ok(Self {
+            rules: compiled,
+            trie,
+            pending: HashMap::new(),
+        })
+    }
+
+    /// Compile every `LIMIT_PREFIX_<pattern>_MAX_KEYS` and `_MAX_BYTES`
+    /// variable in `vars`; both limits of a pattern form one rule
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self, String> {
+        let mut limits: Vec<(String, Option<u64>, Option<u64>)> = Vec::new();
+        for (name, raw) in vars {
+            let Some(rest) = name.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let (pattern, is_keys) = if let Some(pattern) = rest.strip_suffix(MAX_KEYS_SUFFIX) {
+                (pattern, true)
+            } else if let Some(pattern) = rest.strip_suffix(MAX_BYTES_SUFFIX) {
+                (pattern, false)
+            } else {
+                return Err(format!(
+                    "Invalid quota variable {}: expected a name ending in {} or {}",
+                    name, MAX_KEYS_SUFFIX, MAX_BYTES_SUFFIX
+                ));
+            };
+            let limit: u64 = raw
+                .trim()
+                .parse()
+                .map_err(|_| format!("Invalid limit '{}' for {}: expected a count", raw, name))?;
+            let index = match limits.iter().position(|(p, _, _)| p == pattern) {
+                Some(index) => index,
+                None => {
+                    limits.push((pattern.to_string(), None, None));
+                    limits.len() - 1
+                }
+            };
+            if is_keys {
+                limits[index].1 = Some(limit);
+            } else {
+                limits[index].2 = Some(limit);
+            }
+        }
+        Self::compile(
+            limits
+                .iter()
+                .map(|(pattern, keys, bytes)| (pattern.as_str(), *keys, *bytes)),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Every rule, in prefix order
+    pub fn rules(&self) -> &[QuotaRule] {
+        &self.rules
+    }
+
+    /// The rule with the longest prefix `key` starts with
+    fn rule_for(&self, key: &str) -> Option<usize> {
+        let mut node = 0;
+        let mut found = self.trie.first()?.rule;
+        for &byte in key.as_bytes() {
+            match self.trie[node].children.iter().find(|(b, _)| *b == byte) {
+                Some(&(_, child)) => node = child,
+                None => break,
+            }
+            found = self.trie[node].rule.or(found);
+        }
+        found
+    }
+
+    /// Check a set of changes about to be made, each a key with the value
+    /// bytes it holds now and will hold after (`None` where it doesn't
+    /// exist)
+    ///
+    /// Returns the limit that would be exceeded on failure.
+    pub fn check<'a>(
+        &self,
+        changes: impl IntoIterator<Item = (&'a str, Option<u64>, Option<u64>)>,
+    ) -> Result<(), String> {
+        let mut deltas: HashMap<usize, (i64, i64)> = HashMap::new();
+        for (key, before, after) in changes {
+            let Some(rule) = self.rule_for(key) else {
+                continue;
+            };
+            let delta = deltas.entry(rule).or_default();
+            delta.0 += i64::from(after.is_some()) - i64::from(before.is_some());
+            delta.1 += entry_bytes(key, after) as i64 - entry_bytes(key, before) as i64;
+        }
+        let mut touched: Vec<_> = deltas.into_iter().collect();
+        touched.sort_unstable_by_key(|(rule, _)| *rule);
+        for (index, (keys, bytes)) in touched {
+            let rule = &self.rules[index];
+            if let Some(max) = rule.max_keys
+                && keys > 0
+                && rule.keys.saturating_add_signed(keys) > max
+            {
+                return Err(format!("'{}' allows at most {} keys", rule.pattern(), max));
+            }
+            if let Some(max) = rule.max_bytes
+                && bytes > 0
+                && rule.bytes.saturating_add_signed(bytes) > max
+            {
+                return Err(format!("'{}' allows at most {} bytes", rule.pattern(), max));
+            }
+        }
+        Ok(())
+    }
+
+    /// Note that `key`, currently holding `bytes` of value (`None` if
+    /// absent), is about to change; `settle` accounts for the change
+    pub fn touch(&mut self, key: &str, bytes: Option<u64>) {
+        if self.rules.is_empty() || self.pending.contains_key(key) {
+            return;
+        }
+        self.pending.insert(key.to_string(), bytes);
+    }
+
+    /// Account for every touched key, given the value bytes it holds now
+    pub fn settle(&mut self, current: impl Fn(&str) -> Option<u64>) {
+        for (key, before) in std::mem::take(&mut self.pending) {
+            let Some(index) = self.rule_for(&key) else {
+                continue;
+            };
+            let after = current(&key);
+            let rule = &mut self.rules[index];
+            rule.keys = (rule.keys + u64::from(after.is_some())) - u64::from(before.is_some());
+            rule.bytes = (rule.bytes + entry_bytes(&key, after)) - entry_bytes(&key, before);
+        }
+    }
+
+    /// Recount every rule's usage from scratch, for when the store changed
+    /// without its keys being touched (a restore, a custom command)
+    pub fn recount<'a>(&mut self, entries: impl IntoIterator<Item = (&'a str, u64)>) {
+        if self.rules.is_empty() {
+            return;
+        }
+        self.pending.clear();
+        for rule in &mut self.rules {
+            rule.keys = 0;
+            rule.bytes = 0;
+        }
+        for (key, bytes) in entries {
+            if let Some(index) = self.rule_for(key) {
+                self.rules[index].keys += 1;
+                self.rules[index].bytes += entry_bytes(key, Some(bytes));
+            }
+        }
+    }
+}
+
+/// Bytes a key counts for: its own length plus its value's, or nothing if
+/// it doesn't exist
+fn entry_bytes(key: &str, value_bytes: Option<u64>) -> u64 {
+    value_bytes.map_or(0, |bytes| key.len() as u64 + bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quotas(rules: &[(&str, Option<u64>, Option<u64>)]) -> PrefixQuotas {
+        PrefixQuotas::compile(rules.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn test_longest_prefix_rule_wins() {
+        let quotas = quotas(&[
+            ("*", None, None),
+            ("session:*", None, None),
+            ("session:admin:*", None, None),
+        ]);
+        let pattern = |key| quotas.rule_for(key).map(|i| quotas.rules[i].pattern());
+        assert_eq!(
+            pattern("session:admin:1").as_deref(),
+            Some("session:admin:*")
+        );
+        assert_eq!(pattern("session:admin").as_deref(), Some("session:*"));
+        assert_eq!(pattern("session:1").as_deref(), Some("session:*"));
+        assert_eq!(pattern("user:1").as_deref(), Some("*"));
+
+        let narrow = PrefixQuotas::compile([("a:b:*", None, None)]).unwrap();
+        assert_eq!(narrow.rule_for("a:c"), None);
+        assert_eq!(narrow.rule_for("a:b:"), Some(0));
+    }
+
+    #[test]
+    fn test_check_counts_only_growth() {
+        let mut quotas = quotas(&[("s:*", Some(2), Some(20))]);
+        assert_eq!(quotas.check([("s:1", None, Some(5))]), Ok(()));
+        quotas.touch("s:1", None);
+        quotas.touch("s:2", None);
+        quotas.settle(|_| Some(5));
+        assert_eq!((quotas.rules[0].keys(), quotas.rules[0].bytes()), (2, 16));
+
+        assert_eq!(
+            quotas.check([("s:3", None, Some(1))]),
+            Err("'s:*' allows at most 2 keys".to_string())
+        );
+        assert_eq!(
+            quotas.check([("s:1", Some(5), Some(10))]),
+            Err("'s:*' allows at most 20 bytes".to_string())
+        );
+        // A move within the rule changes nothing
+        assert_eq!(
+            quotas.check([("s:1", Some(5), None), ("s:3", None, Some(5))]),
+            Ok(())
+        );
+        // Shrinking is allowed even over a limit
+        quotas.rules[0].bytes = 50;
+        assert_eq!(quotas.check([("s:1", Some(5), Some(1))]), Ok(()));
+        // Keys no rule covers are never limited
+        assert_eq!(quotas.check([("other", None, Some(1000))]), Ok(()));
+    }
+
+    #[test]
+    fn test_env_names_and_bad_patterns() {
+        let quotas = PrefixQuotas::from_vars([
+            (
+                "LIMIT_PREFIX_session:*_MAX_KEYS".to_string(),
+                "10".to_string(),
+            ),
+            (
+                "LIMIT_PREFIX_session:*_MAX_BYTES".to_string(),
+                "4096".to_string(),
+            ),
+            (
+                "LIMIT_PREFIX_cache:*_MAX_BYTES".to_string(),
+                "100".to_string(),
+            ),
+            ("VALIDATE_session:*".to_string(), "json".to_string()),
+        ])
+        .unwrap();
+        let rules: Vec<String> = quotas.rules().iter().map(ToString::to_string).collect();
+        assert_eq!(
+            rules,
+            [
+                "cache:* keys=0/unlimited bytes=0/100",
+                "session:* keys=0/10 bytes=0/4096"
+            ]
+        );
+
+        let err = PrefixQuotas::from_vars([(
+            "LIMIT_PREFIX_user:*:name_MAX_KEYS".to_string(),
+            "1".to_string(),
+        )])
+        .unwrap_err();
+        assert!(
+            err.starts_with("Invalid quota pattern 'user:*:name'"),
+            "{}",
+            err
+        );
+        let err = PrefixQuotas::from_vars([(
+            "LIMIT_PREFIX_user:*_MAX_ITEMS".to_string(),
+            "1".to_string(),
+        )])
+        .unwrap_err();
+        assert!(err.starts_with("Invalid quota variable"), "{}", err);
+        let err = PrefixQuotas::from_vars([(
+            "LIMIT_PREFIX_user:*_MAX_KEYS".to_string(),
+            "lots".to_string(),
+        )])
+        .unwrap_err();
+        assert!(err.starts_with("Invalid limit 'lots'"), "{}", err);
+    }
+}
@@ -0,0 +1,357 @@
+//! Reads served off the main thread from a published copy of the store
+//!
+//! Every command normally goes through the single main thread, so a GET
+//! waits behind the writes queued ahead of it even though reads never
+//! conflict. With `READ_SNAPSHOT_MAX_AGE_MS` set, the main thread publishes
+//! an immutable copy of the store, and the threads that parse commands (IO
+//! threads, or the callers of `KvEngine::execute`) answer GET, GETWITHTTL,
+//! and GETMATCHING from it instead of queueing them.
+//!
+//! A copy is used while it is current or younger than the max age;
+//! otherwise the read goes through the main thread as usual, and the main
+//! thread republishes after it. While writes keep coming, the copy is
+//! retaken once it is older than half the max age, so a stream of writes
+//! costs one copy of the store per half max age.
+//!
+//! A read ending in `FRESH` (or carrying an `ID` clause) always goes
+//! through the main thread, so it sees every write before it. So do GET
+//! and GETWITHTTL while `TRACK_KEY_ACCESS` or `TRACK_HOT_KEYS` is on, since
+//! only the main thread counts the reads of a key.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::commands;
+use crate::handler::Store;
+use crate::parser::{Command, CommandType};
+use crate::processor::CommandProcessor;
+use crate::thread::Reply;
+
+/// One published copy of the store
+struct View {
+    store: Store,
+    /// The processor's snapshot id when the copy was taken
+    snapshot_id: u64,
+    published_at: Instant,
+}
+
+/// The latest copy of the store, published by the main thread and read by
+/// any thread
+pub struct ReadSnapshot {
+    max_age: Duration,
+    latest: RwLock<Option<Arc<View>>>,
+    /// The processor's snapshot id as of the main thread's latest command
+    current_id: AtomicU64,
+    /// Whether single-key reads are left to the main thread to count
+    key_reads_counted: bool,
+}
+
+impl ReadSnapshot {
+    /// Serve reads from copies at most `max_age` old, once one is published
+    pub fn new(max_age: Duration) -> Self {
+        Self {
+            max_age,
+            latest: RwLock::new(None),
+            current_id: AtomicU64::new(0),
+            key_reads_counted: false,
+        }
+    }
+
+    /// Leave GET and GETWITHTTL to the main thread, which counts each read
+    /// of a key for INSPECT and HOTKEYS; GETMATCHING reads no single key and
+    /// is still answered from the copy
+    pub fn key_reads_counted(mut self, counted: bool) -> Self {
+        self.key_reads_counted = counted;
+        self
+    }
+
+    /// The oldest a copy may be and still answer a read
+    pub fn max_age(&self) -> Duration {
+        self.max_age
+    }
+
+    /// Answer `command` from the latest copy, or `None` if it has to go
+    /// through the main thread: it isn't a read a copy can answer, it asks
+    /// to be fresh, or no copy is recent enough
+    pub fn serve(&self, command: &Command) -> Option<Reply> {
+        self.serve_at(command, Instant::now())
+    }
+
+    fn serve_at(&self, command: &Command, now: Instant) -> Option<Reply> {
+        let servable = match command.command_type {
+            CommandType::Get(_) | CommandType::GetWithTtl(_) => !self.key_reads_counted,
+            CommandType::GetMatching(_) => true,
+            _ => false,
+        };
+        if !servable || command.fresh || command.idempotency_key.is_some() {
+            return None;
+        }
+        let view = self.view_at(now)?;
+        commands::read_only(&view.store, &command.command_type)
+    }
+
+    /// The latest copy, if it is current or young enough
+    fn view_at(&self, now: Instant) -> Option<Arc<View>> {
+        let view = self
+            .latest
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()?;
+        let current = view.snapshot_id == self.current_id.load(Ordering::Acquire);
+        let age = now.saturating_duration_since(view.published_at);
+        (current || age <= self.max_age).then_some(view)
+    }
+
+    /// Note the store's state after a command the main thread applied,
+    /// retaking the copy if the store changed and the copy is older than
+    /// half the max age
+    ///
+    /// Called before the command is answered, so no caller can see a write
+    /// acknowledged while the copy still passes for current. A processor
+    /// without a snapshot id never publishes.
+    pub(crate) fn refresh(&self, processor: &dyn CommandProcessor) {
+        self.refresh_at(processor, Instant::now())
+    }
+
+    fn refresh_at(&self, processor: &dyn CommandProcessor, now: Instant) {
+        let Some(id) = processor.snapshot_id() else {
+            return;
+        };
+        self.current_id.store(id, Ordering::Release);
+        let due = match &*self.latest.read().unwrap_or_else(PoisonError::into_inner) {
+            Some(view) => {
+                view.snapshot_id != id
+                    && now.saturating_duration_since(view.published_at) >= self.max_age / 2
+            }
+            None => true,
+        };
+        if !due {
+            return;
+        }
+        let Some(store) = processor.store_copy() else {
+            return;
+        };
+        let view = Arc::new(View {
+            store,
+            snapshot_id: id,
+            published_at: now,
+        });
+        *self.latest.write().unwrap_or_else(PoisonError::into_inner) = Some(view);
+    }
+
+    /// Stop answering reads, once the main thread stops applying commands
+    pub(crate) fn withdraw(&self) {
+        *self.latest.write().unwrap_or_else(PoisonError::into_inner) = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::engine::{EngineOptions, KvEngine};
+    use crate::handler::{CommandHandler, HandlerError};
+    use crate::processor::MessageContext;
+    use crate::thread::DIRECT_SUBMISSION;
+
+    fn apply(handler: &mut CommandHandler, line: &str) {
+        let ctx = MessageContext {
+            line_number: 1,
+            io_thread_id: DIRECT_SUBMISSION,
+            enqueued_at: Instant::now(),
+            trace_id: None,
+        };
+        handler.process(line.parse().unwrap(), &ctx).unwrap();
+    }
+
+    fn serve_at(reads: &ReadSnapshot, line: &str, now: Instant) -> Option<Reply> {
+        reads.serve_at(&line.parse().unwrap(), now)
+    }
+
+    #[test]
+    fn test_serves_only_plain_reads_from_the_copy() {
+        let reads = ReadSnapshot::new(Duration::from_secs(1));
+        let mut handler = CommandHandler::new();
+        apply(&mut handler, "SET user:1 Alice");
+        let now = Instant::now();
+        // Nothing is served before the first copy is published
+        assert_eq!(serve_at(&reads, "GET user:1", now), None);
+
+        reads.refresh_at(&handler, now);
+        assert_eq!(
+            serve_at(&reads, "GET user:1", now),
+            Some(Ok("GET user:1 = Alice".to_string()))
+        );
+        assert_eq!(
+            serve_at(&reads, "GETMATCHING user:*", now),
+            Some(Ok("user:1 Alice".to_string()))
+        );
+        assert_eq!(
+            serve_at(&reads, "GET user:2", now),
+            Some(Err(HandlerError::not_found("user:2")))
+        );
+        assert_eq!(serve_at(&reads, "GET user:1 FRESH", now), None);
+        assert_eq!(serve_at(&reads, "GET user:1 ID req-1", now), None);
+        assert_eq!(serve_at(&reads, "GETDEFAULT user:1 nobody", now), None);
+        assert_eq!(serve_at(&reads, "SET user:1 Bob", now), None);
+
+        reads.withdraw();
+        assert_eq!(serve_at(&reads, "GET user:1", now), None);
+    }
+
+    #[test]
+    fn test_counted_key_reads_go_to_the_main_thread() {
+        let reads = ReadSnapshot::new(Duration::from_secs(1)).key_reads_counted(true);
+        let mut handler = CommandHandler::new();
+        apply(&mut handler, "SET user:1 Alice");
+        let now = Instant::now();
+        reads.refresh_at(&handler, now);
+
+        assert_eq!(serve_at(&reads, "GET user:1", now), None);
+        assert_eq!(serve_at(&reads, "GETWITHTTL user:1", now), None);
+        assert_eq!(
+            serve_at(&reads, "GETMATCHING user:*", now),
+            Some(Ok("user:1 Alice".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_stale_copy_is_only_served_within_the_max_age() {
+        let max_age = Duration::from_millis(100);
+        let reads = ReadSnapshot::new(max_age);
+        let mut handler = CommandHandler::new();
+        let start = Instant::now();
+        reads.refresh_at(&handler, start);
+
+        // A write soon after publishing doesn't retake the copy yet
+        apply(&mut handler, "SET a 1");
+        reads.refresh_at(&handler, start + Duration::from_millis(10));
+        assert_eq!(
+            serve_at(&reads, "GET a", start + max_age),
+            Some(Err(HandlerError::not_found("a")))
+        );
+        // Past the max age the stale copy is refused
+        assert_eq!(serve_at(&reads, "GET a", start + max_age * 2), None);
+
+        // The main thread retakes it after answering that read
+        reads.refresh_at(&handler, start + max_age * 2);
+        assert_eq!(
+            serve_at(&reads, "GET a", start + max_age * 2),
+            Some(Ok("GET a = 1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_copy_is_retaken_after_half_the_max_age() {
+        let max_age = Duration::from_millis(100);
+        let reads = ReadSnapshot::new(max_age);
+        let mut handler = CommandHandler::new();
+        let start = Instant::now();
+        reads.refresh_at(&handler, start);
+
+        apply(&mut handler, "SET a 1");
+        reads.refresh_at(&handler, start + max_age / 2);
+        assert_eq!(
+            serve_at(&reads, "GET a", start + max_age / 2),
+            Some(Ok("GET a = 1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_current_copy_is_served_at_any_age() {
+        let reads = ReadSnapshot::new(Duration::from_millis(100));
+        let mut handler = CommandHandler::new();
+        apply(&mut handler, "SET a 1");
+        let start = Instant::now();
+        reads.refresh_at(&handler, start);
+        // Reads don't move the snapshot id, so the copy stays current
+        apply(&mut handler, "GET a");
+        reads.refresh_at(&handler, start + Duration::from_secs(60));
+
+        assert_eq!(
+            serve_at(&reads, "GET a", start + Duration::from_secs(3600)),
+            Some(Ok("GET a = 1".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_processor_without_snapshot_id_never_publishes() {
+        struct Opaque;
+        impl CommandProcessor for Opaque {
+            fn process(
+                &mut self,
+                _command: Command,
+                _ctx: &MessageContext,
+            ) -> Result<String, HandlerError> {
+                Ok("OK".to_string())
+            }
+        }
+
+        let reads = ReadSnapshot::new(Duration::from_secs(1));
+        let now = Instant::now();
+        reads.refresh_at(&Opaque, now);
+        assert_eq!(serve_at(&reads, "GET a", now), None);
+    }
+
+    /// Throughput of a 95%-read workload from several caller threads, with
+    /// every read through the main thread and with a read snapshot
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_read_snapshot_scaling`
+    #[test]
+    #[ignore]
+    fn bench_read_snapshot_scaling() {
+        const KEYS: usize = 1000;
+        const OPS_PER_THREAD: usize = 100_000;
+
+        for (label, max_age) in [
+            ("main thread", None),
+            ("read snapshot", Some(Duration::from_millis(50))),
+        ] {
+            for threads in [1, 2, 4, 8] {
+                let config = Config {
+                    read_snapshot_max_age: max_age,
+                    ..Config::default()
+                };
+                let engine = KvEngine::start(EngineOptions {
+                    config: Arc::new(config),
+                });
+                for key in 0..KEYS {
+                    engine.execute(&format!("SET key:{} {}", key, key)).unwrap();
+                }
+                // Let the startup copy go stale, so the first read retakes it
+                // with every key in it
+                if let Some(max_age) = max_age {
+                    std::thread::sleep(max_age * 2);
+                }
+
+                let start = Instant::now();
+                std::thread::scope(|scope| {
+                    for thread in 0..threads {
+                        let engine = &engine;
+                        scope.spawn(move || {
+                            for op in 0..OPS_PER_THREAD {
+                                let key = (op * 31 + thread * 7) % KEYS;
+                                let line = if op % 20 == 0 {
+                                    format!("SET key:{} {}", key, op)
+                                } else {
+                                    format!("GET key:{}", key)
+                                };
+                                engine.execute(&line).unwrap();
+                            }
+                        });
+                    }
+                });
+                let elapsed = start.elapsed();
+                let summary = engine.shutdown();
+                println!(
+                    "{:>13}, {} threads: {:>9.0} ops/s ({} reads from the snapshot)",
+                    label,
+                    threads,
+                    (threads * OPS_PER_THREAD) as f64 / elapsed.as_secs_f64(),
+                    summary.snapshot_reads
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,188 @@
+//! Workload recording and deterministic replay
+//!
+//! A session file holds one accepted command per line, prefixed with the time
+//! in microseconds since recording started and a tab (`<micros>\t<line>`):
+//!
+//! ```text
+//! 0       SET user:1001 John
+//! 1520    GET user:1001
+//! 48211   DELETE user:1001
+//! ```
+//!
+//! IO threads record each line right after it parses successfully, so reads
+//! and commands the handler later rejects are captured too - only lines that
+//! never became a command are left out. Replaying feeds the lines back
+//! through the normal input channel, sleeping between them to reproduce the
+//! original (optionally scaled) inter-command timing.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Writes accepted commands and their relative timestamps to a session file
+pub struct Recorder {
+    writer: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Create (or truncate) a session file; timestamps are relative to now
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            start: Instant::now(),
+        })
+    }
+
+    /// Append one command line stamped with the time since recording started
+    pub fn record(&mut self, line: &str) -> io::Result<()> {
+        let micros = self.start.elapsed().as_micros();
+        writeln!(self.writer, "{}\t{}", micros, line.trim())
+    }
+
+    /// Flush buffered records to disk
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// How closely a replay follows the recorded timing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pacing {
+    /// Preserve inter-command gaps divided by this factor (1.0 = real time)
+    Speed(f64),
+    /// Send every command immediately
+    AsFastAsPossible,
+}
+
+/// Replay a session file, handing each command line to `send` on schedule
+///
+/// `send` returns `false` to stop the replay early (e.g. when the IO threads
+/// have gone away). Returns the number of commands replayed.
+pub fn replay<F>(path: &Path, pacing: Pacing, mut send: F) -> io::Result<usize>
+where
+    F: FnMut(String) -> bool,
+{
+    let reader = BufReader::new(File::open(path)?);
+    let start = Instant::now();
+    let mut replayed = 0;
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.is_empty() {
+            continue;
+        }
+
+        let (micros, command) = line
+            .split_once('\t')
+            .and_then(|(micros, command)| Some((micros.parse::<u64>().ok()?, command)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("malformed session record at line {}: '{}'", index + 1, line),
+                )
+            })?;
+
+        if let Pacing::Speed(speed) = pacing {
+            let due = Duration::from_micros(micros).div_f64(speed);
+            if let Some(wait) = due.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+
+        if !send(command.to_string()) {
+            break;
+        }
+        replayed += 1;
+    }
+
+    Ok(replayed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("crabby_{}_{}.crkv", name, std::process::id()))
+    }
+
+    /// Record three commands with known gaps and return the session path
+    fn record_session(name: &str) -> std::path::PathBuf {
+        let path = temp_path(name);
+        let mut recorder = Recorder::create(&path).unwrap();
+        recorder.record("SET a 1").unwrap();
+        thread::sleep(Duration::from_millis(60));
+        recorder.record("GET a").unwrap();
+        thread::sleep(Duration::from_millis(40));
+        recorder.record("DELETE a").unwrap();
+        recorder.flush().unwrap();
+        path
+    }
+
+    fn replay_offsets(path: &Path, pacing: Pacing) -> Vec<(String, Duration)> {
+        let start = Instant::now();
+        let mut seen = Vec::new();
+        let count = replay(path, pacing, |line| {
+            seen.push((line, start.elapsed()));
+            true
+        })
+        .unwrap();
+        assert_eq!(count, seen.len());
+        seen
+    }
+
+    fn assert_near(actual: Duration, expected_ms: u64) {
+        // Generous tolerance: sleeps overshoot on loaded CI machines
+        let expected = Duration::from_millis(expected_ms);
+        let diff = actual.abs_diff(expected);
+        assert!(
+            diff < Duration::from_millis(25),
+            "expected ~{:?}, got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_replay_preserves_timing() {
+        let path = record_session("timing");
+        let seen = replay_offsets(&path, Pacing::Speed(1.0));
+
+        let lines: Vec<&str> = seen.iter().map(|(line, _)| line.as_str()).collect();
+        assert_eq!(lines, ["SET a 1", "GET a", "DELETE a"]);
+        assert_near(seen[0].1, 0);
+        assert_near(seen[1].1, 60);
+        assert_near(seen[2].1, 100);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_scaled_and_unpaced() {
+        let path = record_session("scaled");
+
+        let seen = replay_offsets(&path, Pacing::Speed(2.0));
+        assert_near(seen[1].1, 30);
+        assert_near(seen[2].1, 50);
+
+        let seen = replay_offsets(&path, Pacing::AsFastAsPossible);
+        assert_eq!(seen.len(), 3);
+        assert_near(seen[2].1, 0);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_rejects_malformed_record() {
+        let path = temp_path("malformed");
+        std::fs::write(&path, "0\tSET a 1\nnot-a-record\n").unwrap();
+
+        let err = replay(&path, Pacing::AsFastAsPossible, |_| true).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,508 @@
+//! Built-in end-to-end check for a freshly deployed build (`selftest`)
+//!
+//! A canned workload goes through a real pool (two IO threads, the main
+//! thread, a `CommandHandler`) one line at a time, and every response is
+//! compared with the one this build should give. Which commands need a case
+//! comes from the command table, so a new command without one fails the
+//! check. Files the workload reads and writes (LOADJSON, APPLY, DUMPJSON,
+//! DUMP PARALLEL, SHUTDOWN SAVE) live in a scratch directory that is removed
+//! afterwards; nothing at `SNAPSHOT_PATH` or in the working directory is
+//! touched, and the environment is ignored.
+
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::Duration;
+
+use crate::commands;
+use crate::config::Config;
+use crate::handler::{CommandHandler, HandlerError};
+use crate::output::OutputSinks;
+use crate::parser::{Command, ParseStats};
+use crate::processor::{CommandProcessor, MessageContext};
+use crate::thread::{RunSummary, ThreadPoolBuilder};
+use crate::version;
+
+/// How long to wait for the main thread to answer one line
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// What a case's line should come back with
+enum Expect {
+    Response(String),
+    /// For responses with run-dependent parts (capacities, timings), only
+    /// the start is checked
+    Prefix(String),
+    Error(HandlerError),
+}
+
+/// One line of the workload and the command it exercises
+struct Case {
+    command: &'static str,
+    line: String,
+    expect: Expect,
+}
+
+fn case(command: &'static str, line: impl Into<String>, response: impl Into<String>) -> Case {
+    Case {
+        command,
+        line: line.into(),
+        expect: Expect::Response(response.into()),
+    }
+}
+
+fn prefix_case(command: &'static str, line: &str, prefix: &str) -> Case {
+    Case {
+        command,
+        line: line.to_string(),
+        expect: Expect::Prefix(prefix.to_string()),
+    }
+}
+
+fn error_case(command: &'static str, line: &str, error: HandlerError) -> Case {
+    Case {
+        command,
+        line: line.to_string(),
+        expect: Expect::Error(error),
+    }
+}
+
+/// The workload, in the order it is applied; `dir` is the scratch directory
+fn workload(dir: &Path) -> Vec<Case> {
+    let json = dir.join("selftest.json");
+    let good_patch = dir.join("good.patch");
+    let bad_patch = dir.join("bad.patch");
+    let dump_json = dir.join("dump.json");
+    let dump_dir = dir.join("dump");
+    vec![
+        case("SET", "SET user:1 Ada", "SET user:1 = Ada"),
+        case("SET", "SET user:2 Grace", "SET user:2 = Grace"),
+        case("GET", "GET user:1", "GET user:1 = Ada"),
+        error_case("GET", "GET user:9", HandlerError::not_found("user:9")),
+        case("PARSESTATS", "PARSESTATS", "GET 2\nSET 2\nPARSESTATS 1"),
+        // Two SETs so far; the GETs leave it alone
+        case("SNAPSHOTID", "SNAPSHOTID", "2"),
+        // Pinned: replicas on builds that hash differently would never match
+        case("FINGERPRINT", "FINGERPRINT", "fee6c1522d114ac8"),
+        case("GETWITHTTL", "GETWITHTTL user:1", "Ada\n-1"),
+        case("GETWITHTTL", "GETWITHTTL user:9", "(nil)"),
+        case("GETDEFAULT", "GETDEFAULT user:9 nobody", "nobody"),
+        case("GETORSET", "GETORSET user:1 nobody", "Ada"),
+        case("APPENDCAP", "APPENDCAP note 5 hello", "5"),
+        case("APPENDCAP", "APPENDCAP note 5 world", "5"),
+        case("INCRBOUNDED", "INCRBOUNDED hits 2", "1"),
+        case("INCRBOUNDED", "INCRBOUNDED hits 2", "2"),
+        error_case(
+            "INCRBOUNDED",
+            "INCRBOUNDED hits 2",
+            HandlerError::LimitReached,
+        ),
+        case("INCRIF", "INCRIF hits user:1 Ada", "3"),
+        error_case(
+            "INCRIF",
+            "INCRIF hits user:1 Grace",
+            HandlerError::ConditionNotMet,
+        ),
+        case("GETAT", "GETAT hits 2", "2"),
+        error_case(
+            "GETAT",
+            "GETAT hits 1",
+            HandlerError::VersionEvicted {
+                key: "hits".to_string(),
+                version: 1,
+                oldest: 2,
+            },
+        ),
+        error_case(
+            "GETAT",
+            "GETAT hits 4",
+            HandlerError::VersionNeverExisted {
+                key: "hits".to_string(),
+                version: 4,
+                latest: 3,
+            },
+        ),
+        case(
+            "SETCRC",
+            "SETCRC checked hello",
+            "SETCRC checked = hello (crc32: 3610a686)",
+        ),
+        case("GETCRC", "GETCRC checked", "hello"),
+        case(
+            "SET",
+            r#"SET doc {"a":{"b":[1,"two"]}}"#,
+            r#"SET doc = {"a":{"b":[1,"two"]}}"#,
+        ),
+        case("JGET", "JGET doc a.b[1]", "two"),
+        error_case(
+            "JGET",
+            "JGET doc a.c",
+            HandlerError::PathNotFound {
+                key: "doc".to_string(),
+                path: "a.c".to_string(),
+            },
+        ),
+        case("MERGE", "MERGE total hits hits STRATEGY sum", "6"),
+        case("RENAMENX", "RENAMENX user:2 user:3", "1"),
+        case("RENAMENX", "RENAMENX user:1 user:3", "0"),
+        case(
+            "SORTBYVALUE",
+            "SORTBYVALUE user:* asc",
+            "user:1 Ada\nuser:3 Grace",
+        ),
+        case(
+            "GETMATCHING",
+            "GETMATCHING user:*",
+            "user:1 Ada\nuser:3 Grace",
+        ),
+        case(
+            "SCAN",
+            "SCAN 0 COUNT 100",
+            "0\nchecked\ndoc\nhits\nnote\ntotal\nuser:1\nuser:3",
+        ),
+        case("WAITKEY", "WAITKEY user:1 0", "1"),
+        case("WAITKEY", "WAITKEY user:9 0", "0"),
+        case("LINDEX", "LINDEX queue 0", "(nil)"),
+        error_case("LINDEX", "LINDEX user:1 0", HandlerError::WrongType),
+        case("LTRIM", "LTRIM queue 0 1", "OK"),
+        case("LMOVE", "LMOVE queue done LEFT RIGHT", "(nil)"),
+        case(
+            "LOADJSON",
+            format!("LOADJSON {}", json.display()),
+            "loaded:2\nskipped:1",
+        ),
+        case("GET", "GET loaded:1", "GET loaded:1 = one"),
+        case(
+            "VALUELENGTHSTATS",
+            "VALUELENGTHSTATS",
+            "strings:9\nmin:1\nmin_key:hits\nmax:21\nmax_key:doc\navg:5.22",
+        ),
+        case(
+            "PREFIXHISTOGRAM",
+            "PREFIXHISTOGRAM :",
+            "(none) 5\nloaded 2\nuser 2",
+        ),
+        case("KEYSLOT", "KEYSLOT {user:1}:cart", "10778"),
+        case("SLOTSTATS", "SLOTSTATS 1", "0-16383 9"),
+        case("HOTKEYS", "HOTKEYS 1", "hits 8"),
+        case(
+            "INSPECT",
+            "INSPECT note",
+            "value:hello\nversion:2\n(key access tracking disabled)",
+        ),
+        case("DELETE", "DELETE note", "DELETED note (was: hello)"),
+        error_case("DELETE", "DELETE note", HandlerError::not_found("note")),
+        error_case("INSPECT", "INSPECT note", HandlerError::not_found("note")),
+        // Recreated by the first GETORSET only, and gone again after
+        case("GETORSET", "GETORSET note hi", "hi"),
+        case("GETORSET", "GETORSET note bye", "hi"),
+        case("DELETE", "DELETE note", "DELETED note (was: hi)"),
+        case(
+            "DUMPJSON",
+            format!("DUMPJSON {}", dump_json.display()),
+            "dumped:7\nskipped:1",
+        ),
+        case(
+            "DUMP",
+            format!("DUMP PARALLEL {} 2", dump_dir.display()),
+            format!(
+                "dumped:8\nparts:2\nmanifest:{}",
+                dump_dir.join("dump.manifest").display()
+            ),
+        ),
+        case("EXPLAIN", "EXPLAIN GET user:1", "GET key=user:1"),
+        case("GENSET", "GENSET gen: 3", "3"),
+        case("GET", "GET gen:2", "GET gen:2 = value"),
+        case("RENAMEPREFIX", "RENAMEPREFIX gen: g:", "3"),
+        error_case(
+            "RENAMEPREFIX",
+            "RENAMEPREFIX g:1 g:2",
+            HandlerError::KeyExists("g:2".to_string()),
+        ),
+        case(
+            "DEFINE",
+            "DEFINE peek AS GET $1; GETDEFAULT $2 none END",
+            "OK",
+        ),
+        case(
+            "CALL",
+            "CALL peek g:1 absent",
+            "1) GET g:1 = value\n2) none",
+        ),
+        error_case(
+            "CALL",
+            "CALL missing g:1",
+            HandlerError::UnknownProcedure("missing".to_string()),
+        ),
+        case(
+            "APPLY",
+            format!("APPLY {}", good_patch.display()),
+            "applied:2",
+        ),
+        error_case(
+            "APPLY",
+            &format!("APPLY {}", bad_patch.display()),
+            HandlerError::ApplyFailed(format!(
+                "{}:2: LINDEX user:1 0: {}",
+                bad_patch.display(),
+                HandlerError::WrongType
+            )),
+        ),
+        case("UNDELETE", "UNDELETE user:9", "0"),
+        case("PURGE", "PURGE user:*", "0"),
+        case("SETINT", "SETINT total 7", "SETINT total = 7"),
+        case("INCRBOUNDED", "INCRBOUNDED total 10", "8"),
+        case("GET", "GET total", "GET total = 8"),
+        case("CONVERT", "CONVERT hits INT", "OK"),
+        error_case("CONVERT", "CONVERT g:1 INT", HandlerError::NotAnInteger),
+        case(
+            "CHECK",
+            "CHECK",
+            "keys_checked:11\nchecksum_mismatches:0\nidempotency_entries_removed:0",
+        ),
+        prefix_case("INFO", "INFO", "keys:11\n"),
+        case(
+            "VERSION",
+            "VERSION",
+            version::fields(true)
+                .into_iter()
+                .map(|(field, value)| format!("{}:{}", field, value))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        ),
+        prefix_case("STATS", "STATS", "commands:"),
+        prefix_case("STATSRESET", "STATSRESET", "commands:"),
+        case("SHUTDOWN", "SHUTDOWN SAVE", "OK (saved 11 keys)"),
+    ]
+}
+
+/// Hands every result to the selftest as it is applied
+struct ResultForwarder {
+    handler: CommandHandler,
+    results: Sender<(usize, Result<String, HandlerError>)>,
+}
+
+impl CommandProcessor for ResultForwarder {
+    fn process(&mut self, command: Command, ctx: &MessageContext) -> Result<String, HandlerError> {
+        let result = self.handler.process_command(command);
+        let _ = self.results.send((ctx.line_number, result.clone()));
+        result
+    }
+}
+
+/// How one command fared
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandReport {
+    pub command: &'static str,
+    /// Workload lines exercising the command
+    pub cases: usize,
+    /// What went wrong, one entry per failed line
+    pub failures: Vec<String>,
+}
+
+impl CommandReport {
+    /// A command without a single case fails: nothing vouches for it
+    pub fn passed(&self) -> bool {
+        self.cases > 0 && self.failures.is_empty()
+    }
+}
+
+/// The outcome of `run_selftest`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SelftestReport {
+    /// One entry per command in the command table, in table order
+    pub commands: Vec<CommandReport>,
+    /// Problems outside any one command (setup, counters that don't add up)
+    pub problems: Vec<String>,
+    pub summary: RunSummary,
+}
+
+impl SelftestReport {
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty() && self.commands.iter().all(CommandReport::passed)
+    }
+}
+
+impl fmt::Display for SelftestReport {
+    /// A PASS/FAIL table, one row per command, then any other problems
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<18} {:>5}  RESULT", "COMMAND", "CASES")?;
+        for report in &self.commands {
+            let result = if report.passed() { "PASS" } else { "FAIL" };
+            writeln!(f, "{:<18} {:>5}  {}", report.command, report.cases, result)?;
+            if report.cases == 0 {
+                writeln!(f, "    no selftest case")?;
+            }
+            for failure in &report.failures {
+                writeln!(f, "    {}", failure.replace('\n', "\\n"))?;
+            }
+        }
+        for problem in &self.problems {
+            writeln!(f, "{}", problem)?;
+        }
+        write!(
+            f,
+            "selftest {}",
+            if self.passed() { "PASSED" } else { "FAILED" }
+        )
+    }
+}
+
+/// Run the canned workload through a fresh pool and check every response
+pub fn run_selftest() -> SelftestReport {
+    let dir = std::env::temp_dir().join(format!("crabby_selftest_{}", std::process::id()));
+    let mut problems = Vec::new();
+    if let Err(err) = std::fs::create_dir_all(&dir).and_then(|()| {
+        std::fs::write(
+            dir.join("selftest.json"),
+            r#"{"loaded:1": "one", "loaded:2": "two", "skipped": 3}"#,
+        )?;
+        std::fs::write(dir.join("good.patch"), "SET patched 1\nDELETE patched\n")?;
+        std::fs::write(dir.join("bad.patch"), "SET g:1 patched\nLINDEX user:1 0\n")
+    }) {
+        problems.push(format!("cannot prepare {}: {}", dir.display(), err));
+    }
+
+    let (results_tx, results_rx) = mpsc::channel();
+    let parse_stats = Arc::new(ParseStats::new());
+    let processor = ResultForwarder {
+        handler: CommandHandler::new()
+            .with_snapshot_path(Some(dir.join("selftest.crkvs")))
+            .with_hot_keys(16)
+            .with_value_history(1)
+            .with_parse_stats(Arc::clone(&parse_stats)),
+        results: results_tx,
+    };
+    let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+        .io_threads(2)
+        .output(OutputSinks::discard())
+        .processor(Box::new(processor))
+        .parse_stats(parse_stats)
+        .build();
+    let main_handle = pool.start_main_thread();
+    let sender = pool.get_string_sender();
+
+    let cases = workload(&dir);
+    let mut commands: Vec<CommandReport> = commands::names()
+        .map(|command| CommandReport {
+            command,
+            cases: 0,
+            failures: Vec::new(),
+        })
+        .collect();
+    let mut expected = RunSummary::default();
+    for (i, case) in cases.iter().enumerate() {
+        let line_number = i + 1;
+        let failure = check_case(case, line_number, &sender, &results_rx, &mut expected);
+        match commands.iter_mut().find(|r| r.command == case.command) {
+            Some(report) => {
+                report.cases += 1;
+                report.failures.extend(failure);
+            }
+            None => problems.push(format!("case for unknown command {}", case.command)),
+        }
+    }
+
+    drop(sender);
+    pool.shutdown();
+    let summary = match main_handle.join() {
+        Ok(summary) => summary,
+        Err(_) => {
+            problems.push("main thread panicked".to_string());
+            RunSummary::default()
+        }
+    };
+    problems.extend(check_counters(&summary, &expected));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    SelftestReport {
+        commands,
+        problems,
+        summary,
+    }
+}
+
+/// Send one case's line and compare the result with what it expects,
+/// counting the outcome the summary should show
+fn check_case(
+    case: &Case,
+    line_number: usize,
+    sender: &Sender<(String, usize)>,
+    results: &Receiver<(usize, Result<String, HandlerError>)>,
+    expected: &mut RunSummary,
+) -> Option<String> {
+    let fail = |detail: String| Some(format!("line {} `{}`: {}", line_number, case.line, detail));
+    // A line that doesn't parse never reaches the main thread to answer
+    if let Err(err) = case.line.parse::<Command>() {
+        return fail(format!("does not parse: {}", err));
+    }
+    if sender.send((case.line.clone(), line_number)).is_err() {
+        return fail("the pool stopped accepting input".to_string());
+    }
+    let result = match results.recv_timeout(RESPONSE_TIMEOUT) {
+        Ok((answered, result)) if answered == line_number => result,
+        Ok((answered, _)) => return fail(format!("got the answer for line {}", answered)),
+        Err(_) => return fail(format!("no answer within {:?}", RESPONSE_TIMEOUT)),
+    };
+
+    expected.processed += 1;
+    match &result {
+        Ok(_) => expected.succeeded += 1,
+        Err(err) if err.is_soft() => expected.soft_errors += 1,
+        Err(_) => expected.hard_errors += 1,
+    }
+    let matches = match (&case.expect, &result) {
+        (Expect::Response(want), Ok(got)) => got == want,
+        (Expect::Prefix(want), Ok(got)) => got.starts_with(want.as_str()),
+        (Expect::Error(want), Err(got)) => got == want,
+        _ => false,
+    };
+    if matches {
+        return None;
+    }
+    let want = match &case.expect {
+        Expect::Response(want) => format!("{:?}", want),
+        Expect::Prefix(want) => format!("{:?}...", want),
+        Expect::Error(want) => format!("error '{}'", want),
+    };
+    let got = match &result {
+        Ok(got) => format!("{:?}", got),
+        Err(got) => format!("error '{}'", got),
+    };
+    fail(format!("expected {}, got {}", want, got))
+}
+
+/// Compare the run summary with the outcomes the cases saw
+fn check_counters(summary: &RunSummary, expected: &RunSummary) -> Vec<String> {
+    let counters = [
+        ("processed", summary.processed, expected.processed),
+        ("succeeded", summary.succeeded, expected.succeeded),
+        ("soft_errors", summary.soft_errors, expected.soft_errors),
+        ("hard_errors", summary.hard_errors, expected.hard_errors),
+    ];
+    let mut problems: Vec<String> = counters
+        .iter()
+        .filter(|(_, got, want)| got != want)
+        .map(|(name, got, want)| format!("summary {} is {}, expected {}", name, got, want))
+        .collect();
+    let outcomes = summary.succeeded + summary.soft_errors + summary.hard_errors;
+    if outcomes != summary.processed {
+        problems.push(format!(
+            "summary outcomes add up to {}, but {} commands were processed",
+            outcomes, summary.processed
+        ));
+    }
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selftest_passes() {
+        let report = run_selftest();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.summary.processed, workload(Path::new("/")).len());
+    }
+}
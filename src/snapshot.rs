@@ -0,0 +1,1017 @@
+//! Point-in-time store snapshots for planned restarts
+//!
+//! A snapshot holds every key with its full value (strings, lists,
+//! checksummed strings, and integers), plus any soft-deleted keys. The layout is
+//! little-endian:
+//!
+//! ```text
+//! "CRKVSNAP" | version: u8 | key count: u64 | created: u64 | entries... | crc32: u32
+//! entry  = tag: u8 | key | body
+//!        | 3: u8 | key | deleted at: u64 | tag: u8 | body   (tombstone)
+//! string = length: u32 | UTF-8 bytes
+//! body   = string                      (tag 0, Str)
+//!        | count: u32 | string * count (tag 1, List)
+//!        | string | crc: u32           (tag 2, Checksummed)
+//!        | i64                         (tag 4, Int)
+//! ```
+//!
+//! `created` and `deleted at` are seconds since the Unix epoch; version 1
+//! snapshots have no creation time and are still read. Tombstones need
+//! version 3, which is only written when there are any, so a store that
+//! never soft-deletes stays readable by older builds. Integers likewise
+//! need version 4, written only when some value is one. The key count covers
+//! tombstones too. The trailing CRC-32 covers everything
+//! before it, so a truncated or corrupted file is rejected instead of
+//! loading part of the store. A version newer than this build knows is
+//! refused as such before anything past the header is read, since its
+//! layout can't be assumed.
+//!
+//! Keys and values are length-prefixed bytes, never delimited, so tabs,
+//! newlines, and any other characters round-trip unchanged.
+//!
+//! A parallel dump (`DUMP PARALLEL`) writes the store as several snapshot
+//! files, one per worker, plus a text manifest naming them:
+//!
+//! ```text
+//! CRKVMANIFEST 1
+//! <part file name> <key count> <the part's trailing crc32, hex>
+//! ...
+//! ```
+//!
+//! `load` accepts either a snapshot or a manifest.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::crc32::{Crc32, crc32};
+use crate::glob::glob_match;
+use crate::handler::{Tombstone, Tombstones, Value};
+
+const MAGIC: &[u8; 8] = b"CRKVSNAP";
+const VERSION: u8 = 4;
+/// The version written when there are tombstones but no integers
+const VERSION_WITH_TOMBSTONES: u8 = 3;
+/// The version written when there are neither tombstones nor integers
+const VERSION_WITHOUT_TOMBSTONES: u8 = 2;
+
+const MANIFEST_MAGIC: &str = "CRKVMANIFEST";
+const MANIFEST_VERSION: u32 = 1;
+
+/// File name of the manifest a parallel dump writes into its directory
+pub const MANIFEST_NAME: &str = "dump.manifest";
+
+const TAG_STR: u8 = 0;
+const TAG_LIST: u8 = 1;
+const TAG_CHECKSUMMED: u8 = 2;
+const TAG_TOMBSTONE: u8 = 3;
+const TAG_INT: u8 = 4;
+
+/// Why a snapshot could not be written or read
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(PathBuf, std::io::Error),
+    /// The file is not a snapshot this version can read
+    Corrupt(PathBuf, String),
+    /// The snapshot has a format version newer than this build knows
+    TooNew(PathBuf, u8),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            SnapshotError::Corrupt(path, reason) => {
+                write!(f, "{}: corrupt snapshot ({})", path.display(), reason)
+            }
+            SnapshotError::TooNew(path, version) => write!(
+                f,
+                "{}: snapshot version {} was written by a newer build (this build reads versions 1 to {})",
+                path.display(),
+                version,
+                VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+/// Write `store` to `path`, replacing any previous snapshot
+///
+/// The snapshot is written to a temporary file next to `path` and renamed
+/// over it, so a crash mid-write leaves the previous snapshot intact.
+pub fn save(store: &HashMap<String, Value>, path: &Path) -> Result<(), SnapshotError> {
+    save_with_tombstones(store, &Tombstones::new(), path)
+}
+
+/// Write `store` and the soft-deleted keys in `tombstones` to `path`, the
+/// same way as `save`
+pub fn save_with_tombstones(
+    store: &HashMap<String, Value>,
+    tombstones: &Tombstones,
+    path: &Path,
+) -> Result<(), SnapshotError> {
+    let bytes = encode(
+        store.iter(),
+        tombstones.iter(),
+        store.len() + tombstones.len(),
+    );
+    write_replacing(path, &bytes)
+}
+
+/// What a parallel dump wrote
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParallelDump {
+    /// The manifest to load the dump from
+    pub manifest: PathBuf,
+    pub parts: usize,
+    pub keys: usize,
+}
+
+/// Write `store` into `dir` as up to `threads` snapshot files, encoded and
+/// written by that many worker threads, plus a manifest listing them
+///
+/// The manifest is written last, replacing any earlier one, so it only ever
+/// names parts that were completely written. Part files left over from an
+/// earlier dump with more parts are not removed.
+pub fn save_parallel(
+    store: &HashMap<String, Value>,
+    dir: &Path,
+    threads: usize,
+) -> Result<ParallelDump, SnapshotError> {
+    fs::create_dir_all(dir).map_err(|err| SnapshotError::Io(dir.to_path_buf(), err))?;
+
+    let entries: Vec<(&String, &Value)> = store.iter().collect();
+    let per_part = entries.len().div_ceil(threads.max(1)).max(1);
+    let parts: Vec<(String, usize, u32)> = thread::scope(|scope| {
+        let workers: Vec<_> = entries
+            .chunks(per_part)
+            .enumerate()
+            .map(|(i, part)| {
+                scope.spawn(move || {
+                    let name = format!("dump.part{}", i);
+                    let buf = encode(part.iter().copied(), std::iter::empty(), part.len());
+                    write_replacing(&dir.join(&name), &buf)?;
+                    let crc = u32::from_le_bytes(buf[buf.len() - 4..].try_into().unwrap());
+                    Ok((name, part.len(), crc))
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("dump worker panicked"))
+            .collect::<Result<_, SnapshotError>>()
+    })?;
+
+    let mut manifest = format!("{} {}\n", MANIFEST_MAGIC, MANIFEST_VERSION);
+    for (name, keys, crc) in &parts {
+        manifest.push_str(&format!("{} {} {:08x}\n", name, keys, crc));
+    }
+    let path = dir.join(MANIFEST_NAME);
+    write_replacing(&path, manifest.as_bytes())?;
+    Ok(ParallelDump {
+        manifest: path,
+        parts: parts.len(),
+        keys: store.len(),
+    })
+}
+
+/// The snapshot bytes for `count` entries, live and tombstoned
+fn encode<'a>(
+    entries: impl Iterator<Item = (&'a String, &'a Value)> + Clone,
+    tombstones: impl Iterator<Item = (&'a String, &'a Tombstone)> + Clone,
+    count: usize,
+) -> Vec<u8> {
+    let has_ints = entries
+        .clone()
+        .map(|(_, value)| value)
+        .chain(tombstones.clone().map(|(_, tombstone)| &tombstone.value))
+        .any(|value| matches!(value, Value::Int(_)));
+    let mut tombstones = tombstones.peekable();
+    let mut buf = Vec::new();
+    buf.extend_from_slice(MAGIC);
+    buf.push(if has_ints {
+        VERSION
+    } else if tombstones.peek().is_some() {
+        VERSION_WITH_TOMBSTONES
+    } else {
+        VERSION_WITHOUT_TOMBSTONES
+    });
+    buf.extend_from_slice(&(count as u64).to_le_bytes());
+    let created = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    buf.extend_from_slice(&created.to_le_bytes());
+
+    for (key, value) in entries {
+        buf.push(value_tag(value));
+        put_string(&mut buf, key);
+        put_body(&mut buf, value);
+    }
+    for (key, tombstone) in tombstones {
+        buf.push(TAG_TOMBSTONE);
+        put_string(&mut buf, key);
+        buf.extend_from_slice(&tombstone.deleted_at.to_le_bytes());
+        buf.push(value_tag(&tombstone.value));
+        put_body(&mut buf, &tombstone.value);
+    }
+    let crc = crc32(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+fn value_tag(value: &Value) -> u8 {
+    match value {
+        Value::Str(_) => TAG_STR,
+        Value::List(_) => TAG_LIST,
+        Value::Checksummed { .. } => TAG_CHECKSUMMED,
+        Value::Int(_) => TAG_INT,
+    }
+}
+
+fn put_body(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Str(s) => put_string(buf, s),
+        Value::List(items) => {
+            buf.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            for item in items {
+                put_string(buf, item);
+            }
+        }
+        Value::Checksummed { value, crc } => {
+            put_string(buf, value);
+            buf.extend_from_slice(&crc.to_le_bytes());
+        }
+        Value::Int(n) => buf.extend_from_slice(&n.to_le_bytes()),
+    }
+}
+
+/// Write `bytes` to a temporary file next to `path` and rename it over
+/// `path`
+fn write_replacing(path: &Path, bytes: &[u8]) -> Result<(), SnapshotError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, bytes)
+        .and_then(|()| fs::rename(&tmp, path))
+        .map_err(|err| SnapshotError::Io(path.to_path_buf(), err))
+}
+
+/// Read the store back from a snapshot written by `save`, or from the
+/// manifest of a dump written by `save_parallel`, leaving out tombstones
+pub fn load(path: &Path) -> Result<HashMap<String, Value>, SnapshotError> {
+    load_with_tombstones(path).map(|(store, _)| store)
+}
+
+/// Read the store and its soft-deleted keys back from a snapshot written by
+/// `save_with_tombstones` (a parallel dump never has tombstones)
+pub fn load_with_tombstones(
+    path: &Path,
+) -> Result<(HashMap<String, Value>, Tombstones), SnapshotError> {
+    let mut magic = [0; MANIFEST_MAGIC.len()];
+    let is_manifest = File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .is_ok_and(|()| magic == MANIFEST_MAGIC.as_bytes());
+    if is_manifest {
+        return load_manifest(path).map(|store| (store, Tombstones::new()));
+    }
+    load_snapshot(path)
+}
+
+fn load_snapshot(path: &Path) -> Result<(HashMap<String, Value>, Tombstones), SnapshotError> {
+    let reader = SnapshotReader::open(path)?;
+    // Cap the pre-allocation so a bogus count can't exhaust memory up front
+    let mut store = HashMap::with_capacity(reader.header().key_count.min(1 << 20) as usize);
+    let mut tombstones = Tombstones::new();
+    for entry in reader {
+        match entry? {
+            (key, SnapshotEntry::Live(value)) => store.insert(key, value).map(drop),
+            (key, SnapshotEntry::Deleted(tombstone)) => tombstones.insert(key, tombstone).map(drop),
+        };
+    }
+    Ok((store, tombstones))
+}
+
+/// Load every part a manifest lists, one thread per part
+///
+/// Each part must match the checksum and key count the manifest recorded
+/// for it, so a part from another dump is caught even if it is intact.
+fn load_manifest(path: &Path) -> Result<HashMap<String, Value>, SnapshotError> {
+    let text =
+        fs::read_to_string(path).map_err(|err| SnapshotError::Io(path.to_path_buf(), err))?;
+    let mut lines = text.lines();
+    if lines.next() != Some(&format!("{} {}", MANIFEST_MAGIC, MANIFEST_VERSION)) {
+        return Err(corrupt(path, "unsupported manifest version"));
+    }
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let mut parts = Vec::new();
+    for line in lines {
+        let fields: Vec<&str> = line.split(' ').collect();
+        let [name, keys, crc] = fields[..] else {
+            return Err(corrupt(path, &format!("bad manifest line '{}'", line)));
+        };
+        // Parts always sit next to the manifest
+        let (Ok(keys), Ok(crc)) = (keys.parse::<usize>(), u32::from_str_radix(crc, 16)) else {
+            return Err(corrupt(path, &format!("bad manifest line '{}'", line)));
+        };
+        if Path::new(name).file_name() != Some(name.as_ref()) {
+            return Err(corrupt(path, &format!("bad part name '{}'", name)));
+        }
+        parts.push((dir.join(name), keys, crc));
+    }
+
+    let loaded: Vec<HashMap<String, Value>> = thread::scope(|scope| {
+        let workers: Vec<_> = parts
+            .iter()
+            .map(|(part, keys, crc)| {
+                scope.spawn(move || {
+                    let (store, _) = load_snapshot(part)?;
+                    let trailer =
+                        trailing_crc(part).map_err(|err| SnapshotError::Io(part.clone(), err))?;
+                    if trailer != *crc {
+                        return Err(corrupt(part, "checksum does not match the manifest"));
+                    }
+                    if store.len() != *keys {
+                        return Err(corrupt(part, "key count does not match the manifest"));
+                    }
+                    Ok(store)
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("load worker panicked"))
+            .collect::<Result<_, SnapshotError>>()
+    })?;
+
+    // Grow the first part's map rather than rehashing it into a new one
+    let mut loaded = loaded.into_iter();
+    let mut store = loaded.next().unwrap_or_default();
+    for part in loaded {
+        store.extend(part);
+    }
+    Ok(store)
+}
+
+/// The checksum at the end of a snapshot file
+fn trailing_crc(path: &Path) -> io::Result<u32> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::End(-4))?;
+    let mut trailer = [0; 4];
+    file.read_exact(&mut trailer)?;
+    Ok(u32::from_le_bytes(trailer))
+}
+
+/// Print what a snapshot holds without loading it into a store
+///
+/// Emits the header, then every key matching the `keys` glob, then the value
+/// of `get` (`(nil)` if it isn't there). The file is checked end to end
+/// before anything is emitted, so a corrupt snapshot fails with the same
+/// error `load` gives; entries are streamed both times, never held.
+pub fn inspect(
+    path: &Path,
+    keys: Option<&str>,
+    get: Option<&str>,
+    mut emit: impl FnMut(String),
+) -> Result<(), SnapshotError> {
+    SnapshotReader::open(path)?.try_for_each(|entry| entry.map(drop))?;
+
+    let mut reader = SnapshotReader::open(path)?;
+    let header = reader.header().clone();
+    emit(format!("version: {}", header.version));
+    emit(format!("keys: {}", header.key_count));
+    emit(match header.created {
+        Some(secs) => format!("created: {} (unix seconds)", secs),
+        None => "created: unknown (version 1 snapshot)".to_string(),
+    });
+
+    // A key can be both live and tombstoned (set again after a soft delete)
+    let mut found = None;
+    let mut found_deleted = None;
+    for entry in &mut reader {
+        let (key, entry) = entry?;
+        let deleted = matches!(entry, SnapshotEntry::Deleted(_));
+        if keys.is_some_and(|pattern| glob_match(pattern, &key)) {
+            emit(if deleted {
+                format!("{} (deleted)", key)
+            } else {
+                key.clone()
+            });
+        }
+        if get == Some(key.as_str()) {
+            match entry {
+                SnapshotEntry::Live(value) => found = Some(value),
+                SnapshotEntry::Deleted(tombstone) => found_deleted = Some(tombstone),
+            }
+        }
+    }
+    if let Some(key) = get {
+        emit(match found {
+            Some(value) => format!("{} = {}", key, value),
+            None => format!("{} = (nil)", key),
+        });
+        if let Some(tombstone) = found_deleted {
+            emit(format!(
+                "{} = {} (deleted at {}, unix seconds)",
+                key, tombstone.value, tombstone.deleted_at
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// One key read from a snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub enum SnapshotEntry {
+    Live(Value),
+    /// A soft-deleted key, kept so UNDELETE still works after a restart
+    Deleted(Tombstone),
+}
+
+/// What a snapshot's header says about it
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotHeader {
+    pub version: u8,
+    pub key_count: u64,
+    /// When the snapshot was written, in seconds since the Unix epoch
+    /// (version 1 snapshots don't record it)
+    pub created: Option<u64>,
+}
+
+/// Streams the entries of a snapshot file, checking its CRC as it goes
+///
+/// Entries are yielded as they are read, so the checksum is only known to
+/// match once iteration ends cleanly; callers that act on entries must treat
+/// them as provisional until then. A structural problem (a short read, an
+/// unknown tag) is reported as a checksum mismatch unless the rest of the
+/// file checks out, which keeps corruption errors the same as checking the
+/// CRC up front.
+pub struct SnapshotReader {
+    path: PathBuf,
+    input: BufReader<File>,
+    crc: Crc32,
+    /// Bytes left before the trailing checksum
+    remaining: u64,
+    header: SnapshotHeader,
+    entries_left: u64,
+    done: bool,
+}
+
+impl SnapshotReader {
+    /// Open `path` and read its header
+    pub fn open(path: &Path) -> Result<Self, SnapshotError> {
+        let io = |err| SnapshotError::Io(path.to_path_buf(), err);
+        let file = File::open(path).map_err(io)?;
+        let len = file.metadata().map_err(io)?.len();
+        let mut reader = Self {
+            path: path.to_path_buf(),
+            input: BufReader::new(file),
+            crc: Crc32::new(),
+            remaining: len
+                .checked_sub(4)
+                .ok_or_else(|| corrupt(path, "too short"))?,
+            header: SnapshotHeader {
+                version: 0,
+                key_count: 0,
+                created: None,
+            },
+            entries_left: 0,
+            done: false,
+        };
+
+        match reader.array::<8>() {
+            Ok(magic) if &magic == MAGIC => {}
+            Ok(_) => return Err(corrupt(path, "bad magic")),
+            Err(err) if is_structural(&err) => return Err(corrupt(path, "bad magic")),
+            Err(err) => return Err(io(err)),
+        }
+        match reader.read_header() {
+            Ok(header) if (1..=VERSION).contains(&header.version) => {
+                reader.entries_left = header.key_count;
+                reader.header = header;
+                Ok(reader)
+            }
+            Ok(header) if header.version > VERSION => {
+                Err(SnapshotError::TooNew(path.to_path_buf(), header.version))
+            }
+            Ok(header) => Err(reader.fail(&format!("unsupported version {}", header.version))),
+            Err(err) => Err(reader.structural(err, "truncated header")),
+        }
+    }
+
+    pub fn header(&self) -> &SnapshotHeader {
+        &self.header
+    }
+
+    fn read_header(&mut self) -> io::Result<SnapshotHeader> {
+        let [version] = self.array()?;
+        let key_count = u64::from_le_bytes(self.array()?);
+        // Version 1 predates the creation time
+        let created = if version >= 2 {
+            Some(u64::from_le_bytes(self.array()?))
+        } else {
+            None
+        };
+        Ok(SnapshotHeader {
+            version,
+            key_count,
+            created,
+        })
+    }
+
+    fn entry(&mut self) -> io::Result<(String, SnapshotEntry)> {
+        let [tag] = self.array()?;
+        let key = self.string()?;
+        if tag == TAG_TOMBSTONE && self.header.version >= 3 {
+            let deleted_at = u64::from_le_bytes(self.array()?);
+            let [tag] = self.array()?;
+            let value = self.body(tag)?;
+            return Ok((key, SnapshotEntry::Deleted(Tombstone { value, deleted_at })));
+        }
+        Ok((key, SnapshotEntry::Live(self.body(tag)?)))
+    }
+
+    fn body(&mut self, tag: u8) -> io::Result<Value> {
+        let value = match tag {
+            TAG_STR => Value::Str(self.string()?),
+            TAG_LIST => {
+                let count = u32::from_le_bytes(self.array()?);
+                let mut items = VecDeque::new();
+                for _ in 0..count {
+                    items.push_back(self.string()?);
+                }
+                Value::List(items)
+            }
+            TAG_CHECKSUMMED => Value::Checksummed {
+                value: self.string()?,
+                crc: u32::from_le_bytes(self.array()?),
+            },
+            TAG_INT if self.header.version >= 4 => Value::Int(i64::from_le_bytes(self.array()?)),
+            _ => return Err(io::ErrorKind::InvalidData.into()),
+        };
+        Ok(value)
+    }
+
+    /// Read `buf.len()` bytes of the body; running past the body is
+    /// reported as `UnexpectedEof`
+    fn fill(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        if buf.len() as u64 > self.remaining {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.input.read_exact(buf)?;
+        self.crc.update(buf);
+        self.remaining -= buf.len() as u64;
+        Ok(())
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buf = [0; N];
+        self.fill(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = u32::from_le_bytes(self.array()?) as u64;
+        // Check before allocating so a corrupt length can't exhaust memory
+        if len > self.remaining {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut buf = vec![0; len as usize];
+        self.fill(&mut buf)?;
+        String::from_utf8(buf).map_err(|_| io::ErrorKind::InvalidData.into())
+    }
+
+    /// Hash whatever is left of the body and compare it with the trailer
+    fn finish(&mut self) -> Result<(), SnapshotError> {
+        let path = self.path.clone();
+        let io = |err| SnapshotError::Io(path.clone(), err);
+        let mut chunk = [0; 8192];
+        while self.remaining > 0 {
+            let len = chunk.len().min(self.remaining as usize);
+            self.fill(&mut chunk[..len]).map_err(io)?;
+        }
+        let mut trailer = [0; 4];
+        self.input.read_exact(&mut trailer).map_err(io)?;
+        if self.crc.finish() != u32::from_le_bytes(trailer) {
+            return Err(corrupt(&self.path, "checksum mismatch"));
+        }
+        Ok(())
+    }
+
+    /// The error for a structurally bad file: a checksum mismatch if the
+    /// rest of the file doesn't check out, otherwise `reason`
+    fn fail(&mut self, reason: &str) -> SnapshotError {
+        self.done = true;
+        match self.finish() {
+            Ok(()) => corrupt(&self.path, reason),
+            Err(err) => err,
+        }
+    }
+
+    fn structural(&mut self, err: io::Error, reason: &str) -> SnapshotError {
+        if is_structural(&err) {
+            self.fail(reason)
+        } else {
+            self.done = true;
+            SnapshotError::Io(self.path.clone(), err)
+        }
+    }
+}
+
+impl Iterator for SnapshotReader {
+    type Item = Result<(String, SnapshotEntry), SnapshotError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        if self.entries_left == 0 {
+            self.done = true;
+            let trailing = self.remaining > 0;
+            return match self.finish() {
+                Ok(()) if trailing => Some(Err(corrupt(&self.path, "trailing bytes"))),
+                Ok(()) => None,
+                Err(err) => Some(Err(err)),
+            };
+        }
+        self.entries_left -= 1;
+        Some(match self.entry() {
+            Ok(entry) => Ok(entry),
+            Err(err) => Err(self.structural(err, "truncated entry")),
+        })
+    }
+}
+
+fn is_structural(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::InvalidData
+    )
+}
+
+fn corrupt(path: &Path, reason: &str) -> SnapshotError {
+    SnapshotError::Corrupt(path.to_path_buf(), reason.to_string())
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "crabby_snapshot_{}_{}.crkvs",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn test_round_trip_all_value_types() {
+        let path = temp_path("round_trip");
+        let mut store = HashMap::new();
+        store.insert("name".to_string(), Value::Str("line\nbreak é".to_string()));
+        store.insert(
+            "queue".to_string(),
+            Value::List(["a", "", "c"].iter().map(|s| s.to_string()).collect()),
+        );
+        store.insert("empty".to_string(), Value::List(VecDeque::new()));
+        store.insert(
+            "doc".to_string(),
+            Value::Checksummed {
+                value: "hello".to_string(),
+                crc: crc32(b"hello"),
+            },
+        );
+
+        save(&store, &path).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, store);
+    }
+
+    #[test]
+    fn test_integers_round_trip_and_need_version_4() {
+        let path = temp_path("ints");
+        let mut store = HashMap::new();
+        store.insert("plain".to_string(), Value::Str("42".to_string()));
+        save(&store, &path).unwrap();
+        let version = |path: &Path| SnapshotReader::open(path).unwrap().header().version;
+        assert_eq!(version(&path), VERSION_WITHOUT_TOMBSTONES);
+
+        for n in [0, -1, i64::MIN, i64::MAX] {
+            store.insert(format!("n{}", n), Value::Int(n));
+        }
+        save(&store, &path).unwrap();
+        assert_eq!(version(&path), VERSION);
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, store);
+    }
+
+    #[test]
+    fn test_tombstones_round_trip_alongside_live_keys() {
+        let path = temp_path("tombstones");
+        let mut store = HashMap::new();
+        store.insert("a".to_string(), Value::Str("new".to_string()));
+        let mut tombstones = Tombstones::new();
+        for (key, value) in [("a", "old"), ("gone", "bye")] {
+            let tombstone = Tombstone {
+                value: Value::Str(value.to_string()),
+                deleted_at: 1_700_000_000,
+            };
+            tombstones.insert(key.to_string(), tombstone);
+        }
+
+        save_with_tombstones(&store, &tombstones, &path).unwrap();
+        let (loaded, loaded_tombstones) = load_with_tombstones(&path).unwrap();
+        assert_eq!(loaded, store);
+        assert_eq!(loaded_tombstones, tombstones);
+        assert_eq!(load(&path).unwrap(), store);
+
+        let mut lines = Vec::new();
+        inspect(&path, Some("*"), Some("a"), |line| lines.push(line)).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(lines[..2], ["version: 3", "keys: 3"]);
+        assert!(lines.contains(&"gone (deleted)".to_string()), "{:?}", lines);
+        assert_eq!(
+            lines[lines.len() - 2..],
+            ["a = new", "a = old (deleted at 1700000000, unix seconds)"]
+        );
+    }
+
+    #[test]
+    fn test_rejects_corruption() {
+        let path = temp_path("corrupt");
+        let mut store = HashMap::new();
+        store.insert("k".to_string(), Value::Str("v".to_string()));
+        save(&store, &path).unwrap();
+        let good = fs::read(&path).unwrap();
+
+        let mut flipped = good.clone();
+        flipped[20] ^= 0xff;
+        // A corrupt key length runs past the end while streaming
+        let mut bad_length = good.clone();
+        bad_length[26] ^= 0xff;
+        let cases = [
+            (flipped, "checksum mismatch"),
+            (bad_length, "checksum mismatch"),
+            (good[..good.len() - 3].to_vec(), "checksum mismatch"),
+            (b"JUNK".to_vec(), "bad magic"),
+            (Vec::new(), "too short"),
+        ];
+        for (bytes, reason) in cases {
+            fs::write(&path, bytes).unwrap();
+            let err = load(&path).unwrap_err();
+            assert!(err.to_string().contains(reason), "{}", err);
+        }
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(load(&path), Err(SnapshotError::Io(..))));
+    }
+
+    #[test]
+    fn test_round_trip_keeps_tricky_bytes() {
+        let path = temp_path("tricky");
+        let tricky = [
+            "tab\tseparated\tvalue",
+            "crlf\r\nline",
+            "nul\0byte",
+            "",
+            " leading and trailing ",
+            "emoji 🦀 and é",
+            "key\tvalue\nkey2\tvalue2",
+        ];
+        let mut store = HashMap::new();
+        for (i, value) in tricky.iter().enumerate() {
+            store.insert(format!("{}\t{}", value, i), Value::Str(value.to_string()));
+        }
+        store.insert("big".to_string(), Value::Str("x\n".repeat(1 << 19)));
+        store.insert(
+            "list".to_string(),
+            Value::List(tricky.iter().map(|s| s.to_string()).collect()),
+        );
+
+        save(&store, &path).unwrap();
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded, store);
+    }
+
+    #[test]
+    fn test_refuses_snapshots_from_newer_builds() {
+        let path = temp_path("too_new");
+        let mut store = HashMap::new();
+        store.insert("k".to_string(), Value::Str("v".to_string()));
+        save(&store, &path).unwrap();
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[MAGIC.len()] = VERSION + 1;
+        fs::write(&path, bytes).unwrap();
+
+        let err = load(&path).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(
+            matches!(err, SnapshotError::TooNew(_, version) if version == VERSION + 1),
+            "{}",
+            err
+        );
+        assert!(
+            err.to_string().ends_with(&format!(
+                "snapshot version {} was written by a newer build (this build reads versions 1 to {})",
+                VERSION + 1,
+                VERSION
+            )),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_reads_version_1_snapshots() {
+        let path = temp_path("v1");
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(1);
+        bytes.extend_from_slice(&1u64.to_le_bytes());
+        bytes.push(TAG_STR);
+        put_string(&mut bytes, "k");
+        put_string(&mut bytes, "v");
+        let crc = crc32(&bytes);
+        bytes.extend_from_slice(&crc.to_le_bytes());
+        fs::write(&path, bytes).unwrap();
+
+        let reader = SnapshotReader::open(&path).unwrap();
+        assert_eq!(reader.header().created, None);
+        let loaded = load(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded["k"], Value::Str("v".to_string()));
+    }
+
+    #[test]
+    fn test_inspect_prints_header_keys_and_value() {
+        let path = temp_path("inspect");
+        let mut store = HashMap::new();
+        for key in ["user:1", "user:2", "session:9"] {
+            store.insert(key.to_string(), Value::Str(format!("{}-value", key)));
+        }
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        save(&store, &path).unwrap();
+
+        let mut lines = Vec::new();
+        inspect(&path, Some("user:*"), Some("session:9"), |line| {
+            lines.push(line)
+        })
+        .unwrap();
+        assert_eq!(lines[..2], ["version: 2", "keys: 3"]);
+        let created: u64 = lines[2]
+            .strip_prefix("created: ")
+            .and_then(|rest| rest.strip_suffix(" (unix seconds)"))
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(created >= before.as_secs());
+        let mut listed = lines[3..5].to_vec();
+        listed.sort();
+        assert_eq!(listed, ["user:1", "user:2"]);
+        assert_eq!(lines[5], "session:9 = session:9-value");
+        assert_eq!(lines.len(), 6);
+
+        let mut lines = Vec::new();
+        inspect(&path, None, Some("missing"), |line| lines.push(line)).unwrap();
+        assert_eq!(lines.last().unwrap(), "missing = (nil)");
+
+        // Corruption is caught before anything is printed
+        let mut bytes = fs::read(&path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        let mut lines = Vec::new();
+        let err = inspect(&path, Some("*"), None, |line| lines.push(line)).unwrap_err();
+        fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("checksum mismatch"), "{}", err);
+        assert!(lines.is_empty());
+    }
+
+    fn mixed_store(keys: usize) -> HashMap<String, Value> {
+        (0..keys)
+            .map(|i| {
+                let value = match i % 3 {
+                    0 => Value::Str(format!("value {}", i)),
+                    1 => Value::List([i.to_string(), "x".to_string()].into()),
+                    _ => Value::Checksummed {
+                        value: i.to_string(),
+                        crc: crc32(i.to_string().as_bytes()),
+                    },
+                };
+                (format!("key:{}", i), value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_parallel_dump_round_trips_through_manifest() {
+        let dir = temp_path("parallel_dump");
+        let store = mixed_store(1000);
+
+        let dump = save_parallel(&store, &dir, 4).unwrap();
+        assert_eq!(dump.parts, 4);
+        assert_eq!(dump.keys, 1000);
+        assert_eq!(dump.manifest, dir.join(MANIFEST_NAME));
+        let part = load(&dir.join("dump.part3")).unwrap();
+        assert_eq!(part.len(), 250);
+        assert_eq!(load(&dump.manifest).unwrap(), store);
+
+        // More threads than keys writes one part per key; none are empty
+        let small = mixed_store(2);
+        assert_eq!(save_parallel(&small, &dir, 8).unwrap().parts, 2);
+        assert_eq!(load(&dump.manifest).unwrap(), small);
+        let empty = HashMap::new();
+        assert_eq!(save_parallel(&empty, &dir, 3).unwrap().parts, 0);
+        assert!(load(&dump.manifest).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_rejects_parts_from_another_dump() {
+        let dir = temp_path("parallel_mismatch");
+        save_parallel(&mixed_store(10), &dir, 2).unwrap();
+        let manifest = dir.join(MANIFEST_NAME);
+
+        // A valid snapshot, but not the one the manifest recorded
+        save(&mixed_store(3), &dir.join("dump.part1")).unwrap();
+        let err = load(&manifest).unwrap_err();
+        assert!(err.to_string().contains("dump.part1"), "{}", err);
+        assert!(
+            err.to_string()
+                .contains("checksum does not match the manifest"),
+            "{}",
+            err
+        );
+
+        fs::remove_file(dir.join("dump.part1")).unwrap();
+        assert!(matches!(load(&manifest), Err(SnapshotError::Io(..))));
+
+        for contents in [
+            "CRKVMANIFEST 9\n",
+            "CRKVMANIFEST 1\nonly two\n",
+            "CRKVMANIFEST 1\n../x 1 00000000\n",
+        ] {
+            fs::write(&manifest, contents).unwrap();
+            let err = load(&manifest).unwrap_err();
+            assert!(matches!(err, SnapshotError::Corrupt(..)), "{}", err);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Compares a single-threaded snapshot with parallel dumps of a large
+    /// store
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_parallel_dump`
+    #[test]
+    #[ignore]
+    fn bench_parallel_dump() {
+        const KEYS: usize = 5_000_000;
+
+        let store = mixed_store(KEYS);
+        let path = temp_path("bench_single");
+        let start = std::time::Instant::now();
+        save(&store, &path).unwrap();
+        println!(
+            "[Bench] {} keys, single snapshot: {:?}",
+            KEYS,
+            start.elapsed()
+        );
+        let start = std::time::Instant::now();
+        assert_eq!(load(&path).unwrap().len(), KEYS);
+        println!("[Bench] {} keys, single load: {:?}", KEYS, start.elapsed());
+        fs::remove_file(&path).unwrap();
+
+        let dir = temp_path("bench_parallel");
+        for threads in [2, 4, 8] {
+            let start = std::time::Instant::now();
+            save_parallel(&store, &dir, threads).unwrap();
+            println!(
+                "[Bench] {} keys, parallel dump with {} threads: {:?}",
+                KEYS,
+                threads,
+                start.elapsed()
+            );
+        }
+        let start = std::time::Instant::now();
+        assert_eq!(load(&dir.join(MANIFEST_NAME)).unwrap().len(), KEYS);
+        println!(
+            "[Bench] {} keys, parallel load: {:?}",
+            KEYS,
+            start.elapsed()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
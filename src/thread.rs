@@ -1,10 +1,40 @@
-use std::sync::mpsc::{self, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
-use crate::config::Config;
-use crate::handler::CommandHandler;
-use crate::parser::Command;
+use crate::breaker::ParseErrorBreaker;
+#[cfg(debug_assertions)]
+use crate::config::DelayPosition;
+use crate::config::{Config, Distribution, OutputErrorPolicy};
+use crate::custom::CommandRegistry;
+use crate::events::{Event, EventLog};
+use crate::filter::CommandFilter;
+use crate::handler::{CommandHandler, HandlerError};
+use crate::import_report::{DEFAULT_OVERWRITE_KEYS, ImportReport};
+use crate::input::FileLines;
+use crate::ordering::{self, KeyOrder, KeySequence, KeySequencer};
+use crate::output::OutputSinks;
+use crate::parser::{Command, CommandType, ParseOptions, ParseStats};
+use crate::processor::{CommandProcessor, MessageContext};
+use crate::read_snapshot::ReadSnapshot;
+use crate::record::Recorder;
+use crate::snapshot;
+use crate::status;
+use crate::transform::{self, LineTransformer};
+
+/// Result of executing one command, as sent back on a reply channel
+pub type Reply = Result<String, HandlerError>;
+
+/// `io_thread_id` used for commands submitted straight to the main thread
+/// (e.g. through `KvEngine`) rather than parsed by an IO thread
+pub const DIRECT_SUBMISSION: usize = usize::MAX;
 
 /// Message type for communication between IO threads and main thread
 #[derive(Debug)]
@@ -12,52 +42,757 @@ pub struct CommandMessage {
     pub command: Command,
     pub line_number: usize,
     pub io_thread_id: usize,
+    /// Where to send the result, for callers waiting on it
+    pub reply: Option<Sender<Reply>>,
+    /// The line's place among the lines for its key, when per-key ordering
+    /// is on (direct submissions are never stamped)
+    pub sequence: Option<KeySequence>,
+    /// When the command was handed to the main thread
+    pub enqueued_at: Instant,
+    /// The id from the line's `[trace:<id>]` tag, or a generated one when
+    /// trace ids are shown
+    pub trace_id: Option<String>,
+    /// The result of a read an IO thread already answered from the read
+    /// snapshot, for the main thread to report rather than apply
+    pub answer: Option<Reply>,
 }
 
-/// ThreadPool manages multiple IO threads and one main processing thread
-pub struct ThreadPool {
-    io_threads: Vec<IoThread>,
-    main_thread: Option<MainThread>,
-    string_sender: Sender<(String, usize)>,
-    #[allow(dead_code)] // Used in debug builds for test hooks
+/// A handler error tagged with the command it came from
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandFailure {
+    pub line_number: usize,
+    pub io_thread_id: usize,
+    pub error: HandlerError,
+    /// Trace id to print with the error, when trace ids are shown
+    pub trace_id: Option<String>,
+}
+
+/// Commands handed to the main thread and not yet answered, and the most
+/// there have been at once
+///
+/// IO threads count a command as they send it, so the gauge includes
+/// commands still queued in the channel. Direct submissions are counted
+/// when the main thread receives them. A command stays in flight while
+/// per-key ordering holds it back or a WAITKEY is parked, until its
+/// result is reported.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    current: AtomicUsize,
+    max: AtomicUsize,
+}
+
+impl InFlight {
+    fn sent(&self) {
+        let now = self.current.fetch_add(1, Ordering::Relaxed) + 1;
+        self.max.fetch_max(now, Ordering::Relaxed);
+    }
+
+    fn finished(&self) {
+        self.current.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Commands in flight right now
+    pub fn current(&self) -> usize {
+        self.current.load(Ordering::Relaxed)
+    }
+
+    /// The most commands that have been in flight at once
+    pub fn max(&self) -> usize {
+        self.max.load(Ordering::Relaxed)
+    }
+}
+
+/// Counters reported by the main thread once every command has been handled
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RunSummary {
+    pub processed: usize,
+    pub succeeded: usize,
+    /// Expected failures such as GET misses
+    pub soft_errors: usize,
+    pub hard_errors: usize,
+    /// Extra attempts spent on commands that failed transiently
+    pub retries: usize,
+    /// Commands sent to the main thread and not yet answered (0 once the
+    /// run ends)
+    pub in_flight: usize,
+    /// The most commands that were in flight at once
+    pub max_in_flight: usize,
+    /// Input lines a line transformer dropped before parsing
+    pub dropped_lines: usize,
+    /// Reads answered from the read snapshot rather than by the main thread
+    /// (included in `processed`)
+    pub snapshot_reads: usize,
+    /// Commands the command filter kept from the main thread, by type
+    pub filtered: BTreeMap<&'static str, usize>,
+    /// The most touched keys with approximate command counts, when hot-key
+    /// tracking is on
+    pub hot_keys: Vec<(String, u64)>,
+    /// Creates and overwrites by SET-like commands, with `--import-report`
+    pub import: Option<ImportReport>,
+    /// An output stream failed partway through, so some output was lost
+    pub output_truncated: bool,
+    /// Input stopped early because too many lines failed to parse
+    /// (`ABORT_IF_PARSE_ERROR_RATE`)
+    pub parse_error_abort: bool,
+    /// Lines IO threads had already taken when the parse-error breaker
+    /// tripped, dropped without being parsed
+    pub unparsed_lines: usize,
+    /// Change in the number of keys over the run, set once the run ends
+    pub key_delta: i64,
+    /// How the store was restored from a snapshot at startup, if it was
+    pub recovery: Option<RecoveryReport>,
+}
+
+impl RunSummary {
+    /// Whether the run should exit with a failure code
+    ///
+    /// Only hard errors count unless `soft_errors_fail` is set.
+    pub fn is_failure(&self, soft_errors_fail: bool) -> bool {
+        self.hard_errors > 0 || (soft_errors_fail && self.soft_errors > 0)
+    }
+}
+
+/// Live counters updated by the main thread, readable while it runs
+#[derive(Debug, Default)]
+struct RunCounters {
+    processed: AtomicUsize,
+    succeeded: AtomicUsize,
+    soft_errors: AtomicUsize,
+    hard_errors: AtomicUsize,
+    retries: AtomicUsize,
+    dropped_lines: AtomicUsize,
+    snapshot_reads: AtomicUsize,
+    unparsed_lines: AtomicUsize,
+    filtered: Mutex<BTreeMap<&'static str, usize>>,
+    /// Lines answered by the main thread or found to produce no command
+    settled: AtomicUsize,
+    /// Successful parses per keyword, for PARSESTATS
+    parsed: Arc<ParseStats>,
+    /// Commands sent to the main thread and not yet answered
+    in_flight: Arc<InFlight>,
+    /// Fed by the IO threads with every parse, when `ABORT_IF_PARSE_ERROR_RATE`
+    /// is set
+    parse_errors: Option<ParseErrorBreaker>,
+}
+
+impl RunCounters {
+    /// Count a command's final result as a success or a soft or hard error
+    fn count_result(&self, result: &Reply) {
+        let counter = match result {
+            Ok(_) => &self.succeeded,
+            Err(error) if error.is_soft() => &self.soft_errors,
+            Err(_) => &self.hard_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn count_filtered(&self, name: &'static str) {
+        *self
+            .filtered
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(name)
+            .or_insert(0) += 1;
+    }
+
+    fn snapshot(&self) -> RunSummary {
+        RunSummary {
+            processed: self.processed.load(Ordering::Relaxed),
+            succeeded: self.succeeded.load(Ordering::Relaxed),
+            soft_errors: self.soft_errors.load(Ordering::Relaxed),
+            hard_errors: self.hard_errors.load(Ordering::Relaxed),
+            retries: self.retries.load(Ordering::Relaxed),
+            in_flight: self.in_flight.current(),
+            max_in_flight: self.in_flight.max(),
+            dropped_lines: self.dropped_lines.load(Ordering::Relaxed),
+            snapshot_reads: self.snapshot_reads.load(Ordering::Relaxed),
+            filtered: self
+                .filtered
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone(),
+            hot_keys: Vec::new(),
+            import: None,
+            output_truncated: false,
+            parse_error_abort: self
+                .parse_errors
+                .as_ref()
+                .is_some_and(ParseErrorBreaker::is_tripped),
+            unparsed_lines: self.unparsed_lines.load(Ordering::Relaxed),
+            key_delta: 0,
+            recovery: None,
+        }
+    }
+}
+
+/// Outcome of a `ThreadPool::shutdown`
+#[derive(Debug, Default)]
+pub struct ShutdownReport {
+    /// IO threads that exited cleanly
+    pub successful: usize,
+    /// IO threads that panicked
+    pub panicked: usize,
+    /// Times an IO thread recovered the shared receiver from a poisoned lock
+    pub poison_recoveries: usize,
+    /// Where each cleanly exited IO thread spent its time, by thread id
+    pub io_thread_timings: Vec<IoThreadTiming>,
+}
+
+/// How one IO thread split its time, for spotting receiver lock contention
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IoThreadTiming {
+    pub id: usize,
+    /// Lines taken off the shared channel
+    pub lines: usize,
+    /// Time spent acquiring the receiver lock and waiting in `recv()`
+    pub receive_wait: Duration,
+    /// Time spent parsing lines into commands
+    pub parse: Duration,
+    /// Time spent waiting for room in a full command queue
+    pub send_wait: Duration,
+}
+
+/// A line an IO thread took that will never reach the main thread as a
+/// command, reported so ordered output doesn't wait for it
+#[derive(Debug)]
+struct LineNotice {
+    line_number: usize,
+    io_thread_id: usize,
+    /// Why the line didn't parse (`None` for blank or skipped lines)
+    parse_error: Option<String>,
+}
+
+/// Restart timing for a store restored from a snapshot
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecoveryReport {
+    /// Keys restored
+    pub keys: usize,
+    /// Time spent reading and decoding the snapshot
+    pub snapshot_load: Duration,
+    /// Time from the main thread starting until it took its first command
+    pub ready: Duration,
+}
+
+/// State shared by every IO thread in a pool
+struct IoShared {
+    /// Times an IO thread recovered the shared receiver from a poisoned lock
+    poison_recoveries: AtomicUsize,
+    /// Session recorder fed with every successfully parsed line
+    recorder: Option<Mutex<Recorder>>,
+    /// Stamps lines taken off the shared channel when per-key ordering is on
+    sequencer: Option<Arc<KeySequencer>>,
+    /// Where lines that produce no command are reported when output is
+    /// ordered by line number
+    line_notices: Option<Sender<LineNotice>>,
+    /// Applied to every line before it is parsed
+    transformers: Vec<Box<dyn LineTransformer>>,
+    /// Shared with the main thread, for the dropped line count
+    counters: Arc<RunCounters>,
+    /// Give lines without a `[trace:<id>]` tag a generated trace id
+    generate_trace_ids: bool,
+    /// Which parsed commands are passed on to the main thread
+    filter: CommandFilter,
+    /// Custom commands lines are parsed against
+    commands: CommandRegistry,
+    /// Holds IO threads back from taking lines while the pool is paused
+    pause: PauseGate,
+    /// Where chaos injections in IO threads are reported
+    events: EventLog,
+    /// Answers reads without the main thread, when a read snapshot is on
+    /// and per-key ordering is off
+    reads: Option<Arc<ReadSnapshot>>,
+    /// Macros to expand, and test hooks in debug builds
     config: Arc<Config>,
 }
 
-impl ThreadPool {
-    /// Creates a new ThreadPool with specified number of IO threads
-    pub fn new(num_io_threads: usize, config: Arc<Config>) -> Self {
-        // Channel for IO threads to send parsed commands to main thread
-        let (command_tx, command_rx) = mpsc::channel::<CommandMessage>();
+/// Lets `ThreadPool::pause` stop IO threads from taking more lines
+///
+/// The flag is checked before every line, so running threads only pay for
+/// an atomic load; paused threads sleep on the condvar until `resume`.
+#[derive(Debug, Default)]
+struct PauseGate {
+    paused: AtomicBool,
+    lock: Mutex<()>,
+    resumed: Condvar,
+}
 
-        // Create a SINGLE shared channel for distributing raw strings to IO threads
-        let (string_tx, string_rx) = mpsc::channel::<(String, usize)>();
+impl PauseGate {
+    fn pause(&self) {
+        self.paused.store(true, Ordering::SeqCst);
+    }
 
-        // Wrap the receiver in Arc<Mutex<>> so all IO threads can share it
-        let shared_string_rx = Arc::new(Mutex::new(string_rx));
-
-        // Create IO threads - each gets a clone of the Arc'd receiver
-        let mut io_threads = Vec::with_capacity(num_io_threads);
-        for id in 0..num_io_threads {
-            io_threads.push(IoThread::new(
-                id,
-                command_tx.clone(),
-                Arc::clone(&shared_string_rx),
-                Arc::clone(&config),
-            ));
+    fn resume(&self) {
+        // Under the lock, so a thread between checking the flag and waiting
+        // can't miss the wakeup
+        let _guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.paused.store(false, Ordering::SeqCst);
+        self.resumed.notify_all();
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Block while the pool is paused
+    fn wait(&self) {
+        if !self.is_paused() {
+            return;
         }
+        let guard = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let _guard = self
+            .resumed
+            .wait_while(guard, |_| self.is_paused())
+            .unwrap_or_else(PoisonError::into_inner);
+    }
+}
 
-        // Drop the original command_tx so only IO threads hold senders
-        drop(command_tx);
+/// A line on an IO thread's own channel, already transformed (`None` if a
+/// transformer dropped it), with its trace id and, when per-key ordering is
+/// on, the dispatcher's stamp
+type DispatchedLine = (Option<String>, usize, Option<KeySequence>, Option<String>);
 
-        // Create main thread
-        let main_thread = MainThread::new(command_rx);
+/// Where an IO thread takes its input lines from
+enum LineSource {
+    /// The pool-wide channel every IO thread competes for
+    Shared(Arc<Mutex<Receiver<(String, usize)>>>),
+    /// This thread's own channel, fed by the pool's dispatcher
+    Dedicated(Receiver<DispatchedLine>),
+}
+
+impl IoShared {
+    /// Take the trace tag off a line and run the rest through the
+    /// transformers, counting it if one drops it
+    ///
+    /// Returns the transformed line and its trace id. Blank lines are passed
+    /// through untouched so they are skipped as blank rather than counted as
+    /// dropped.
+    fn transform(&self, line: String, line_number: usize) -> (Option<String>, Option<String>) {
+        let (line, trace_id) = match transform::take_trace_id(&line) {
+            Some((id, rest)) => (rest.to_string(), Some(id.to_string())),
+            None => (line, None),
+        };
+        if line.trim().is_empty() {
+            return (Some(line), trace_id);
+        }
+        let trace_id = trace_id.or_else(|| self.generate_trace_ids.then(generate_trace_id));
+        let transformed = transform::apply(&self.transformers, line, line_number);
+        if transformed.is_none() {
+            self.counters.dropped_lines.fetch_add(1, Ordering::Relaxed);
+        }
+        (transformed, trace_id)
+    }
+
+    /// Count a line that produced no command, reporting it if output is
+    /// ordered
+    fn notify(&self, line_number: usize, io_thread_id: usize, parse_error: Option<String>) {
+        self.counters.settled.fetch_add(1, Ordering::Relaxed);
+        if let Some(notices) = &self.line_notices {
+            let _ = notices.send(LineNotice {
+                line_number,
+                io_thread_id,
+                parse_error,
+            });
+        }
+    }
+}
+
+/// Builder for a `ThreadPool` with optional components
+///
+/// # Example
+/// ```no_run
+/// use multi_threader::config::Config;
+/// use multi_threader::thread::ThreadPoolBuilder;
+///
+/// let pool = ThreadPoolBuilder::new(Config::from_env())
+///     .io_threads(8)
+///     .build();
+/// ```
+pub struct ThreadPoolBuilder {
+    num_io_threads: usize,
+    config: Arc<Config>,
+    recorder: Option<Recorder>,
+    output: Option<OutputSinks>,
+    dry_run: bool,
+    save_on_exit: bool,
+    distribution: Distribution,
+    per_key_ordering: bool,
+    processor: Option<Box<dyn CommandProcessor>>,
+    transformers: Vec<Box<dyn LineTransformer>>,
+    show_trace_ids: bool,
+    filter: CommandFilter,
+    commands: CommandRegistry,
+    parse_stats: Option<Arc<ParseStats>>,
+    import_report: bool,
+    file_lines: Option<Arc<FileLines>>,
+    events: EventLog,
+    read_snapshot_max_age: Option<Duration>,
+}
 
+impl ThreadPoolBuilder {
+    /// Start building a pool with 4 IO threads, the configured distribution,
+    /// and no optional components
+    pub fn new(config: Arc<Config>) -> Self {
         Self {
+            num_io_threads: 4,
+            distribution: config.distribution,
+            per_key_ordering: config.per_key_ordering,
+            read_snapshot_max_age: config.read_snapshot_max_age,
+            transformers: transform::from_config(&config),
+            config,
+            recorder: None,
+            output: None,
+            dry_run: false,
+            save_on_exit: false,
+            processor: None,
+            show_trace_ids: false,
+            filter: CommandFilter::new(),
+            commands: CommandRegistry::new(),
+            parse_stats: None,
+            import_report: false,
+            file_lines: None,
+            events: EventLog::default(),
+        }
+    }
+
+    /// Set the number of IO threads
+    pub fn io_threads(mut self, num_io_threads: usize) -> Self {
+        self.num_io_threads = num_io_threads;
+        self
+    }
+
+    /// Record every successfully parsed line into a replayable session
+    pub fn recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Route responses, errors, and the summary (defaults to stdout/stderr)
+    pub fn output(mut self, output: OutputSinks) -> Self {
+        self.output = Some(output);
+        self
+    }
+
+    /// Apply commands to a shadow copy of the store so nothing is kept
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Write a snapshot when the main thread finishes, unless a
+    /// `SHUTDOWN SAVE` already did
+    pub fn save_on_exit(mut self, save_on_exit: bool) -> Self {
+        self.save_on_exit = save_on_exit;
+        self
+    }
+
+    /// Prefix responses and errors with their command's trace id, generating
+    /// one for lines without a `[trace:<id>]` tag
+    pub fn show_trace_ids(mut self, show_trace_ids: bool) -> Self {
+        self.show_trace_ids = show_trace_ids;
+        self
+    }
+
+    /// Count how many SET-like writes created, repeated, or changed a key,
+    /// and which keys were overwritten most, for the run summary
+    pub fn import_report(mut self, import_report: bool) -> Self {
+        self.import_report = import_report;
+        self
+    }
+
+    /// Name lines as `file:line` in output, and summarize each file's
+    /// commands on its own as well as the run's
+    pub fn file_lines(mut self, files: Arc<FileLines>) -> Self {
+        self.file_lines = Some(files);
+        self
+    }
+
+    /// Report snapshots, chaos injections, and the shutdown summary to
+    /// `events`
+    pub fn events(mut self, events: EventLog) -> Self {
+        self.events = events;
+        self
+    }
+
+    /// Keep commands the filter doesn't allow from reaching the main thread,
+    /// counting them by type in the run summary
+    pub fn command_filter(mut self, filter: CommandFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Choose how input lines are spread across IO threads (defaults to the
+    /// config's `DISTRIBUTION`)
+    pub fn distribution(mut self, distribution: Distribution) -> Self {
+        self.distribution = distribution;
+        self
+    }
+
+    /// Apply commands for the same key in input order, however the IO
+    /// threads interleave (defaults to the config's `PER_KEY_ORDERING`)
+    pub fn per_key_ordering(mut self, per_key_ordering: bool) -> Self {
+        self.per_key_ordering = per_key_ordering;
+        self
+    }
+
+    /// Answer GET, GETWITHTTL, and GETMATCHING from a copy of the store at
+    /// most `max_age` old instead of on the main thread, or always on the
+    /// main thread with `None` (defaults to the config's
+    /// `READ_SNAPSHOT_MAX_AGE_MS`)
+    ///
+    /// IO threads only answer reads themselves without per-key ordering,
+    /// which needs every command for a key applied in input order.
+    pub fn read_snapshot_max_age(mut self, max_age: Option<Duration>) -> Self {
+        self.read_snapshot_max_age = max_age;
+        self
+    }
+
+    /// Run every input line through `transformer` before parsing it, after
+    /// the config's built-ins and any transformers added earlier
+    pub fn line_transformer(mut self, transformer: Box<dyn LineTransformer>) -> Self {
+        self.transformers.push(transformer);
+        self
+    }
+
+    /// Apply commands with `processor` instead of a `CommandHandler` built
+    /// from the config; a snapshot at `SNAPSHOT_PATH` is still restored into it
+    pub fn processor(mut self, processor: Box<dyn CommandProcessor>) -> Self {
+        self.processor = Some(processor);
+        self
+    }
+
+    /// Parse and run the custom commands in `registry`
+    ///
+    /// The default handler runs them itself; a custom processor built
+    /// around a `CommandHandler` needs that handler's `commands()` here.
+    pub fn commands(mut self, registry: CommandRegistry) -> Self {
+        self.commands = registry;
+        self
+    }
+
+    /// Count successful parses into `parse_stats` rather than a fresh set,
+    /// so a custom processor can answer PARSESTATS from them
+    pub fn parse_stats(mut self, parse_stats: Arc<ParseStats>) -> Self {
+        self.parse_stats = Some(parse_stats);
+        self
+    }
+
+    /// Spawn the IO threads and prepare (but don't start) the main thread
+    pub fn build(self) -> ThreadPool {
+        let config = self.config;
+
+        // Channel for IO threads to send parsed commands to main thread,
+        // bounded so a slow main thread makes them wait instead of queueing
+        let (command_tx, command_rx) =
+            mpsc::sync_channel::<CommandMessage>(config.command_queue_capacity);
+
+        // Create a SINGLE channel for submitting raw strings to the pool
+        let (string_tx, string_rx) = mpsc::channel::<(String, usize)>();
+
+        let sequencer = self.per_key_ordering.then(|| Arc::new(KeySequencer::new()));
+        let reads = self.read_snapshot_max_age.map(|max_age| {
+            let counted = config.track_key_access || config.track_hot_keys;
+            Arc::new(ReadSnapshot::new(max_age).key_reads_counted(counted))
+        });
+        let (notice_tx, notice_rx) = mpsc::channel::<LineNotice>();
+        let line_notices = config.output_ordered.then_some(notice_tx);
+        let counters = Arc::new(RunCounters {
+            parsed: self.parse_stats.unwrap_or_default(),
+            parse_errors: config
+                .abort_if_parse_error_rate
+                .map(|rate| ParseErrorBreaker::new(config.parse_error_window.max(1), rate)),
+            ..RunCounters::default()
+        });
+        let shared = Arc::new(IoShared {
+            poison_recoveries: AtomicUsize::new(0),
+            recorder: self.recorder.map(Mutex::new),
+            sequencer: sequencer.clone(),
+            line_notices,
+            transformers: self.transformers,
+            counters: Arc::clone(&counters),
+            generate_trace_ids: self.show_trace_ids,
+            filter: self.filter,
+            commands: self.commands.clone(),
+            pause: PauseGate::default(),
+            events: self.events.clone(),
+            reads: reads.clone().filter(|_| !self.per_key_ordering),
+            config: Arc::clone(&config),
+        });
+
+        // Either every IO thread shares the string receiver (work stealing), or
+        // each gets its own channel and a dispatcher routes lines into them
+        let (sources, dispatcher) = match self.distribution {
+            Distribution::Shared => {
+                let shared_string_rx = Arc::new(Mutex::new(string_rx));
+                let sources = (0..self.num_io_threads)
+                    .map(|_| LineSource::Shared(Arc::clone(&shared_string_rx)))
+                    .collect();
+                (sources, None)
+            }
+            distribution => {
+                let (senders, sources): (Vec<_>, Vec<_>) = (0..self.num_io_threads)
+                    .map(|_| {
+                        let (tx, rx) = mpsc::channel();
+                        (tx, LineSource::Dedicated(rx))
+                    })
+                    .unzip();
+                let shared = Arc::clone(&shared);
+                let dispatcher =
+                    thread::spawn(move || dispatch_lines(distribution, shared, string_rx, senders));
+                (sources, Some(dispatcher))
+            }
+        };
+
+        let io_threads: Vec<IoThread> = sources
+            .into_iter()
+            .enumerate()
+            .map(|(id, source)| IoThread::new(id, command_tx.clone(), source, Arc::clone(&shared)))
+            .collect();
+
+        // Create main thread
+        let shutdown_requested = Arc::new(AtomicBool::new(false));
+        let mut output = self.output.unwrap_or_else(OutputSinks::stdio);
+        if config.output_ordered {
+            output = output.ordered(
+                config.output_order_max_buffered,
+                config.output_order_max_wait,
+            );
+        }
+        if let Some(files) = &self.file_lines {
+            output = output.file_lines(Arc::clone(files));
+        }
+        let mut main_thread = MainThread::new(
+            command_rx,
+            config,
+            output,
+            Arc::clone(&counters),
+            Arc::clone(&shutdown_requested),
+        );
+        main_thread.dry_run = self.dry_run;
+        main_thread.save_on_exit = self.save_on_exit;
+        main_thread.show_trace_ids = self.show_trace_ids;
+        main_thread.import_report = self.import_report;
+        main_thread.ordering = sequencer.map(KeyOrder::new);
+        main_thread.line_notices = notice_rx;
+        main_thread.processor = self.processor;
+        main_thread.commands = self.commands;
+        main_thread.file_lines = self.file_lines;
+        main_thread.events = self.events;
+        main_thread.reads = reads.clone();
+
+        // The pool keeps the original command_tx for direct submissions; it is
+        // dropped on shutdown so the main thread can see the disconnect
+        ThreadPool {
             io_threads,
             main_thread: Some(main_thread),
             string_sender: string_tx,
-            config,
+            command_sender: command_tx,
+            dispatcher,
+            shared,
+            counters,
+            shutdown_requested,
+            reads,
+        }
+    }
+}
+
+/// A WAITKEY whose key was missing, parked until the key is written or its
+/// deadline passes
+struct KeyWaiter {
+    key: String,
+    deadline: Instant,
+    line_number: usize,
+    io_thread_id: usize,
+    trace_id: Option<String>,
+    reply: Option<Sender<Reply>>,
+}
+
+/// WAITKEY commands parked by the main thread
+///
+/// The main thread is the only one touching the store, so a blocking wait
+/// there would also block the SET it is waiting for. Instead the waiter is
+/// parked and the main thread keeps processing, checking parked keys after
+/// every successful write and waking up for the earliest deadline.
+#[derive(Default)]
+struct KeyWaiters {
+    waiting: Vec<KeyWaiter>,
+}
+
+impl KeyWaiters {
+    fn park(&mut self, waiter: KeyWaiter) {
+        self.waiting.push(waiter);
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.waiting.iter().map(|waiter| waiter.deadline).min()
+    }
+
+    /// Remove and return the waiters whose deadline has passed
+    fn expire(&mut self, now: Instant) -> Vec<KeyWaiter> {
+        self.take_where(|waiter| waiter.deadline <= now)
+    }
+
+    /// Remove and return the waiters whose key now exists
+    fn take_present(&mut self, exists: impl Fn(&str) -> bool) -> Vec<KeyWaiter> {
+        self.take_where(|waiter| exists(&waiter.key))
+    }
+
+    fn drain(&mut self) -> Vec<KeyWaiter> {
+        std::mem::take(&mut self.waiting)
+    }
+
+    // Keeps the rest in arrival order so equal deadlines resolve FIFO
+    fn take_where(&mut self, mut pred: impl FnMut(&KeyWaiter) -> bool) -> Vec<KeyWaiter> {
+        if self.waiting.is_empty() {
+            return Vec::new();
         }
+        let (taken, kept) = std::mem::take(&mut self.waiting)
+            .into_iter()
+            .partition(|waiter| pred(waiter));
+        self.waiting = kept;
+        taken
+    }
+}
+
+/// ThreadPool manages multiple IO threads and one main processing thread
+pub struct ThreadPool {
+    io_threads: Vec<IoThread>,
+    main_thread: Option<MainThread>,
+    string_sender: Sender<(String, usize)>,
+    command_sender: SyncSender<CommandMessage>,
+    /// Routes lines to per-thread channels (absent for `Distribution::Shared`)
+    dispatcher: Option<JoinHandle<()>>,
+    shared: Arc<IoShared>,
+    counters: Arc<RunCounters>,
+    shutdown_requested: Arc<AtomicBool>,
+    reads: Option<Arc<ReadSnapshot>>,
+}
+
+/// Answers reads from the pool's read snapshot on the calling thread,
+/// counting them with the commands the main thread answers
+#[derive(Clone)]
+pub struct SnapshotReader {
+    reads: Arc<ReadSnapshot>,
+    counters: Arc<RunCounters>,
+}
+
+impl SnapshotReader {
+    /// Answer `command` from the read snapshot, or `None` if it has to be
+    /// sent to the main thread
+    pub fn serve(&self, command: &Command) -> Option<Reply> {
+        let result = self.reads.serve(command)?;
+        self.counters.processed.fetch_add(1, Ordering::Relaxed);
+        self.counters.snapshot_reads.fetch_add(1, Ordering::Relaxed);
+        self.counters.count_result(&result);
+        Some(result)
+    }
+}
+
+impl ThreadPool {
+    /// Creates a new ThreadPool with specified number of IO threads
+    pub fn new(num_io_threads: usize, config: Arc<Config>) -> Self {
+        ThreadPoolBuilder::new(config)
+            .io_threads(num_io_threads)
+            .build()
     }
 
     /// Get a sender to submit raw string inputs to IO threads (shared channel)
@@ -65,8 +800,94 @@ impl ThreadPool {
         self.string_sender.clone()
     }
 
+    /// Get a sender for submitting already-parsed commands straight to the
+    /// main thread, bypassing the IO threads
+    ///
+    /// Messages sent here should use `DIRECT_SUBMISSION` as their
+    /// `io_thread_id`. Every clone must be dropped before the main thread can
+    /// finish. The queue is bounded by `COMMAND_QUEUE_CAPACITY`, so a send
+    /// blocks while it is full.
+    pub fn get_command_sender(&self) -> SyncSender<CommandMessage> {
+        self.command_sender.clone()
+    }
+
+    /// Report that a line number will never be sent (e.g. it was dropped as
+    /// a duplicate), so output ordered by line number doesn't wait for it
+    pub fn skip_line(&self, line_number: usize) {
+        self.shared.notify(line_number, DIRECT_SUBMISSION, None);
+    }
+
+    /// Whether the main thread has stopped applying commands (after a
+    /// SHUTDOWN, at `MAX_TOTAL_COMMANDS`, on the first failed command with
+    /// `HALT_ON_ERROR`, or on failed output with `ON_OUTPUT_ERROR=stop`) or
+    /// too many lines failed to parse (`ABORT_IF_PARSE_ERROR_RATE`), so
+    /// input producers can stop sending
+    pub fn shutdown_requested(&self) -> bool {
+        self.shutdown_requested.load(Ordering::Relaxed)
+            || self
+                .counters
+                .parse_errors
+                .as_ref()
+                .is_some_and(ParseErrorBreaker::is_tripped)
+    }
+
+    /// Stop IO threads from taking lines until `resume`
+    ///
+    /// Input sent meanwhile stays queued in the channel. A thread already
+    /// waiting for input when the pool is paused may still take one line,
+    /// but holds it unparsed until the pool resumes.
+    pub fn pause(&self) {
+        self.shared.pause.pause();
+        status!("[ThreadPool] Paused IO threads");
+    }
+
+    /// Let paused IO threads take lines again
+    pub fn resume(&self) {
+        self.shared.pause.resume();
+        status!("[ThreadPool] Resumed IO threads");
+    }
+
+    /// Whether the IO threads are paused
+    pub fn is_paused(&self) -> bool {
+        self.shared.pause.is_paused()
+    }
+
+    /// A reader for answering commands from the read snapshot before
+    /// submitting them directly, if the pool has one
+    pub fn snapshot_reader(&self) -> Option<SnapshotReader> {
+        self.reads.as_ref().map(|reads| SnapshotReader {
+            reads: Arc::clone(reads),
+            counters: Arc::clone(&self.counters),
+        })
+    }
+
+    /// Snapshot of the main thread's counters so far
+    pub fn stats(&self) -> RunSummary {
+        self.counters.snapshot()
+    }
+
+    /// Wait until the first `lines` line numbers sent have all been answered
+    /// or found to produce no command, so a producer can finish one input
+    /// before starting the next
+    ///
+    /// Gives up, returning `false`, once the main thread stops taking
+    /// commands or exits.
+    pub fn wait_for_lines(&self, lines: usize, main_thread: &JoinHandle<RunSummary>) -> bool {
+        loop {
+            if self.counters.settled.load(Ordering::Relaxed) >= lines {
+                return true;
+            }
+            if self.shutdown_requested() || main_thread.is_finished() {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
     /// Start the main processing thread
-    pub fn start_main_thread(&mut self) -> JoinHandle<()> {
+    ///
+    /// The returned handle yields the run summary.
+    pub fn start_main_thread(&mut self) -> JoinHandle<RunSummary> {
         self.main_thread
             .take()
             .expect("Main thread already started")
@@ -74,12 +895,28 @@ impl ThreadPool {
     }
 
     /// Shutdown all threads gracefully
-    pub fn shutdown(self) {
-        println!("[ThreadPool] Initiating graceful shutdown...");
+    pub fn shutdown(self) -> ShutdownReport {
+        status!("[ThreadPool] Initiating graceful shutdown...");
+
+        // Paused IO threads would never drain their input
+        self.shared.pause.resume();
 
         // Drop the string_sender to signal IO threads that no more input is coming
         drop(self.string_sender);
-        println!("[ThreadPool] String sender dropped - signaling IO threads to finish");
+        drop(self.command_sender);
+        // A main thread that was never started will never empty the command
+        // queue; dropping its receiver fails the sends of IO threads waiting
+        // on a full queue, instead of leaving them blocked forever
+        drop(self.main_thread);
+        status!("[ThreadPool] String sender dropped - signaling IO threads to finish");
+
+        // The dispatcher exits once the input is drained, dropping the
+        // per-thread senders so those IO threads see the disconnect too
+        if let Some(dispatcher) = self.dispatcher
+            && dispatcher.join().is_err()
+        {
+            eprintln!("[ThreadPool] Line dispatcher panicked");
+        }
 
         // Collect all the join handles
         let handles: Vec<_> = self
@@ -92,165 +929,1192 @@ impl ThreadPool {
             .collect();
 
         // Wait for all IO threads to finish
-        let mut successful_shutdowns = 0;
+        let mut report = ShutdownReport::default();
         let total_threads = handles.len();
 
         for (id, handle) in handles {
-            println!("[ThreadPool] Waiting for IO thread {} to finish...", id);
+            status!("[ThreadPool] Waiting for IO thread {} to finish...", id);
             match handle.join() {
-                Ok(_) => {
-                    println!("[ThreadPool] IO thread {} finished successfully", id);
-                    successful_shutdowns += 1;
+                Ok(timing) => {
+                    status!("[ThreadPool] IO thread {} finished successfully", id);
+                    report.successful += 1;
+                    report.io_thread_timings.push(timing);
                 }
                 Err(e) => {
                     eprintln!("[ThreadPool] IO thread {} panicked: {:?}", id, e);
+                    report.panicked += 1;
                 }
             }
         }
 
-        println!(
+        status!(
             "[ThreadPool] All IO threads shut down ({}/{} successful)",
-            successful_shutdowns, total_threads
+            report.successful,
+            total_threads
         );
+
+        report.poison_recoveries = self.shared.poison_recoveries.load(Ordering::Relaxed);
+
+        // Every IO thread has exited, so the recording is complete
+        if let Some(recorder) = &self.shared.recorder
+            && let Err(e) = recorder
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .flush()
+        {
+            eprintln!("[ThreadPool] Failed to flush session recording: {}", e);
+        }
+        if report.poison_recoveries > 0 {
+            status!(
+                "[ThreadPool] Recovered from {} poisoned receiver lock(s)",
+                report.poison_recoveries
+            );
+        }
+
         // At this point, all IO threads have dropped their command_senders
         // The main thread's receiver will get disconnected and exit naturally
+        report
     }
 }
 
 /// IO Thread responsible for receiving strings and parsing commands
 pub struct IoThread {
     id: usize,
-    handle: JoinHandle<()>,
+    handle: JoinHandle<IoThreadTiming>,
 }
 
 impl IoThread {
     fn new(
         id: usize,
-        command_sender: Sender<CommandMessage>,
-        string_receiver: Arc<Mutex<Receiver<(String, usize)>>>,
-        config: Arc<Config>,
+        command_sender: SyncSender<CommandMessage>,
+        source: LineSource,
+        shared: Arc<IoShared>,
     ) -> Self {
-        let handle = thread::spawn(move || {
-            Self::run(id, string_receiver, command_sender, config);
-        });
+        let handle = thread::spawn(move || Self::run(id, source, command_sender, shared));
 
         Self { id, handle }
     }
 
     fn run(
         id: usize,
-        string_receiver: Arc<Mutex<Receiver<(String, usize)>>>,
-        command_sender: Sender<CommandMessage>,
-        _config: Arc<Config>,
-    ) {
-        println!("[IO Thread {}] Started", id);
+        source: LineSource,
+        command_sender: SyncSender<CommandMessage>,
+        shared: Arc<IoShared>,
+    ) -> IoThreadTiming {
+        status!("[IO Thread {}] Started", id);
+        let mut timing = IoThreadTiming {
+            id,
+            ..IoThreadTiming::default()
+        };
 
-        // Process incoming strings from the shared channel (work-stealing)
+        // Process incoming strings from the shared channel (work-stealing) or
+        // this thread's own channel
         loop {
-            let result = string_receiver.lock().unwrap().recv();
+            shared.pause.wait();
+            let receive_start = Instant::now();
+            let result = match &source {
+                LineSource::Shared(string_receiver) => {
+                    let receiver =
+                        Self::lock_receiver(id, string_receiver, &shared.poison_recoveries);
+                    let result = receiver.recv();
+
+                    // Deliberately panic while holding the guard (only in debug builds)
+                    #[cfg(debug_assertions)]
+                    if let Ok((_, line_number)) = &result {
+                        shared.config.test.maybe_panic_io_thread(*line_number);
+                    }
+
+                    match (result, &shared.sequencer) {
+                        // Stamp while the lock still fixes this line's place in
+                        // the input, after transforming so the stamp uses the
+                        // key that will be parsed
+                        (Ok((line, line_number)), Some(sequencer)) => {
+                            let (line, trace_id) = shared.transform(line, line_number);
+                            let sequence = line.as_deref().and_then(|line| sequencer.stamp(line));
+                            Ok((line, line_number, sequence, trace_id))
+                        }
+                        (result, _) => {
+                            drop(receiver);
+                            result.map(|(line, line_number)| {
+                                let (line, trace_id) = shared.transform(line, line_number);
+                                (line, line_number, None, trace_id)
+                            })
+                        }
+                    }
+                }
+                LineSource::Dedicated(receiver) => {
+                    let result = receiver.recv();
 
-            match result {
-                Ok((raw_string, line_number)) => {
-                    // Inject random delay for testing (only in debug builds)
                     #[cfg(debug_assertions)]
-                    _config.test.maybe_sleep_io_thread();
+                    if let Ok((_, line_number, _, _)) = &result {
+                        shared.config.test.maybe_panic_io_thread(*line_number);
+                    }
+
+                    result
+                }
+            };
+            timing.receive_wait += receive_start.elapsed();
+
+            match result {
+                Ok((line, line_number, sequence, trace_id)) => {
+                    // Taken while paused: hold it until the pool resumes
+                    shared.pause.wait();
+                    timing.lines += 1;
 
-                    // Skip empty lines
-                    if raw_string.trim().is_empty() {
+                    // Too many parse errors: drop what was already taken
+                    if let Some(breaker) = &shared.counters.parse_errors
+                        && breaker.is_tripped()
+                    {
+                        shared
+                            .counters
+                            .unparsed_lines
+                            .fetch_add(1, Ordering::Relaxed);
+                        shared.notify(line_number, id, None);
+                        if let (Some(sequencer), Some(sequence)) = (&shared.sequencer, sequence) {
+                            sequencer.abandon(sequence);
+                        }
                         continue;
                     }
-                    println!(
-                        "[IO Thread {}] Processing line {}: {}",
-                        id, line_number, raw_string
+
+                    // Inject random delay for testing (only in debug builds)
+                    #[cfg(debug_assertions)]
+                    {
+                        let slept = shared.config.test.maybe_sleep_io_thread();
+                        if !slept.is_zero() {
+                            shared.events.emit(
+                                Event::new("chaos")
+                                    .field("kind", "io_sleep")
+                                    .field("line", line_number)
+                                    .field("ms", slept.as_millis()),
+                            );
+                        }
+                    }
+
+                    // Skip empty lines and lines a transformer dropped
+                    let raw_string = match line {
+                        Some(line) if !line.trim().is_empty() => line,
+                        _ => {
+                            shared.notify(line_number, id, None);
+                            if let (Some(sequencer), Some(sequence)) = (&shared.sequencer, sequence)
+                            {
+                                sequencer.abandon(sequence);
+                            }
+                            continue;
+                        }
+                    };
+                    // A macro line is logged, parsed, and recorded as its expansion
+                    let id_clause = shared.config.idempotency_window > 0;
+                    let expansion = shared.config.macros.expand(&raw_string, id_clause);
+                    let line = match &expansion {
+                        Ok(Some(expanded)) => expanded.as_str(),
+                        _ => raw_string.as_str(),
+                    };
+                    let trace = trace_tag(trace_id.as_deref());
+                    status!(
+                        "[IO Thread {}] Processing line {}: {}{}{}",
+                        id,
+                        line_number,
+                        raw_string,
+                        match &expansion {
+                            Ok(Some(expanded)) => format!(" (expands to: {})", expanded),
+                            _ => String::new(),
+                        },
+                        trace
                     );
 
                     // Parse the string into a Command
-                    match raw_string.parse::<Command>() {
+                    let parse_start = Instant::now();
+                    let parsed = match &expansion {
+                        Err(err) => Err(err.clone()),
+                        Ok(_) => Command::parse_with(
+                            line,
+                            ParseOptions {
+                                custom: Some(&shared.commands),
+                                id_clause,
+                            },
+                        ),
+                    };
+                    timing.parse += parse_start.elapsed();
+                    if let Ok(command) = &parsed {
+                        shared.counters.parsed.record(command.command_type.name());
+                    }
+                    if let Some(breaker) = &shared.counters.parse_errors
+                        && breaker.record(parsed.is_err())
+                    {
+                        eprintln!(
+                            "[IO Thread {}] Error: {} of the last {} lines failed to parse, over ABORT_IF_PARSE_ERROR_RATE={}; is the input in the wrong format? Reading no more input",
+                            id,
+                            breaker.errors(),
+                            breaker.window(),
+                            breaker.max_rate()
+                        );
+                        shared.events.emit(
+                            Event::new("parse_error_abort")
+                                .field("line", line_number)
+                                .field("errors", breaker.errors())
+                                .field("window", breaker.window()),
+                        );
+                    }
+                    match parsed {
+                        Ok(command) if !shared.filter.allows(&command.command_type) => {
+                            shared.counters.count_filtered(command.command_type.name());
+                            shared.notify(line_number, id, None);
+                            if let (Some(sequencer), Some(sequence)) = (&shared.sequencer, sequence)
+                            {
+                                sequencer.abandon(sequence);
+                            }
+                        }
                         Ok(command) => {
+                            // Targeted delay so other IO threads can overtake this command
+                            #[cfg(debug_assertions)]
+                            {
+                                let name = command.command_type.name();
+                                let delay = shared
+                                    .config
+                                    .test
+                                    .maybe_delay_command(DelayPosition::After, name);
+                                if !delay.is_zero() {
+                                    shared.events.emit(
+                                        Event::new("chaos")
+                                            .field("kind", "delay_after")
+                                            .field("command", name)
+                                            .field("line", line_number)
+                                            .field("ms", delay.as_millis()),
+                                    );
+                                }
+                            }
+
+                            if let Some(recorder) = &shared.recorder
+                                && let Err(e) = recorder
+                                    .lock()
+                                    .unwrap_or_else(PoisonError::into_inner)
+                                    .record(line)
+                            {
+                                eprintln!(
+                                    "[IO Thread {}] Failed to record line {}: {}",
+                                    id, line_number, e
+                                );
+                            }
+
+                            // The main thread only reports a read answered here
+                            let answer = shared
+                                .reads
+                                .as_ref()
+                                .and_then(|reads| reads.serve(&command))
+                                .map(|result| result.map_err(|err| err.at_line(line_number)));
+
                             let msg = CommandMessage {
                                 command,
                                 line_number,
                                 io_thread_id: id,
+                                reply: None,
+                                sequence,
+                                enqueued_at: Instant::now(),
+                                trace_id,
+                                answer,
                             };
 
-                            // Send to main thread for processing
-                            if command_sender.send(msg).is_err() {
+                            // Send to main thread for processing, counted first
+                            // so the main thread can't finish it before then;
+                            // blocks while the command queue is full
+                            shared.counters.in_flight.sent();
+                            let send_start = Instant::now();
+                            let sent = command_sender.send(msg);
+                            timing.send_wait += send_start.elapsed();
+                            if sent.is_err() {
+                                shared.counters.in_flight.finished();
                                 eprintln!("[IO Thread {}] Main thread disconnected", id);
                                 break;
                             }
                         }
                         Err(parse_err) => {
-                            eprintln!(
-                                "[IO Thread {}] Parse Error at line {}: {} (line: '{}')",
-                                id, line_number, parse_err, raw_string
+                            // Ordered output reports the error in its place instead
+                            if shared.line_notices.is_none() {
+                                eprintln!(
+                                    "[IO Thread {}] Parse Error at line {}: {} (line: '{}'){}",
+                                    id, line_number, parse_err, raw_string, trace
+                                );
+                            }
+                            shared.notify(
+                                line_number,
+                                id,
+                                Some(format!("{} (line: '{}')", parse_err, raw_string)),
                             );
+                            if let (Some(sequencer), Some(sequence)) = (&shared.sequencer, sequence)
+                            {
+                                sequencer.abandon(sequence);
+                            }
                         }
                     }
                 }
                 Err(_) => {
                     // Channel disconnected, no more work
-                    println!("[IO Thread {}] Channel disconnected", id);
+                    status!("[IO Thread {}] Channel disconnected", id);
                     break;
                 }
             }
         }
 
-        println!("[IO Thread {}] Shutting down", id);
+        status!("[IO Thread {}] Shutting down", id);
+        timing
     }
-}
 
-/// Main Thread responsible for processing commands and accessing the store
-pub struct MainThread {
-    command_receiver: Receiver<CommandMessage>,
+    /// Lock the shared receiver, recovering it if another IO thread panicked
+    /// while holding the lock
+    ///
+    /// The receiver itself is never left half-updated by a panic (the panic can
+    /// only happen after `recv()` returned), so it is safe to keep using it.
+    fn lock_receiver<'a>(
+        id: usize,
+        string_receiver: &'a Mutex<Receiver<(String, usize)>>,
+        poison_recoveries: &AtomicUsize,
+    ) -> MutexGuard<'a, Receiver<(String, usize)>> {
+        match string_receiver.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => {
+                eprintln!(
+                    "[IO Thread {}] Receiver lock was poisoned by a panicked IO thread, recovering",
+                    id
+                );
+                poison_recoveries.fetch_add(1, Ordering::Relaxed);
+                string_receiver.clear_poison();
+                poisoned.into_inner()
+            }
+        }
+    }
 }
 
-impl MainThread {
-    fn new(command_receiver: Receiver<CommandMessage>) -> Self {
-        Self { command_receiver }
+/// Route lines from the pool's input channel to per-IO-thread channels until
+/// the input disconnects
+///
+/// Lines are transformed here rather than on the IO threads, so routing and
+/// stamping see the key that will actually be parsed.
+fn dispatch_lines(
+    distribution: Distribution,
+    shared: Arc<IoShared>,
+    input: Receiver<(String, usize)>,
+    outputs: Vec<Sender<DispatchedLine>>,
+) {
+    let mut next = 0;
+    for (line, line_number) in input {
+        let (line, trace_id) = shared.transform(line, line_number);
+        let slot = match distribution {
+            Distribution::KeyHash => key_slot(line.as_deref().unwrap_or(""), outputs.len()),
+            _ => {
+                let slot = next;
+                next = (next + 1) % outputs.len();
+                slot
+            }
+        };
+        let sequence = shared
+            .sequencer
+            .as_ref()
+            .zip(line.as_deref())
+            .and_then(|(sequencer, line)| sequencer.stamp(line));
+        // Only fails if that IO thread panicked
+        if outputs[slot]
+            .send((line, line_number, sequence, trace_id))
+            .is_err()
+        {
+            eprintln!(
+                "[Dispatcher] IO thread {} has exited, dropping line {}",
+                slot, line_number
+            );
+        }
     }
+    status!("[Dispatcher] Input drained, closing IO thread channels");
+}
 
-    /// Start the main processing thread
-    pub fn start(self) -> JoinHandle<()> {
-        thread::spawn(move || {
-            self.run();
-        })
-    }
+/// A fresh trace id for a line that came without one: eight hex digits
+fn generate_trace_id() -> String {
+    format!("{:08x}", rand::random::<u32>())
+}
 
-    fn run(self) {
-        println!("[Main Thread] Started");
+/// ` [trace:<id>]` for log lines about a traced command, or nothing
+fn trace_tag(trace_id: Option<&str>) -> String {
+    trace_id.map_or_else(String::new, |id| format!(" [trace:{}]", id))
+}
 
-        let mut handler = CommandHandler::new();
-        let mut processed_count = 0;
+/// The IO thread that parses `line` under `Distribution::KeyHash`, chosen
+/// by hashing its `ordering::routing_key`
+fn key_slot(line: &str, slots: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    ordering::routing_key(line).hash(&mut hasher);
+    (hasher.finish() % slots as u64) as usize
+}
+
+/// Pause before the first retry of a transiently failing command; doubles
+/// with each further retry up to `MAX_RETRY_BACKOFF`
+const RETRY_BACKOFF: Duration = Duration::from_millis(1);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Hot keys listed in the run summary
+const SUMMARY_HOT_KEYS: usize = 10;
+
+/// The message a panic was raised with, for reporting a panicked thread
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown panic payload".to_string())
+}
+
+/// How one file's commands fared, for the per-file lines of the summary
+#[derive(Debug, Clone, Copy, Default)]
+struct FileResults {
+    succeeded: usize,
+    soft_errors: usize,
+    hard_errors: usize,
+}
+
+/// Main Thread responsible for processing commands and accessing the store
+pub struct MainThread {
+    command_receiver: Receiver<CommandMessage>,
+    config: Arc<Config>,
+    output: OutputSinks,
+    counters: Arc<RunCounters>,
+    /// Work on a snapshot of the store and skip writes outside it
+    dry_run: bool,
+    /// Write a snapshot when the run ends
+    save_on_exit: bool,
+    /// Prefix responses and errors with their trace id
+    show_trace_ids: bool,
+    /// Have the default handler count overwrites for the summary
+    import_report: bool,
+    /// Set once a SHUTDOWN succeeds, read by `ThreadPool::shutdown_requested`
+    shutdown_requested: Arc<AtomicBool>,
+    /// Holds back commands that overtook an earlier command for their key
+    ordering: Option<KeyOrder>,
+    /// Lines that produced no command, for ordered output
+    line_notices: Receiver<LineNotice>,
+    /// Applies commands in place of the default `CommandHandler`
+    processor: Option<Box<dyn CommandProcessor>>,
+    /// Custom commands the default `CommandHandler` runs
+    commands: CommandRegistry,
+    /// The files of a multi-file run, to count each file's results by
+    file_lines: Option<Arc<FileLines>>,
+    /// Results per file, indexed like `file_lines`
+    file_results: Vec<FileResults>,
+    /// Where snapshots, chaos injections, and the shutdown summary are
+    /// reported
+    events: EventLog,
+    /// Published after every command for reads answered off this thread
+    reads: Option<Arc<ReadSnapshot>>,
+    /// Write attempts so far, for the failure injection hook
+    #[cfg(debug_assertions)]
+    write_attempts: u64,
+}
+
+impl MainThread {
+    fn new(
+        command_receiver: Receiver<CommandMessage>,
+        config: Arc<Config>,
+        output: OutputSinks,
+        counters: Arc<RunCounters>,
+        shutdown_requested: Arc<AtomicBool>,
+    ) -> Self {
+        Self {
+            command_receiver,
+            config,
+            output,
+            counters,
+            dry_run: false,
+            save_on_exit: false,
+            show_trace_ids: false,
+            import_report: false,
+            shutdown_requested,
+            ordering: None,
+            line_notices: mpsc::channel().1,
+            processor: None,
+            commands: CommandRegistry::new(),
+            file_lines: None,
+            file_results: Vec::new(),
+            events: EventLog::default(),
+            reads: None,
+            #[cfg(debug_assertions)]
+            write_attempts: 0,
+        }
+    }
 
-        // Process commands from the queue
-        while let Ok(msg) = self.command_receiver.recv() {
-            processed_count += 1;
+    /// Start the main processing thread
+    pub fn start(self) -> JoinHandle<RunSummary> {
+        thread::spawn(move || self.run())
+    }
+
+    fn run(mut self) -> RunSummary {
+        status!("[Main Thread] Started");
 
-            match handler.process_command(msg.command) {
-                Ok(response) => {
-                    println!(
-                        "[Line {} | IO Thread {}] {}",
-                        msg.line_number, msg.io_thread_id, response
+        let started = Instant::now();
+        let mut seeded = match self.processor.take() {
+            Some(processor) => processor,
+            None => Box::new(
+                CommandHandler::with_capacity(self.config.store_initial_capacity)
+                    .with_idempotency_window(self.config.idempotency_window)
+                    .with_snapshot_path(self.config.snapshot_path.clone())
+                    .with_value_case(self.config.value_case)
+                    .with_set_duplicate_policy(self.config.set_duplicate_policy)
+                    .with_soft_delete(self.config.soft_delete, self.config.tombstone_ttl_secs)
+                    .with_hot_keys(if self.config.track_hot_keys {
+                        self.config.hot_keys_capacity
+                    } else {
+                        0
+                    })
+                    .with_validation(self.config.validation_rules.clone())
+                    .with_quotas(self.config.prefix_quotas.clone())
+                    .with_key_access(self.config.track_key_access)
+                    .with_value_history(self.config.value_history_depth)
+                    // Overwrites are counted in a sketch the size of the
+                    // hot-key one, when that is configured
+                    .with_import_report(if !self.import_report {
+                        0
+                    } else if self.config.track_hot_keys {
+                        self.config.hot_keys_capacity
+                    } else {
+                        DEFAULT_OVERWRITE_KEYS
+                    })
+                    .with_procedures(self.config.procedures.clone())
+                    .with_commands(self.commands.clone())
+                    .with_parse_stats(Arc::clone(&self.counters.parsed))
+                    .with_in_flight(Arc::clone(&self.counters.in_flight)),
+            ),
+        };
+        let counters = Arc::clone(&self.counters);
+
+        // Once stopped, every command is answered with ShuttingDown
+        let mut stopped = false;
+        // Whether the store on disk is already current (or must not be replaced)
+        let mut skip_exit_save = self.dry_run;
+        let mut recovery = None;
+        if let Some(path) = self.config.snapshot_path.as_deref().filter(|p| p.exists()) {
+            let load_start = Instant::now();
+            match snapshot::load_with_tombstones(path) {
+                Ok((store, tombstones)) => {
+                    let keys = store.len();
+                    let snapshot_load = load_start.elapsed();
+                    seeded.restore(store);
+                    seeded.restore_tombstones(tombstones);
+                    let report = RecoveryReport {
+                        keys,
+                        snapshot_load,
+                        ready: started.elapsed(),
+                    };
+                    status!(
+                        "[Main Thread] Recovered {} keys from {} (snapshot load {:?}, ready in {:?})",
+                        keys,
+                        path.display(),
+                        report.snapshot_load,
+                        report.ready
+                    );
+                    self.events.emit(
+                        Event::new("snapshot_restored")
+                            .field("path", path.display())
+                            .field("keys", keys)
+                            .field("load_ms", report.snapshot_load.as_millis()),
                     );
+                    recovery = Some(report);
                 }
                 Err(err) => {
+                    // Running on an empty store would let a later save
+                    // overwrite the data this snapshot still holds
                     eprintln!(
-                        "[Line {} | IO Thread {}] Error: {}",
-                        msg.line_number, msg.io_thread_id, err
+                        "[Main Thread] Error restoring snapshot: {}; refusing all commands",
+                        err
+                    );
+                    self.events.emit(
+                        Event::new("snapshot_restore_failed")
+                            .field("path", path.display())
+                            .field("error", &err),
+                    );
+                    counters.hard_errors.fetch_add(1, Ordering::Relaxed);
+                    stopped = true;
+                    skip_exit_save = true;
+                    self.shutdown_requested.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+
+        let keys_before = seeded.key_count();
+        // A dry run applies everything to a shadow copy, so later lines see
+        // earlier effects while the seeded store is never touched
+        let mut handler = match (self.dry_run, seeded.shadow()) {
+            (false, _) => seeded,
+            (true, Some(shadow)) => {
+                status!("[Main Thread] Dry run: changes go to a shadow copy of the store");
+                shadow
+            }
+            (true, None) => {
+                eprintln!(
+                    "[Main Thread] Error: this processor can't run dry; refusing all commands"
+                );
+                counters.hard_errors.fetch_add(1, Ordering::Relaxed);
+                stopped = true;
+                self.shutdown_requested.store(true, Ordering::Relaxed);
+                seeded
+            }
+        };
+
+        if !stopped && let Some(reads) = &self.reads {
+            reads.refresh(&*handler);
+        }
+
+        let mut waiters = KeyWaiters::default();
+
+        // Process commands from the queue, waking up for WAITKEY deadlines
+        'receive: loop {
+            if self.stop_accepting() {
+                break;
+            }
+            for waiter in waiters.expire(Instant::now()) {
+                self.finish(
+                    waiter.line_number,
+                    waiter.io_thread_id,
+                    waiter.trace_id.as_deref(),
+                    waiter.reply,
+                    Ok("0".to_string()),
+                );
+            }
+            let received = match waiters.next_deadline() {
+                Some(deadline) => self
+                    .command_receiver
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now())),
+                None => self
+                    .command_receiver
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            // With per-key ordering, a command may release others held behind
+            // it; once input ends, everything still held is released in order
+            if let Ok(msg) = &received
+                && msg.io_thread_id == DIRECT_SUBMISSION
+            {
+                self.counters.in_flight.sent();
+            }
+            let (batch, disconnected) = match (received, self.ordering.as_mut()) {
+                (Ok(msg), None) => (vec![msg], false),
+                (Ok(msg), Some(ordering)) => (ordering.admit(msg), false),
+                (Err(RecvTimeoutError::Timeout), _) => continue,
+                (Err(RecvTimeoutError::Disconnected), None) => break,
+                (Err(RecvTimeoutError::Disconnected), Some(ordering)) => (ordering.drain(), true),
+            };
+            self.take_line_notices();
+            if disconnected && !batch.is_empty() {
+                status!(
+                    "[Main Thread] Releasing {} commands still held for ordering",
+                    batch.len()
+                );
+            }
+
+            for msg in batch {
+                if self.stop_accepting() {
+                    break 'receive;
+                }
+
+                // Drain whatever was queued behind the SHUTDOWN without applying it
+                if stopped {
+                    counters.processed.fetch_add(1, Ordering::Relaxed);
+                    self.finish(
+                        msg.line_number,
+                        msg.io_thread_id,
+                        msg.trace_id.as_deref(),
+                        msg.reply,
+                        Err(HandlerError::ShuttingDown),
+                    );
+                    continue;
+                }
+
+                // Targeted delay holding up everything queued behind this command
+                #[cfg(debug_assertions)]
+                {
+                    let name = msg.command.command_type.name();
+                    let delay = self
+                        .config
+                        .test
+                        .maybe_delay_command(DelayPosition::Before, name);
+                    if !delay.is_zero() {
+                        self.events.emit(
+                            Event::new("chaos")
+                                .field("kind", "delay_before")
+                                .field("command", name)
+                                .field("line", msg.line_number)
+                                .field("ms", delay.as_millis()),
+                        );
+                    }
+                }
+
+                counters.processed.fetch_add(1, Ordering::Relaxed);
+
+                let is_write = msg.command.command_type.is_write();
+                let shutdown = match msg.command.command_type {
+                    CommandType::Shutdown(save) => Some(save),
+                    _ => None,
+                };
+                let wait = match &msg.command.command_type {
+                    CommandType::WaitKey(key, timeout_ms) if *timeout_ms > 0 => {
+                        Some((key.clone(), Duration::from_millis(*timeout_ms)))
+                    }
+                    _ => None,
+                };
+
+                let ctx = MessageContext {
+                    line_number: msg.line_number,
+                    io_thread_id: msg.io_thread_id,
+                    enqueued_at: msg.enqueued_at,
+                    trace_id: msg.trace_id,
+                };
+                let trace = trace_tag(ctx.trace_id.as_deref());
+                if !trace.is_empty() {
+                    status!(
+                        "[Main Thread] Applying line {}: {}{}",
+                        ctx.line_number,
+                        msg.command.command_type,
+                        trace
+                    );
+                }
+                let (result, retries) = match msg.answer {
+                    Some(answer) => {
+                        counters.snapshot_reads.fetch_add(1, Ordering::Relaxed);
+                        (answer, 0)
+                    }
+                    None => self.apply_with_retries(&mut *handler, msg.command, &ctx),
+                };
+                counters.retries.fetch_add(retries, Ordering::Relaxed);
+                // Before answering, so an acknowledged write is never hidden
+                // behind a copy that still passes for current, and nothing
+                // is read from the copy once a stop is acknowledged
+                if let Some(reads) = &self.reads {
+                    let halting = match (shutdown, &result) {
+                        (Some(_), Ok(_)) => true,
+                        (_, Err(_)) => self.config.halt_on_error,
+                        _ => false,
+                    };
+                    if halting {
+                        reads.withdraw();
+                    } else {
+                        reads.refresh(&*handler);
+                    }
+                }
+
+                // A WAITKEY miss is answered later, by a write or its deadline
+                if let (Some((key, timeout)), Ok("0")) = (&wait, result.as_deref()) {
+                    waiters.park(KeyWaiter {
+                        key: key.clone(),
+                        deadline: Instant::now() + *timeout,
+                        line_number: msg.line_number,
+                        io_thread_id: msg.io_thread_id,
+                        trace_id: ctx.trace_id,
+                        reply: msg.reply,
+                    });
+                    continue;
+                }
+
+                let line_number = msg.line_number;
+                self.finish(
+                    msg.line_number,
+                    msg.io_thread_id,
+                    ctx.trace_id.as_deref(),
+                    msg.reply,
+                    result.clone(),
+                );
+                let halt = match (shutdown, &result) {
+                    (Some(save), Ok(_)) => {
+                        status!(
+                            "[Main Thread] SHUTDOWN at line {}, no further commands will be applied{}",
+                            line_number,
+                            trace
+                        );
+                        if save {
+                            self.events
+                                .emit(Event::new("snapshot_saved").field("trigger", "shutdown"));
+                        }
+                        skip_exit_save |= save;
+                        true
+                    }
+                    (_, Err(err)) if self.config.halt_on_error => {
+                        eprintln!(
+                            "[Main Thread] HALT_ON_ERROR: stopping at line {} after: {}{}",
+                            line_number, err, trace
+                        );
+                        true
+                    }
+                    _ => false,
+                };
+                if halt {
+                    stopped = true;
+                    self.shutdown_requested.store(true, Ordering::Relaxed);
+                    // No command can set an awaited key any more
+                    for waiter in waiters.drain() {
+                        self.finish(
+                            waiter.line_number,
+                            waiter.io_thread_id,
+                            waiter.trace_id.as_deref(),
+                            waiter.reply,
+                            Ok("0".to_string()),
+                        );
+                    }
+                    continue;
+                }
+                if is_write && result.is_ok() {
+                    for waiter in waiters.take_present(|key| handler.contains_key(key)) {
+                        self.finish(
+                            waiter.line_number,
+                            waiter.io_thread_id,
+                            waiter.trace_id.as_deref(),
+                            waiter.reply,
+                            Ok("1".to_string()),
+                        );
+                    }
+                }
+            }
+            if disconnected {
+                break;
+            }
+        }
+
+        // Notices for the last lines can land after the last command
+        self.take_line_notices();
+        if let Some(reads) = &self.reads {
+            reads.withdraw();
+        }
+
+        if let Some(cap) = self.config.max_total_commands
+            && self.command_cap_reached()
+        {
+            status!(
+                "[Main Thread] MAX_TOTAL_COMMANDS ({}) reached, no further commands will be accepted",
+                cap
+            );
+            self.shutdown_requested.store(true, Ordering::Relaxed);
+        }
+        if self.config.on_output_error == OutputErrorPolicy::Stop && self.output.truncated() {
+            eprintln!(
+                "[Main Thread] Output failed and ON_OUTPUT_ERROR=stop, no further commands will be accepted"
+            );
+            self.shutdown_requested.store(true, Ordering::Relaxed);
+        }
+
+        if self.save_on_exit && !skip_exit_save {
+            match handler.save_snapshot() {
+                Ok(keys) => {
+                    status!("[Main Thread] Saved {} keys to snapshot on exit", keys);
+                    self.events.emit(
+                        Event::new("snapshot_saved")
+                            .field("trigger", "exit")
+                            .field("keys", keys),
+                    );
+                }
+                Err(err) => {
+                    eprintln!("[Main Thread] Error: {}", err);
+                    self.events.emit(
+                        Event::new("snapshot_save_failed")
+                            .field("trigger", "exit")
+                            .field("error", &err),
                     );
+                    counters.hard_errors.fetch_add(1, Ordering::Relaxed);
                 }
             }
         }
 
-        println!("[Main Thread] Processed {} commands", processed_count);
-        println!("[Main Thread] Shutting down");
+        // No command can set the key any more
+        for waiter in waiters.drain() {
+            self.finish(
+                waiter.line_number,
+                waiter.io_thread_id,
+                waiter.trace_id.as_deref(),
+                waiter.reply,
+                Ok("0".to_string()),
+            );
+        }
+
+        let mut summary = counters.snapshot();
+        summary.key_delta = handler.key_count() as i64 - keys_before as i64;
+        summary.recovery = recovery;
+        summary.hot_keys = handler.hot_keys(SUMMARY_HOT_KEYS);
+        summary.import = handler.import_report();
+        status!("[Main Thread] Processed {} commands", summary.processed);
+        if let Some(files) = &self.file_lines {
+            for (index, name) in files.names().iter().enumerate() {
+                let results = self.file_results.get(index).copied().unwrap_or_default();
+                self.output.summary(&format!(
+                    "file {}: processed={} succeeded={} soft_errors={} hard_errors={}",
+                    name,
+                    results.succeeded + results.soft_errors + results.hard_errors,
+                    results.succeeded,
+                    results.soft_errors,
+                    results.hard_errors
+                ));
+            }
+        }
+        self.output
+            .summary(&format!("processed: {}", summary.processed));
+        self.output
+            .summary(&format!("succeeded: {}", summary.succeeded));
+        self.output
+            .summary(&format!("soft_errors: {}", summary.soft_errors));
+        self.output
+            .summary(&format!("hard_errors: {}", summary.hard_errors));
+        self.output
+            .summary(&format!("retries: {}", summary.retries));
+        if summary.dropped_lines > 0 {
+            self.output
+                .summary(&format!("dropped_lines: {}", summary.dropped_lines));
+        }
+        if self.reads.is_some() {
+            self.output
+                .summary(&format!("snapshot_reads: {}", summary.snapshot_reads));
+        }
+        if !summary.filtered.is_empty() {
+            let by_type: Vec<String> = summary
+                .filtered
+                .iter()
+                .map(|(name, count)| format!("{} {}", name, count))
+                .collect();
+            self.output.summary(&format!(
+                "filtered: {} ({})",
+                summary.filtered.values().sum::<usize>(),
+                by_type.join(", ")
+            ));
+        }
+        if !summary.hot_keys.is_empty() {
+            let top: Vec<String> = summary
+                .hot_keys
+                .iter()
+                .map(|(key, count)| format!("{} {}", key, count))
+                .collect();
+            self.output
+                .summary(&format!("hot_keys: {}", top.join(", ")));
+        }
+        if let Some(import) = &summary.import {
+            self.output
+                .summary(&format!("import_created: {}", import.created));
+            self.output
+                .summary(&format!("import_identical: {}", import.identical));
+            self.output
+                .summary(&format!("import_changed: {}", import.changed));
+            if !import.most_overwritten.is_empty() {
+                let top: Vec<String> = import
+                    .most_overwritten
+                    .iter()
+                    .map(|(key, count)| format!("{} {}", key, count))
+                    .collect();
+                self.output
+                    .summary(&format!("most_overwritten: {}", top.join(", ")));
+            }
+        }
+        if self.dry_run {
+            self.output
+                .summary(&format!("key_delta: {:+}", summary.key_delta));
+        }
+        if self.output.truncated() {
+            self.output.summary("output_truncated: true");
+        }
+        if summary.parse_error_abort {
+            self.output.summary("parse_error_abort: true");
+            self.output
+                .summary(&format!("unparsed_lines: {}", summary.unparsed_lines));
+        }
+        self.events.emit(
+            Event::new("shutdown")
+                .field("processed", summary.processed)
+                .field("succeeded", summary.succeeded)
+                .field("soft_errors", summary.soft_errors)
+                .field("hard_errors", summary.hard_errors)
+                .field("retries", summary.retries)
+                .field("keys", handler.key_count())
+                .field("events_dropped", self.events.dropped()),
+        );
+        self.output.flush();
+        summary.output_truncated = self.output.truncated();
+
+        status!("[Main Thread] Shutting down");
+        summary
+    }
+
+    /// Whether to stop taking commands: `MAX_TOTAL_COMMANDS` were processed,
+    /// or output failed under `ON_OUTPUT_ERROR=stop`
+    fn stop_accepting(&self) -> bool {
+        self.command_cap_reached()
+            || (self.config.on_output_error == OutputErrorPolicy::Stop && self.output.truncated())
+    }
+
+    /// Whether `MAX_TOTAL_COMMANDS` commands have been processed already
+    fn command_cap_reached(&self) -> bool {
+        self.config
+            .max_total_commands
+            .is_some_and(|cap| self.counters.processed.load(Ordering::Relaxed) >= cap)
+    }
+
+    /// Report lines that produced no command, so ordered output can move
+    /// past them
+    fn take_line_notices(&mut self) {
+        while let Ok(notice) = self.line_notices.try_recv() {
+            match notice.parse_error {
+                Some(error) => {
+                    self.output
+                        .parse_error(notice.line_number, notice.io_thread_id, &error)
+                }
+                None => self.output.skip_line(notice.line_number),
+            }
+        }
+    }
+
+    /// Count and report a command's final result, then hand it to the caller
+    fn finish(
+        &mut self,
+        line_number: usize,
+        io_thread_id: usize,
+        trace_id: Option<&str>,
+        reply: Option<Sender<Reply>>,
+        result: Reply,
+    ) {
+        self.counters.in_flight.finished();
+        self.counters.count_result(&result);
+        let shown_trace = trace_id.filter(|_| self.show_trace_ids);
+        match &result {
+            Ok(response) => match shown_trace {
+                Some(id) => {
+                    let response = format!("[trace:{}] {}", id, response);
+                    self.output.response(line_number, io_thread_id, &response);
+                }
+                None => self.output.response(line_number, io_thread_id, response),
+            },
+            Err(error) => {
+                let failure = CommandFailure {
+                    line_number,
+                    io_thread_id,
+                    error: error.clone(),
+                    trace_id: shown_trace.map(str::to_string),
+                };
+                self.output.failure(&failure, self.config.soft_error_sink);
+            }
+        }
+        if let Some((file, _)) = self
+            .file_lines
+            .as_ref()
+            .filter(|_| io_thread_id != DIRECT_SUBMISSION)
+            .and_then(|files| files.locate(line_number))
+        {
+            if self.file_results.len() <= file {
+                self.file_results.resize(file + 1, FileResults::default());
+            }
+            let results = &mut self.file_results[file];
+            match &result {
+                Ok(_) => results.succeeded += 1,
+                Err(error) if error.is_soft() => results.soft_errors += 1,
+                Err(_) => results.hard_errors += 1,
+            }
+        }
+        // Counted last, so a producer waiting on it sees the result counted
+        self.counters.settled.fetch_add(1, Ordering::Relaxed);
+
+        // The caller may have stopped waiting; that's not an error here
+        if let Some(reply) = reply {
+            let _ = reply.send(result);
+        }
+    }
+
+    /// Apply a command, retrying transient failures up to
+    /// `command_max_retries` times with a growing backoff
+    ///
+    /// Returns the final result and how many retries it took.
+    fn apply_with_retries(
+        &mut self,
+        handler: &mut dyn CommandProcessor,
+        command: Command,
+        ctx: &MessageContext,
+    ) -> (Reply, usize) {
+        let max_retries = self.config.command_max_retries as usize;
+        let mut retries = 0;
+        let mut backoff = RETRY_BACKOFF;
+
+        loop {
+            // Keep a copy only while another attempt is still possible
+            if retries == max_retries {
+                return (self.apply(handler, command, ctx), retries);
+            }
+            let result = self.apply(handler, command.clone(), ctx);
+
+            match result {
+                Err(err) if err.is_transient() => {
+                    retries += 1;
+                    status!(
+                        "[Main Thread] Line {}: {}, retry {}/{} in {:?}{}",
+                        ctx.line_number,
+                        err,
+                        retries,
+                        max_retries,
+                        backoff,
+                        trace_tag(ctx.trace_id.as_deref())
+                    );
+                    thread::sleep(backoff);
+                    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                }
+                result => return (result, retries),
+            }
+        }
+    }
+
+    fn apply(
+        &mut self,
+        handler: &mut dyn CommandProcessor,
+        command: Command,
+        ctx: &MessageContext,
+    ) -> Reply {
+        if self.dry_run && command.command_type.is_external_write() {
+            return Ok("dry run: nothing written".to_string());
+        }
+
+        // Simulated persistence failure, before the write touches the store
+        #[cfg(debug_assertions)]
+        if command.command_type.is_write() {
+            self.write_attempts += 1;
+            if self.config.test.should_fail_write(self.write_attempts) {
+                self.events.emit(
+                    Event::new("chaos")
+                        .field("kind", "fail_write")
+                        .field("command", command.command_type.name())
+                        .field("attempt", self.write_attempts),
+                );
+                return Err(HandlerError::Transient(format!(
+                    "injected failure on write attempt {}",
+                    self.write_attempts
+                )));
+            }
+        }
+
+        if !self.config.catch_handler_panics {
+            return self.process(handler, command, ctx);
+        }
+        let name = command.command_type.name();
+        panic::catch_unwind(AssertUnwindSafe(|| self.process(handler, command, ctx)))
+            .unwrap_or_else(|payload| {
+                Err(HandlerError::Panicked(format!(
+                    "{}: {}",
+                    name,
+                    panic_message(payload.as_ref())
+                )))
+            })
+    }
+
+    fn process(
+        &self,
+        handler: &mut dyn CommandProcessor,
+        command: Command,
+        ctx: &MessageContext,
+    ) -> Reply {
+        // Simulated handler bug (only in debug builds)
+        #[cfg(debug_assertions)]
+        self.config
+            .test
+            .maybe_panic_handler(command.command_type.name());
+
+        handler.process(command, ctx)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::time::Duration;
 
     #[test]
     fn test_thread_pool_creation() {
@@ -288,4 +2152,1162 @@ mod tests {
         // Wait for main thread to finish
         main_handle.join().unwrap();
     }
+
+    #[test]
+    fn test_shutdown_reports_io_thread_timings() {
+        let mut pool = ThreadPool::new(2, Config::from_env());
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for i in 1..=20 {
+            sender.send((format!("SET key{} value", i), i)).unwrap();
+        }
+        drop(sender);
+
+        let report = pool.shutdown();
+        main_handle.join().unwrap();
+
+        assert_eq!(report.io_thread_timings.len(), 2);
+        let lines: usize = report.io_thread_timings.iter().map(|t| t.lines).sum();
+        assert_eq!(lines, 20);
+        let parse: Duration = report.io_thread_timings.iter().map(|t| t.parse).sum();
+        assert!(parse > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_round_robin_spreads_lines_evenly() {
+        let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+            .io_threads(4)
+            .distribution(Distribution::RoundRobin)
+            .output(OutputSinks::discard())
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for i in 1..=20 {
+            sender.send((format!("SET key{} value", i), i)).unwrap();
+        }
+        drop(sender);
+
+        let report = pool.shutdown();
+        assert_eq!(main_handle.join().unwrap().processed, 20);
+        let lines: Vec<usize> = report.io_thread_timings.iter().map(|t| t.lines).collect();
+        assert_eq!(lines, [5, 5, 5, 5]);
+    }
+
+    #[test]
+    fn test_key_slot_follows_the_key() {
+        for slots in [1, 3, 8] {
+            let slot = key_slot("SET user:1 alice", slots);
+            assert!(slot < slots);
+            assert_eq!(key_slot("GET user:1", slots), slot);
+            assert_eq!(key_slot("DELETE  user:1 ID req-7", slots), slot);
+        }
+        assert_eq!(key_slot("INFO", 8), key_slot("INFO", 8));
+    }
+
+    #[test]
+    fn test_run_summary_exit_policy() {
+        let soft_only = RunSummary {
+            processed: 2,
+            succeeded: 1,
+            soft_errors: 1,
+            hard_errors: 0,
+            retries: 0,
+            in_flight: 0,
+            max_in_flight: 0,
+            dropped_lines: 0,
+            snapshot_reads: 0,
+            filtered: BTreeMap::new(),
+            hot_keys: Vec::new(),
+            import: None,
+            output_truncated: false,
+            parse_error_abort: false,
+            unparsed_lines: 0,
+            key_delta: 0,
+            recovery: None,
+        };
+        assert!(!soft_only.is_failure(false));
+        assert!(soft_only.is_failure(true));
+
+        let hard = RunSummary {
+            hard_errors: 1,
+            ..soft_only
+        };
+        assert!(hard.is_failure(false));
+    }
+
+    #[test]
+    fn test_dry_run_sees_earlier_lines_and_writes_nothing() {
+        use crate::output::OutputOptions;
+
+        let summary_path =
+            std::env::temp_dir().join(format!("crabby_dry_run_{}.txt", std::process::id()));
+        let dump_path =
+            std::env::temp_dir().join(format!("crabby_dry_run_{}.json", std::process::id()));
+        let output = OutputSinks::open(&OutputOptions {
+            summary: Some(summary_path.clone()),
+            ..OutputOptions::default()
+        })
+        .unwrap();
+
+        // One IO thread keeps the lines in order
+        let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+            .io_threads(1)
+            .output(output)
+            .dry_run(true)
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for (i, line) in [
+            "SET name alice",
+            "INCRBOUNDED name 10",
+            "SET counter 1",
+            "INCRBOUNDED counter 10",
+            "DELETE ghost",
+            &format!("DUMPJSON {}", dump_path.display()),
+        ]
+        .iter()
+        .enumerate()
+        {
+            sender.send((line.to_string(), i + 1)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        // INCRBOUNDED sees the string SET on the line before it
+        assert_eq!(summary.succeeded, 4);
+        assert_eq!(summary.hard_errors, 1);
+        assert_eq!(summary.soft_errors, 1);
+        assert_eq!(summary.key_delta, 2);
+        assert!(summary.is_failure(false));
+        assert!(!dump_path.exists());
+        let report = std::fs::read_to_string(&summary_path).unwrap();
+        assert!(report.ends_with("key_delta: +2\n"), "{}", report);
+        std::fs::remove_file(summary_path).unwrap();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_poisoned_receiver_recovery() {
+        use crate::config::TestConfig;
+
+        let config = Arc::new(Config {
+            test: TestConfig {
+                panic_io_thread_on_line: Some(2),
+                ..TestConfig::default()
+            },
+            ..Config::default()
+        });
+        let mut pool = ThreadPool::new(3, config);
+        let main_handle = pool.start_main_thread();
+
+        let sender = pool.get_string_sender();
+        for i in 1..=10 {
+            sender.send((format!("SET key{} value", i), i)).unwrap();
+        }
+        drop(sender);
+
+        let report = pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        // The panicking thread's line is lost; every other line still drains
+        assert_eq!(report.panicked, 1);
+        assert_eq!(report.successful, 2);
+        assert_eq!(report.poison_recoveries, 1);
+        assert_eq!(summary.processed, 9);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_delay_rules_reorder_pipeline() {
+        use crate::config::{DelayRules, TestConfig};
+        use crate::output::OutputOptions;
+
+        let config = Arc::new(Config {
+            test: TestConfig {
+                delay_rules: DelayRules::parse("after:SET:150ms").unwrap(),
+                ..TestConfig::default()
+            },
+            ..Config::default()
+        });
+        let responses =
+            std::env::temp_dir().join(format!("crabby_delay_rules_{}.txt", std::process::id()));
+        let output = OutputSinks::open(&OutputOptions {
+            responses: Some(responses.clone()),
+            ..OutputOptions::default()
+        })
+        .unwrap();
+
+        let mut pool = ThreadPoolBuilder::new(config)
+            .io_threads(2)
+            .output(output)
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        sender.send(("SET k v".to_string(), 1)).unwrap();
+        sender.send(("DELETE k".to_string(), 2)).unwrap();
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        // The delayed SET lands after the DELETE, which misses and leaves the key set
+        assert_eq!(summary.soft_errors, 1);
+        assert_eq!(
+            std::fs::read_to_string(&responses).unwrap(),
+            "[Line 1] SET k = v\n"
+        );
+        std::fs::remove_file(responses).unwrap();
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_key_hash_keeps_per_key_order() {
+        use crate::config::{DelayRules, TestConfig};
+
+        let config = Arc::new(Config {
+            test: TestConfig {
+                delay_rules: DelayRules::parse("after:SET:150ms").unwrap(),
+                ..TestConfig::default()
+            },
+            ..Config::default()
+        });
+        // Same lines as test_delay_rules_reorder_pipeline, but both go to the
+        // thread that owns `k`, so the delayed SET can't be overtaken
+        let mut pool = ThreadPoolBuilder::new(config)
+            .io_threads(2)
+            .distribution(Distribution::KeyHash)
+            .output(OutputSinks::discard())
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        sender.send(("SET k v".to_string(), 1)).unwrap();
+        sender.send(("DELETE k".to_string(), 2)).unwrap();
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(summary.soft_errors, 0);
+    }
+
+    #[test]
+    fn test_io_threads_answer_reads_from_the_read_snapshot() {
+        let run = |per_key_ordering: bool| {
+            // A zero max age retakes the copy after every write, so reads
+            // answered from it still see the SET
+            let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+                .io_threads(2)
+                .read_snapshot_max_age(Some(Duration::ZERO))
+                .per_key_ordering(per_key_ordering)
+                .output(OutputSinks::discard())
+                .build();
+            let main_handle = pool.start_main_thread();
+            let sender = pool.get_string_sender();
+            sender.send(("SET a 1".to_string(), 1)).unwrap();
+            assert!(pool.wait_for_lines(1, &main_handle));
+            for (line, line_number) in [("GET a", 2), ("GETMATCHING a*", 3), ("GET a FRESH", 4)] {
+                sender.send((line.to_string(), line_number)).unwrap();
+            }
+            drop(sender);
+            pool.shutdown();
+            main_handle.join().unwrap()
+        };
+
+        let summary = run(false);
+        assert_eq!(summary.processed, 4);
+        assert_eq!(summary.succeeded, 4);
+        assert_eq!(summary.snapshot_reads, 2);
+
+        // Per-key ordering sends every read through the main thread
+        let summary = run(true);
+        assert_eq!(summary.succeeded, 4);
+        assert_eq!(summary.snapshot_reads, 0);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_caught_handler_panic_keeps_main_thread_running() {
+        use crate::config::TestConfig;
+
+        let config = Arc::new(Config {
+            catch_handler_panics: true,
+            test: TestConfig {
+                panic_handler_on: Some("INCRBOUNDED".to_string()),
+                ..TestConfig::default()
+            },
+            ..Config::default()
+        });
+        // One IO thread keeps the lines in order
+        let mut pool = ThreadPoolBuilder::new(config)
+            .io_threads(1)
+            .output(OutputSinks::discard())
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for (i, line) in ["SET a 1", "INCRBOUNDED a 5", "GET a", "SET b 2"]
+            .iter()
+            .enumerate()
+        {
+            sender.send((line.to_string(), i + 1)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        assert_eq!(summary.processed, 4);
+        assert_eq!(summary.succeeded, 3);
+        assert_eq!(summary.hard_errors, 1);
+    }
+
+    #[test]
+    fn test_injected_processor_sees_every_command() {
+        use crate::processor::RecordingProcessor;
+
+        let processor = RecordingProcessor::new(CommandHandler::new());
+        let log = processor.log();
+        let started = Instant::now();
+        let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+            .io_threads(2)
+            .per_key_ordering(true)
+            .output(OutputSinks::discard())
+            .processor(Box::new(processor))
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for (i, line) in ["SET a 1", "GET a", "BOGUS", "DELETE a"].iter().enumerate() {
+            sender.send((line.to_string(), i + 1)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        assert_eq!(summary.succeeded, 3);
+        let mut seen = log.lock().unwrap().clone();
+        seen.sort_by_key(|(ctx, _)| ctx.line_number);
+        let names: Vec<(usize, &str)> = seen
+            .iter()
+            .map(|(ctx, command)| (ctx.line_number, command.command_type.name()))
+            .collect();
+        assert_eq!(names, [(1, "SET"), (2, "GET"), (4, "DELETE")]);
+        for (ctx, _) in &seen {
+            assert!(ctx.io_thread_id < 2);
+            assert!(ctx.enqueued_at >= started && ctx.enqueued_at <= Instant::now());
+        }
+    }
+
+    #[test]
+    fn test_line_transformers_rewrite_and_drop_before_parsing() {
+        use crate::transform::{KeyLowercaser, PrefixStripper};
+
+        for distribution in [Distribution::Shared, Distribution::KeyHash] {
+            let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+                .io_threads(2)
+                .output(OutputSinks::discard())
+                .distribution(distribution)
+                .per_key_ordering(true)
+                .line_transformer(Box::new(PrefixStripper::new("app: ").drop_unmatched(true)))
+                .line_transformer(Box::new(KeyLowercaser))
+                .build();
+            let main_handle = pool.start_main_thread();
+            let sender = pool.get_string_sender();
+            let lines = [
+                "app: SET User:1 Ada",
+                "other: SET user:1 Grace",
+                "",
+                "app: GET user:1",
+                "app: DELETE USER:1",
+            ];
+            for (i, line) in lines.iter().enumerate() {
+                sender.send((line.to_string(), i + 1)).unwrap();
+            }
+            drop(sender);
+            pool.shutdown();
+            let summary = main_handle.join().unwrap();
+
+            // The other app's line is dropped, not parsed, and keys line up
+            // however the stripped lines were routed
+            assert_eq!(summary.dropped_lines, 1, "{:?}", distribution);
+            assert_eq!(summary.processed, 3, "{:?}", distribution);
+            assert_eq!(summary.succeeded, 3, "{:?}", distribution);
+        }
+    }
+
+    #[test]
+    fn test_paused_io_threads_take_no_lines_until_resumed() {
+        let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+            .io_threads(2)
+            .output(OutputSinks::discard())
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+
+        pool.pause();
+        assert!(pool.is_paused());
+        for i in 0..3 {
+            sender.send((format!("SET k{} v", i), i + 1)).unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.stats().processed, 0);
+
+        pool.resume();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.stats().processed < 3 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(pool.stats().processed, 3);
+
+        drop(sender);
+        pool.shutdown();
+        main_handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_summary_lists_hot_keys_when_tracked() {
+        let config = Config {
+            track_hot_keys: true,
+            hot_keys_capacity: 4,
+            ..Config::default()
+        };
+        let mut pool = ThreadPoolBuilder::new(Arc::new(config))
+            .io_threads(1)
+            .output(OutputSinks::discard())
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        let input = ["SET a 1", "GET a", "SET b 2", "GET a", "INFO", "DELETE b"];
+        for (i, line) in input.into_iter().enumerate() {
+            sender.send((line.to_string(), i + 1)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+        assert_eq!(
+            summary.hot_keys,
+            [("a".to_string(), 3), ("b".to_string(), 2)]
+        );
+    }
+
+    #[test]
+    fn test_import_report_counts_overlap() {
+        use crate::output::OutputOptions;
+
+        let summary_path =
+            std::env::temp_dir().join(format!("crabby_import_{}.txt", std::process::id()));
+        let output = OutputSinks::open(&OutputOptions {
+            summary: Some(summary_path.clone()),
+            ..OutputOptions::default()
+        })
+        .unwrap();
+        // One IO thread keeps the lines in order
+        let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+            .io_threads(1)
+            .output(output)
+            .import_report(true)
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        // Two exports that overlap on a and b; only a changes between them
+        let input = [
+            "SET a 1",
+            "SET b 1",
+            "SET c 1",
+            "SET a 2",
+            "SET b 1",
+            "SET d 1",
+            "SET a 2",
+            "SETCRC e 1",
+            "SETCRC e 1",
+            "GET a",
+            "APPENDCAP c 8 x",
+        ];
+        for (i, line) in input.into_iter().enumerate() {
+            sender.send((line.to_string(), i + 1)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        assert_eq!(
+            summary.import,
+            Some(ImportReport {
+                created: 5,
+                identical: 3,
+                changed: 1,
+                most_overwritten: vec![
+                    ("a".to_string(), 2),
+                    ("b".to_string(), 1),
+                    ("e".to_string(), 1),
+                ],
+            })
+        );
+        let report = std::fs::read_to_string(&summary_path).unwrap();
+        std::fs::remove_file(summary_path).unwrap();
+        assert!(
+            report.ends_with(
+                "import_created: 5\nimport_identical: 3\nimport_changed: 1\nmost_overwritten: a 2, b 1, e 1\n"
+            ),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn test_in_flight_gauge_rises_while_main_thread_is_held() {
+        let config = Config {
+            command_queue_capacity: 5,
+            ..Config::default()
+        };
+        let mut pool = ThreadPoolBuilder::new(Arc::new(config))
+            .io_threads(2)
+            .output(OutputSinks::discard())
+            .build();
+        let sender = pool.get_string_sender();
+
+        // Nothing is applied until the main thread starts, so every command
+        // the IO threads send stays in flight
+        for i in 1..=5 {
+            sender.send((format!("SET k{} v", i), i)).unwrap();
+            wait_for_in_flight(&pool, i);
+        }
+        assert_eq!(pool.stats().in_flight, 5);
+
+        let main_handle = pool.start_main_thread();
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+        assert_eq!(summary.succeeded, 5);
+        assert_eq!(summary.in_flight, 0);
+        assert_eq!(summary.max_in_flight, 5);
+    }
+
+    /// Wait until `count` commands are in flight (queued or being sent)
+    fn wait_for_in_flight(pool: &ThreadPool, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.stats().in_flight < count {
+            assert!(Instant::now() < deadline, "gauge stuck below {}", count);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_shutdown_drains_a_full_command_queue() {
+        let config = Config {
+            command_queue_capacity: 2,
+            ..Config::default()
+        };
+        let mut pool = ThreadPoolBuilder::new(Arc::new(config))
+            .io_threads(1)
+            .output(OutputSinks::discard())
+            .build();
+        let sender = pool.get_string_sender();
+        sender.send(("SHUTDOWN".to_string(), 1)).unwrap();
+        for i in 2..=10 {
+            sender.send((format!("SET k{} v", i), i)).unwrap();
+        }
+        drop(sender);
+
+        // Two commands queued and the IO thread blocked sending a third
+        wait_for_in_flight(&pool, 3);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(pool.stats().in_flight, 3);
+
+        let main_handle = pool.start_main_thread();
+        let report = pool.shutdown();
+        let summary = main_handle.join().unwrap();
+        assert_eq!(report.successful, 1);
+        // Everything behind the SHUTDOWN is still answered, none applied
+        assert_eq!(summary.processed, 10);
+        assert_eq!(summary.succeeded, 1);
+        assert_eq!(summary.soft_errors, 9);
+        assert_eq!(summary.key_delta, 0);
+        assert_eq!(summary.in_flight, 0);
+    }
+
+    #[test]
+    fn test_shutdown_before_the_main_thread_starts_does_not_hang() {
+        let config = Config {
+            command_queue_capacity: 1,
+            ..Config::default()
+        };
+        let pool = ThreadPoolBuilder::new(Arc::new(config))
+            .io_threads(1)
+            .output(OutputSinks::discard())
+            .build();
+        let sender = pool.get_string_sender();
+        for i in 1..=5 {
+            sender.send((format!("SET k{} v", i), i)).unwrap();
+        }
+        drop(sender);
+        wait_for_in_flight(&pool, 2);
+
+        // The IO thread is blocked on the full queue until shutdown drops it
+        let report = pool.shutdown();
+        assert_eq!(report.successful, 1);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_slow_main_thread_fills_the_command_queue_without_loss() {
+        use crate::config::{DelayRules, TestConfig};
+
+        let capacity = 2;
+        let io_threads = 2;
+        let config = Arc::new(Config {
+            command_queue_capacity: capacity,
+            test: TestConfig {
+                delay_rules: DelayRules::parse("before:SET:5ms").unwrap(),
+                ..TestConfig::default()
+            },
+            ..Config::default()
+        });
+        let mut pool = ThreadPoolBuilder::new(config)
+            .io_threads(io_threads)
+            .output(OutputSinks::discard())
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for i in 1..=30 {
+            sender.send((format!("SET k{} v", i), i)).unwrap();
+        }
+        drop(sender);
+        let report = pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        assert_eq!(summary.succeeded, 30);
+        assert_eq!(summary.key_delta, 30);
+        assert_eq!(summary.in_flight, 0);
+        // The queue filled: beyond it, only the command being applied and
+        // one blocked send per IO thread can be in flight
+        assert!(summary.max_in_flight >= capacity, "{:?}", summary);
+        assert!(
+            summary.max_in_flight <= capacity + 1 + io_threads,
+            "{:?}",
+            summary
+        );
+        let send_wait: Duration = report.io_thread_timings.iter().map(|t| t.send_wait).sum();
+        assert!(send_wait > Duration::ZERO);
+    }
+
+    #[test]
+    fn test_parse_stats_count_each_verb() {
+        let parse_stats = Arc::new(ParseStats::new());
+        let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+            .io_threads(2)
+            .output(OutputSinks::discard())
+            .parse_stats(Arc::clone(&parse_stats))
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        let mut lines = Vec::new();
+        for i in 0..5 {
+            lines.push(format!("SET k{} v", i));
+        }
+        for i in 0..3 {
+            lines.push(format!("GET k{}", i));
+        }
+        // Neither counts: one doesn't parse, the other is blank
+        lines.push("GET".to_string());
+        lines.push("   ".to_string());
+        for (i, line) in lines.into_iter().enumerate() {
+            sender.send((line, i + 1)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        main_handle.join().unwrap();
+        assert_eq!(parse_stats.counts(), [("SET", 5), ("GET", 3)]);
+
+        // PARSESTATS reports the same counts
+        let mut handler = CommandHandler::new().with_parse_stats(parse_stats);
+        assert_eq!(
+            handler.process_command("PARSESTATS".parse().unwrap()),
+            Ok("SET 5\nGET 3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_macro_lines_expand_before_parsing() {
+        let config = Config {
+            macros: crate::macros::Macros::compile([("SETJ", "SET $1 $2 EX 3600")]).unwrap(),
+            ..Config::default()
+        };
+        let mut pool = ThreadPoolBuilder::new(Arc::new(config))
+            .io_threads(1)
+            .output(OutputSinks::discard())
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        // The second line is missing an argument, so it never reaches the store
+        let input = ["SETJ session:9 tok", "SETJ session:10", "GET session:9"];
+        for (i, line) in input.into_iter().enumerate() {
+            sender.send((line.to_string(), i + 1)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+        assert_eq!(summary.processed, 2);
+        assert_eq!(summary.succeeded, 2);
+        assert_eq!(summary.key_delta, 1);
+    }
+
+    #[test]
+    fn test_command_filter_keeps_commands_from_main_thread() {
+        use crate::filter::CommandFilter;
+
+        let run = |name: &str, filter: CommandFilter| {
+            let path = std::env::temp_dir().join(format!(
+                "crabby_filter_{}_{}.crkvs",
+                name,
+                std::process::id()
+            ));
+            let config = Config {
+                snapshot_path: Some(path.clone()),
+                ..Config::default()
+            };
+            let mut pool = ThreadPoolBuilder::new(Arc::new(config))
+                .io_threads(1)
+                .output(OutputSinks::discard())
+                .save_on_exit(true)
+                .command_filter(filter)
+                .build();
+            let main_handle = pool.start_main_thread();
+            let sender = pool.get_string_sender();
+            let input = ["SET a 1", "SET b 2", "GET a", "DELETE b", "INFO", "GET b"];
+            for (i, line) in input.into_iter().enumerate() {
+                sender.send((line.to_string(), i + 1)).unwrap();
+            }
+            drop(sender);
+            pool.shutdown();
+            let summary = main_handle.join().unwrap();
+            let store = snapshot::load(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            let mut keys: Vec<String> = store.into_keys().collect();
+            keys.sort();
+            (keys, summary)
+        };
+
+        let (keys, summary) = run("none", CommandFilter::new());
+        assert_eq!(keys, ["a"]);
+        assert!(summary.filtered.is_empty());
+        assert_eq!(summary.processed, 6);
+
+        // Only the writes that create keys: nothing is read or deleted
+        let (keys, summary) = run("only", CommandFilter::new().only("SET").unwrap());
+        assert_eq!(keys, ["a", "b"]);
+        assert_eq!(summary.processed, 2);
+        let filtered: Vec<(&str, usize)> = summary.filtered.into_iter().collect();
+        assert_eq!(filtered, [("DELETE", 1), ("GET", 2), ("INFO", 1)]);
+
+        let (keys, summary) = run("skip", CommandFilter::new().skip("DELETE,INFO").unwrap());
+        assert_eq!(keys, ["a", "b"]);
+        assert_eq!(summary.processed, 4);
+        assert_eq!(summary.soft_errors, 0);
+        assert_eq!(summary.filtered.values().sum::<usize>(), 2);
+    }
+
+    #[test]
+    fn test_max_total_commands_stops_main_thread() {
+        use crate::processor::RecordingProcessor;
+
+        let processor = RecordingProcessor::new(CommandHandler::new());
+        let log = processor.log();
+        let config = Config {
+            max_total_commands: Some(3),
+            ..Config::default()
+        };
+        let mut pool = ThreadPoolBuilder::new(Arc::new(config))
+            .io_threads(1)
+            .output(OutputSinks::discard())
+            .processor(Box::new(processor))
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for i in 1..=10 {
+            sender.send((format!("SET key{} v", i), i)).unwrap();
+        }
+        drop(sender);
+        let summary = main_handle.join().unwrap();
+        assert!(pool.shutdown_requested());
+        pool.shutdown();
+
+        assert_eq!(summary.processed, 3);
+        assert_eq!(summary.succeeded, 3);
+        let lines: Vec<usize> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ctx, _)| ctx.line_number)
+            .collect();
+        assert_eq!(lines, [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_halt_on_error_stops_at_first_failure() {
+        use crate::processor::RecordingProcessor;
+
+        let processor = RecordingProcessor::new(CommandHandler::new());
+        let log = processor.log();
+        let config = Config {
+            halt_on_error: true,
+            ..Config::default()
+        };
+        let mut pool = ThreadPoolBuilder::new(Arc::new(config))
+            .io_threads(1)
+            .output(OutputSinks::discard())
+            .processor(Box::new(processor))
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for (i, line) in ["SET a 1", "GET missing", "SET b 2", "SET c 3"]
+            .into_iter()
+            .enumerate()
+        {
+            sender.send((line.to_string(), i + 1)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        // The miss halts the run; later lines are refused, never applied
+        assert_eq!(summary.succeeded, 1);
+        assert_eq!(summary.soft_errors, 3);
+        let lines: Vec<usize> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(ctx, _)| ctx.line_number)
+            .collect();
+        assert_eq!(lines, [1, 2]);
+    }
+
+    #[test]
+    fn test_dry_run_refuses_processor_without_shadow() {
+        use crate::processor::{CommandProcessor, MessageContext};
+
+        struct Echo;
+        impl CommandProcessor for Echo {
+            fn process(&mut self, command: Command, _ctx: &MessageContext) -> Reply {
+                Ok(command.command_type.name().to_string())
+            }
+        }
+
+        let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+            .io_threads(1)
+            .output(OutputSinks::discard())
+            .dry_run(true)
+            .processor(Box::new(Echo))
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        sender.send(("SET a 1".to_string(), 1)).unwrap();
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        // Refusing the run is a hard error; the command itself is answered
+        // with ShuttingDown
+        assert_eq!(summary.succeeded, 0);
+        assert_eq!(summary.hard_errors, 1);
+        assert_eq!(summary.soft_errors, 1);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_procedure_steps_are_not_interleaved_under_chaos() {
+        use crate::config::TestConfig;
+        use crate::procedure::Procedure;
+        use crate::processor::{CommandProcessor, MessageContext};
+        use std::collections::HashMap;
+
+        const CALLS: usize = 300;
+
+        struct Forward {
+            handler: CommandHandler,
+            results: Sender<(String, Reply)>,
+        }
+        impl CommandProcessor for Forward {
+            fn process(&mut self, command: Command, _ctx: &MessageContext) -> Reply {
+                let name = command.command_type.name().to_string();
+                let result = self.handler.process_command(command);
+                let _ = self.results.send((name, result.clone()));
+                result
+            }
+        }
+
+        let config = Arc::new(Config {
+            test: TestConfig {
+                random_sleep_io_thread: true,
+                io_sleep_min_ms: 0,
+                io_sleep_max_ms: 1,
+                ..TestConfig::default()
+            },
+            ..Config::default()
+        });
+        let twice = Procedure::compile(
+            &["INCRBOUNDED $1 1000000", "INCRBOUNDED $1 1000000"],
+            ParseOptions::default(),
+        )
+        .unwrap();
+        let (results, received) = mpsc::channel();
+        let mut pool = ThreadPoolBuilder::new(config)
+            .io_threads(4)
+            .output(OutputSinks::discard())
+            .processor(Box::new(Forward {
+                handler: CommandHandler::new()
+                    .with_procedures(HashMap::from([("twice".to_string(), twice)])),
+                results,
+            }))
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for i in 0..CALLS {
+            sender
+                .send(("CALL twice hits".to_string(), 2 * i + 1))
+                .unwrap();
+            sender
+                .send(("INCRBOUNDED hits 1000000".to_string(), 2 * i + 2))
+                .unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+        assert_eq!(summary.succeeded, 2 * CALLS);
+
+        // Plain increments land between calls, never between a call's steps
+        let mut calls = 0;
+        let mut highest = 0;
+        for (name, result) in received.try_iter() {
+            let response = result.unwrap();
+            let values: Vec<u64> = match name.as_str() {
+                "CALL" => {
+                    calls += 1;
+                    response
+                        .lines()
+                        .map(|line| line.split_once(") ").unwrap().1.parse().unwrap())
+                        .collect()
+                }
+                _ => vec![response.parse().unwrap()],
+            };
+            for value in values {
+                assert_eq!(value, highest + 1, "{}: {}", name, response);
+                highest = value;
+            }
+        }
+        assert_eq!(calls, CALLS);
+        assert_eq!(highest, 3 * CALLS as u64);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_per_key_ordering_under_chaos() {
+        use crate::config::TestConfig;
+        use crate::output::OutputOptions;
+
+        const PAIRS: usize = 10_000;
+        const KEYS: usize = 100;
+
+        let config = Arc::new(Config {
+            per_key_ordering: true,
+            test: TestConfig {
+                random_sleep_io_thread: true,
+                io_sleep_min_ms: 0,
+                io_sleep_max_ms: 1,
+                ..TestConfig::default()
+            },
+            ..Config::default()
+        });
+        let responses =
+            std::env::temp_dir().join(format!("crabby_per_key_order_{}.txt", std::process::id()));
+        let output = OutputSinks::open(&OutputOptions {
+            responses: Some(responses.clone()),
+            ..OutputOptions::default()
+        })
+        .unwrap();
+
+        let mut pool = ThreadPoolBuilder::new(config)
+            .io_threads(4)
+            .output(output)
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for i in 0..PAIRS {
+            let key = format!("k{}", i % KEYS);
+            sender
+                .send((format!("SET {} v_{}", key, i), 2 * i + 1))
+                .unwrap();
+            sender.send((format!("GET {}", key), 2 * i + 2)).unwrap();
+        }
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+        assert_eq!(summary.succeeded, 2 * PAIRS);
+
+        // Every GET returns the value of the SET on the line just before it
+        let text = std::fs::read_to_string(&responses).unwrap();
+        std::fs::remove_file(responses).unwrap();
+        let mut gets = 0;
+        for line in text.lines() {
+            let (number, response) = line
+                .strip_prefix("[Line ")
+                .and_then(|rest| rest.split_once("] "))
+                .unwrap();
+            let number: usize = number.parse().unwrap();
+            if number.is_multiple_of(2) {
+                let i = number / 2 - 1;
+                assert_eq!(response, format!("GET k{} = v_{}", i % KEYS, i));
+                gets += 1;
+            }
+        }
+        assert_eq!(gets, PAIRS);
+    }
+
+    #[cfg(debug_assertions)]
+    fn run_with_failing_writes(max_retries: u32, fail_every: u64) -> RunSummary {
+        use crate::config::TestConfig;
+
+        let config = Arc::new(Config {
+            command_max_retries: max_retries,
+            test: TestConfig {
+                fail_write_every: Some(fail_every),
+                ..TestConfig::default()
+            },
+            ..Config::default()
+        });
+        // One IO thread keeps the write attempt order deterministic
+        let mut pool = ThreadPool::new(1, config);
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        for i in 1..=4 {
+            sender.send((format!("SET key{} v", i), i)).unwrap();
+        }
+        sender.send(("GET key1".to_string(), 5)).unwrap();
+        drop(sender);
+        pool.shutdown();
+        main_handle.join().unwrap()
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_transient_failures_are_retried() {
+        // Attempts 2, 4 and 6 fail; each is retried once and then succeeds
+        let summary = run_with_failing_writes(3, 2);
+        assert_eq!(summary.succeeded, 5);
+        assert_eq!(summary.hard_errors, 0);
+        assert_eq!(summary.retries, 3);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_transient_failures_reported_without_retries() {
+        let summary = run_with_failing_writes(0, 2);
+        assert_eq!(summary.succeeded, 3);
+        assert_eq!(summary.hard_errors, 2);
+        assert_eq!(summary.retries, 0);
+
+        // Every attempt fails, so each write exhausts its retries
+        let summary = run_with_failing_writes(2, 1);
+        assert_eq!(summary.hard_errors, 4);
+        assert_eq!(summary.retries, 8);
+    }
+
+    #[test]
+    fn test_command_failing_twice_then_succeeding_is_retried() {
+        use crate::processor::{CommandProcessor, MessageContext};
+
+        /// Fails the first two attempts at every SET, and misses every GET
+        struct Flaky {
+            attempts: usize,
+        }
+        impl CommandProcessor for Flaky {
+            fn process(&mut self, command: Command, _ctx: &MessageContext) -> Reply {
+                match command.command_type {
+                    CommandType::Set(..) => {
+                        self.attempts += 1;
+                        if self.attempts <= 2 {
+                            return Err(HandlerError::Transient(format!(
+                                "attempt {}",
+                                self.attempts
+                            )));
+                        }
+                        Ok("OK".to_string())
+                    }
+                    _ => {
+                        self.attempts += 1;
+                        Err(HandlerError::not_found("k"))
+                    }
+                }
+            }
+        }
+
+        let config = Arc::new(Config {
+            command_max_retries: 3,
+            ..Config::default()
+        });
+        let mut pool = ThreadPoolBuilder::new(config)
+            .io_threads(1)
+            .output(OutputSinks::discard())
+            .processor(Box::new(Flaky { attempts: 0 }))
+            .build();
+        let main_handle = pool.start_main_thread();
+        let sender = pool.get_string_sender();
+        sender.send(("SET k v".to_string(), 1)).unwrap();
+        sender.send(("GET k".to_string(), 2)).unwrap();
+        drop(sender);
+        pool.shutdown();
+        let summary = main_handle.join().unwrap();
+
+        // Two retries for the SET; the GET miss is answered on its first try
+        assert_eq!(summary.succeeded, 1);
+        assert_eq!(summary.soft_errors, 1);
+        assert_eq!(summary.hard_errors, 0);
+        assert_eq!(summary.retries, 2);
+    }
+
+    /// Compares the distribution strategies on uniform and skewed keys
+    ///
+    /// Run with `cargo test --release -- --ignored --nocapture bench_distribution`
+    #[test]
+    #[ignore]
+    fn bench_distribution() {
+        const LINES: usize = 200_000;
+
+        let uniform = |i: usize| format!("SET key:{} value", i % 10_000);
+        // Nine lines in ten hit the same key, so key_hash piles them on one thread
+        let skewed = |i: usize| {
+            let key = if i.is_multiple_of(10) { i % 10_000 } else { 0 };
+            format!("SET key:{} value", key)
+        };
+        let workloads: [(&str, &dyn Fn(usize) -> String); 2] =
+            [("uniform", &uniform), ("skewed", &skewed)];
+
+        for (workload, line) in workloads {
+            for distribution in [
+                Distribution::Shared,
+                Distribution::RoundRobin,
+                Distribution::KeyHash,
+            ] {
+                let mut pool = ThreadPoolBuilder::new(Arc::new(Config::default()))
+                    .io_threads(4)
+                    .distribution(distribution)
+                    .output(OutputSinks::discard())
+                    .build();
+                let start = Instant::now();
+                let main_handle = pool.start_main_thread();
+                let sender = pool.get_string_sender();
+                for i in 0..LINES {
+                    sender.send((line(i), i + 1)).unwrap();
+                }
+                drop(sender);
+                let report = pool.shutdown();
+                main_handle.join().unwrap();
+                let busiest = report.io_thread_timings.iter().map(|t| t.lines).max();
+                println!(
+                    "[Bench] {} {:?}: {:?} (busiest IO thread parsed {} lines)",
+                    workload,
+                    distribution,
+                    start.elapsed(),
+                    busiest.unwrap_or(0)
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,233 @@
+//! Input line transformers applied by IO threads before parsing
+//!
+//! A pool runs its lines through a chain of `LineTransformer`s in order; any
+//! of them can rewrite the line or drop it. The chain defaults to the
+//! built-ins enabled in the config (`STRIP_PREFIX`, `LOWERCASE_KEYS`), and
+//! `ThreadPoolBuilder::line_transformer` appends more.
+//!
+//! Before the chain runs, a leading `[trace:<id>]` tag is always taken off
+//! the line (`take_trace_id`) and carried alongside the command, so the tag
+//! must open the raw line.
+
+use crate::config::Config;
+use crate::glob::glob_match;
+
+/// Rewrites a raw input line before it is parsed
+pub trait LineTransformer: Send + Sync {
+    /// The line to parse instead of `raw`, or `None` to drop it
+    fn transform(&self, raw: String, line_number: usize) -> Option<String>;
+}
+
+impl<F> LineTransformer for F
+where
+    F: Fn(String, usize) -> Option<String> + Send + Sync,
+{
+    fn transform(&self, raw: String, line_number: usize) -> Option<String> {
+        self(raw, line_number)
+    }
+}
+
+/// The built-in transformers the config asks for, in the order they run
+pub fn from_config(config: &Config) -> Vec<Box<dyn LineTransformer>> {
+    let mut chain: Vec<Box<dyn LineTransformer>> = Vec::new();
+    if let Some(pattern) = &config.strip_prefix {
+        chain.push(Box::new(
+            PrefixStripper::new(pattern.clone()).drop_unmatched(config.strip_prefix_drop_unmatched),
+        ));
+    }
+    if config.lowercase_keys {
+        chain.push(Box::new(KeyLowercaser));
+    }
+    chain
+}
+
+/// Run `raw` through every transformer in `chain`, stopping at the first
+/// that drops it
+pub fn apply(
+    chain: &[Box<dyn LineTransformer>],
+    raw: String,
+    line_number: usize,
+) -> Option<String> {
+    chain.iter().try_fold(raw, |line, transformer| {
+        transformer.transform(line, line_number)
+    })
+}
+
+/// Split a leading `[trace:<id>]` tag off `line`, returning the id and the
+/// rest of the line
+///
+/// Ids can't be empty or contain whitespace; a line without a valid tag is
+/// left alone.
+pub fn take_trace_id(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix("[trace:")?;
+    let (id, rest) = rest.split_once(']')?;
+    if id.is_empty() || id.contains(char::is_whitespace) {
+        return None;
+    }
+    Some((id, rest.trim_start()))
+}
+
+/// Removes the shortest leading run of a line that matches a glob, such as
+/// `*app\[*\]: ` for a syslog header
+pub struct PrefixStripper {
+    pattern: String,
+    drop_unmatched: bool,
+}
+
+impl PrefixStripper {
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Self {
+            pattern: pattern.into(),
+            drop_unmatched: false,
+        }
+    }
+
+    /// Drop lines with no matching prefix instead of passing them through
+    pub fn drop_unmatched(mut self, drop_unmatched: bool) -> Self {
+        self.drop_unmatched = drop_unmatched;
+        self
+    }
+}
+
+impl LineTransformer for PrefixStripper {
+    fn transform(&self, raw: String, _line_number: usize) -> Option<String> {
+        let end = (1..=raw.len())
+            .filter(|&end| raw.is_char_boundary(end))
+            .find(|&end| glob_match(&self.pattern, &raw[..end]));
+        match end {
+            Some(end) => Some(raw[end..].to_string()),
+            None if self.drop_unmatched => None,
+            None => Some(raw),
+        }
+    }
+}
+
+/// Lower-cases the key arguments of a line, leaving the keyword and any
+/// values, paths, or options untouched
+///
+/// Lines for commands without keys, and unknown commands, pass through
+/// unchanged.
+pub struct KeyLowercaser;
+
+impl LineTransformer for KeyLowercaser {
+    fn transform(&self, raw: String, _line_number: usize) -> Option<String> {
+        let mut tokens = raw.split_whitespace();
+        let keyword = tokens.next().unwrap_or("");
+        let args: Vec<&str> = tokens.collect();
+        let keys = leading_keys(keyword, &args);
+        if args[..keys]
+            .iter()
+            .all(|key| !key.chars().any(char::is_uppercase))
+        {
+            return Some(raw);
+        }
+
+        let mut line = keyword.to_string();
+        for (i, arg) in args.iter().enumerate() {
+            line.push(' ');
+            if i < keys {
+                line.push_str(&arg.to_lowercase());
+            } else {
+                line.push_str(arg);
+            }
+        }
+        Some(line)
+    }
+}
+
+/// How many of a command's leading arguments are keys (or, for
+/// SORTBYVALUE, GENSET, PURGE, and RENAMEPREFIX, a key pattern or prefix)
+fn leading_keys(keyword: &str, args: &[&str]) -> usize {
+    let keys = match keyword {
+        "SET" | "GET" | "GETWITHTTL" | "JGET" | "DELETE" | "LTRIM" | "LINDEX" | "APPENDCAP"
+        | "GETDEFAULT" | "GETORSET" | "INCRBOUNDED" | "SETCRC" | "GETCRC" | "SORTBYVALUE"
+        | "WAITKEY" | "GENSET" | "UNDELETE" | "PURGE" | "GETMATCHING" | "INSPECT" | "KEYSLOT"
+        | "GETAT" => 1,
+        "LMOVE" | "RENAMENX" | "RENAMEPREFIX" | "INCRIF" => 2,
+        // The destination and every source, up to the STRATEGY clause
+        "MERGE" => args.iter().position(|arg| *arg == "STRATEGY").unwrap_or(0),
+        _ => 0,
+    };
+    keys.min(args.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(transformer: &dyn LineTransformer, raw: &str) -> Option<String> {
+        transformer.transform(raw.to_string(), 1)
+    }
+
+    #[test]
+    fn test_prefix_stripper_takes_shortest_match() {
+        let stripper = PrefixStripper::new(r"*app\[*\]: ");
+        assert_eq!(
+            run(&stripper, "Oct 17 host app[42]: SET k v: w").as_deref(),
+            Some("SET k v: w")
+        );
+        assert_eq!(run(&stripper, "SET k v").as_deref(), Some("SET k v"));
+
+        let strict = PrefixStripper::new("> ").drop_unmatched(true);
+        assert_eq!(run(&strict, "> GET k").as_deref(), Some("GET k"));
+        assert_eq!(run(&strict, "GET k"), None);
+    }
+
+    #[test]
+    fn test_key_lowercaser_touches_only_keys() {
+        let cases = [
+            ("SET User:1 Ada Lovelace", "SET user:1 Ada Lovelace"),
+            ("RENAMENX Old New", "RENAMENX old new"),
+            (
+                "MERGE Total A B STRATEGY concat SEPARATOR X",
+                "MERGE total a b STRATEGY concat SEPARATOR X",
+            ),
+            ("LOADJSON Config.json", "LOADJSON Config.json"),
+            ("GET  already:lower", "GET  already:lower"),
+            ("INFO", "INFO"),
+        ];
+        for (raw, expected) in cases {
+            assert_eq!(
+                run(&KeyLowercaser, raw).as_deref(),
+                Some(expected),
+                "{}",
+                raw
+            );
+        }
+    }
+
+    #[test]
+    fn test_take_trace_id_splits_leading_tag() {
+        assert_eq!(
+            take_trace_id("[trace:abc123] SET k v"),
+            Some(("abc123", "SET k v"))
+        );
+        assert_eq!(take_trace_id("[trace:x]GET k"), Some(("x", "GET k")));
+        for line in [
+            "SET k v",
+            "[trace:] GET k",
+            "[trace:a b] GET k",
+            "[trace:x GET k",
+        ] {
+            assert_eq!(take_trace_id(line), None, "{}", line);
+        }
+    }
+
+    #[test]
+    fn test_chain_runs_in_order_and_stops_at_drop() {
+        let chain: Vec<Box<dyn LineTransformer>> = vec![
+            Box::new(PrefixStripper::new("*: ").drop_unmatched(true)),
+            Box::new(KeyLowercaser),
+            Box::new(|line: String, line_number: usize| {
+                (line_number != 3).then(|| format!("{} ID req-{}", line, line_number))
+            }),
+        ];
+
+        assert_eq!(
+            apply(&chain, "host: SET Key v".to_string(), 1).as_deref(),
+            Some("SET key v ID req-1")
+        );
+        assert_eq!(apply(&chain, "SET Key v".to_string(), 2), None);
+        assert_eq!(apply(&chain, "host: SET Key v".to_string(), 3), None);
+    }
+}
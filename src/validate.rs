@@ -0,0 +1,261 @@
+//! Per-key-pattern value validation (`VALIDATE_<glob>=<type>`)
+//!
+//! Each rule pairs a key glob with the kind of value its keys may hold.
+//! Commands that write a string value check it against the most specific
+//! rule whose glob matches the key, and fail without writing if it doesn't
+//! conform. Keys no rule matches accept anything.
+
+use std::fmt;
+
+use crate::glob::glob_match;
+use crate::json::{self, JsonPath};
+
+/// Environment variable prefix of a rule; the rest of the name is its glob
+pub const ENV_PREFIX: &str = "VALIDATE_";
+
+/// What a rule requires a value to be
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueKind {
+    /// A base-10 signed 64-bit integer
+    Integer,
+    /// `true` or `false`
+    Bool,
+    /// A complete JSON document
+    Json,
+}
+
+impl ValueKind {
+    fn parse(raw: &str) -> Option<Self> {
+        match raw {
+            "integer" => Some(Self::Integer),
+            "bool" => Some(Self::Bool),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Integer => "integer",
+            Self::Bool => "bool",
+            Self::Json => "json",
+        }
+    }
+
+    fn accepts(self, value: &str) -> bool {
+        match self {
+            Self::Integer => value.parse::<i64>().is_ok(),
+            Self::Bool => matches!(value, "true" | "false"),
+            Self::Json => json::extract(
+                value,
+                &JsonPath {
+                    segments: Vec::new(),
+                },
+            )
+            .is_ok(),
+        }
+    }
+}
+
+/// One `glob=type` rule and the writes it has rejected
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationRule {
+    pattern: String,
+    kind: ValueKind,
+    rejected: u64,
+}
+
+impl ValidationRule {
+    /// Characters before the first wildcard, which rank rules when more
+    /// than one matches a key
+    fn literal_prefix(&self) -> usize {
+        self.pattern
+            .find(['*', '?', '[', '\\'])
+            .unwrap_or(self.pattern.len())
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+}
+
+impl fmt::Display for ValidationRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.pattern, self.kind.name())
+    }
+}
+
+/// The compiled rule set, most specific rule first
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ValidationRules {
+    rules: Vec<ValidationRule>,
+}
+
+impl ValidationRules {
+    /// Compile rules from `(glob, type)` pairs; an unknown type is an error
+    pub fn compile<'a>(
+        rules: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self, String> {
+        let mut compiled = rules
+            .into_iter()
+            .map(|(pattern, kind)| {
+                let kind = ValueKind::parse(kind.trim()).ok_or_else(|| {
+                    format!(
+                        "Unknown validation type '{}' for {}{}: expected integer|bool|json",
+                        kind, ENV_PREFIX, pattern
+                    )
+                })?;
+                Ok(ValidationRule {
+                    pattern: pattern.to_string(),
+                    kind,
+                    rejected: 0,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        // Longest literal prefix first, then longest pattern, so the first
+        // match is the most specific
+        compiled.sort_by(|a, b| {
+            b.literal_prefix()
+                .cmp(&a.literal_prefix())
+                .then_with(|| b.pattern.len().cmp(&a.pattern.len()))
+                .then_with(|| a.pattern.cmp(&b.pattern))
+        });
+        Ok(Self { rules: compiled })
+    }
+
+    /// Compile every `VALIDATE_<glob>` variable in `vars`
+    pub fn from_vars(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self, String> {
+        let rules: Vec<(String, String)> = vars
+            .into_iter()
+            .filter_map(|(name, kind)| Some((name.strip_prefix(ENV_PREFIX)?.to_string(), kind)))
+            .filter(|(pattern, _)| !pattern.is_empty())
+            .collect();
+        Self::compile(
+            rules
+                .iter()
+                .map(|(pattern, kind)| (pattern.as_str(), kind.as_str())),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Check `value` against the rule for `key`, counting a rejection
+    ///
+    /// Returns the violated rule, as written in the environment, on failure.
+    pub fn check(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let Some(rule) = self
+            .rules
+            .iter_mut()
+            .find(|rule| glob_match(&rule.pattern, key))
+        else {
+            return Ok(());
+        };
+        if rule.kind.accepts(value) {
+            return Ok(());
+        }
+        rule.rejected += 1;
+        Err(rule.to_string())
+    }
+
+    /// Every rule, most specific first
+    pub fn rules(&self) -> &[ValidationRule] {
+        &self.rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(pairs: &[(&str, &str)]) -> ValidationRules {
+        ValidationRules::compile(pairs.iter().copied()).unwrap()
+    }
+
+    #[test]
+    fn test_each_rule_type_accepts_and_rejects() {
+        let mut rules = rules(&[
+            ("counter:*", "integer"),
+            ("flag:*", "bool"),
+            ("user:*", "json"),
+        ]);
+
+        for value in ["0", "-42", "9223372036854775807"] {
+            assert_eq!(rules.check("counter:a", value), Ok(()), "{}", value);
+        }
+        for value in ["", "1.5", "ten", "9223372036854775808"] {
+            assert_eq!(
+                rules.check("counter:a", value),
+                Err("counter:*=integer".to_string()),
+                "{}",
+                value
+            );
+        }
+
+        assert_eq!(rules.check("flag:on", "true"), Ok(()));
+        assert_eq!(rules.check("flag:on", "false"), Ok(()));
+        assert!(rules.check("flag:on", "yes").is_err());
+        assert!(rules.check("flag:on", "TRUE").is_err());
+
+        assert_eq!(
+            rules.check("user:1", r#"{"name":"Ada","tags":[1,2]}"#),
+            Ok(())
+        );
+        assert_eq!(rules.check("user:1", "3"), Ok(()));
+        assert!(rules.check("user:1", "{name:Ada}").is_err());
+        assert!(rules.check("user:1", r#"{"a":1} trailing"#).is_err());
+
+        let rejected: Vec<(String, u64)> = rules
+            .rules()
+            .iter()
+            .map(|rule| (rule.to_string(), rule.rejected()))
+            .collect();
+        assert_eq!(
+            rejected,
+            [
+                ("counter:*=integer".to_string(), 4),
+                ("flag:*=bool".to_string(), 2),
+                ("user:*=json".to_string(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unruled_keys_accept_anything() {
+        let mut rules = rules(&[("counter:*", "integer")]);
+        assert_eq!(rules.check("session:1", "not a number"), Ok(()));
+        assert_eq!(rules.check("counter", "not a number"), Ok(()));
+        assert_eq!(ValidationRules::default().check("counter:a", "x"), Ok(()));
+    }
+
+    #[test]
+    fn test_longest_prefix_rule_wins() {
+        let mut rules = rules(&[
+            ("*", "json"),
+            ("counter:*", "json"),
+            ("counter:hits:*", "integer"),
+        ]);
+        assert!(rules.check("counter:hits:today", "[1]").is_err());
+        assert_eq!(rules.check("counter:other", "[1]"), Ok(()));
+        assert!(rules.check("misc", "plain").is_err());
+    }
+
+    #[test]
+    fn test_unknown_types_and_env_names() {
+        let err = ValidationRules::compile([("counter:*", "float")]).unwrap_err();
+        assert_eq!(
+            err,
+            "Unknown validation type 'float' for VALIDATE_counter:*: expected integer|bool|json"
+        );
+
+        let rules = ValidationRules::from_vars([
+            ("VALIDATE_flag:*".to_string(), "bool".to_string()),
+            ("VALIDATE_".to_string(), "bool".to_string()),
+            ("VALUE_CASE".to_string(), "lower".to_string()),
+        ])
+        .unwrap();
+        let names: Vec<String> = rules.rules().iter().map(ToString::to_string).collect();
+        assert_eq!(names, ["flag:*=bool"]);
+    }
+}
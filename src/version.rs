@@ -0,0 +1,108 @@
+//! What this build is, for VERSION, `--version`, and the startup banner
+//!
+//! The git commit comes from `build.rs` and is missing when the crate was
+//! built outside a git checkout. Features are the optional parts compiled
+//! in: `chaos` is the delay and failure injection debug builds carry, and
+//! `ffi` the C API.
+
+/// The crate version, from `Cargo.toml`
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The git commit the crate was built from, if known
+pub const GIT_HASH: Option<&str> = option_env!("CRABBY_GIT_HASH");
+
+/// Where keys live; there is one backend
+pub const STORE_BACKEND: &str = "hashmap";
+
+/// The optional parts compiled into this build, in name order
+pub fn features() -> Vec<&'static str> {
+    let mut features = Vec::new();
+    if cfg!(debug_assertions) {
+        features.push("chaos");
+    }
+    if cfg!(feature = "ffi") {
+        features.push("ffi");
+    }
+    features
+}
+
+/// The features joined by `separator`, or `none`
+fn feature_list(separator: &str) -> String {
+    let features = features();
+    if features.is_empty() {
+        "none".to_string()
+    } else {
+        features.join(separator)
+    }
+}
+
+/// One line naming the build, e.g.
+/// `multi_threader 0.1.0 (git 1a2b3c4d5e6f; features: chaos, ffi)`
+pub fn banner() -> String {
+    format!(
+        "{} {} (git {}; features: {})",
+        env!("CARGO_PKG_NAME"),
+        VERSION,
+        GIT_HASH.unwrap_or("unknown"),
+        feature_list(", ")
+    )
+}
+
+/// The build and its backends as `(field, value)` pairs; `persistence`
+/// says whether snapshots are configured
+pub fn fields(persistence: bool) -> Vec<(&'static str, String)> {
+    vec![
+        ("version", VERSION.to_string()),
+        ("git_hash", GIT_HASH.unwrap_or("unknown").to_string()),
+        ("features", feature_list(",")),
+        (
+            "profile",
+            if cfg!(debug_assertions) {
+                "debug"
+            } else {
+                "release"
+            }
+            .to_string(),
+        ),
+        ("store", STORE_BACKEND.to_string()),
+        (
+            "persistence",
+            if persistence { "snapshot" } else { "off" }.to_string(),
+        ),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fields_describe_this_build() {
+        let fields = fields(false);
+        let field = |name: &str| {
+            fields
+                .iter()
+                .find(|(field, _)| *field == name)
+                .map(|(_, value)| value.as_str())
+                .unwrap()
+        };
+        assert_eq!(field("version"), env!("CARGO_PKG_VERSION"));
+        assert!(!field("git_hash").is_empty());
+        assert_eq!(
+            field("features").split(',').any(|f| f == "chaos"),
+            cfg!(debug_assertions)
+        );
+        assert_eq!(
+            field("features").split(',').any(|f| f == "ffi"),
+            cfg!(feature = "ffi")
+        );
+        assert_eq!(field("store"), "hashmap");
+        assert_eq!(field("persistence"), "off");
+        assert_eq!(
+            super::fields(true)[5],
+            ("persistence", "snapshot".to_string())
+        );
+
+        assert!(banner().starts_with(&format!("multi_threader {} (git ", VERSION)));
+    }
+}
@@ -0,0 +1,423 @@
+//! Runs the `multi_threader` binary end to end
+#![cfg(unix)]
+
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn test_closed_stdout_truncates_without_panicking() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("crabby_closed_stdout");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input: String = (0..50_000).map(|i| format!("SET key{} v\n", i)).collect();
+    std::fs::write(dir.join("input.txt"), input).unwrap();
+
+    for policy in ["continue", "stop"] {
+        let stderr = run_until_stdout_closes(&dir, policy);
+        assert!(!stderr.contains("panicked"), "{}", stderr);
+        assert!(stderr.contains("dropping further output"), "{}", stderr);
+        assert_eq!(
+            stderr.contains("ON_OUTPUT_ERROR=stop, no further commands"),
+            policy == "stop",
+            "{}",
+            stderr
+        );
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Run the binary in `dir`, hang up on its stdout early, and return its
+/// stderr once it exits with the truncation code
+fn run_until_stdout_closes(dir: &Path, policy: &str) -> String {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+        .current_dir(dir)
+        .env("ON_OUTPUT_ERROR", policy)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Read a little, like `head`, then hang up
+    let mut stdout = child.stdout.take().unwrap();
+    let mut start = [0; 64];
+    stdout.read_exact(&mut start).unwrap();
+    drop(stdout);
+
+    let deadline = Instant::now() + Duration::from_secs(60);
+    let status = loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            break status;
+        }
+        if Instant::now() > deadline {
+            child.kill().unwrap();
+            panic!("the binary hung after its stdout closed");
+        }
+        thread::sleep(Duration::from_millis(50));
+    };
+    let mut stderr = String::new();
+    child
+        .stderr
+        .take()
+        .unwrap()
+        .read_to_string(&mut stderr)
+        .unwrap();
+    assert_eq!(status.code(), Some(3), "{}", stderr);
+    stderr
+}
+
+#[test]
+fn test_selftest_subcommand_passes() {
+    let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+        .arg("selftest")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert!(stdout.contains("VALUELENGTHSTATS"), "{}", stdout);
+    assert!(stdout.ends_with("selftest PASSED\n"), "{}", stdout);
+}
+
+#[test]
+fn test_version_flag_prints_the_banner() {
+    let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+        .arg("--version")
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert_eq!(output.status.code(), Some(0), "{}", stdout);
+    assert!(
+        stdout.starts_with("multi_threader 0.1.0 (git "),
+        "{}",
+        stdout
+    );
+    assert_eq!(stdout.lines().count(), 1, "{}", stdout);
+}
+
+#[test]
+fn test_trace_id_follows_a_command_end_to_end() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("crabby_trace_ids");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = "SET a 1\n[trace:abc123] GET missing\n[trace:def456] SET b 2\n";
+    std::fs::write(dir.join("input.txt"), input).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+        .current_dir(&dir)
+        .arg("--show-trace-ids")
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(0), "{}", stderr);
+
+    let traced = |text: &str, id: &str, stage: &str| {
+        text.lines()
+            .any(|line| line.contains(stage) && line.contains(&format!("[trace:{}]", id)))
+    };
+    let stages = [
+        (&stdout, "[IO Thread", "abc123"),
+        (&stdout, "[Main Thread] Applying line 2: GET", "abc123"),
+        (&stderr, "Warning: Key 'missing' not found", "abc123"),
+        (&stdout, "[IO Thread", "def456"),
+        (&stdout, "[Main Thread] Applying line 3: SET", "def456"),
+        (&stdout, "SET b = 2", "def456"),
+    ];
+    for (text, stage, id) in stages {
+        assert!(
+            traced(text, id, stage),
+            "{} not traced in:\n{}",
+            stage,
+            text
+        );
+    }
+
+    // An untagged line gets a generated id, and tags never reach the parser
+    let response = stdout
+        .lines()
+        .find(|line| line.contains("SET a = 1"))
+        .unwrap();
+    assert!(response.contains("[trace:"), "{}", response);
+    assert!(!stderr.contains("Parse error"), "{}", stderr);
+}
+
+#[test]
+fn test_unknown_validation_type_stops_startup() {
+    let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+        .current_dir(env!("CARGO_TARGET_TMPDIR"))
+        .env("VALIDATE_counter:*", "float")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr);
+    assert!(
+        stderr.contains("Unknown validation type 'float' for VALIDATE_counter:*"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn test_bad_prefix_quota_stops_startup() {
+    let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+        .current_dir(env!("CARGO_TARGET_TMPDIR"))
+        .env("LIMIT_PREFIX_user:*:name_MAX_KEYS", "10")
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(2), "{}", stderr);
+    assert!(
+        stderr.contains("Invalid quota pattern 'user:*:name'"),
+        "{}",
+        stderr
+    );
+}
+
+// The panic hook only exists in debug builds
+#[cfg(debug_assertions)]
+#[test]
+fn test_main_thread_panic_exits_cleanly() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("crabby_main_panic");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("input.txt"), "SET a 1\nINCRBOUNDED a 5\nGET a\n").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+        .current_dir(&dir)
+        .env("TEST_PANIC_HANDLER_ON", "INCRBOUNDED")
+        .env("CATCH_HANDLER_PANICS", "false")
+        .output()
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(4), "{}", stderr);
+    assert!(
+        stderr.contains(
+            "Error: main processing thread panicked: [Test] Deliberate handler panic on INCRBOUNDED"
+        ),
+        "{}",
+        stderr
+    );
+    // Only the handler's panic, not a second one from joining the thread
+    assert_eq!(stderr.matches("panicked at").count(), 1, "{}", stderr);
+}
+
+#[test]
+fn test_dir_mode_summarizes_each_file_and_fails_fast() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("crabby_dir_mode");
+    let logs = dir.join("logs");
+    std::fs::create_dir_all(&logs).unwrap();
+    std::fs::write(logs.join("a.log"), "SET a 1\nGET a\n").unwrap();
+    std::fs::write(logs.join("b.log"), "SET b 2\nINCRBOUNDED b 1\nGET a\n").unwrap();
+    std::fs::write(logs.join("c.log"), "GET b\n").unwrap();
+    std::fs::write(logs.join("notes.txt"), "SET ignored 1\n").unwrap();
+
+    let run = |extra: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+            .current_dir(&dir)
+            // b.log increments the key it just set
+            .env("PER_KEY_ORDERING", "1")
+            .args(["--dir", "logs", "--glob", "*.log"])
+            .args(["--responses-out", "responses.txt"])
+            .args(["--errors-out", "errors.txt"])
+            .args(["--summary-out", "summary.txt"])
+            .args(extra)
+            .output()
+            .unwrap();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        (
+            output,
+            read("responses.txt"),
+            read("errors.txt"),
+            read("summary.txt"),
+        )
+    };
+
+    let (output, responses, errors, summary) = run(&[]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(
+        summary.starts_with(
+            "file a.log: processed=2 succeeded=2 soft_errors=0 hard_errors=0\n\
+         file b.log: processed=3 succeeded=2 soft_errors=0 hard_errors=1\n\
+         file c.log: processed=1 succeeded=1 soft_errors=0 hard_errors=0\n\
+         processed: 6\nsucceeded: 5\nsoft_errors: 0\nhard_errors: 1\n"
+        ),
+        "{}",
+        summary
+    );
+    // Files share one store, and each is finished before the next starts
+    assert!(
+        responses.contains("[Line b.log:3] GET a = 1\n"),
+        "{}",
+        responses
+    );
+    assert!(
+        responses.contains("[Line c.log:1] GET b = 2\n"),
+        "{}",
+        responses
+    );
+    assert!(errors.contains("[Line b.log:2] Error: "), "{}", errors);
+    assert!(!responses.contains("ignored"), "{}", responses);
+
+    let (output, responses, _, summary) = run(&["--fail-fast"]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(1), "{}", stderr);
+    assert!(
+        stderr.contains("b.log had 1 hard errors; skipping the remaining files"),
+        "{}",
+        stderr
+    );
+    assert!(!summary.contains("c.log"), "{}", summary);
+    assert!(summary.contains("\nprocessed: 5\n"), "{}", summary);
+    assert!(!responses.contains("c.log"), "{}", responses);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_events_out_records_a_run_in_order() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("crabby_events_out");
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("input.txt"), "SET a 1\nSET b 2\nGET a\n").unwrap();
+    let _ = std::fs::remove_file(dir.join("store.snap"));
+
+    let run = |extra: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+            .current_dir(&dir)
+            .env("SNAPSHOT_PATH", "store.snap")
+            .env("TEST_FAIL_WRITE_EVERY", "2")
+            .env("COMMAND_MAX_RETRIES", "1")
+            .args(["--events-out", "events.ndjson"])
+            .args(extra)
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        assert_eq!(output.status.code(), Some(0), "{}", stderr);
+        std::fs::read_to_string(dir.join("events.ndjson"))
+            .unwrap()
+            .lines()
+            .map(|line| multi_threader::json::parse_flat_object(line).unwrap().pairs)
+            .collect::<Vec<_>>()
+    };
+    let kinds = |events: &[Vec<(String, String)>]| {
+        events
+            .iter()
+            .map(|pairs| {
+                assert_eq!(pairs[0].0, "ts_ms");
+                assert_eq!(pairs[1].0, "event");
+                pairs[1].1.clone()
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let first = run(&["--save-on-exit"]);
+    let chaos: &[&str] = if cfg!(debug_assertions) {
+        &["chaos"]
+    } else {
+        &[]
+    };
+    let expected: Vec<&str> = ["startup"]
+        .iter()
+        .chain(chaos)
+        .chain(&["snapshot_saved", "shutdown"])
+        .copied()
+        .collect();
+    assert_eq!(kinds(&first), expected);
+    assert!(first[0].contains(&("save_on_exit".to_string(), "true".to_string())));
+    let shutdown = first.last().unwrap();
+    assert!(shutdown.contains(&("processed".to_string(), "3".to_string())));
+    assert!(shutdown.contains(&("events_dropped".to_string(), "0".to_string())));
+
+    let second = run(&[]);
+    let expected: Vec<&str> = ["startup", "snapshot_restored"]
+        .iter()
+        .chain(chaos)
+        .chain(&["shutdown"])
+        .copied()
+        .collect();
+    assert_eq!(kinds(&second), expected);
+    assert!(second[1].contains(&("keys".to_string(), "2".to_string())));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_crlf_input_gives_the_same_responses_as_lf() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let crlf = std::fs::read(fixtures.join("line_endings_crlf.txt")).unwrap();
+    // A checkout that converted line endings would make this test vacuous
+    assert!(crlf.windows(2).any(|pair| pair == b"\r\n"));
+
+    let responses = |fixture: &str| {
+        let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(format!("crabby_{}", fixture));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::copy(fixtures.join(fixture), dir.join("input.txt")).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+            .current_dir(&dir)
+            .env("PER_KEY_ORDERING", "1")
+            .env("OUTPUT_ORDERED", "1")
+            .args(["--responses-out", "responses.txt"])
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(output.status.code(), Some(0), "{}", stderr);
+        let responses = std::fs::read(dir.join("responses.txt")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        responses
+    };
+
+    let lf = responses("line_endings_lf.txt");
+    assert_eq!(responses("line_endings_crlf.txt"), lf);
+    let lf = String::from_utf8(lf).unwrap();
+    assert!(lf.contains("GET greeting = hello world\n"), "{}", lf);
+    assert!(lf.contains("GET count = 42\n"), "{}", lf);
+    assert!(!lf.contains('\r'), "{:?}", lf);
+}
+
+#[test]
+fn test_parse_error_rate_aborts_garbage_input() {
+    // Run `input`, stopping once over three in four of the last 100 lines
+    // failed; a whole window of half failures doesn't, however IO threads
+    // interleave
+    let run = |name: &str, input: String| {
+        let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(name);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("input.txt"), input).unwrap();
+        let output = Command::new(env!("CARGO_BIN_EXE_multi_threader"))
+            .current_dir(&dir)
+            .env("ABORT_IF_PARSE_ERROR_RATE", "0.75")
+            .env("PARSE_ERROR_WINDOW", "100")
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        output
+    };
+
+    // A JSON file fed in by mistake: every line fails to parse
+    let garbage: String = (0..20_000)
+        .map(|i| format!("{{\"id\": {}, \"name\": \"user{}\"}}\n", i, i))
+        .collect();
+    let output = run("crabby_parse_garbage", garbage);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(output.status.code(), Some(5), "{}", stderr);
+    assert!(
+        stderr.contains("ABORT_IF_PARSE_ERROR_RATE=0.75"),
+        "{}",
+        stderr
+    );
+    assert!(stdout.contains("parse_error_abort: true"), "{}", stdout);
+    // Lines already queued are dropped rather than parsed one by one
+    let reported = stderr.matches("Parse Error at line").count();
+    assert!((100..1000).contains(&reported), "{} parse errors", reported);
+
+    // Half the lines failing is within the limit, so the run completes
+    let half: String = (0..2_000)
+        .map(|i| format!("SET key{} v\n{{\"id\": {}}}\n", i, i))
+        .collect();
+    let output = run("crabby_parse_half_garbage", half);
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_ne!(output.status.code(), Some(5), "{}", stderr);
+    assert!(stdout.contains("processed: 2000"), "{}", stdout);
+    assert!(!stdout.contains("parse_error_abort"), "{}", stdout);
+}
@@ -0,0 +1,325 @@
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use multi_threader::config::Config;
+use multi_threader::custom::CustomStore;
+use multi_threader::engine::{EngineError, EngineOptions, KvEngine};
+use multi_threader::handler::{HandlerError, Value};
+use multi_threader::parser::{Command, CommandType};
+
+#[test]
+fn test_engine_mixed_workload() {
+    let engine = KvEngine::start(EngineOptions::default());
+
+    assert_eq!(
+        engine.execute("SET user:1 Alice").unwrap(),
+        "SET user:1 = Alice"
+    );
+    assert_eq!(engine.execute("GET user:1").unwrap(), "GET user:1 = Alice");
+    assert_eq!(
+        engine.execute("GET user:2"),
+        Err(EngineError::Handler(HandlerError::not_found("user:2")))
+    );
+    assert!(matches!(
+        engine.execute("BOGUS command"),
+        Err(EngineError::Parse(_))
+    ));
+
+    let cmd = Command::new(CommandType::GetDefault(
+        "user:2".to_string(),
+        "nobody".to_string(),
+    ));
+    assert_eq!(engine.execute_command(cmd).unwrap(), "nobody");
+
+    // Parse errors never reach the main thread, so they aren't counted
+    let stats = engine.stats();
+    assert_eq!(stats.processed, 4);
+    assert_eq!(stats.soft_errors, 1);
+
+    // Only lines `execute` parsed count, PARSESTATS included
+    assert_eq!(
+        engine.execute("PARSESTATS").unwrap(),
+        "GET 2\nPARSESTATS 1\nSET 1"
+    );
+
+    let summary = engine.shutdown();
+    assert_eq!(summary.processed, 5);
+    assert_eq!(summary.succeeded, 4);
+    assert_eq!(summary.soft_errors, 1);
+    assert_eq!(summary.hard_errors, 0);
+}
+
+#[test]
+fn test_engine_shared_across_threads() {
+    let engine = KvEngine::start(EngineOptions::default());
+
+    thread::scope(|scope| {
+        for t in 0..4 {
+            let engine = &engine;
+            scope.spawn(move || {
+                for i in 0..25 {
+                    engine.execute(&format!("SET key:{}:{} v", t, i)).unwrap();
+                }
+            });
+        }
+    });
+
+    assert_eq!(
+        engine.execute("INFO").unwrap().lines().next(),
+        Some("keys:100")
+    );
+    assert_eq!(engine.shutdown().processed, 101);
+}
+
+#[test]
+fn test_waitkey_wakes_when_key_is_set() {
+    let engine = KvEngine::start(EngineOptions::default());
+
+    thread::scope(|scope| {
+        let engine = &engine;
+        let waiter = scope.spawn(move || {
+            let start = Instant::now();
+            (engine.execute("WAITKEY job:done 5000"), start.elapsed())
+        });
+
+        // The main thread keeps serving commands while WAITKEY is parked
+        thread::sleep(Duration::from_millis(50));
+        assert!(engine.execute("GET job:done").is_err());
+        engine.execute("SET job:done yes").unwrap();
+
+        let (result, elapsed) = waiter.join().unwrap();
+        assert_eq!(result.unwrap(), "1");
+        assert!(elapsed < Duration::from_secs(5));
+    });
+
+    engine.shutdown();
+}
+
+#[test]
+fn test_waitkey_times_out() {
+    let engine = KvEngine::start(EngineOptions::default());
+
+    let start = Instant::now();
+    assert_eq!(engine.execute("WAITKEY never 50").unwrap(), "0");
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    // An existing key answers immediately
+    engine.execute("SET here 1").unwrap();
+    assert_eq!(engine.execute("WAITKEY here 5000").unwrap(), "1");
+
+    let summary = engine.shutdown();
+    assert_eq!(summary.succeeded, 3);
+}
+
+#[test]
+fn test_id_is_plain_text_without_an_idempotency_window() {
+    let engine = KvEngine::start(EngineOptions::default());
+
+    assert_eq!(engine.execute("SET k my ID 7").unwrap(), "SET k = my ID 7");
+    assert_eq!(engine.execute("GET k").unwrap(), "GET k = my ID 7");
+    assert_eq!(engine.execute("SET ID x").unwrap(), "SET ID = x");
+    assert_eq!(engine.execute("GET ID").unwrap(), "GET ID = x");
+    engine.shutdown();
+}
+
+#[test]
+fn test_shutdown_save_and_restart_round_trip() {
+    const KEYS: usize = 100_000;
+
+    let dir = std::env::temp_dir();
+    let snapshot = dir.join(format!("crabby_restart_{}.crkvs", std::process::id()));
+    let before = dir.join(format!("crabby_restart_before_{}.json", std::process::id()));
+    let after = dir.join(format!("crabby_restart_after_{}.json", std::process::id()));
+    let options = EngineOptions {
+        config: Arc::new(Config {
+            snapshot_path: Some(snapshot.clone()),
+            ..Config::default()
+        }),
+    };
+
+    let engine = KvEngine::start(options.clone());
+    for i in 0..KEYS {
+        engine
+            .execute(&format!("SET key:{} value:{}", i, i))
+            .unwrap();
+    }
+    engine.execute("SETCRC doc checked").unwrap();
+    engine
+        .execute(&format!("DUMPJSON {}", before.display()))
+        .unwrap();
+    assert_eq!(
+        engine.execute("SHUTDOWN SAVE").unwrap(),
+        "OK (saved 100001 keys)"
+    );
+    // Nothing is applied after the shutdown
+    assert_eq!(
+        engine.execute("SET late 1"),
+        Err(EngineError::Handler(HandlerError::ShuttingDown))
+    );
+    let summary = engine.shutdown();
+    assert_eq!(summary.recovery, None);
+
+    let engine = KvEngine::start(options);
+    assert!(engine.execute("INFO").unwrap().starts_with("keys:100001\n"));
+    assert_eq!(engine.execute("GETCRC doc").unwrap(), "checked");
+    engine
+        .execute(&format!("DUMPJSON {}", after.display()))
+        .unwrap();
+    let summary = engine.shutdown();
+
+    let dumped_before = std::fs::read(&before).unwrap();
+    let dumped_after = std::fs::read(&after).unwrap();
+    for path in [&snapshot, &before, &after] {
+        std::fs::remove_file(path).unwrap();
+    }
+    assert!(
+        dumped_before == dumped_after,
+        "store changed across restart"
+    );
+
+    let recovery = summary
+        .recovery
+        .expect("restart should restore the snapshot");
+    assert_eq!(recovery.keys, KEYS + 1);
+    assert!(recovery.snapshot_load > Duration::ZERO);
+    assert!(recovery.ready >= recovery.snapshot_load);
+}
+
+#[test]
+fn test_corrupt_snapshot_refuses_commands() {
+    let snapshot = std::env::temp_dir().join(format!(
+        "crabby_corrupt_restart_{}.crkvs",
+        std::process::id()
+    ));
+    std::fs::write(&snapshot, b"not a snapshot").unwrap();
+
+    let engine = KvEngine::start(EngineOptions {
+        config: Arc::new(Config {
+            snapshot_path: Some(snapshot.clone()),
+            ..Config::default()
+        }),
+    });
+    assert_eq!(
+        engine.execute("SET a 1"),
+        Err(EngineError::Handler(HandlerError::ShuttingDown))
+    );
+    let summary = engine.shutdown();
+
+    // The unreadable snapshot is left for someone to look at
+    assert_eq!(std::fs::read(&snapshot).unwrap(), b"not a snapshot");
+    std::fs::remove_file(&snapshot).unwrap();
+    assert_eq!(summary.hard_errors, 1);
+    assert_eq!(summary.recovery, None);
+}
+
+/// REVERSE key: reverse the string stored at key
+fn reverse(store: &mut CustomStore, args: &[String]) -> Result<String, HandlerError> {
+    let key = args.first().map(String::as_str).unwrap_or("");
+    let reversed: String = match store.get(key) {
+        Some(Value::Str(value)) => value.chars().rev().collect(),
+        Some(_) => return Err(HandlerError::WrongType),
+        None => return Err(HandlerError::not_found(key)),
+    };
+    store.set(key, Value::Str(reversed.clone()))?;
+    Ok(reversed)
+}
+
+#[test]
+fn test_registered_command_reverses_a_string() {
+    let engine = KvEngine::start(EngineOptions::default());
+    engine.register_command("REVERSE", reverse).unwrap();
+
+    engine.execute("SET greeting hello").unwrap();
+    assert_eq!(engine.execute("REVERSE greeting").unwrap(), "olleh");
+    assert_eq!(
+        engine.execute("GET greeting").unwrap(),
+        "GET greeting = olleh"
+    );
+    assert_eq!(
+        engine.execute("REVERSE missing"),
+        Err(EngineError::Handler(HandlerError::not_found("missing")))
+    );
+    engine.execute("SETCRC checked v").unwrap();
+    assert_eq!(
+        engine.execute("REVERSE checked"),
+        Err(EngineError::Handler(HandlerError::WrongType))
+    );
+    engine.shutdown();
+}
+
+fn engine_with_read_snapshot(max_age: Duration) -> KvEngine {
+    let config = Config {
+        read_snapshot_max_age: Some(max_age),
+        ..Config::default()
+    };
+    KvEngine::start(EngineOptions {
+        config: Arc::new(config),
+    })
+}
+
+#[test]
+fn test_read_snapshot_answers_reads_within_the_max_age() {
+    let engine = engine_with_read_snapshot(Duration::from_secs(5));
+
+    engine.execute("SET a 1").unwrap();
+    // The copy published at startup is young enough to answer, so a plain
+    // GET doesn't see the write yet; FRESH goes through the main thread
+    assert_eq!(
+        engine.execute("GET a"),
+        Err(EngineError::Handler(HandlerError::not_found("a")))
+    );
+    assert_eq!(engine.execute("GET a FRESH").unwrap(), "GET a = 1");
+
+    // Once the main thread stops, no read is answered from the copy
+    engine.execute("SHUTDOWN").unwrap();
+    assert_eq!(
+        engine.execute("GET a"),
+        Err(EngineError::Handler(HandlerError::ShuttingDown))
+    );
+
+    let summary = engine.shutdown();
+    assert_eq!(summary.processed, 5);
+    assert_eq!(summary.snapshot_reads, 1);
+}
+
+#[test]
+fn test_inspect_counts_reads_with_a_read_snapshot() {
+    let config = Config {
+        read_snapshot_max_age: Some(Duration::from_secs(5)),
+        track_key_access: true,
+        ..Config::default()
+    };
+    let engine = KvEngine::start(EngineOptions {
+        config: Arc::new(config),
+    });
+
+    engine.execute("SET a 1").unwrap();
+    // The copy published at startup would still answer, without the key;
+    // the GET reaches the main thread instead and is counted
+    assert_eq!(engine.execute("GET a").unwrap(), "GET a = 1");
+    let inspect = engine.execute("INSPECT a").unwrap();
+    assert!(inspect.ends_with("\naccess_count:1"), "{}", inspect);
+    engine.shutdown();
+}
+
+#[test]
+fn test_read_snapshot_is_never_older_than_the_max_age() {
+    let max_age = Duration::from_millis(50);
+    let engine = engine_with_read_snapshot(max_age);
+
+    for round in 0..5 {
+        engine.execute(&format!("SET a {}", round)).unwrap();
+        thread::sleep(max_age * 2);
+        // The copy is past its max age, so this read goes through the main
+        // thread, which retakes the copy for the one after it
+        for _ in 0..2 {
+            assert_eq!(
+                engine.execute("GET a").unwrap(),
+                format!("GET a = {}", round)
+            );
+        }
+    }
+
+    let summary = engine.shutdown();
+    assert_eq!(summary.snapshot_reads, 5);
+}
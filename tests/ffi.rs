@@ -0,0 +1,44 @@
+//! Compiles `tests/ffi/smoke.c` against the static library and runs it
+//!
+//! Run with `cargo test --features ffi`.
+#![cfg(all(feature = "ffi", target_os = "linux"))]
+
+use std::path::PathBuf;
+use std::process::Command;
+
+#[test]
+fn test_c_program_set_get_delete() {
+    let manifest_dir = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let tmp_dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+
+    // Build the static library into a private target dir: the one cargo
+    // leaves in target/debug belongs to whichever feature set built last
+    let target_dir = tmp_dir.join("ffi-target");
+    let status = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--features", "ffi", "--target-dir"])
+        .arg(&target_dir)
+        .current_dir(&manifest_dir)
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success(), "building the static library failed");
+    let library = target_dir.join("debug/libmulti_threader.a");
+
+    let program = tmp_dir.join("crabby_ffi_smoke");
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .arg(manifest_dir.join("tests/ffi/smoke.c"))
+        .arg("-I")
+        .arg(manifest_dir.join("include"))
+        .arg(&library)
+        .args(["-lpthread", "-ldl", "-lm", "-lrt", "-lutil", "-o"])
+        .arg(&program)
+        .status()
+        .expect("failed to run the C compiler");
+    assert!(status.success(), "compiling smoke.c failed");
+
+    let output = Command::new(&program).output().unwrap();
+    assert!(
+        output.status.success(),
+        "smoke test failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,43 @@
+/* Exercises the C API end to end; run by tests/ffi.rs */
+#include <stdio.h>
+#include <string.h>
+
+#include "crabby.h"
+
+static int failures = 0;
+
+static void expect(CrabbyEngine *engine, const char *line, int32_t status, const char *text)
+{
+    char buf[128];
+    int32_t got = crabby_execute(engine, line, buf, sizeof buf);
+    if (got != status || strcmp(buf, text) != 0) {
+        fprintf(stderr, "%s: expected (%d, \"%s\"), got (%d, \"%s\")\n",
+                line, status, text, got, buf);
+        failures++;
+    }
+}
+
+int main(void)
+{
+    CrabbyOptions options = { .store_initial_capacity = 16 };
+    CrabbyEngine *engine = crabby_open(&options);
+    if (engine == NULL) {
+        fprintf(stderr, "crabby_open failed\n");
+        return 1;
+    }
+
+    expect(engine, "SET user:1001 John", CRABBY_OK, "SET user:1001 = John");
+    expect(engine, "GET user:1001", CRABBY_OK, "GET user:1001 = John");
+    expect(engine, "DELETE user:1001", CRABBY_OK, "DELETED user:1001 (was: John)");
+    expect(engine, "GET user:1001", CRABBY_ERR_SOFT, "Key 'user:1001' not found");
+
+    if (crabby_execute(engine, NULL, NULL, 0) != CRABBY_ERR_NULL_ARG) {
+        fprintf(stderr, "NULL line was not rejected\n");
+        failures++;
+    }
+
+    crabby_close(engine);
+    crabby_close(NULL);
+
+    return failures == 0 ? 0 : 1;
+}